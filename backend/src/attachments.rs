@@ -0,0 +1,678 @@
+//E-book files attached to books: EPUB and PDF uploads, ranged downloads and EPUB metadata
+
+use crate::{
+    authors,
+    book_from_row,
+    book_write_error,
+    covers,
+    get_header,
+    get_id,
+    get_path_segment,
+    get_query_param,
+    isbn,
+    language,
+    multipart,
+    normalize_book,
+    storage,
+    tag_matches,
+    with_content_type,
+    with_header,
+    write_audit,
+    Book,
+    RequestContext,
+    BAD_REQUEST,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    NOT_MODIFIED,
+    OK_RESPONSE,
+    PARTIAL_CONTENT,
+    PAYLOAD_TOO_LARGE,
+    RANGE_NOT_SATISFIABLE,
+    UNSUPPORTED_MEDIA_TYPE,
+};
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, NoTls, Row, Transaction };
+use quick_xml::events::{ BytesStart, Event };
+use quick_xml::Reader;
+use serde_json::{ json, Value };
+use std::collections::HashMap;
+use std::env;
+use std::io::{ Cursor, Read };
+use zip::ZipArchive;
+
+//File formats accepted, with their media types and file extensions
+const FILE_FORMATS: [(&str, &str, &str); 2] = [("epub", "application/epub+zip", "epub"), ("pdf", "application/pdf", "pdf")];
+
+//Storage prefix uploads are written under until their row is committed
+const STAGING_PREFIX: &str = "upload-staging";
+
+//Largest part of an EPUB read while looking for metadata, so a zip bomb cannot exhaust memory
+const MAX_EPUB_ENTRY: u64 = 16 * 1024 * 1024;
+
+//File attached to a book as stored
+#[derive(Serialize)]
+struct BookFile {
+    id: i32,
+    book_id: i32,
+    format: String,
+    content_type: String,
+    filename: String,
+    byte_size: i64,
+    checksum: String,
+    uploaded_at: Option<DateTime<Utc>>,
+}
+
+//Uploaded file as its row records it
+struct Upload {
+    format: &'static str,
+    filename: String,
+    byte_size: i64,
+    checksum: String,
+}
+
+//map book_files row to BookFile
+fn file_from_row(row: &Row) -> BookFile {
+    BookFile {
+        id: row.get(0),
+        book_id: row.get(1),
+        format: row.get(2),
+        content_type: row.get(3),
+        filename: row.get(4),
+        byte_size: row.get(5),
+        checksum: row.get(6),
+        uploaded_at: row.get(7),
+    }
+}
+
+//Bibliographic fields of an EPUB's OPF package, shaped like Book so it can be posted back as one
+#[derive(Serialize, Default)]
+struct EpubMetadata {
+    title: Option<String>,
+    author: Option<String>,
+    authors: Vec<String>,
+    isbn10: Option<String>,
+    isbn13: Option<String>,
+    publisher: Option<String>,
+    publication_year: Option<i32>,
+    language: Option<String>,
+    format: String,
+    #[serde(skip)]
+    cover: Option<(Vec<u8>, String)>,
+}
+
+//largest file accepted in bytes, from FILE_MAX_BYTES (default 50 MiB)
+fn max_file_bytes() -> usize {
+    env::var("FILE_MAX_BYTES").ok().and_then(|bytes| bytes.parse().ok()).unwrap_or(50 * 1024 * 1024)
+}
+
+//storage key of a book's file in a format
+pub fn storage_key(book_id: i32, format: &str) -> String {
+    format!("files/{}/{}", book_id, format)
+}
+
+//media type of a format
+pub fn content_type(format: &str) -> &'static str {
+    FILE_FORMATS.iter().find(|(name, _, _)| *name == format).map(|(_, content_type, _)| *content_type).unwrap_or("application/octet-stream")
+}
+
+//read one entry of a zip archive, refusing entries larger than MAX_EPUB_ENTRY
+fn read_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>, String> {
+    let entry = archive.by_name(name).map_err(|_| format!("{} is missing", name))?;
+    if entry.size() > MAX_EPUB_ENTRY {
+        return Err(format!("{} is too large", name));
+    }
+    let mut data = Vec::new();
+    entry.take(MAX_EPUB_ENTRY).read_to_end(&mut data).map_err(|e| format!("{} is unreadable: {}", name, e))?;
+    Ok(data)
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name.as_bytes())
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.trim().to_string())
+}
+
+//resolve a manifest href against the directory of the OPF file
+fn resolve_href(opf_path: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut parts: Vec<&str> = opf_path.split('/').collect();
+    parts.pop();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    //hrefs are URLs, so a space arrives as %20
+    parts.join("/").replace("%20", " ")
+}
+
+//read the OPF package of an EPUB: title, creators, language, ISBN, publisher, date and cover
+fn read_epub(data: &[u8]) -> Result<EpubMetadata, String> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(|e| format!("not a zip archive: {}", e))?;
+    if read_entry(&mut archive, "mimetype")?.trim_ascii() != b"application/epub+zip" {
+        return Err("mimetype is not application/epub+zip".to_string());
+    }
+
+    //the container names the package document
+    let container = read_entry(&mut archive, "META-INF/container.xml")?;
+    let mut reader = Reader::from_reader(container.as_slice());
+    let mut buffer = Vec::new();
+    let mut opf_path = None;
+    loop {
+        match reader.read_event_into(&mut buffer).map_err(|e| format!("container.xml is invalid: {}", e))? {
+            Event::Start(element) | Event::Empty(element) if element.local_name().as_ref() == b"rootfile" => {
+                opf_path = attribute(&element, "full-path");
+                break;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+    let opf_path = opf_path.ok_or("container.xml names no package document")?;
+    let opf = read_entry(&mut archive, &opf_path)?;
+
+    let mut metadata = EpubMetadata { format: "ebook".to_string(), ..EpubMetadata::default() };
+    let mut creators: Vec<(Option<String>, Option<String>, String)> = Vec::new();
+    let mut roles: HashMap<String, String> = HashMap::new();
+    let mut identifiers: Vec<(Option<String>, String)> = Vec::new();
+    let mut dates = Vec::new();
+    let mut manifest: Vec<(String, String, String, String)> = Vec::new();
+    let mut cover_id = None;
+
+    let mut reader = Reader::from_reader(opf.as_slice());
+    let mut buffer = Vec::new();
+    let mut current: Option<(String, BytesStart<'static>)> = None;
+    let mut text = String::new();
+    loop {
+        let event = reader.read_event_into(&mut buffer).map_err(|e| format!("{} is invalid: {}", opf_path, e))?;
+        match event {
+            Event::Start(element) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).to_string();
+                current = Some((name, element.into_owned()));
+                text.clear();
+            }
+            Event::Empty(element) =>
+                match element.local_name().as_ref() {
+                    b"item" =>
+                        manifest.push((
+                            attribute(&element, "id").unwrap_or_default(),
+                            attribute(&element, "href").unwrap_or_default(),
+                            attribute(&element, "media-type").unwrap_or_default(),
+                            attribute(&element, "properties").unwrap_or_default(),
+                        )),
+                    //EPUB 2 names the cover image with <meta name="cover" content="item id"/>
+                    b"meta" if attribute(&element, "name").as_deref() == Some("cover") => cover_id = attribute(&element, "content"),
+                    _ => {}
+                }
+            Event::Text(content) => text.push_str(&content.unescape().map_err(|e| e.to_string())?),
+            Event::CData(content) => text.push_str(&String::from_utf8_lossy(&content)),
+            Event::End(_) => {
+                if let Some((name, element)) = current.take() {
+                    let value = text.trim().to_string();
+                    match name.as_str() {
+                        "title" if metadata.title.is_none() && !value.is_empty() => metadata.title = Some(value),
+                        "creator" if !value.is_empty() => creators.push((attribute(&element, "id"), attribute(&element, "role"), value)),
+                        //"en-US" and the like become the plain language code the catalog keeps
+                        "language" if metadata.language.is_none() =>
+                            metadata.language = language::normalize(value.split(['-', '_']).next().unwrap_or_default()).ok(),
+                        "identifier" => identifiers.push((attribute(&element, "scheme"), value)),
+                        "publisher" if metadata.publisher.is_none() && !value.is_empty() => metadata.publisher = Some(value),
+                        "date" => dates.push(value),
+                        //EPUB 3 gives creator roles as <meta refines="#id" property="role">aut</meta>
+                        "meta" if attribute(&element, "property").as_deref() == Some("role") => {
+                            if let Some(id) = attribute(&element, "refines") {
+                                roles.insert(id.trim_start_matches('#').to_string(), value);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                text.clear();
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+        buffer.clear();
+    }
+
+    //authors are creators with no role or the role "aut"; illustrators and editors are left out
+    metadata.authors = creators
+        .into_iter()
+        .filter(|(id, role, _)| {
+            let role = role.clone().or_else(|| id.as_ref().and_then(|id| roles.get(id).cloned()));
+            role.is_none_or(|role| role == "aut")
+        })
+        .map(|(_, _, name)| authors::display_name(&name))
+        .collect();
+    if !metadata.authors.is_empty() {
+        metadata.author = Some(metadata.authors.join("; "));
+    }
+    //an identifier counts as an ISBN when it says so or when it checks out as one
+    metadata.isbn13 = identifiers
+        .iter()
+        .map(|(scheme, value)| (scheme, value.trim_start_matches("urn:isbn:").trim_start_matches("URN:ISBN:")))
+        .filter(|(scheme, value)| scheme.as_deref().is_some_and(|scheme| scheme.eq_ignore_ascii_case("isbn")) || isbn::parse(value).is_ok())
+        .find_map(|(_, value)| isbn::parse(value).ok());
+    metadata.isbn10 = metadata.isbn13.as_deref().and_then(isbn::to_isbn10);
+    metadata.publication_year = dates.iter().find_map(|date| date.get(..4).and_then(|year| year.parse().ok()));
+
+    //EPUB 3 marks the cover in the manifest; EPUB 2 points at it from <meta name="cover">
+    let cover = manifest
+        .iter()
+        .find(|(_, _, _, properties)| properties.split_whitespace().any(|property| property == "cover-image"))
+        .or_else(|| manifest.iter().find(|(id, _, _, _)| Some(id) == cover_id.as_ref()))
+        .filter(|(_, _, media_type, _)| media_type.starts_with("image/"));
+    if let Some((_, href, media_type, _)) = cover {
+        metadata.cover = read_entry(&mut archive, &resolve_href(&opf_path, href)).ok().map(|data| (data, media_type.clone()));
+    }
+    Ok(metadata)
+}
+
+//work out the format of an upload from its bytes; EPUBs are read for their metadata on the way
+fn identify(data: &[u8]) -> Result<(&'static str, Option<EpubMetadata>), (String, String)> {
+    if data.starts_with(b"%PDF-") {
+        return Ok(("pdf", None));
+    }
+    if data.starts_with(b"PK\x03\x04") {
+        return match read_epub(data) {
+            Ok(metadata) => Ok(("epub", Some(metadata))),
+            Err(e) => Err((UNSUPPORTED_MEDIA_TYPE.to_string(), format!("Not a valid EPUB: {}", e))),
+        };
+    }
+    Err((UNSUPPORTED_MEDIA_TYPE.to_string(), "File must be an EPUB or a PDF".to_string()))
+}
+
+//the upload: a multipart `file` field, or the raw body named by ?filename=
+fn get_upload(request: &str, body: &[u8]) -> Result<(Vec<u8>, Option<String>), (String, String)> {
+    if get_header(request, "Content-Type").unwrap_or_default().to_lowercase().starts_with("multipart/form-data") {
+        match multipart::parse(request, body).map(|parts| multipart::take_file(parts, "file")) {
+            Ok(Some(file)) => Ok((file.data, file.filename)),
+            Ok(None) => Err((BAD_REQUEST.to_string(), "No file in upload".to_string())),
+            Err(e) => Err((BAD_REQUEST.to_string(), e)),
+        }
+    } else {
+        Ok((body.to_vec(), get_query_param(request, "filename")))
+    }
+}
+
+//check an upload against its limits and any checksum the client sent with it
+fn check_upload(request: &str, data: &[u8]) -> Result<String, (String, String)> {
+    if data.is_empty() {
+        return Err((BAD_REQUEST.to_string(), "File is empty".to_string()));
+    }
+    if data.len() > max_file_bytes() {
+        return Err((PAYLOAD_TOO_LARGE.to_string(), format!("File may be at most {} bytes", max_file_bytes())));
+    }
+    let checksum = covers::checksum(data);
+    if let Some(expected) = get_header(request, "X-Checksum-Sha256") {
+        if !expected.eq_ignore_ascii_case(&checksum) {
+            return Err((BAD_REQUEST.to_string(), format!("Checksum mismatch: received file has SHA-256 {}", checksum)));
+        }
+    }
+    Ok(checksum)
+}
+
+//fill the blank fields of a book from EPUB metadata; existing values are never overwritten.
+//Returns the names of the fields filled in.
+fn prefill_book(transaction: &mut Transaction, context: &RequestContext, current: &Book, metadata: &EpubMetadata) -> Result<Vec<String>, (String, String)> {
+    let mut book: Book = serde_json::from_value(serde_json::to_value(current).unwrap()).unwrap();
+    let mut filled = Vec::new();
+    if book.isbn13.is_none() && book.isbn10.is_none() && metadata.isbn13.is_some() {
+        book.isbn13 = metadata.isbn13.clone();
+        filled.push("isbn13".to_string());
+    }
+    if book.language.is_none() && metadata.language.is_some() {
+        book.language = metadata.language.clone();
+        filled.push("language".to_string());
+    }
+    if book.publisher.is_none() && metadata.publisher.is_some() {
+        book.publisher = metadata.publisher.clone();
+        filled.push("publisher".to_string());
+    }
+    if book.publication_year.is_none() && metadata.publication_year.is_some() {
+        book.publication_year = metadata.publication_year;
+        filled.push("publication_year".to_string());
+    }
+    if book.format.is_none() {
+        book.format = Some("ebook".to_string());
+        filled.push("format".to_string());
+    }
+    //a year out of range is dropped rather than failing the upload
+    if normalize_book(&mut book).is_err() {
+        book.publication_year = current.publication_year;
+        filled.retain(|field| field != "publication_year");
+        normalize_book(&mut book).map_err(|e| (BAD_REQUEST.to_string(), e))?;
+    }
+    if filled.is_empty() {
+        return Ok(filled);
+    }
+
+    let id = current.id.unwrap_or_default();
+    let row = transaction
+        .query_one(
+            "UPDATE books SET isbn10 = $1, isbn13 = $2, publisher = $3, publication_year = $4, language = $5, format = $6, version = version + 1 WHERE id = $7 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+            &[&book.isbn10, &book.isbn13, &book.publisher, &book.publication_year, &book.language, &book.format, &id]
+        )
+        .map_err(book_write_error)?;
+    let after = book_from_row(&row);
+    write_audit(transaction, context, "update", "books", id, Some(current), Some(&after)).map_err(|_| (INTERNAL_ERROR.to_string(), "Internal error".to_string()))?;
+    Ok(filled)
+}
+
+//handle post file request: attach an EPUB or PDF to a book, replacing any earlier file in that format.
+//EPUB metadata fills the book's blank fields and supplies a missing cover unless ?prefill=false.
+pub fn handle_post_file_request(request: &str, body: &[u8], context: &RequestContext) -> (String, String) {
+    let id = match get_id(request).parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => return (BAD_REQUEST.to_string(), "Invalid book id".to_string()),
+    };
+    let (data, filename) = match get_upload(request, body) {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    let checksum = match check_upload(request, &data) {
+        Ok(checksum) => checksum,
+        Err(e) => return e,
+    };
+    let (format, metadata) = match identify(&data) {
+        Ok(identified) => identified,
+        Err(e) => return e,
+    };
+    let prefill = get_query_param(request, "prefill").as_deref() != Some("false");
+
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+    let book = match transaction.query_opt("SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+        Ok(Some(row)) => book_from_row(&row),
+        Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+
+    //the upload is staged and takes the place of any earlier file only once its row is committed,
+    //so a failed upload neither loses the earlier file nor leaves a row pointing at a file that is not there
+    let storage = storage::configured_storage();
+    let key = storage_key(id, format);
+    let staging = format!("{}/{}", STAGING_PREFIX, key);
+    if let Err(e) = storage.put(&staging, &data) {
+        eprintln!("Unable to store file for book {}: {}", id, e);
+        return (INTERNAL_ERROR.to_string(), "Failed to store file".to_string());
+    }
+    let extension = FILE_FORMATS.iter().find(|(name, _, _)| *name == format).map(|(_, _, extension)| *extension).unwrap_or(format);
+    let upload = Upload {
+        format,
+        filename: filename.filter(|filename| !filename.trim().is_empty()).unwrap_or_else(|| format!("book-{}.{}", id, extension)),
+        byte_size: data.len() as i64,
+        checksum,
+    };
+    let attached = attach_file(&mut transaction, context, &book, &upload, metadata.as_ref(), prefill)
+        .and_then(|attached| match transaction.commit() {
+            Ok(()) => Ok(attached),
+            Err(_) => Err((INTERNAL_ERROR.to_string(), "Internal error".to_string())),
+        })
+        .map(|attached| {
+            //a cover taken from the EPUB is staged the same way; failing to place it does not undo the upload
+            if !attached["cover"].is_null() {
+                let _ = covers::publish_cover(context, id);
+            }
+            attached
+        })
+        .and_then(|attached| match storage.rename(&staging, &key) {
+            Ok(()) => Ok(attached),
+            Err(e) => {
+                eprintln!("Unable to store file for book {}: {}", id, e);
+                Err((INTERNAL_ERROR.to_string(), "Failed to store file".to_string()))
+            }
+        });
+    match attached {
+        Ok(response) => (OK_RESPONSE.to_string(), response.to_string()),
+        Err(e) => {
+            if let Err(e) = storage.delete(&staging) {
+                eprintln!("Unable to delete staged file of book {}: {}", id, e);
+            }
+            covers::discard_cover(context, id);
+            e
+        }
+    }
+}
+
+//record an uploaded file of a book locked by the caller, prefilling the book from EPUB metadata if asked
+fn attach_file(
+    transaction: &mut Transaction,
+    context: &RequestContext,
+    book: &Book,
+    upload: &Upload,
+    metadata: Option<&EpubMetadata>,
+    prefill: bool
+) -> Result<Value, (String, String)> {
+    let internal = |_| (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+    let id = book.id.unwrap_or_default();
+    let before = transaction
+        .query_opt("SELECT id, book_id, format, content_type, filename, byte_size, checksum, uploaded_at FROM book_files WHERE book_id = $1 AND format = $2", &[&id, &upload.format])
+        .map_err(internal)?
+        .map(|row| file_from_row(&row));
+    let row = transaction
+        .query_one(
+            "INSERT INTO book_files (book_id, format, content_type, filename, byte_size, checksum) VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (book_id, format) DO UPDATE SET content_type = EXCLUDED.content_type, filename = EXCLUDED.filename, byte_size = EXCLUDED.byte_size, checksum = EXCLUDED.checksum, uploaded_at = now()
+            RETURNING id, book_id, format, content_type, filename, byte_size, checksum, uploaded_at",
+            &[&id, &upload.format, &content_type(upload.format), &upload.filename, &upload.byte_size, &upload.checksum]
+        )
+        .map_err(internal)?;
+    let file = file_from_row(&row);
+    let action = if before.is_some() { "update" } else { "create" };
+    write_audit(transaction, context, action, "book_files", file.id, before.as_ref(), Some(&file)).map_err(internal)?;
+
+    //prefilling is a convenience: if it cannot be done, the upload still stands
+    let mut prefilled = Vec::new();
+    let mut cover = None;
+    if let (true, Some(metadata)) = (prefill, metadata) {
+        let mut savepoint = transaction.transaction().map_err(internal)?;
+        match prefill_book(&mut savepoint, context, book, metadata) {
+            Ok(fields) => {
+                savepoint.commit().map_err(internal)?;
+                prefilled = fields;
+            }
+            Err((_, e)) => {
+                savepoint.rollback().map_err(internal)?;
+                eprintln!("Prefill of book {} skipped: {}", id, e);
+            }
+        }
+        let has_cover = transaction.query_opt("SELECT 1 FROM book_covers WHERE book_id = $1", &[&id]).map_err(internal)?.is_some();
+        if let (false, Some((data, media_type))) = (has_cover, &metadata.cover) {
+            let mut savepoint = transaction.transaction().map_err(internal)?;
+            match covers::store_cover(&mut savepoint, context, id, data, Some(media_type)) {
+                Ok(stored) => {
+                    savepoint.commit().map_err(internal)?;
+                    cover = Some(stored);
+                }
+                Err((_, e)) => {
+                    savepoint.rollback().map_err(internal)?;
+                    eprintln!("Cover from EPUB for book {} skipped: {}", id, e);
+                }
+            }
+        }
+    }
+    Ok(json!({ "file": file, "metadata": metadata, "prefilled": prefilled, "cover": cover }))
+}
+
+//handle file metadata request: read an EPUB without storing it, to prefill a new book
+pub fn handle_file_metadata_request(request: &str, body: &[u8]) -> (String, String) {
+    let (data, _) = match get_upload(request, body) {
+        Ok(upload) => upload,
+        Err(e) => return e,
+    };
+    let checksum = match check_upload(request, &data) {
+        Ok(checksum) => checksum,
+        Err(e) => return e,
+    };
+    match identify(&data) {
+        Ok((format, metadata)) => {
+            let has_cover = metadata.as_ref().is_some_and(|metadata| metadata.cover.is_some());
+            let response = json!({
+                "format": format,
+                "content_type": content_type(format),
+                "byte_size": data.len(),
+                "checksum": checksum,
+                "metadata": metadata,
+                "has_cover": has_cover,
+            });
+            (OK_RESPONSE.to_string(), response.to_string())
+        }
+        Err(e) => e,
+    }
+}
+
+//handle get book files request
+pub fn handle_get_book_files_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            match client.query_opt("SELECT 1 FROM books WHERE id = $1 AND deleted_at IS NULL", &[&id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+            match client.query("SELECT id, book_id, format, content_type, filename, byte_size, checksum, uploaded_at FROM book_files WHERE book_id = $1 ORDER BY format", &[&id]) {
+                Ok(rows) => {
+                    let files: Vec<BookFile> = rows.iter().map(file_from_row).collect();
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&files).unwrap())
+                }
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid book id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//byte range of a Range header over a file of `size` bytes.
+//None means serve the whole file: no header, another unit, or several ranges. Err means unsatisfiable.
+fn parse_range(header: Option<&str>, size: usize) -> Result<Option<(usize, usize)>, ()> {
+    let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return Ok(None),
+    };
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let range = match (start.trim().parse::<usize>().ok(), end.trim().parse::<usize>().ok()) {
+        //the last n bytes
+        (None, Some(suffix)) if start.trim().is_empty() && suffix > 0 => (size.saturating_sub(suffix), size.checked_sub(1).ok_or(())?),
+        (Some(start), None) if end.trim().is_empty() => (start, size.checked_sub(1).ok_or(())?),
+        (Some(start), Some(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        _ => return Err(()),
+    };
+    if range.0 >= size { Err(()) } else { Ok(Some(range)) }
+}
+
+//filename for Content-Disposition, kept to characters that need no quoting
+fn safe_filename(filename: &str) -> String {
+    filename
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || " .-_()".contains(c) { c } else { '_' })
+        .collect()
+}
+
+//handle get file request: download with Range support for resuming and for readers that page through PDFs
+pub fn handle_get_file_request(request: &str) -> (String, Vec<u8>) {
+    let format = get_path_segment(request, 6).to_string();
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let file = match
+                client.query_opt(
+                    "SELECT f.id, f.book_id, f.format, f.content_type, f.filename, f.byte_size, f.checksum, f.uploaded_at FROM book_files f JOIN books b ON b.id = f.book_id WHERE f.book_id = $1 AND f.format = $2 AND b.deleted_at IS NULL",
+                    &[&id, &format]
+                )
+            {
+                Ok(Some(row)) => file_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), b"File not found".to_vec()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+            };
+            let tag = format!("\"{}\"", file.checksum);
+            if matches!(get_header(request, "If-None-Match"), Some(header) if tag_matches(header, &tag, true)) {
+                return (with_header(NOT_MODIFIED, "ETag", &tag), Vec::new());
+            }
+
+            let data = match storage::configured_storage().get(&storage_key(id, &format)) {
+                Ok(Some(data)) => data,
+                Ok(None) => return (NOT_FOUND.to_string(), b"File missing from storage".to_vec()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+            };
+            //If-Range: a range of a file that has changed since would be spliced into the wrong bytes
+            let range_header = match get_header(request, "If-Range") {
+                Some(if_range) if !tag_matches(if_range, &tag, false) => None,
+                _ => get_header(request, "Range"),
+            };
+            let range = match parse_range(range_header, data.len()) {
+                Ok(range) => range,
+                Err(_) => {
+                    let status_line = with_header(RANGE_NOT_SATISFIABLE, "Content-Range", &format!("bytes */{}", data.len()));
+                    return (status_line, Vec::new());
+                }
+            };
+
+            let status_line = match range {
+                Some(_) => with_content_type(PARTIAL_CONTENT, &file.content_type),
+                None => with_content_type(OK_RESPONSE, &file.content_type),
+            };
+            let status_line = with_header(&status_line, "ETag", &tag);
+            let status_line = with_header(&status_line, "Accept-Ranges", "bytes");
+            let status_line = with_header(&status_line, "Content-Disposition", &format!("attachment; filename=\"{}\"", safe_filename(&file.filename)));
+            match range {
+                Some((start, end)) => {
+                    let status_line = with_header(&status_line, "Content-Range", &format!("bytes {}-{}/{}", start, end, data.len()));
+                    (with_header(&status_line, "Content-Length", &(end - start + 1).to_string()), data[start..=end].to_vec())
+                }
+                None => (with_header(&status_line, "Content-Length", &data.len().to_string()), data),
+            }
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), b"Invalid book id".to_vec()),
+        _ => (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+    }
+}
+
+//handle delete file request
+pub fn handle_delete_file_request(request: &str, context: &RequestContext) -> (String, String) {
+    let format = get_path_segment(request, 6).to_string();
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            let before = match
+                transaction.query_opt(
+                    "DELETE FROM book_files WHERE book_id = $1 AND format = $2 RETURNING id, book_id, format, content_type, filename, byte_size, checksum, uploaded_at",
+                    &[&id, &format]
+                )
+            {
+                Ok(Some(row)) => file_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "File not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            if write_audit(&mut transaction, context, "delete", "book_files", before.id, Some(&before), None).and_then(|_| transaction.commit()).is_err() {
+                return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+            }
+
+            //the file goes after the row; a leftover file is harmless, a dangling row is not
+            if let Err(e) = storage::configured_storage().delete(&storage_key(id, &format)) {
+                eprintln!("Unable to delete file of book {}: {}", id, e);
+            }
+            (OK_RESPONSE.to_string(), "File deleted".to_string())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid book id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
@@ -0,0 +1,710 @@
+//Authors and their links to books
+
+use crate::{
+    apply_merge_patch,
+    book_from_row,
+    etag,
+    get_cascade,
+    get_id,
+    get_include_deleted,
+    get_patch_request_body,
+    get_query_param,
+    get_timestamp_param,
+    internal_error,
+    not_modified,
+    precondition_failed,
+    with_header,
+    write_audit,
+    Book,
+    RequestContext,
+    BAD_REQUEST,
+    CONFLICT,
+    DB_URL,
+    FORBIDDEN,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    NOT_MODIFIED,
+    OK_RESPONSE,
+    PRECONDITION_FAILED,
+};
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, NoTls, Row, Transaction };
+use postgres::Error as PostgresError;
+use serde_json::{ json, Value };
+
+//Roles an author can have on a book
+pub const ROLES: [&str; 4] = ["author", "editor", "translator", "illustrator"];
+
+//Author struct with id, name, sort name, life years, bio and version
+#[derive(Serialize, Deserialize)]
+pub struct Author {
+    pub id: Option<i32>,
+    pub name: String,
+    pub sort_name: Option<String>,
+    pub birth_year: Option<i32>,
+    pub death_year: Option<i32>,
+    pub bio: Option<String>,
+    pub version: Option<i32>,
+    #[serde(skip_deserializing)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+//Author as credited on a book
+#[derive(Serialize)]
+struct BookCredit {
+    role: String,
+    position: i32,
+    author: Author,
+}
+
+//Book an author is credited on
+#[derive(Serialize)]
+struct AuthorCredit {
+    role: String,
+    position: i32,
+    book: Book,
+}
+
+//Requested link between a book and an author
+#[derive(Serialize, Deserialize)]
+struct CreditRequest {
+    author_id: i32,
+    #[serde(default = "default_role")]
+    role: String,
+}
+
+fn default_role() -> String {
+    "author".to_string()
+}
+
+//map authors row to Author
+pub fn author_from_row(row: &Row) -> Author {
+    Author {
+        id: row.get(0),
+        name: row.get(1),
+        sort_name: row.get(2),
+        birth_year: row.get(3),
+        death_year: row.get(4),
+        bio: row.get(5),
+        version: row.get(6),
+        created_at: row.get(7),
+        updated_at: row.get(8),
+        deleted_at: row.get(9),
+    }
+}
+
+//"Herbert, Frank" -> "Frank Herbert"; other names are only trimmed
+pub fn display_name(name: &str) -> String {
+    match name.split(',').map(str::trim).collect::<Vec<_>>().as_slice() {
+        [last, first] if !last.is_empty() && !first.is_empty() => format!("{} {}", first, last),
+        _ => name.trim().to_string(),
+    }
+}
+
+//"Frank Herbert" -> "Herbert, Frank"
+pub fn sort_name(name: &str) -> String {
+    let name = display_name(name);
+    match name.rsplit_once(' ') {
+        Some((first, last)) => format!("{}, {}", last, first.trim()),
+        None => name,
+    }
+}
+
+//split a free-form author string such as "Terry Pratchett & Neil Gaiman" into names.
+//Commas separate names only when every part is a full name, so "Herbert, Frank" stays one author.
+pub fn split_names(authors: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for part in authors.split([';', '&']).flat_map(|part| part.split(" and ")) {
+        let part = part.trim();
+        if part.is_empty() {
+            continue;
+        }
+        let listed: Vec<&str> = part
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        if listed.len() > 1 && listed.iter().all(|name| name.contains(' ')) {
+            names.extend(listed.into_iter().map(display_name));
+        } else {
+            names.push(display_name(part));
+        }
+    }
+    names
+}
+
+//the inverse of split_names; "&" keeps names with a comma or a single word apart
+pub fn join_names(names: &[String]) -> String {
+    names.join(" & ")
+}
+
+//validate an author and fill in the sort name
+pub fn validate_author(author: &mut Author) -> Result<(), String> {
+    author.name = author.name.trim().to_string();
+    if author.name.is_empty() {
+        return Err("Author name is required".to_string());
+    }
+    if let (Some(birth), Some(death)) = (author.birth_year, author.death_year) {
+        if death < birth {
+            return Err("Death year is before birth year".to_string());
+        }
+    }
+    if author.sort_name.as_deref().unwrap_or_default().trim().is_empty() {
+        author.sort_name = Some(sort_name(&author.name));
+    }
+    Ok(())
+}
+
+//find a live author by either spelling of their name, creating one if needed
+fn find_or_create_author(transaction: &mut Transaction, context: &RequestContext, name: &str) -> Result<i32, PostgresError> {
+    if
+        let Some(row) = transaction.query_opt(
+            "SELECT id FROM authors WHERE (lower(name) = lower($1) OR lower(sort_name) = lower($2)) AND deleted_at IS NULL ORDER BY id LIMIT 1",
+            &[&name, &sort_name(name)]
+        )?
+    {
+        return Ok(row.get(0));
+    }
+
+    let row = transaction.query_one(
+        "INSERT INTO authors (name, sort_name) VALUES ($1, $2) RETURNING id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at",
+        &[&name, &sort_name(name)]
+    )?;
+    let author = author_from_row(&row);
+    let id = author.id.unwrap_or_default();
+    write_audit(transaction, context, "create", "authors", id, None, Some(&author))?;
+    Ok(id)
+}
+
+//a live book with this title credited to this author, compared case-insensitively
+pub fn find_book(transaction: &mut Transaction, title: &str, author: &str) -> Result<Option<i32>, PostgresError> {
+    let row = transaction.query_opt(
+        "SELECT b.id FROM books b WHERE lower(b.title) = lower($1) AND b.deleted_at IS NULL AND (lower(b.author) = lower($2) OR EXISTS (
+            SELECT 1 FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = b.id AND a.deleted_at IS NULL AND lower(a.name) = lower($2)
+        )) ORDER BY b.id LIMIT 1",
+        &[&title.trim(), &author.trim()]
+    )?;
+    Ok(row.map(|row| row.get(0)))
+}
+
+//replace a book's "author" role credits with the names in its author string
+pub fn link_book_authors(transaction: &mut Transaction, context: &RequestContext, book_id: i32, authors: &str) -> Result<(), PostgresError> {
+    transaction.execute("DELETE FROM book_authors WHERE book_id = $1 AND role = 'author'", &[&book_id])?;
+    for (position, name) in split_names(authors).iter().enumerate() {
+        let author_id = find_or_create_author(transaction, context, name)?;
+        transaction.execute(
+            "INSERT INTO book_authors (book_id, author_id, role, position) VALUES ($1, $2, 'author', $3) ON CONFLICT DO NOTHING",
+            &[&book_id, &author_id, &(position as i32)]
+        )?;
+    }
+    Ok(())
+}
+
+//split legacy author strings into linked authors for books that have no links yet
+pub fn migrate_book_authors(client: &mut Client) -> Result<(), PostgresError> {
+    let mut transaction = client.transaction()?;
+    let context = RequestContext {
+        actor: Some("migration".to_string()),
+        client_ip: None,
+        request_id: crate::next_request_id(),
+    };
+
+    let rows = transaction.query(
+        "SELECT id, author FROM books WHERE NOT EXISTS (SELECT 1 FROM book_authors WHERE book_id = books.id)",
+        &[]
+    )?;
+    for row in rows {
+        let author: String = row.get(1);
+        link_book_authors(&mut transaction, &context, row.get(0), &author)?;
+    }
+    transaction.commit()
+}
+
+//credits of a book as stored links
+fn get_credit_requests(transaction: &mut Transaction, book_id: i32) -> Result<Vec<CreditRequest>, PostgresError> {
+    Ok(
+        transaction
+            .query("SELECT author_id, role FROM book_authors WHERE book_id = $1 ORDER BY position, role", &[&book_id])?
+            .iter()
+            .map(|row| CreditRequest { author_id: row.get(0), role: row.get(1) })
+            .collect()
+    )
+}
+
+//credits of a book that show, i.e. those of authors not in the trash
+fn get_visible_credits(transaction: &mut Transaction, book_id: i32) -> Result<Vec<CreditRequest>, PostgresError> {
+    Ok(
+        transaction
+            .query(
+                "SELECT ba.author_id, ba.role FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = $1 AND a.deleted_at IS NULL ORDER BY ba.position, ba.role",
+                &[&book_id]
+            )?
+            .iter()
+            .map(|row| CreditRequest { author_id: row.get(0), role: row.get(1) })
+            .collect()
+    )
+}
+
+//live books crediting an author
+fn get_credited_books(transaction: &mut Transaction, author_id: i32) -> Result<Vec<i32>, PostgresError> {
+    Ok(
+        transaction
+            .query(
+                "SELECT DISTINCT ba.book_id FROM book_authors ba JOIN books b ON b.id = ba.book_id WHERE ba.author_id = $1 AND b.deleted_at IS NULL ORDER BY ba.book_id",
+                &[&author_id]
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    )
+}
+
+//trash or restore an author and audit the credits that this hides or shows on each book
+fn set_author_trashed(transaction: &mut Transaction, context: &RequestContext, id: i32, trashed: bool) -> Result<Author, PostgresError> {
+    let credited = get_credited_books(transaction, id)?;
+    let mut previous = Vec::new();
+    for book_id in &credited {
+        previous.push(get_visible_credits(transaction, *book_id)?);
+    }
+
+    let row = transaction.query_one(
+        "UPDATE authors SET deleted_at = CASE WHEN $2 THEN now() END, version = version + 1 WHERE id = $1 RETURNING id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at",
+        &[&id, &trashed]
+    )?;
+
+    for (book_id, previous) in credited.into_iter().zip(previous) {
+        let credits = json!(get_visible_credits(transaction, book_id)?);
+        write_audit::<Value>(transaction, context, "update", "book_authors", book_id, Some(&json!(previous)), Some(&credits))?;
+    }
+    Ok(author_from_row(&row))
+}
+
+//deserialize author from request body without id
+fn get_author_request_body(request: &str) -> Result<Author, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize book credits from request body
+fn get_credits_request_body(request: &str) -> Result<Vec<CreditRequest>, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//handle post author request
+pub fn handle_post_author_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_author_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(mut author), Ok(mut client)) => {
+            if let Err(e) = validate_author(&mut author) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let author = match insert_author(&mut transaction, context, &author) {
+                Ok(author) => author,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = transaction.commit() {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&author).unwrap())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//insert a validated author and audit it
+pub fn insert_author(transaction: &mut Transaction, context: &RequestContext, author: &Author) -> Result<Author, PostgresError> {
+    let row = transaction.query_one(
+        "INSERT INTO authors (name, sort_name, birth_year, death_year, bio) VALUES ($1, $2, $3, $4, $5) RETURNING id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at",
+        &[&author.name, &author.sort_name, &author.birth_year, &author.death_year, &author.bio]
+    )?;
+    let author = author_from_row(&row);
+    write_audit(transaction, context, "create", "authors", author.id.unwrap_or_default(), None, Some(&author))?;
+    Ok(author)
+}
+
+//handle get author request
+pub fn handle_get_author_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(include_deleted), Ok(mut client)) =>
+            match
+                client.query_one(
+                    "SELECT id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at FROM authors WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+                    &[&id, &include_deleted]
+                )
+            {
+                Ok(row) => {
+                    let author = author_from_row(&row);
+
+                    //conditional GET
+                    let version = author.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&author).unwrap())
+                }
+                _ => (NOT_FOUND.to_string(), "Author not found".to_string()),
+            }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all author request, optionally filtered by ?name=
+pub fn handle_get_all_author_request(request: &str) -> (String, String) {
+    match
+        (
+            get_timestamp_param(request, "created_after"),
+            get_timestamp_param(request, "updated_since"),
+            get_include_deleted(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(created_after), Ok(updated_since), Ok(include_deleted), Ok(mut client)) => {
+            let name = get_query_param(request, "name").map(|name| format!("%{}%", name));
+            let authors: Vec<Author> = match
+                client.query(
+                    "SELECT id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at FROM authors WHERE ($1::timestamptz IS NULL OR created_at > $1) AND ($2::timestamptz IS NULL OR updated_at >= $2) AND ($3 OR deleted_at IS NULL) AND ($4::varchar IS NULL OR name ILIKE $4 OR sort_name ILIKE $4) ORDER BY sort_name, id",
+                    &[&created_after, &updated_since, &include_deleted, &name]
+                )
+            {
+                Ok(rows) => rows.iter().map(author_from_row).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&authors).unwrap())
+        }
+        (_, _, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        (Err(_), _, _, _) | (_, Err(_), _, _) =>
+            (BAD_REQUEST.to_string(), "Invalid timestamp filter".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get author books request
+pub fn handle_get_author_books_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let credits: Vec<AuthorCredit> = match
+                client.query(
+                    "SELECT b.id, b.title, b.author, b.genre, b.isbn10, b.isbn13, b.version, b.created_at, b.updated_at, b.deleted_at, b.publisher, b.publication_year, b.page_count, b.language, b.format, b.edition, ba.role, ba.position FROM book_authors ba JOIN books b ON b.id = ba.book_id WHERE ba.author_id = $1 AND b.deleted_at IS NULL ORDER BY b.title, b.id",
+                    &[&id]
+                )
+            {
+                Ok(rows) => rows.iter().map(|row| AuthorCredit { role: row.get(16), position: row.get(17), book: book_from_row(row) }).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&credits).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book authors request
+pub fn handle_get_book_authors_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let credits: Vec<BookCredit> = match
+                client.query(
+                    "SELECT a.id, a.name, a.sort_name, a.birth_year, a.death_year, a.bio, a.version, a.created_at, a.updated_at, a.deleted_at, ba.role, ba.position FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = $1 AND a.deleted_at IS NULL ORDER BY ba.position, ba.role",
+                    &[&id]
+                )
+            {
+                Ok(rows) => rows.iter().map(|row| BookCredit { role: row.get(10), position: row.get(11), author: author_from_row(row) }).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&credits).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put book authors request; replaces every credit and rewrites the book's author string
+pub fn handle_put_book_authors_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_credits_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(credits), Ok(mut client)) => {
+            if let Some(credit) = credits.iter().find(|credit| !ROLES.contains(&credit.role.as_str())) {
+                return (BAD_REQUEST.to_string(), format!("Unknown role: {}", credit.role));
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked book
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //every credited author must exist
+            let mut names = Vec::new();
+            for credit in &credits {
+                match
+                    transaction.query_opt(
+                        "SELECT name FROM authors WHERE id = $1 AND deleted_at IS NULL",
+                        &[&credit.author_id]
+                    )
+                {
+                    Ok(Some(row)) if credit.role == "author" => names.push(row.get::<_, String>(0)),
+                    Ok(Some(_)) => {}
+                    Ok(None) => return (BAD_REQUEST.to_string(), format!("Author {} not found", credit.author_id)),
+                    Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+                }
+            }
+
+            let previous = match get_credit_requests(&mut transaction, id) {
+                Ok(previous) => previous,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = transaction.execute("DELETE FROM book_authors WHERE book_id = $1", &[&id]) {
+                return internal_error(e);
+            }
+            for (position, credit) in credits.iter().enumerate() {
+                if let Err(e) =
+                    transaction.execute(
+                        "INSERT INTO book_authors (book_id, author_id, role, position) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+                        &[&id, &credit.author_id, &credit.role, &(position as i32)]
+                    )
+                {
+                    return internal_error(e);
+                }
+            }
+
+            //the author string follows the "author" credits; books credited only to editors keep theirs
+            let author = if names.is_empty() { before.author.clone() } else { join_names(&names) };
+            let row = match
+                transaction.query_one(
+                    "UPDATE books SET author = $1, version = version + 1 WHERE id = $2 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+                    &[&author, &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = book_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "books", id, Some(&before), Some(&after)) {
+                return internal_error(e);
+            }
+            let (previous, credits) = (json!(previous), json!(credits));
+            if let Err(e) = write_audit::<Value>(&mut transaction, context, "update", "book_authors", id, Some(&previous), Some(&credits)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), credits.to_string())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put author request
+pub fn handle_put_author_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_author_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(author), Ok(mut client)) => update_author(&mut client, request, context, id, |_| Ok(author)),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle patch author request
+pub fn handle_patch_author_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_patch_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(patch), Ok(mut client)) =>
+            update_author(&mut client, request, context, id, |current| {
+                apply_merge_patch(current, &patch).map_err(|e| format!("Invalid patch: {}", e))
+            }),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//lock an author, build its replacement from the stored row and write it back
+fn update_author(
+    client: &mut Client,
+    request: &str,
+    context: &RequestContext,
+    id: i32,
+    replacement: impl FnOnce(&Author) -> Result<Author, String>
+) -> (String, String) {
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return internal_error(e),
+    };
+    let before = match
+        transaction.query_opt(
+            "SELECT id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at FROM authors WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+            &[&id]
+        )
+    {
+        Ok(Some(row)) => author_from_row(&row),
+        Ok(None) => return (NOT_FOUND.to_string(), "Author not found".to_string()),
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+
+    //check the If-Match precondition against the locked row
+    if precondition_failed(request, before.version.unwrap_or_default()) {
+        return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+    }
+
+    let mut author = match replacement(&before) {
+        Ok(author) => author,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+    if let Err(e) = validate_author(&mut author) {
+        return (BAD_REQUEST.to_string(), e);
+    }
+
+    let row = match
+        transaction.query_one(
+            "UPDATE authors SET name = $1, sort_name = $2, birth_year = $3, death_year = $4, bio = $5, version = version + 1 WHERE id = $6 RETURNING id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at",
+            &[&author.name, &author.sort_name, &author.birth_year, &author.death_year, &author.bio, &id]
+        )
+    {
+        Ok(row) => row,
+        Err(e) => return internal_error(e),
+    };
+    let after = author_from_row(&row);
+    if let Err(e) = write_audit(&mut transaction, context, "update", "authors", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+        return internal_error(e);
+    }
+
+    (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+}
+
+//handle restore author request
+pub fn handle_restore_author_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at FROM authors WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => author_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Author not found in trash".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //its credits show again on the books that kept them
+            let after = match set_author_trashed(&mut transaction, context, id, false) {
+                Ok(author) => author,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "restore", "authors", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete author request; authors credited on live books are kept unless cascading, which hides their credits until the author is restored
+pub fn handle_delete_author_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_cascade(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(cascade), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, name, sort_name, birth_year, death_year, bio, version, created_at, updated_at, deleted_at FROM authors WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => author_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Author not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let credited = match get_credited_books(&mut transaction, id) {
+                Ok(credited) => credited,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            if !credited.is_empty() && !cascade {
+                let conflict = json!({ "error": "Author is credited on books", "book_ids": credited });
+                return (CONFLICT.to_string(), conflict.to_string());
+            }
+
+            //move the row to the trash; the retention job purges it later.
+            //Its credits stay in place, hidden while the author is trashed, so a restore brings them back.
+            let after = match set_author_trashed(&mut transaction, context, id, true) {
+                Ok(author) => author,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "authors", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), "Author deleted".to_string())
+        }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joined_names_split_back_into_the_same_names() {
+        for names in [
+            vec!["Frank Herbert"],
+            vec!["Terry Pratchett", "Neil Gaiman"],
+            vec!["Plato", "Benjamin Jowett"],
+            vec!["Homer", "Virgil", "Ovid"],
+        ] {
+            let names: Vec<String> = names.into_iter().map(str::to_string).collect();
+            assert_eq!(split_names(&join_names(&names)), names);
+        }
+    }
+
+    #[test]
+    fn splits_free_form_author_strings() {
+        assert_eq!(split_names("Herbert, Frank"), vec!["Frank Herbert"]);
+        assert_eq!(split_names("Terry Pratchett, Neil Gaiman"), vec!["Terry Pratchett", "Neil Gaiman"]);
+        assert_eq!(split_names("Good Omens and Neil Gaiman; ;"), vec!["Good Omens", "Neil Gaiman"]);
+    }
+}
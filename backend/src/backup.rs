@@ -0,0 +1,590 @@
+//Full-library backup to a JSON Lines archive, and restore into an empty database
+
+use crate::{
+    attachments,
+    covers,
+    get_header,
+    is_admin,
+    multipart,
+    notifications,
+    storage,
+    with_content_type,
+    with_header,
+    RequestContext,
+    BAD_REQUEST,
+    CONFLICT,
+    DB_URL,
+    FORBIDDEN,
+    INTERNAL_ERROR,
+    OK_RESPONSE,
+};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chrono::Utc;
+use postgres::{ Client, IsolationLevel, NoTls, Statement, Transaction };
+use serde_json::{ json, Map, Value };
+use sha2::{ Digest, Sha256 };
+use std::collections::{ BTreeMap, HashMap };
+use std::fs::File;
+use std::io::{ self, BufRead, BufReader, BufWriter, Write };
+
+//Names the archive format in its header line
+const FORMAT: &str = "library-backup";
+
+//Archive version written; restore reads this and every earlier version
+const VERSION: i64 = 1;
+
+//Rows fetched per round trip while backing up
+const BACKUP_BATCH: i32 = 500;
+
+//Storage prefix restored files wait under until the restore commits
+const STAGING_PREFIX: &str = "restore-staging";
+
+//A table in the archive: its serial key, if any, and the columns holding ids of other tables
+struct Table {
+    name: &'static str,
+    key: Option<&'static str>,
+    references: &'static [(&'static str, &'static str)],
+}
+
+//Every table, parents before children, which is the order rows are written and restored in.
+//genres.parent_id points into its own table and is filled in once all genres exist.
+const TABLES: [Table; 22] = [
+    Table { name: "users", key: Some("id"), references: &[] },
+    Table { name: "authors", key: Some("id"), references: &[] },
+    Table { name: "genres", key: Some("id"), references: &[("parent_id", "genres")] },
+    Table { name: "tags", key: Some("id"), references: &[] },
+    Table { name: "series", key: Some("id"), references: &[] },
+    Table { name: "books", key: Some("id"), references: &[] },
+    Table { name: "copies", key: Some("id"), references: &[("book_id", "books")] },
+    Table { name: "loans", key: Some("id"), references: &[("user_id", "users"), ("book_id", "books"), ("copy_id", "copies")] },
+    Table { name: "holds", key: Some("id"), references: &[("user_id", "users"), ("book_id", "books")] },
+    Table { name: "reviews", key: Some("id"), references: &[("book_id", "books"), ("user_id", "users")] },
+    Table { name: "book_authors", key: None, references: &[("book_id", "books"), ("author_id", "authors")] },
+    Table { name: "book_genres", key: None, references: &[("book_id", "books"), ("genre_id", "genres")] },
+    Table { name: "book_tags", key: None, references: &[("book_id", "books"), ("tag_id", "tags")] },
+    Table { name: "book_series", key: None, references: &[("series_id", "series"), ("book_id", "books")] },
+    Table { name: "book_covers", key: None, references: &[("book_id", "books")] },
+    Table { name: "book_files", key: Some("id"), references: &[("book_id", "books")] },
+    Table { name: "book_identifiers", key: None, references: &[("book_id", "books")] },
+    Table { name: "reading_history", key: None, references: &[("user_id", "users"), ("book_id", "books")] },
+    Table { name: "metadata_cache", key: None, references: &[] },
+    Table { name: "notification_preferences", key: None, references: &[("user_id", "users")] },
+    Table { name: "notification_log", key: Some("id"), references: &[("user_id", "users"), ("loan_id", "loans"), ("hold_id", "holds")] },
+    Table { name: "audit_log", key: Some("id"), references: &[] },
+];
+
+//Audit resource types whose resource_id is the id of another table
+const AUDIT_RESOURCES: [(&str, &str); 3] = [("book_covers", "books"), ("book_series", "series"), ("notification_preferences", "users")];
+
+fn table(name: &str) -> Option<&'static Table> {
+    TABLES.iter().find(|table| table.name == name)
+}
+
+//Writer that hashes everything passing through, so the archive can carry its own checksum
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+//What a backup or restore covered
+#[derive(Serialize)]
+pub struct Summary {
+    rows: BTreeMap<String, i64>,
+    files: i64,
+}
+
+//columns of each table in the live schema, in table order
+fn get_columns(client: &mut impl postgres::GenericClient) -> Result<HashMap<String, Vec<String>>, postgres::Error> {
+    let names: Vec<&str> = TABLES.iter().map(|table| table.name).collect();
+    let mut columns: HashMap<String, Vec<String>> = HashMap::new();
+    for row in client.query(
+        "SELECT table_name::text, column_name::text FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = ANY($1) ORDER BY table_name, ordinal_position",
+        &[&names]
+    )? {
+        columns.entry(row.get(0)).or_default().push(row.get(1));
+    }
+    Ok(columns)
+}
+
+//write every table, then cover and e-book files, then a trailer with row counts and the SHA-256 of all that came before.
+//An archive cut short has no trailer, which restore treats as corrupt.
+pub fn write_backup(out: &mut dyn Write) -> Result<Summary, String> {
+    let mut client = Client::connect(DB_URL, NoTls).map_err(|e| e.to_string())?;
+    //one snapshot for the whole archive, so rows written later never point at rows missed earlier
+    let mut transaction = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .map_err(|e| e.to_string())?;
+    let columns = get_columns(&mut transaction).map_err(|e| e.to_string())?;
+    let mut out = HashingWriter { inner: BufWriter::new(out), hasher: Sha256::new() };
+
+    let tables: Vec<Value> = TABLES.iter()
+        .map(|table| {
+            let references: Map<String, Value> = table.references.iter().map(|(column, parent)| (column.to_string(), json!(parent))).collect();
+            json!({
+                "name": table.name,
+                "key": table.key,
+                "references": references,
+                "columns": columns.get(table.name).cloned().unwrap_or_default(),
+            })
+        })
+        .collect();
+    let header = json!({ "format": FORMAT, "version": VERSION, "created_at": Utc::now(), "tables": tables });
+    writeln!(out, "{}", header).map_err(|e| e.to_string())?;
+
+    let mut summary = Summary { rows: BTreeMap::new(), files: 0 };
+    for table in TABLES.iter() {
+        //keyed tables in key order; the rest by the ids they hold, or failing that their first column
+        let order = match (table.key, table.references) {
+            (Some(key), _) => key.to_string(),
+            (None, []) => columns.get(table.name).and_then(|columns| columns.first()).cloned().unwrap_or_default(),
+            (None, references) => references.iter().map(|(column, _)| *column).collect::<Vec<_>>().join(", "),
+        };
+        let portal = transaction
+            .bind(&format!("SELECT row_to_json(t)::text FROM {} t ORDER BY {}", table.name, order), &[])
+            .map_err(|e| e.to_string())?;
+        let mut count = 0;
+        loop {
+            let rows = transaction.query_portal(&portal, BACKUP_BATCH).map_err(|e| e.to_string())?;
+            for row in &rows {
+                //the row is already JSON, so it is spliced in rather than parsed and printed again
+                writeln!(out, "{{\"table\":\"{}\",\"row\":{}}}", table.name, row.get::<_, String>(0)).map_err(|e| e.to_string())?;
+            }
+            count += rows.len() as i64;
+            if rows.len() < BACKUP_BATCH as usize {
+                break;
+            }
+        }
+        summary.rows.insert(table.name.to_string(), count);
+    }
+
+    //cover images live in storage rather than the database, so they travel alongside the rows
+    let storage = storage::configured_storage();
+    for row in transaction.query("SELECT book_id FROM book_covers ORDER BY book_id", &[]).map_err(|e| e.to_string())? {
+        let book_id: i32 = row.get(0);
+        for size in covers::stored_sizes() {
+            match storage.get(&covers::storage_key(book_id, size)) {
+                Ok(Some(data)) => {
+                    let line = json!({ "cover": book_id, "size": size, "data": BASE64.encode(data) });
+                    writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+                    summary.files += 1;
+                }
+                Ok(None) => eprintln!("Backup skipped {} cover of book {}: not in storage", size, book_id),
+                Err(e) => eprintln!("Backup skipped {} cover of book {}: {}", size, book_id, e),
+            }
+        }
+    }
+    //e-book files likewise
+    for row in transaction.query("SELECT book_id, format FROM book_files ORDER BY book_id, format", &[]).map_err(|e| e.to_string())? {
+        let (book_id, format): (i32, String) = (row.get(0), row.get(1));
+        match storage.get(&attachments::storage_key(book_id, &format)) {
+            Ok(Some(data)) => {
+                let line = json!({ "attachment": book_id, "format": format, "data": BASE64.encode(data) });
+                writeln!(out, "{}", line).map_err(|e| e.to_string())?;
+                summary.files += 1;
+            }
+            Ok(None) => eprintln!("Backup skipped {} file of book {}: not in storage", format, book_id),
+            Err(e) => eprintln!("Backup skipped {} file of book {}: {}", format, book_id, e),
+        }
+    }
+
+    let checksum = format!("{:x}", out.hasher.clone().finalize());
+    let trailer = json!({ "end": { "rows": summary.rows, "files": summary.files, "sha256": checksum } });
+    writeln!(out.inner, "{}", trailer).and_then(|_| out.inner.flush()).map_err(|e| e.to_string())?;
+    Ok(summary)
+}
+
+//Outcome of a restore
+#[derive(Serialize)]
+pub struct RestoreReport {
+    version: i64,
+    created_at: Value,
+    rows: BTreeMap<String, i64>,
+    files: i64,
+    remapped_ids: i64,
+}
+
+//Restore state: the new id of every old id, per keyed table, and statements prepared so far
+struct Restore<'a> {
+    transaction: Transaction<'a>,
+    columns: HashMap<String, Vec<String>>,
+    ids: HashMap<&'static str, HashMap<i64, i64>>,
+    statements: HashMap<String, Statement>,
+    //self references resolved once the whole table is in: (table, new id, column, old referenced id)
+    deferred: Vec<(&'static Table, i64, &'static str, i64)>,
+    rows: BTreeMap<String, i64>,
+    files: i64,
+    remapped: i64,
+    //files written under the staging prefix so far: (staging key, final key)
+    staged: &'a mut Vec<(String, String)>,
+}
+
+fn invalid(message: String) -> (String, String) {
+    (BAD_REQUEST.to_string(), message)
+}
+
+fn internal(e: postgres::Error) -> (String, String) {
+    eprintln!("Restore failed: {}", e);
+    (INTERNAL_ERROR.to_string(), format!("Restore failed: {}", e))
+}
+
+impl Restore<'_> {
+    //write a restored file under the staging prefix; it only takes its real key once the restore commits
+    fn stage_file(&mut self, key: String, data: &[u8]) -> Result<(), (String, String)> {
+        let staging = format!("{}/{}", STAGING_PREFIX, key);
+        storage::configured_storage().put(&staging, data).map_err(|e| {
+            eprintln!("Unable to stage {}: {}", key, e);
+            (INTERNAL_ERROR.to_string(), format!("Failed to store {}", key))
+        })?;
+        self.staged.push((staging, key));
+        self.files += 1;
+        Ok(())
+    }
+
+    fn new_id(&self, parent: &str, old: i64) -> Option<i64> {
+        self.ids.get(parent).and_then(|ids| ids.get(&old)).copied()
+    }
+
+    fn insert_row(&mut self, table: &'static Table, row: Value) -> Result<(), (String, String)> {
+        let mut row = match row {
+            Value::Object(row) => row,
+            _ => return Err(invalid(format!("{} row is not an object", table.name))),
+        };
+        let columns = self.columns.get(table.name).cloned().unwrap_or_default();
+        if let Some(column) = row.keys().find(|column| !columns.contains(column)) {
+            return Err(invalid(format!("{} has no column {}", table.name, column)));
+        }
+
+        let mut deferred = Vec::new();
+        for (column, parent) in table.references {
+            let old = match row.get(*column) {
+                Some(Value::Null) | None => continue,
+                Some(value) => value.as_i64().ok_or_else(|| invalid(format!("{}.{} is not an id", table.name, column)))?,
+            };
+            if *parent == table.name {
+                deferred.push((*column, old));
+                row.insert(column.to_string(), Value::Null);
+                continue;
+            }
+            let new = self
+                .new_id(parent, old)
+                .ok_or_else(|| invalid(format!("{} row references missing {} id {}", table.name, parent, old)))?;
+            row.insert(column.to_string(), json!(new));
+        }
+        //audit entries follow the resource they describe; entries about purged rows keep their old id
+        if table.name == "audit_log" {
+            let resource = row.get("resource_type").and_then(Value::as_str).unwrap_or_default();
+            let parent = AUDIT_RESOURCES.iter().find(|(name, _)| *name == resource).map(|(_, parent)| *parent).unwrap_or(resource);
+            if let Some(new) = row.get("resource_id").and_then(Value::as_i64).and_then(|old| self.new_id(parent, old)) {
+                row.insert("resource_id".to_string(), json!(new));
+            }
+        }
+        //dedupe keys name the loan or hold a notice was about, so they follow it to its new id
+        if table.name == "notification_log" {
+            let key = row
+                .get("dedupe_key")
+                .and_then(Value::as_str)
+                .map(|key| notifications::remap_dedupe_key(key, row.get("loan_id").and_then(Value::as_i64), row.get("hold_id").and_then(Value::as_i64)));
+            if let Some(key) = key {
+                row.insert("dedupe_key".to_string(), json!(key));
+            }
+        }
+        let old_key = match table.key {
+            Some(key) => Some(row.remove(key).and_then(|id| id.as_i64()).ok_or_else(|| invalid(format!("{} row has no {}", table.name, key)))?),
+            None => None,
+        };
+
+        let names: Vec<String> = row.keys().map(|column| format!("\"{}\"", column)).collect();
+        let mut sql = format!(
+            "INSERT INTO {table} ({names}) SELECT {names} FROM json_populate_record(NULL::{table}, $1::text::json)",
+            table = table.name,
+            names = names.join(", ")
+        );
+        if let Some(key) = table.key {
+            sql.push_str(&format!(" RETURNING {}::bigint", key));
+        }
+        let statement = match self.statements.get(&sql) {
+            Some(statement) => statement.clone(),
+            None => {
+                let statement = self.transaction.prepare(&sql).map_err(internal)?;
+                self.statements.insert(sql, statement.clone());
+                statement
+            }
+        };
+        let data = Value::Object(row).to_string();
+        match old_key {
+            Some(old) => {
+                let new: i64 = self.transaction.query_one(&statement, &[&data]).map_err(internal)?.get(0);
+                if new != old {
+                    self.remapped += 1;
+                }
+                self.ids.entry(table.name).or_default().insert(old, new);
+                for (column, referenced) in deferred {
+                    self.deferred.push((table, new, column, referenced));
+                }
+            }
+            None => {
+                self.transaction.execute(&statement, &[&data]).map_err(internal)?;
+            }
+        }
+        *self.rows.entry(table.name.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    fn resolve_deferred(&mut self) -> Result<(), (String, String)> {
+        for (table, id, column, old) in std::mem::take(&mut self.deferred) {
+            let new = self
+                .new_id(table.name, old)
+                .ok_or_else(|| invalid(format!("{} row references missing {} id {}", table.name, table.name, old)))?;
+            let sql = format!("UPDATE {} SET {} = $1 WHERE {} = $2", table.name, column, table.key.unwrap_or("id"));
+            self.transaction.execute(&sql, &[&(new as i32), &(id as i32)]).map_err(internal)?;
+        }
+        Ok(())
+    }
+
+    fn restore_cover(&mut self, line: &Value) -> Result<(), (String, String)> {
+        let old = line["cover"].as_i64().ok_or_else(|| invalid("Cover without book id".to_string()))?;
+        let size = line["size"].as_str().filter(|size| covers::stored_sizes().any(|known| known == *size));
+        let size = size.ok_or_else(|| invalid(format!("Unknown cover size for book {}", old)))?;
+        let data = line["data"]
+            .as_str()
+            .and_then(|data| BASE64.decode(data).ok())
+            .ok_or_else(|| invalid(format!("Cover of book {} is not base64", old)))?;
+        let new = self.new_id("books", old).ok_or_else(|| invalid(format!("Cover references missing books id {}", old)))?;
+        self.stage_file(covers::storage_key(new as i32, size), &data)
+    }
+
+    fn restore_attachment(&mut self, line: &Value) -> Result<(), (String, String)> {
+        let old = line["attachment"].as_i64().ok_or_else(|| invalid("File without book id".to_string()))?;
+        //the format becomes part of a storage key, so only the known ones are let through
+        let format = line["format"].as_str().filter(|format| ["epub", "pdf"].contains(format));
+        let format = format.ok_or_else(|| invalid(format!("Unknown file format for book {}", old)))?;
+        let data = line["data"]
+            .as_str()
+            .and_then(|data| BASE64.decode(data).ok())
+            .ok_or_else(|| invalid(format!("File of book {} is not base64", old)))?;
+        let new = self.new_id("books", old).ok_or_else(|| invalid(format!("File references missing books id {}", old)))?;
+        self.stage_file(attachments::storage_key(new as i32, format), &data)
+    }
+}
+
+//restore an archive into an empty database, giving every row a fresh id and rewriting references to match.
+//Nothing is committed unless the checksum, the row counts and every reference check out, and covers and
+//attachments are staged until then so a rejected archive leaves no files behind.
+pub fn restore_backup(input: &mut dyn BufRead) -> Result<RestoreReport, (String, String)> {
+    let mut staged = Vec::new();
+    let result = restore_archive(input, &mut staged);
+    let storage = storage::configured_storage();
+    for (staging, key) in staged {
+        let moved = match result {
+            Ok(_) => storage.rename(&staging, &key),
+            Err(_) => storage.delete(&staging),
+        };
+        if let Err(e) = moved {
+            eprintln!("Unable to {} staged {}: {}", if result.is_ok() { "move" } else { "delete" }, key, e);
+        }
+    }
+    result
+}
+
+fn restore_archive(input: &mut dyn BufRead, staged: &mut Vec<(String, String)>) -> Result<RestoreReport, (String, String)> {
+    let mut client = Client::connect(DB_URL, NoTls).map_err(internal)?;
+    let mut transaction = client.transaction().map_err(internal)?;
+    for table in TABLES.iter() {
+        let occupied: bool = transaction
+            .query_one(&format!("SELECT EXISTS (SELECT 1 FROM {})", table.name), &[])
+            .map_err(internal)?
+            .get(0);
+        if occupied {
+            return Err((CONFLICT.to_string(), format!("Database is not empty: {} has rows", table.name)));
+        }
+    }
+    let columns = get_columns(&mut transaction).map_err(internal)?;
+    //timestamp triggers would stamp every row with today; the archive's own timestamps are kept instead
+    for table in TABLES.iter() {
+        transaction.batch_execute(&format!("ALTER TABLE {} DISABLE TRIGGER USER", table.name)).map_err(internal)?;
+    }
+    let mut restore = Restore {
+        transaction,
+        columns,
+        ids: HashMap::new(),
+        statements: HashMap::new(),
+        deferred: Vec::new(),
+        rows: BTreeMap::new(),
+        files: 0,
+        remapped: 0,
+        staged,
+    };
+
+    let mut hasher = Sha256::new();
+    let mut header: Option<Value> = None;
+    let mut trailer: Option<Value> = None;
+    let mut line = String::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if input.read_line(&mut line).map_err(|e| invalid(format!("Unable to read archive: {}", e)))? == 0 {
+            break;
+        }
+        number += 1;
+        if trailer.is_some() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Err(invalid(format!("Line {}: data after the end of the archive", number)));
+        }
+        let value: Value = serde_json::from_str(&line).map_err(|e| invalid(format!("Line {}: {}", number, e)))?;
+        if value.get("end").is_some() {
+            trailer = Some(value);
+            continue;
+        }
+        hasher.update(line.as_bytes());
+
+        if header.is_none() {
+            if value["format"] != FORMAT {
+                return Err(invalid("Not a library backup".to_string()));
+            }
+            match value["version"].as_i64() {
+                Some(version) if (1..=VERSION).contains(&version) => {}
+                _ => return Err(invalid(format!("Unsupported backup version {}", value["version"]))),
+            }
+            for archived in value["tables"].as_array().cloned().unwrap_or_default() {
+                let name = archived["name"].as_str().unwrap_or_default();
+                if table(name).is_none() {
+                    return Err(invalid(format!("Unknown table {}", name)));
+                }
+            }
+            header = Some(value);
+            continue;
+        }
+
+        if let Some(name) = value.get("table").and_then(Value::as_str) {
+            let table = table(name).ok_or_else(|| invalid(format!("Line {}: unknown table {}", number, name)))?;
+            //genres pointing at one another are complete once the next table starts
+            if !restore.deferred.is_empty() && restore.deferred[0].0.name != table.name {
+                restore.resolve_deferred()?;
+            }
+            let row = value.get("row").cloned().unwrap_or_default();
+            restore.insert_row(table, row).map_err(|(status, e)| (status, format!("Line {}: {}", number, e)))?;
+        } else if value.get("cover").is_some() {
+            restore.restore_cover(&value).map_err(|(status, e)| (status, format!("Line {}: {}", number, e)))?;
+        } else if value.get("attachment").is_some() {
+            restore.restore_attachment(&value).map_err(|(status, e)| (status, format!("Line {}: {}", number, e)))?;
+        } else {
+            return Err(invalid(format!("Line {}: unrecognised entry", number)));
+        }
+    }
+    restore.resolve_deferred()?;
+
+    //integrity: the archive is whole and unaltered, and everything it lists made it in
+    let header = header.ok_or_else(|| invalid("Archive is empty".to_string()))?;
+    let trailer = trailer.ok_or_else(|| invalid("Archive is truncated: no end marker".to_string()))?;
+    let end = &trailer["end"];
+    if end["sha256"].as_str() != Some(format!("{:x}", hasher.finalize()).as_str()) {
+        return Err(invalid("Archive checksum does not match".to_string()));
+    }
+    if end["files"].as_i64() != Some(restore.files) {
+        return Err(invalid(format!("Archive lists {} files but holds {}", end["files"], restore.files)));
+    }
+    for table in TABLES.iter() {
+        let expected = end["rows"][table.name].as_i64().unwrap_or_default();
+        let read = restore.rows.get(table.name).copied().unwrap_or_default();
+        let stored: i64 = restore
+            .transaction
+            .query_one(&format!("SELECT count(*) FROM {}", table.name), &[])
+            .map_err(internal)?
+            .get(0);
+        if expected != read || read != stored {
+            return Err(invalid(format!("{}: archive lists {} rows, read {}, stored {}", table.name, expected, read, stored)));
+        }
+    }
+
+    for table in TABLES.iter() {
+        restore.transaction.batch_execute(&format!("ALTER TABLE {} ENABLE TRIGGER USER", table.name)).map_err(internal)?;
+    }
+    let Restore { transaction, rows, files, remapped, .. } = restore;
+    transaction.commit().map_err(internal)?;
+    Ok(RestoreReport { version: header["version"].as_i64().unwrap_or_default(), created_at: header["created_at"].clone(), rows, files, remapped_ids: remapped })
+}
+
+//handle backup request: admins only, streamed as it is written
+pub fn handle_backup_request(request: &str, context: &RequestContext, stream: &mut dyn Write) -> Option<(String, String)> {
+    if !is_admin(request) {
+        return Some((FORBIDDEN.to_string(), "Admin token required".to_string()));
+    }
+    let filename = format!("library-backup-{}.jsonl", Utc::now().format("%Y%m%d-%H%M%S"));
+    let status_line = with_content_type(OK_RESPONSE, "application/x-ndjson");
+    let status_line = with_header(&status_line, "Content-Disposition", &format!("attachment; filename=\"{}\"", filename));
+    let status_line = with_header(&status_line, "X-Request-Id", &context.request_id);
+    if let Err(e) = stream.write_all(status_line.as_bytes()) {
+        eprintln!("Unable to write stream: {}", e);
+        return None;
+    }
+    //headers are gone by now, so a failure can only cut the archive short; restore rejects it for want of a trailer
+    if let Err(e) = write_backup(stream) {
+        eprintln!("Backup stopped: {}", e);
+    }
+    None
+}
+
+//handle restore request: admins only; the archive is the body or a multipart file field.
+//The body is read whole, so archives over MAX_REQUEST_BYTES (default 64 MiB), as most with covers or
+//attachments are, have to go through `backend restore <file>` instead.
+pub fn handle_restore_request(request: &str, body: &[u8]) -> (String, String) {
+    if !is_admin(request) {
+        return (FORBIDDEN.to_string(), "Admin token required".to_string());
+    }
+    let data = if get_header(request, "Content-Type").unwrap_or_default().to_lowercase().starts_with("multipart/form-data") {
+        match multipart::parse(request, body).map(|parts| multipart::take_file(parts, "file")) {
+            Ok(Some(file)) => file.data,
+            Ok(None) => return (BAD_REQUEST.to_string(), "No file in upload".to_string()),
+            Err(e) => return (BAD_REQUEST.to_string(), e),
+        }
+    } else {
+        body.to_vec()
+    };
+
+    match restore_backup(&mut data.as_slice()) {
+        Ok(report) => (OK_RESPONSE.to_string(), serde_json::to_string(&report).unwrap()),
+        Err(e) => e,
+    }
+}
+
+//`backend backup [<file>]`, writing to stdout without a file
+pub fn run_backup_command(args: &[String]) -> Result<(), String> {
+    let summary = match args {
+        [] => write_backup(&mut io::stdout().lock())?,
+        [path] if path == "-" => write_backup(&mut io::stdout().lock())?,
+        [path] => write_backup(&mut File::create(path).map_err(|e| format!("{}: {}", path, e))?)?,
+        _ => return Err("Usage: backend backup [<file>]".to_string()),
+    };
+    eprintln!("{}", serde_json::to_string_pretty(&summary).unwrap());
+    Ok(())
+}
+
+//`backend restore <file>`, reading stdin for -
+pub fn run_restore_command(args: &[String]) -> Result<(), String> {
+    let result = match args {
+        [path] if path == "-" => restore_backup(&mut io::stdin().lock()),
+        [path] => restore_backup(&mut BufReader::new(File::open(path).map_err(|e| format!("{}: {}", path, e))?)),
+        _ => return Err("Usage: backend restore <file>".to_string()),
+    };
+    let report = result.map_err(|(_, e)| e)?;
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    Ok(())
+}
@@ -0,0 +1,445 @@
+//Bulk CSV import and export
+
+use crate::{
+    authors,
+    get_header,
+    get_path_segment,
+    get_query_param,
+    insert_book,
+    insert_loan,
+    insert_review,
+    insert_user,
+    internal_error,
+    multipart,
+    normalize_book,
+    with_content_type,
+    with_header,
+    Book,
+    Loan,
+    RequestContext,
+    Review,
+    User,
+    BAD_REQUEST,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    OK_RESPONSE,
+};
+use postgres::{ Client, NoTls, Transaction };
+use serde_json::{ json, Map, Value };
+use std::io::{ BufWriter, Write };
+
+//Rows fetched from the database per round trip while exporting
+const EXPORT_BATCH: i32 = 500;
+
+//Kind of value a CSV column holds
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Text,
+    Integer,
+}
+
+//A resource that can be imported and exported as CSV
+struct Resource {
+    name: &'static str,
+    table: &'static str,
+    fields: &'static [(&'static str, Kind)],
+    required: &'static [&'static str],
+    aliases: &'static [(&'static str, &'static str)],
+}
+
+//Columns exported alongside the fields but ignored on import, so an export can be imported again
+const SYSTEM_COLUMNS: [&str; 4] = ["id", "version", "created_at", "updated_at"];
+
+const RESOURCES: [Resource; 5] = [
+    Resource {
+        name: "books",
+        table: "books",
+        fields: &[
+            ("title", Kind::Text),
+            ("author", Kind::Text),
+            ("genre", Kind::Text),
+            ("isbn10", Kind::Text),
+            ("isbn13", Kind::Text),
+            ("publisher", Kind::Text),
+            ("publication_year", Kind::Integer),
+            ("page_count", Kind::Integer),
+            ("language", Kind::Text),
+            ("format", Kind::Text),
+            ("edition", Kind::Text),
+        ],
+        required: &["title", "author"],
+        aliases: &[("authors", "author"), ("year", "publication_year"), ("pages", "page_count")],
+    },
+    Resource {
+        name: "users",
+        table: "users",
+        fields: &[("name", Kind::Text), ("email", Kind::Text)],
+        required: &["name", "email"],
+        aliases: &[("email_address", "email")],
+    },
+    Resource {
+        name: "authors",
+        table: "authors",
+        fields: &[
+            ("name", Kind::Text),
+            ("sort_name", Kind::Text),
+            ("birth_year", Kind::Integer),
+            ("death_year", Kind::Integer),
+            ("bio", Kind::Text),
+        ],
+        required: &["name"],
+        aliases: &[("born", "birth_year"), ("died", "death_year")],
+    },
+    Resource {
+        name: "loans",
+        table: "loans",
+        fields: &[
+            ("user_id", Kind::Integer),
+            ("book_id", Kind::Integer),
+            ("copy_id", Kind::Integer),
+            ("checkout_date", Kind::Text),
+            ("due_date", Kind::Text),
+            ("return_date", Kind::Text),
+        ],
+        required: &["user_id", "book_id", "checkout_date", "due_date"],
+        aliases: &[("borrower_id", "user_id"), ("checked_out", "checkout_date"), ("due", "due_date"), ("returned", "return_date")],
+    },
+    Resource {
+        name: "reviews",
+        table: "reviews",
+        fields: &[("book_id", Kind::Integer), ("user_id", Kind::Integer), ("rating", Kind::Integer), ("review_text", Kind::Text)],
+        required: &["book_id", "rating"],
+        aliases: &[("review", "review_text"), ("text", "review_text"), ("stars", "rating")],
+    },
+];
+
+//Problem with one data row of an import
+#[derive(Serialize)]
+struct RowError {
+    row: usize,
+    errors: Vec<String>,
+}
+
+//Outcome of an import
+#[derive(Serialize)]
+struct ImportReport {
+    resource: String,
+    dry_run: bool,
+    rows: usize,
+    imported: usize,
+    columns: Vec<Value>,
+    ignored_columns: Vec<String>,
+    errors: Vec<RowError>,
+}
+
+fn find_resource(name: &str) -> Option<&'static Resource> {
+    RESOURCES.iter().find(|resource| resource.name == name)
+}
+
+//"Publication Year" and "publication-year" both name publication_year
+fn normalize_header(header: &str) -> String {
+    header
+        .trim()
+        .trim_start_matches('\u{feff}')
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '-' || c == '_')
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+//explicit mapping from ?map=CSV Header:field,Other:field2
+fn get_explicit_mapping(request: &str) -> Vec<(String, String)> {
+    get_query_param(request, "map")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once(':'))
+        .map(|(header, field)| (normalize_header(header), field.trim().to_string()))
+        .collect()
+}
+
+//field each CSV column feeds, or None when the column is ignored
+fn map_headers(resource: &Resource, headers: &[String], explicit: &[(String, String)]) -> Result<Vec<Option<usize>>, String> {
+    for (_, field) in explicit {
+        if !resource.fields.iter().any(|(name, _)| name == field) {
+            return Err(format!("Unknown {} field in map: {}", resource.name, field));
+        }
+    }
+
+    let mut mapping = Vec::new();
+    for header in headers {
+        let header = normalize_header(header);
+        let field = explicit
+            .iter()
+            .find(|(name, _)| *name == header)
+            .map(|(_, field)| field.as_str())
+            .or_else(|| resource.aliases.iter().find(|(alias, _)| *alias == header).map(|(_, field)| *field))
+            .unwrap_or(&header);
+        let index = resource.fields.iter().position(|(name, _)| *name == field);
+        if let Some(index) = index {
+            if mapping.contains(&Some(index)) {
+                return Err(format!("More than one column maps to {}", field));
+            }
+        }
+        mapping.push(index);
+    }
+
+    for required in resource.required {
+        let index = resource.fields.iter().position(|(name, _)| name == required);
+        if !mapping.contains(&index) {
+            return Err(format!("Missing column for {}", required));
+        }
+    }
+    Ok(mapping)
+}
+
+//JSON object for one CSV record, as a POST body would carry it
+fn record_to_json(resource: &Resource, mapping: &[Option<usize>], record: &csv::StringRecord) -> Result<Value, Vec<String>> {
+    let mut object = Map::new();
+    let mut errors = Vec::new();
+    for (value, index) in record.iter().zip(mapping) {
+        let (field, kind) = match index {
+            Some(index) => resource.fields[*index],
+            None => continue,
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        match kind {
+            Kind::Text => {
+                object.insert(field.to_string(), json!(value));
+            }
+            Kind::Integer =>
+                match value.parse::<i32>() {
+                    Ok(number) => {
+                        object.insert(field.to_string(), json!(number));
+                    }
+                    Err(_) => errors.push(format!("{} is not a whole number: {}", field, value)),
+                }
+        }
+    }
+    for required in resource.required {
+        if !object.contains_key(*required) {
+            errors.push(format!("{} is required", required));
+        }
+    }
+    if errors.is_empty() { Ok(Value::Object(object)) } else { Err(errors) }
+}
+
+//a row may only point at a live user or book
+fn check_reference(transaction: &mut Transaction, table: &str, id: i32) -> Result<(), String> {
+    let found = transaction
+        .query_opt(&format!("SELECT 1 FROM {} WHERE id = $1 AND deleted_at IS NULL", table), &[&id])
+        .map_err(|e| e.to_string())?;
+    match found {
+        Some(_) => Ok(()),
+        None if table == "users" => Err(format!("User {} not found", id)),
+        None => Err(format!("Book {} not found", id)),
+    }
+}
+
+//validate and insert one record; runs inside its own savepoint
+fn import_record(resource: &Resource, transaction: &mut Transaction, context: &RequestContext, value: Value) -> Result<(), String> {
+    let invalid = |e: serde_json::Error| e.to_string();
+    let database = |e: postgres::Error| e.as_db_error().map(|e| e.message().to_string()).unwrap_or_else(|| e.to_string());
+    match resource.name {
+        "books" => {
+            let mut book: Book = serde_json::from_value(value).map_err(invalid)?;
+            normalize_book(&mut book)?;
+            insert_book(transaction, context, &book).map(|_| ()).map_err(database)
+        }
+        "users" => {
+            let user: User = serde_json::from_value(value).map_err(invalid)?;
+            insert_user(transaction, context, &user).map(|_| ()).map_err(database)
+        }
+        "loans" => {
+            let loan: Loan = serde_json::from_value(value).map_err(invalid)?;
+            check_reference(transaction, "users", loan.user_id)?;
+            check_reference(transaction, "books", loan.book_id)?;
+            insert_loan(transaction, context, loan).map(|_| ()).map_err(|(_, e)| e)
+        }
+        "reviews" => {
+            let review: Review = serde_json::from_value(value).map_err(invalid)?;
+            if let Some(user_id) = review.user_id {
+                check_reference(transaction, "users", user_id)?;
+            }
+            check_reference(transaction, "books", review.book_id)?;
+            insert_review(transaction, context, &review).map(|_| ()).map_err(database)
+        }
+        _ => {
+            let mut author: authors::Author = serde_json::from_value(value).map_err(invalid)?;
+            authors::validate_author(&mut author)?;
+            authors::insert_author(transaction, context, &author).map(|_| ()).map_err(database)
+        }
+    }
+}
+
+//CSV bytes of an import: a multipart "file" field or the raw body
+fn get_csv_body(request: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+    let content_type = get_header(request, "Content-Type").unwrap_or_default().to_lowercase();
+    if content_type.starts_with("multipart/form-data") {
+        multipart::parse(request, body)
+            .map(|parts| multipart::take_file(parts, "file"))?
+            .map(|part| part.data)
+            .ok_or_else(|| "No file in upload".to_string())
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+//handle import request: every row goes in, or none do; ?dry_run=true only reports.
+//The upload is read whole like any request body, so it is capped at MAX_REQUEST_BYTES (default 64 MiB).
+pub fn handle_import_request(request: &str, body: &[u8], context: &RequestContext) -> (String, String) {
+    let resource = match find_resource(get_path_segment(request, 4)) {
+        Some(resource) => resource,
+        None => return (NOT_FOUND.to_string(), "Unknown import resource".to_string()),
+    };
+    let dry_run = get_query_param(request, "dry_run").as_deref() == Some("true");
+    let data = match get_csv_body(request, body) {
+        Ok(data) => data,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data.as_slice());
+    let headers: Vec<String> = match reader.headers() {
+        Ok(headers) if !headers.is_empty() => headers.iter().map(str::to_string).collect(),
+        _ => return (BAD_REQUEST.to_string(), "CSV header row missing".to_string()),
+    };
+    let mapping = match map_headers(resource, &headers, &get_explicit_mapping(request)) {
+        Ok(mapping) => mapping,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return internal_error(e),
+    };
+
+    let mut report = ImportReport {
+        resource: resource.name.to_string(),
+        dry_run,
+        rows: 0,
+        imported: 0,
+        columns: headers
+            .iter()
+            .zip(&mapping)
+            .filter_map(|(header, index)| index.map(|index| json!({ "column": header, "field": resource.fields[index].0 })))
+            .collect(),
+        ignored_columns: headers
+            .iter()
+            .zip(&mapping)
+            .filter(|(header, index)| index.is_none() && !SYSTEM_COLUMNS.contains(&normalize_header(header).as_str()))
+            .map(|(header, _)| header.clone())
+            .collect(),
+        errors: Vec::new(),
+    };
+
+    //rows are read one at a time; line 1 is the header
+    for (number, record) in reader.records().enumerate() {
+        let row = number + 2;
+        report.rows += 1;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                report.errors.push(RowError { row, errors: vec![e.to_string()] });
+                continue;
+            }
+        };
+        let value = match record_to_json(resource, &mapping, &record) {
+            Ok(value) => value,
+            Err(errors) => {
+                report.errors.push(RowError { row, errors });
+                continue;
+            }
+        };
+
+        //a savepoint per row keeps one bad row from hiding the errors of the rest
+        let mut savepoint = match transaction.transaction() {
+            Ok(savepoint) => savepoint,
+            Err(e) => return internal_error(e),
+        };
+        match import_record(resource, &mut savepoint, context, value) {
+            Ok(()) => {
+                if let Err(e) = savepoint.commit() {
+                    return internal_error(e);
+                }
+                report.imported += 1;
+            }
+            Err(e) => report.errors.push(RowError { row, errors: vec![e] }),
+        }
+    }
+
+    if !report.errors.is_empty() {
+        report.imported = 0;
+        return (BAD_REQUEST.to_string(), serde_json::to_string(&report).unwrap());
+    }
+    if !dry_run {
+        if let Err(e) = transaction.commit() {
+            return internal_error(e);
+        }
+    }
+    (OK_RESPONSE.to_string(), serde_json::to_string(&report).unwrap())
+}
+
+//handle export request: rows are written to the client as they are read, a batch at a time.
+//Returns a response only when nothing has been written yet.
+pub fn handle_export_request(request: &str, context: &RequestContext, stream: &mut dyn Write) -> Option<(String, String)> {
+    let name = get_path_segment(request, 4);
+    let resource = match name.strip_suffix(".csv").and_then(find_resource) {
+        Some(resource) => resource,
+        None => return Some((NOT_FOUND.to_string(), "Unknown export resource".to_string())),
+    };
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return Some((INTERNAL_ERROR.to_string(), "Internal error".to_string())),
+    };
+
+    let columns: Vec<&str> = SYSTEM_COLUMNS[..2]
+        .iter()
+        .copied()
+        .chain(resource.fields.iter().map(|(field, _)| *field))
+        .chain(SYSTEM_COLUMNS[2..].iter().copied())
+        .collect();
+    let select: Vec<String> = columns.iter().map(|column| format!("{}::text", column)).collect();
+    let query = format!("SELECT {} FROM {} WHERE deleted_at IS NULL ORDER BY id", select.join(", "), resource.table);
+
+    //a portal only exists inside a transaction
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return Some(internal_error(e)),
+    };
+    let portal = match transaction.bind(&query, &[]) {
+        Ok(portal) => portal,
+        Err(_) => return Some((INTERNAL_ERROR.to_string(), "Internal error".to_string())),
+    };
+
+    let status_line = with_content_type(OK_RESPONSE, "text/csv; charset=utf-8");
+    let status_line = with_header(&status_line, "Content-Disposition", &format!("attachment; filename=\"{}\"", name));
+    let status_line = with_header(&status_line, "X-Request-Id", &context.request_id);
+    let mut stream = BufWriter::new(stream);
+    if stream.write_all(status_line.as_bytes()).is_err() {
+        return None;
+    }
+
+    let mut writer = csv::Writer::from_writer(stream);
+    let result = writer.write_record(&columns).map_err(|e| e.to_string()).and_then(|_| loop {
+        let rows = transaction.query_portal(&portal, EXPORT_BATCH).map_err(|e| e.to_string())?;
+        for row in &rows {
+            let values: Vec<String> = (0..columns.len()).map(|index| row.get::<_, Option<String>>(index).unwrap_or_default()).collect();
+            writer.write_record(&values).map_err(|e| e.to_string())?;
+        }
+        if rows.len() < EXPORT_BATCH as usize {
+            break writer.flush().map_err(|e| e.to_string());
+        }
+    });
+    if let Err(e) = result {
+        eprintln!("Export of {} stopped: {}", resource.name, e);
+    }
+    None
+}
@@ -0,0 +1,345 @@
+//Importing a Calibre library from its metadata.db
+
+use crate::{
+    authors,
+    get_header,
+    get_query_param,
+    insert_book,
+    insert_review,
+    is_admin,
+    isbn,
+    multipart,
+    next_request_id,
+    normalize_book,
+    series,
+    taxonomy,
+    Book,
+    RequestContext,
+    Review,
+    BAD_REQUEST,
+    DB_URL,
+    FORBIDDEN,
+    INTERNAL_ERROR,
+    OK_RESPONSE,
+};
+use postgres::{ Client, NoTls, Transaction };
+use postgres::Error as PostgresError;
+use rusqlite::{ Connection, OpenFlags };
+use std::env;
+use std::fs;
+use std::path::Path;
+
+//Identifier scheme under which a book's Calibre uuid is kept, so re-imports find it again
+const CALIBRE_SCHEME: &str = "calibre";
+
+//A book as Calibre describes it
+struct CalibreBook {
+    id: i64,
+    uuid: String,
+    title: String,
+    authors: Vec<String>,
+    pubdate: Option<String>,
+    series: Option<String>,
+    series_index: f64,
+    tags: Vec<String>,
+    publisher: Option<String>,
+    identifiers: Vec<(String, String)>,
+    rating: Option<i32>,
+    language: Option<String>,
+    formats: Vec<String>,
+}
+
+//What became of one Calibre book
+#[derive(Serialize)]
+struct EntryResult {
+    calibre_id: i64,
+    title: String,
+    outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+//Outcome of an import
+#[derive(Serialize)]
+pub struct ImportReport {
+    dry_run: bool,
+    books: usize,
+    matched: usize,
+    created: usize,
+    skipped: usize,
+    reviews_created: usize,
+    entries: Vec<EntryResult>,
+}
+
+//values of a one-column query about a book
+fn book_values<T: rusqlite::types::FromSql>(library: &Connection, sql: &str, book: i64) -> rusqlite::Result<Vec<T>> {
+    let mut statement = library.prepare_cached(sql)?;
+    let rows = statement.query_map([book], |row| row.get(0))?;
+    rows.collect()
+}
+
+//read every book of a Calibre library with its links
+fn read_library(library: &Connection) -> rusqlite::Result<Vec<CalibreBook>> {
+    let mut statement = library.prepare("SELECT id, uuid, title, pubdate, series_index FROM books ORDER BY id")?;
+    let mut books = statement
+        .query_map([], |row| {
+            Ok(CalibreBook {
+                id: row.get(0)?,
+                uuid: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                title: row.get(2)?,
+                pubdate: row.get(3)?,
+                series: None,
+                series_index: row.get::<_, Option<f64>>(4)?.unwrap_or(1.0),
+                authors: Vec::new(),
+                tags: Vec::new(),
+                publisher: None,
+                identifiers: Vec::new(),
+                rating: None,
+                language: None,
+                formats: Vec::new(),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    for book in &mut books {
+        let id = book.id;
+        book.series = book_values(library, "SELECT s.name FROM books_series_link l JOIN series s ON s.id = l.series WHERE l.book = ?1", id)?
+            .into_iter()
+            .next();
+        book.authors = book_values(library, "SELECT a.name FROM books_authors_link l JOIN authors a ON a.id = l.author WHERE l.book = ?1 ORDER BY l.id", id)?;
+        book.tags = book_values(library, "SELECT t.name FROM books_tags_link l JOIN tags t ON t.id = l.tag WHERE l.book = ?1 ORDER BY t.name", id)?;
+        book.publisher = book_values(library, "SELECT p.name FROM books_publishers_link l JOIN publishers p ON p.id = l.publisher WHERE l.book = ?1", id)?
+            .into_iter()
+            .next();
+        book.rating = book_values(library, "SELECT r.rating FROM books_ratings_link l JOIN ratings r ON r.id = l.rating WHERE l.book = ?1", id)?
+            .into_iter()
+            .next()
+            .filter(|rating: &i32| *rating > 0);
+        book.language = book_values(library, "SELECT g.lang_code FROM books_languages_link l JOIN languages g ON g.id = l.lang_code WHERE l.book = ?1 ORDER BY l.item_order", id)?
+            .into_iter()
+            .next();
+        book.formats = book_values(library, "SELECT format FROM data WHERE book = ?1", id)?;
+
+        let mut statement = library.prepare_cached("SELECT type, val FROM identifiers WHERE book = ?1 ORDER BY id")?;
+        book.identifiers = statement
+            .query_map([id], |row| Ok((row.get::<_, String>(0)?.to_lowercase(), row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+    }
+    Ok(books)
+}
+
+//ISBN-13 from the book's isbn identifier
+fn get_isbn13(book: &CalibreBook) -> Option<String> {
+    book.identifiers.iter().filter(|(scheme, _)| scheme == "isbn").find_map(|(_, value)| isbn::parse(value).ok())
+}
+
+//a live book imported from this uuid before, with the same ISBN, or the same title and first author
+fn match_book(transaction: &mut Transaction, book: &CalibreBook) -> Result<Option<i32>, PostgresError> {
+    if
+        let Some(row) = transaction.query_opt(
+            "SELECT i.book_id FROM book_identifiers i JOIN books b ON b.id = i.book_id WHERE i.scheme = $1 AND i.value = $2 AND b.deleted_at IS NULL",
+            &[&CALIBRE_SCHEME, &book.uuid]
+        )?
+    {
+        return Ok(Some(row.get(0)));
+    }
+    if let Some(isbn13) = get_isbn13(book) {
+        if let Some(row) = transaction.query_opt("SELECT id FROM books WHERE isbn13 = $1 AND deleted_at IS NULL", &[&isbn13])? {
+            return Ok(Some(row.get(0)));
+        }
+    }
+    authors::find_book(transaction, &book.title, book.authors.first().map(String::as_str).unwrap_or_default())
+}
+
+//catalogue a Calibre book that was not matched
+fn create_book(transaction: &mut Transaction, context: &RequestContext, calibre: &CalibreBook) -> Result<i32, String> {
+    let mut book = Book {
+        title: calibre.title.clone(),
+        author: calibre.authors.join(" & "),
+        isbn13: get_isbn13(calibre),
+        publisher: calibre.publisher.clone(),
+        //Calibre writes 0101-01-01 for an unknown date, which normalizing rejects
+        publication_year: calibre.pubdate.as_deref().and_then(|date| date.get(..4)).and_then(|year| year.parse().ok()),
+        language: calibre.language.clone(),
+        format: if calibre.formats.is_empty() { None } else { Some("ebook".to_string()) },
+        ..Book::default()
+    };
+    if normalize_book(&mut book).is_err() {
+        book.publication_year = None;
+        book.language = None;
+        normalize_book(&mut book)?;
+    }
+    let book = insert_book(transaction, context, &book).map_err(|e| e.to_string())?;
+    Ok(book.id.unwrap_or_default())
+}
+
+//link series, tags, identifiers and rating; links already present are left as they are
+fn link_book(
+    transaction: &mut Transaction,
+    context: &RequestContext,
+    book_id: i32,
+    calibre: &CalibreBook,
+    user_id: Option<i32>
+) -> Result<bool, PostgresError> {
+    if let Some(name) = &calibre.series {
+        let series_id = series::find_or_create_series(transaction, context, name)?;
+        transaction.execute(
+            "INSERT INTO book_series (series_id, book_id, position) VALUES ($1, $2, $3) ON CONFLICT (series_id, book_id) DO NOTHING",
+            &[&series_id, &book_id, &calibre.series_index.max(0.0)]
+        )?;
+    }
+    for tag in calibre.tags.iter().map(|tag| tag.trim()).filter(|tag| !tag.is_empty()) {
+        let tag_id = taxonomy::find_or_create_tag(transaction, context, tag)?;
+        transaction.execute("INSERT INTO book_tags (book_id, tag_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", &[&book_id, &tag_id])?;
+    }
+
+    let uuid = (CALIBRE_SCHEME.to_string(), calibre.uuid.clone());
+    for (scheme, value) in calibre.identifiers.iter().chain([&uuid]).filter(|(_, value)| !value.trim().is_empty()) {
+        transaction.execute(
+            "INSERT INTO book_identifiers (book_id, scheme, value) VALUES ($1, $2, $3) ON CONFLICT (book_id, scheme) DO NOTHING",
+            &[&book_id, scheme, &value.trim()]
+        )?;
+    }
+
+    //Calibre rates out of ten, in half stars
+    let rating = match calibre.rating {
+        Some(rating) => ((rating + 1) / 2).clamp(1, 5),
+        None => return Ok(false),
+    };
+    let reviewed = transaction
+        .query_opt("SELECT 1 FROM reviews WHERE book_id = $1 AND user_id IS NOT DISTINCT FROM $2 AND deleted_at IS NULL", &[&book_id, &user_id])?
+        .is_some();
+    if reviewed {
+        return Ok(false);
+    }
+    insert_review(transaction, context, &Review { book_id, user_id, rating, ..Review::default() })?;
+    Ok(true)
+}
+
+//import a Calibre library; with dry_run nothing is kept
+pub fn import_library(path: &Path, context: &RequestContext, user_id: Option<i32>, dry_run: bool) -> Result<ImportReport, String> {
+    let library = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+        .map_err(|e| format!("Unable to open Calibre library: {}", e))?;
+    let books = read_library(&library).map_err(|e| format!("Not a Calibre metadata.db: {}", e))?;
+
+    let mut client = Client::connect(DB_URL, NoTls).map_err(|e| e.to_string())?;
+    let mut transaction = client.transaction().map_err(|e| e.to_string())?;
+    if let Some(user_id) = user_id {
+        if transaction.query_opt("SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL", &[&user_id]).map_err(|e| e.to_string())?.is_none() {
+            return Err("User not found".to_string());
+        }
+    }
+
+    let mut report = ImportReport { dry_run, books: books.len(), matched: 0, created: 0, skipped: 0, reviews_created: 0, entries: Vec::new() };
+    for calibre in &books {
+        let mut result = EntryResult { calibre_id: calibre.id, title: calibre.title.clone(), outcome: "skipped".to_string(), book_id: None, reason: None };
+        if calibre.title.trim().is_empty() || calibre.authors.is_empty() {
+            result.reason = Some("Title and author are required".to_string());
+        } else {
+            //a savepoint per book, so one bad book is skipped without losing the rest
+            let mut savepoint = transaction.transaction().map_err(|e| e.to_string())?;
+            let imported = match match_book(&mut savepoint, calibre).map_err(|e| e.to_string()) {
+                Ok(Some(book_id)) => Ok(("matched", book_id)),
+                Ok(None) => create_book(&mut savepoint, context, calibre).map(|book_id| ("created", book_id)),
+                Err(e) => Err(e),
+            }
+            .and_then(|(outcome, book_id)| {
+                let reviewed = link_book(&mut savepoint, context, book_id, calibre, user_id).map_err(|e| e.to_string())?;
+                Ok((outcome, book_id, reviewed))
+            });
+            match imported {
+                Ok((outcome, book_id, reviewed)) => {
+                    savepoint.commit().map_err(|e| e.to_string())?;
+                    result.outcome = outcome.to_string();
+                    result.book_id = Some(book_id);
+                    if reviewed {
+                        report.reviews_created += 1;
+                    }
+                }
+                Err(e) => result.reason = Some(e),
+            }
+        }
+
+        match result.outcome.as_str() {
+            "matched" => report.matched += 1,
+            "created" => report.created += 1,
+            _ => report.skipped += 1,
+        }
+        report.entries.push(result);
+    }
+
+    if !dry_run {
+        transaction.commit().map_err(|e| e.to_string())?;
+    }
+    Ok(report)
+}
+
+//handle Calibre import request: an uploaded metadata.db, admins only; ?user_id= owns the ratings
+pub fn handle_import_calibre_request(request: &str, body: &[u8], context: &RequestContext) -> (String, String) {
+    if !is_admin(request) {
+        return (FORBIDDEN.to_string(), "Admin token required".to_string());
+    }
+    let user_id = match get_query_param(request, "user_id").map(|user_id| user_id.parse::<i32>()) {
+        Some(Ok(user_id)) => Some(user_id),
+        Some(Err(_)) => return (BAD_REQUEST.to_string(), "Invalid user id".to_string()),
+        None => None,
+    };
+    let dry_run = get_query_param(request, "dry_run").as_deref() == Some("true");
+    let data = if get_header(request, "Content-Type").unwrap_or_default().to_lowercase().starts_with("multipart/form-data") {
+        match multipart::parse(request, body).map(|parts| multipart::take_file(parts, "file")) {
+            Ok(Some(file)) => file.data,
+            Ok(None) => return (BAD_REQUEST.to_string(), "No file in upload".to_string()),
+            Err(e) => return (BAD_REQUEST.to_string(), e),
+        }
+    } else {
+        body.to_vec()
+    };
+    if !data.starts_with(b"SQLite format 3\0") {
+        return (BAD_REQUEST.to_string(), "Upload is not a SQLite database".to_string());
+    }
+
+    //SQLite reads from a file, so the upload is parked in the temp dir for the duration
+    let path = env::temp_dir().join(format!("calibre-{}.db", next_request_id()));
+    if let Err(e) = fs::write(&path, &data) {
+        eprintln!("Unable to write Calibre upload: {}", e);
+        return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+    }
+    let result = import_library(&path, context, user_id, dry_run);
+    let _ = fs::remove_file(&path);
+
+    match result {
+        Ok(report) => (OK_RESPONSE.to_string(), serde_json::to_string(&report).unwrap()),
+        Err(e) => (BAD_REQUEST.to_string(), e),
+    }
+}
+
+//`backend import-calibre <metadata.db> [--user-id <id>] [--dry-run]`
+pub fn run_import_command(args: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut user_id = None;
+    let mut dry_run = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--user-id" => {
+                let value = args.next().ok_or("--user-id needs a value")?;
+                user_id = Some(value.parse::<i32>().map_err(|_| format!("Invalid user id: {}", value))?);
+            }
+            other if path.is_none() => path = Some(other.to_string()),
+            other => return Err(format!("Unexpected argument: {}", other)),
+        }
+    }
+    let path = path.ok_or("Usage: backend import-calibre <metadata.db> [--user-id <id>] [--dry-run]")?;
+
+    let context = RequestContext {
+        actor: Some(env::var("USER").unwrap_or_else(|_| "import-calibre".to_string())),
+        client_ip: None,
+        request_id: next_request_id(),
+    };
+    let report = import_library(Path::new(&path), &context, user_id, dry_run)?;
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    Ok(())
+}
@@ -0,0 +1,320 @@
+//Citations of books as BibTeX, RIS and CSL-JSON
+
+use crate::{
+    authors,
+    book_from_row,
+    get_id,
+    get_query_param,
+    with_content_type,
+    Book,
+    BAD_REQUEST,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    OK_RESPONSE,
+};
+use postgres::{ Client, NoTls };
+use postgres::Error as PostgresError;
+use serde_json::{ json, Value };
+use std::collections::HashMap;
+
+//Citation formats with their media types
+const FORMATS: [(&str, &str); 3] = [
+    ("bibtex", "application/x-bibtex; charset=utf-8"),
+    ("ris", "application/x-research-info-systems; charset=utf-8"),
+    ("csl-json", "application/vnd.citationstyles.csl+json"),
+];
+
+//Most books one batch may cite
+const MAX_BATCH: usize = 500;
+
+//Leading words a citation key skips
+const ARTICLES: [&str; 3] = ["the", "a", "an"];
+
+//A contributor as cited: family and given names, and their role on the book
+struct Contributor {
+    family: String,
+    given: Option<String>,
+    role: String,
+}
+
+//A book with its contributors and citation key
+struct Citation {
+    key: String,
+    book: Book,
+    contributors: Vec<Contributor>,
+}
+
+impl Citation {
+    fn names(&self, role: &str) -> impl Iterator<Item = &Contributor> {
+        let role = role.to_string();
+        self.contributors.iter().filter(move |contributor| contributor.role == role)
+    }
+}
+
+//"Herbert, Frank" -> family "Herbert", given "Frank"
+fn contributor(sort_name: &str, role: &str) -> Contributor {
+    match sort_name.split_once(',') {
+        Some((family, given)) if !given.trim().is_empty() =>
+            Contributor { family: family.trim().to_string(), given: Some(given.trim().to_string()), role: role.to_string() },
+        _ => Contributor { family: sort_name.trim().to_string(), given: None, role: role.to_string() },
+    }
+}
+
+//lowercase ASCII letters and digits of a word, for citation keys
+fn key_part(word: &str) -> String {
+    word.chars().flat_map(char::to_lowercase).filter(char::is_ascii_alphanumeric).collect()
+}
+
+//key such as herbert1965dune-12: first family name, year, first significant title word and the book id.
+//The id keeps two books that share the rest apart, and the key never depends on what else is exported with it.
+fn citation_key(book: &Book, contributors: &[Contributor]) -> String {
+    let name = contributors.first().map(|contributor| key_part(&contributor.family)).filter(|name| !name.is_empty());
+    let year = book.publication_year.map(|year| year.to_string()).unwrap_or_else(|| "nd".to_string());
+    let word = book
+        .title
+        .split_whitespace()
+        .map(key_part)
+        .filter(|word| !word.is_empty())
+        .find(|word| !ARTICLES.contains(&word.as_str()))
+        .unwrap_or_default();
+    format!("{}{}{}-{}", name.unwrap_or_else(|| "anon".to_string()), year, word, book.id.unwrap_or_default())
+}
+
+//load books in the order asked for, with their credited contributors; ids not found are returned separately
+fn load_citations(client: &mut Client, ids: &[i32]) -> Result<(Vec<Citation>, Vec<i32>), PostgresError> {
+    let mut books: HashMap<i32, Book> = client
+        .query(
+            "SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = ANY($1) AND deleted_at IS NULL",
+            &[&ids]
+        )?
+        .iter()
+        .map(|row| (row.get(0), book_from_row(row)))
+        .collect();
+    let mut credits: HashMap<i32, Vec<Contributor>> = HashMap::new();
+    for row in client.query(
+        "SELECT ba.book_id, a.sort_name, a.name, ba.role FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = ANY($1) AND a.deleted_at IS NULL ORDER BY ba.book_id, ba.position, ba.role",
+        &[&ids]
+    )? {
+        let sort_name: Option<String> = row.get(1);
+        let name: String = row.get(2);
+        let role: String = row.get(3);
+        let sort_name = sort_name.unwrap_or_else(|| authors::sort_name(&name));
+        credits.entry(row.get(0)).or_default().push(contributor(&sort_name, &role));
+    }
+
+    let mut citations = Vec::new();
+    let mut missing = Vec::new();
+    for id in ids {
+        let book = match books.remove(id) {
+            Some(book) => book,
+            None => {
+                if !citations.iter().any(|citation: &Citation| citation.book.id == Some(*id)) {
+                    missing.push(*id);
+                }
+                continue;
+            }
+        };
+        //books catalogued before author credits fall back to the author string
+        let contributors = credits.remove(id).unwrap_or_else(|| {
+            authors::split_names(&book.author).iter().map(|name| contributor(&authors::sort_name(name), "author")).collect()
+        });
+        citations.push(Citation { key: citation_key(&book, &contributors), book, contributors });
+    }
+    Ok((citations, missing))
+}
+
+//escape LaTeX special characters in a BibTeX field value
+fn escape_bibtex(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            '{' | '}' | '&' | '%' | '$' | '#' | '_' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '\r' | '\n' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn bibtex_names(citation: &Citation, role: &str) -> Option<String> {
+    let names: Vec<String> = citation
+        .names(role)
+        .map(|name| match &name.given {
+            Some(given) => format!("{}, {}", escape_bibtex(&name.family), escape_bibtex(given)),
+            None => format!("{{{}}}", escape_bibtex(&name.family)),
+        })
+        .collect();
+    if names.is_empty() { None } else { Some(names.join(" and ")) }
+}
+
+fn bibtex(citation: &Citation) -> String {
+    let book = &citation.book;
+    //double braces keep the title's capitalization
+    let mut fields = vec![("title", format!("{{{}}}", escape_bibtex(&book.title)))];
+    fields.extend(bibtex_names(citation, "author").map(|names| ("author", names)));
+    fields.extend(bibtex_names(citation, "editor").map(|names| ("editor", names)));
+    fields.extend(bibtex_names(citation, "translator").map(|names| ("translator", names)));
+    fields.extend(book.publisher.as_deref().map(|publisher| ("publisher", escape_bibtex(publisher))));
+    fields.extend(book.publication_year.map(|year| ("year", year.to_string())));
+    fields.extend(book.edition.as_deref().map(|edition| ("edition", escape_bibtex(edition))));
+    fields.extend(book.isbn13.as_deref().or(book.isbn10.as_deref()).map(|isbn| ("isbn", isbn.to_string())));
+    fields.extend(book.page_count.map(|pages| ("pagetotal", pages.to_string())));
+    fields.extend(book.language.as_deref().map(|language| ("language", language.to_string())));
+
+    let body: Vec<String> = fields.iter().map(|(name, value)| format!("  {} = {{{}}}", name, value)).collect();
+    format!("@book{{{},\n{}\n}}\n", citation.key, body.join(",\n"))
+}
+
+//RIS values are single lines
+fn ris_line(tag: &str, value: &str) -> String {
+    format!("{}  - {}\r\n", tag, value.replace(['\r', '\n'], " ").trim())
+}
+
+fn ris(citation: &Citation) -> String {
+    let book = &citation.book;
+    let mut lines = vec![ris_line("TY", "BOOK"), ris_line("ID", &citation.key), ris_line("TI", &book.title)];
+    for (role, tag) in [("author", "AU"), ("editor", "A2"), ("translator", "A4")] {
+        for name in citation.names(role) {
+            let name = match &name.given {
+                Some(given) => format!("{}, {}", name.family, given),
+                None => name.family.clone(),
+            };
+            lines.push(ris_line(tag, &name));
+        }
+    }
+    lines.extend(book.publication_year.map(|year| ris_line("PY", &year.to_string())));
+    lines.extend(book.publisher.as_deref().map(|publisher| ris_line("PB", publisher)));
+    lines.extend(book.edition.as_deref().map(|edition| ris_line("ET", edition)));
+    lines.extend([&book.isbn13, &book.isbn10].into_iter().flatten().map(|isbn| ris_line("SN", isbn)));
+    lines.extend(book.page_count.map(|pages| ris_line("SP", &pages.to_string())));
+    lines.extend(book.language.as_deref().map(|language| ris_line("LA", language)));
+    lines.push(ris_line("ER", ""));
+    lines.concat()
+}
+
+fn csl_names(citation: &Citation, role: &str) -> Option<Value> {
+    let names: Vec<Value> = citation
+        .names(role)
+        .map(|name| match &name.given {
+            Some(given) => json!({ "family": name.family, "given": given }),
+            None => json!({ "literal": name.family }),
+        })
+        .collect();
+    if names.is_empty() { None } else { Some(Value::Array(names)) }
+}
+
+fn csl_json(citation: &Citation) -> Value {
+    let book = &citation.book;
+    let mut item = json!({ "id": citation.key, "type": "book", "title": book.title });
+    let fields = [
+        ("author", csl_names(citation, "author")),
+        ("editor", csl_names(citation, "editor")),
+        ("translator", csl_names(citation, "translator")),
+        ("issued", book.publication_year.map(|year| json!({ "date-parts": [[year]] }))),
+        ("publisher", book.publisher.as_ref().map(|publisher| json!(publisher))),
+        ("edition", book.edition.as_ref().map(|edition| json!(edition))),
+        ("ISBN", book.isbn13.as_ref().or(book.isbn10.as_ref()).map(|isbn| json!(isbn))),
+        ("number-of-pages", book.page_count.map(|pages| json!(pages))),
+        ("language", book.language.as_ref().map(|language| json!(language))),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            item[name] = value;
+        }
+    }
+    item
+}
+
+//render citations in a format and pair them with its media type
+fn render(citations: &[Citation], format: &str) -> (String, String) {
+    let media_type = FORMATS.iter().find(|(name, _)| *name == format).map(|(_, media_type)| *media_type).unwrap_or_default();
+    let content = match format {
+        "bibtex" => citations.iter().map(bibtex).collect::<Vec<_>>().join("\n"),
+        "ris" => citations.iter().map(ris).collect::<Vec<_>>().concat(),
+        _ => serde_json::to_string(&citations.iter().map(csl_json).collect::<Vec<_>>()).unwrap(),
+    };
+    (with_content_type(OK_RESPONSE, media_type), content)
+}
+
+//?format=, bibtex when absent
+fn get_format(request: &str) -> Result<String, String> {
+    let format = get_query_param(request, "format").unwrap_or_else(|| "bibtex".to_string());
+    if FORMATS.iter().any(|(name, _)| *name == format) {
+        Ok(format)
+    } else {
+        let names: Vec<&str> = FORMATS.iter().map(|(name, _)| *name).collect();
+        Err(format!("Invalid format (expected one of {})", names.join(", ")))
+    }
+}
+
+//handle get book citation request
+pub fn handle_get_book_citation_request(request: &str) -> (String, String) {
+    let format = match get_format(request) {
+        Ok(format) => format,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) =>
+            match load_citations(&mut client, &[id]) {
+                Ok((citations, _)) if citations.is_empty() => (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Ok((citations, _)) => render(&citations, &format),
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid book id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle batch citation request: ?ids=1,2,3 in the order given
+pub fn handle_get_citations_request(request: &str) -> (String, String) {
+    let format = match get_format(request) {
+        Ok(format) => format,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+    let ids: Result<Vec<i32>, _> = get_query_param(request, "ids")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(str::parse)
+        .collect();
+    let ids = match ids {
+        Ok(ids) if ids.is_empty() => return (BAD_REQUEST.to_string(), "ids is required".to_string()),
+        Ok(ids) if ids.len() > MAX_BATCH => return (BAD_REQUEST.to_string(), format!("At most {} ids per request", MAX_BATCH)),
+        Ok(ids) => ids,
+        Err(_) => return (BAD_REQUEST.to_string(), "Invalid book id in ids".to_string()),
+    };
+
+    match Client::connect(DB_URL, NoTls) {
+        Ok(mut client) =>
+            match load_citations(&mut client, &ids) {
+                Ok((_, missing)) if !missing.is_empty() =>
+                    (NOT_FOUND.to_string(), json!({ "error": "Books not found", "ids": missing }).to_string()),
+                Ok((citations, _)) => render(&citations, &format),
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn citation_key_depends_only_on_the_book() {
+        let book = |id: i32| Book { id: Some(id), title: "The Dune Messiah".to_string(), publication_year: Some(1969), ..Book::default() };
+        let herbert = [contributor("Herbert, Frank", "author")];
+        assert_eq!(citation_key(&book(12), &herbert), "herbert1969dune-12");
+        assert_eq!(citation_key(&book(12), &herbert), citation_key(&book(12), &herbert));
+        assert_ne!(citation_key(&book(12), &herbert), citation_key(&book(13), &herbert));
+        assert_eq!(citation_key(&Book { publication_year: None, ..book(3) }, &[]), "anonnddune-3");
+    }
+}
@@ -0,0 +1,589 @@
+//Physical copies of books and their availability for loan
+
+use crate::{
+    apply_merge_patch,
+    etag,
+    get_id,
+    get_include_deleted,
+    get_patch_request_body,
+    get_query_param,
+    holds,
+    internal_error,
+    not_modified,
+    precondition_failed,
+    with_header,
+    write_audit,
+    Loan,
+    RequestContext,
+    BAD_REQUEST,
+    CONFLICT,
+    DB_URL,
+    FORBIDDEN,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    NOT_MODIFIED,
+    OK_RESPONSE,
+    PRECONDITION_FAILED,
+};
+use chrono::{ DateTime, NaiveDate, Utc };
+use postgres::{ Client, NoTls, Row, Transaction };
+use postgres::error::SqlState;
+use postgres::Error as PostgresError;
+use serde_json::json;
+
+//Shelf states of a copy; whether it is out on loan follows from its loans
+pub const STATUSES: [&str; 4] = ["available", "in_repair", "lost", "withdrawn"];
+
+//Physical condition of a copy
+pub const CONDITIONS: [&str; 5] = ["new", "good", "fair", "poor", "damaged"];
+
+//a copy is on loan while it has a live loan that has not been returned
+pub const ON_LOAN: &str = "EXISTS (SELECT 1 FROM loans WHERE loans.copy_id = copies.id AND loans.return_date IS NULL AND loans.deleted_at IS NULL)";
+
+//Copy struct with id, book, barcode, acquisition date, condition, location, status and version
+#[derive(Serialize, Deserialize)]
+pub struct Copy {
+    pub id: Option<i32>,
+    pub book_id: i32,
+    pub barcode: Option<String>,
+    pub acquired_on: Option<NaiveDate>,
+    pub condition: Option<String>,
+    pub location: Option<String>,
+    #[serde(default = "default_status")]
+    pub status: String,
+    pub version: Option<i32>,
+    #[serde(skip_deserializing)]
+    pub on_loan: bool,
+    #[serde(skip_deserializing)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+//Copy counts for a book
+#[derive(Serialize)]
+struct Availability {
+    book_id: i32,
+    total: i64,
+    available: i64,
+    on_loan: i64,
+}
+
+fn default_status() -> String {
+    "available".to_string()
+}
+
+//map copies row to Copy
+pub fn copy_from_row(row: &Row) -> Copy {
+    Copy {
+        id: row.get(0),
+        book_id: row.get(1),
+        barcode: row.get(2),
+        acquired_on: row.get(3),
+        condition: row.get(4),
+        location: row.get(5),
+        status: row.get(6),
+        version: row.get(7),
+        created_at: row.get(8),
+        updated_at: row.get(9),
+        deleted_at: row.get(10),
+        on_loan: row.get(11),
+    }
+}
+
+//validate a copy's status, condition and book
+fn validate_copy(transaction: &mut Transaction, copy: &mut Copy) -> Result<(), String> {
+    copy.barcode = copy.barcode.as_deref().map(str::trim).filter(|barcode| !barcode.is_empty()).map(str::to_string);
+    if !STATUSES.contains(&copy.status.as_str()) {
+        return Err(format!("Invalid status: {} (expected one of {})", copy.status, STATUSES.join(", ")));
+    }
+    if let Some(condition) = &copy.condition {
+        if !CONDITIONS.contains(&condition.as_str()) {
+            return Err(format!("Invalid condition: {} (expected one of {})", condition, CONDITIONS.join(", ")));
+        }
+    }
+
+    let book = transaction
+        .query_opt("SELECT 1 FROM books WHERE id = $1 AND deleted_at IS NULL", &[&copy.book_id])
+        .map_err(|_| "Internal error".to_string())?;
+    if book.is_none() {
+        return Err(format!("Book {} not found", copy.book_id));
+    }
+    Ok(())
+}
+
+//map a failed copy write to a response; a duplicate barcode is a conflict
+fn copy_write_error(e: PostgresError) -> (String, String) {
+    match e.code() {
+        Some(code) if *code == SqlState::UNIQUE_VIOLATION =>
+            (CONFLICT.to_string(), "A copy with this barcode already exists".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//deserialize copy from request body without id
+fn get_copy_request_body(request: &str) -> Result<Copy, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//lock a live copy
+fn lock_copy(transaction: &mut Transaction, id: i32) -> Result<Option<Copy>, PostgresError> {
+    let row = transaction.query_opt(
+        &format!(
+            "SELECT id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {} FROM copies WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+            ON_LOAN
+        ),
+        &[&id]
+    )?;
+    Ok(row.as_ref().map(copy_from_row))
+}
+
+//create the first copy of a newly catalogued book
+pub fn create_first_copy(transaction: &mut Transaction, context: &RequestContext, book_id: i32) -> Result<(), PostgresError> {
+    let row = transaction.query_one(
+        &format!(
+            "INSERT INTO copies (book_id) VALUES ($1) RETURNING id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {}",
+            ON_LOAN
+        ),
+        &[&book_id]
+    )?;
+    let copy = copy_from_row(&row);
+    write_audit(transaction, context, "create", "copies", copy.id.unwrap_or_default(), None, Some(&copy))
+}
+
+//give every printed book that predates copies one copy and point its loans at it
+pub fn migrate_book_copies(client: &mut Client) -> Result<(), PostgresError> {
+    let mut transaction = client.transaction()?;
+    let context = RequestContext {
+        actor: Some("migration".to_string()),
+        client_ip: None,
+        request_id: crate::next_request_id(),
+    };
+
+    let rows = transaction.query("SELECT id FROM books WHERE format IS DISTINCT FROM 'ebook' AND NOT EXISTS (SELECT 1 FROM copies WHERE book_id = books.id)", &[])?;
+    for row in rows {
+        create_first_copy(&mut transaction, &context, row.get(0))?;
+    }
+    transaction.execute(
+        "UPDATE loans SET copy_id = (SELECT min(id) FROM copies WHERE copies.book_id = loans.book_id) WHERE copy_id IS NULL",
+        &[]
+    )?;
+    transaction.commit()
+}
+
+//pick the copy a loan is for, checking that it belongs to the book and is free to lend;
+//`loan_id` is the loan being updated, which may keep its own copy
+pub fn assign_copy(transaction: &mut Transaction, loan: &mut Loan, loan_id: Option<i32>) -> Result<(), (String, String)> {
+    let internal_error = |_| (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+    let active = loan.return_date.is_none();
+
+    let copy_id = match loan.copy_id {
+        Some(copy_id) => copy_id,
+        None if !active => return Ok(()),
+        None => {
+            let row = transaction
+                .query_opt(
+                    "SELECT id FROM copies WHERE book_id = $1 AND deleted_at IS NULL AND status = 'available' AND NOT EXISTS (SELECT 1 FROM loans WHERE loans.copy_id = copies.id AND loans.return_date IS NULL AND loans.deleted_at IS NULL AND loans.id IS DISTINCT FROM $2) ORDER BY id LIMIT 1 FOR UPDATE",
+                    &[&loan.book_id, &loan_id]
+                )
+                .map_err(internal_error)?;
+            match row {
+                Some(row) => row.get(0),
+                None => return Err((CONFLICT.to_string(), "No copies of this book are available".to_string())),
+            }
+        }
+    };
+
+    let copy = match lock_copy(transaction, copy_id).map_err(internal_error)? {
+        Some(copy) => copy,
+        None => return Err((BAD_REQUEST.to_string(), format!("Copy {} not found", copy_id))),
+    };
+    if copy.book_id != loan.book_id {
+        return Err((BAD_REQUEST.to_string(), format!("Copy {} is not a copy of book {}", copy_id, loan.book_id)));
+    }
+    if active {
+        if copy.status != "available" {
+            return Err((CONFLICT.to_string(), format!("Copy {} is {}", copy_id, copy.status)));
+        }
+        let borrowed = transaction
+            .query_opt(
+                "SELECT 1 FROM loans WHERE copy_id = $1 AND return_date IS NULL AND deleted_at IS NULL AND id IS DISTINCT FROM $2",
+                &[&copy_id, &loan_id]
+            )
+            .map_err(internal_error)?;
+        if borrowed.is_some() {
+            return Err((CONFLICT.to_string(), format!("Copy {} is already on loan", copy_id)));
+        }
+
+        //ready holds claim the free copies of a book; a loan that does not already have this copy
+        //may only take one while there are more free copies than other borrowers' ready holds
+        let held = transaction
+            .query_one(
+                &format!(
+                    "SELECT NOT EXISTS (SELECT 1 FROM loans WHERE id = $3 AND copy_id = $2 AND return_date IS NULL AND deleted_at IS NULL)
+                    AND (SELECT count(*) FROM copies WHERE book_id = $1 AND deleted_at IS NULL AND status = 'available' AND NOT {})
+                        <= (SELECT count(*) FROM holds WHERE book_id = $1 AND status = 'ready' AND user_id <> $4)",
+                    ON_LOAN
+                ),
+                &[&loan.book_id, &copy_id, &loan_id, &loan.user_id]
+            )
+            .map_err(internal_error)?
+            .get::<_, bool>(0);
+        if held {
+            return Err((CONFLICT.to_string(), "Available copies of this book are held for other borrowers".to_string()));
+        }
+    }
+
+    loan.copy_id = Some(copy_id);
+    Ok(())
+}
+
+//handle post copy request
+pub fn handle_post_copy_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_copy_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(mut copy), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = validate_copy(&mut transaction, &mut copy) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let row = match
+                transaction.query_one(
+                    &format!(
+                        "INSERT INTO copies (book_id, barcode, acquired_on, condition, location, status) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {}",
+                        ON_LOAN
+                    ),
+                    &[&copy.book_id, &copy.barcode, &copy.acquired_on, &copy.condition, &copy.location, &copy.status]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return copy_write_error(e),
+            };
+            let copy = copy_from_row(&row);
+            //a new copy may be the one the next hold in line is waiting for
+            let saved = write_audit(&mut transaction, context, "create", "copies", copy.id.unwrap_or_default(), None, Some(&copy))
+                .and_then(|_| holds::promote_holds(&mut transaction, context))
+                .and_then(|_| transaction.commit());
+            if saved.is_err() {
+                return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&copy).unwrap())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get copy request
+pub fn handle_get_copy_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(include_deleted), Ok(mut client)) =>
+            match
+                client.query_one(
+                    &format!(
+                        "SELECT id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {} FROM copies WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+                        ON_LOAN
+                    ),
+                    &[&id, &include_deleted]
+                )
+            {
+                Ok(row) => {
+                    let copy = copy_from_row(&row);
+
+                    //conditional GET
+                    let version = copy.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&copy).unwrap())
+                }
+                _ => (NOT_FOUND.to_string(), "Copy not found".to_string()),
+            }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all copy request, optionally filtered by ?book_id=, ?status= and ?location=
+pub fn handle_get_all_copy_request(request: &str) -> (String, String) {
+    let book_id = get_query_param(request, "book_id").map(|id| id.parse::<i32>());
+    if let Some(Err(_)) = book_id {
+        return (BAD_REQUEST.to_string(), "Invalid book_id".to_string());
+    }
+    let book_id = book_id.and_then(Result::ok);
+    let status = get_query_param(request, "status");
+    let location = get_query_param(request, "location");
+
+    match (get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(include_deleted), Ok(mut client)) => {
+            let copies: Vec<Copy> = match
+                client.query(
+                    &format!(
+                        "SELECT id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {} FROM copies WHERE ($1 OR deleted_at IS NULL) AND ($2::int IS NULL OR book_id = $2) AND ($3::varchar IS NULL OR status = $3) AND ($4::varchar IS NULL OR location = $4) ORDER BY book_id, id",
+                        ON_LOAN
+                    ),
+                    &[&include_deleted, &book_id, &status, &location]
+                )
+            {
+                Ok(rows) => rows.iter().map(copy_from_row).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&copies).unwrap())
+        }
+        (Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book copies request
+pub fn handle_get_book_copies_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let copies: Vec<Copy> = match
+                client.query(
+                    &format!(
+                        "SELECT id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {} FROM copies WHERE book_id = $1 AND deleted_at IS NULL ORDER BY id",
+                        ON_LOAN
+                    ),
+                    &[&id]
+                )
+            {
+                Ok(rows) => rows.iter().map(copy_from_row).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&copies).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book availability request; lost and withdrawn copies are not counted
+pub fn handle_get_book_availability_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            match client.query_opt("SELECT 1 FROM books WHERE id = $1 AND deleted_at IS NULL", &[&id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+
+            let row = match
+                client.query_one(
+                    &format!(
+                        "SELECT count(*), count(*) FILTER (WHERE status = 'available' AND NOT {on_loan}), count(*) FILTER (WHERE {on_loan}) FROM copies WHERE book_id = $1 AND deleted_at IS NULL AND status NOT IN ('lost', 'withdrawn')",
+                        on_loan = ON_LOAN
+                    ),
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let availability = Availability { book_id: id, total: row.get(0), available: row.get(1), on_loan: row.get(2) };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&availability).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put copy request
+pub fn handle_put_copy_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_copy_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(copy), Ok(mut client)) => update_copy(&mut client, request, context, id, |_| Ok(copy)),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle patch copy request
+pub fn handle_patch_copy_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_patch_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(patch), Ok(mut client)) =>
+            update_copy(&mut client, request, context, id, |current| {
+                apply_merge_patch(current, &patch).map_err(|e| format!("Invalid patch: {}", e))
+            }),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//lock a copy, build its replacement from the stored row and write it back
+fn update_copy(
+    client: &mut Client,
+    request: &str,
+    context: &RequestContext,
+    id: i32,
+    replacement: impl FnOnce(&Copy) -> Result<Copy, String>
+) -> (String, String) {
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return internal_error(e),
+    };
+    let before = match lock_copy(&mut transaction, id) {
+        Ok(Some(copy)) => copy,
+        Ok(None) => return (NOT_FOUND.to_string(), "Copy not found".to_string()),
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+
+    //check the If-Match precondition against the locked row
+    if precondition_failed(request, before.version.unwrap_or_default()) {
+        return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+    }
+
+    let mut copy = match replacement(&before) {
+        Ok(copy) => copy,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+    if let Err(e) = validate_copy(&mut transaction, &mut copy) {
+        return (BAD_REQUEST.to_string(), e);
+    }
+
+    //a copy out on loan stays with its book until it comes back; it can still be reported lost
+    if before.on_loan && copy.book_id != before.book_id {
+        return (CONFLICT.to_string(), "Copy is on loan and cannot move to another book".to_string());
+    }
+    if before.on_loan && (copy.status == "in_repair" || copy.status == "withdrawn") {
+        return (CONFLICT.to_string(), format!("Copy is on loan and cannot be marked {}", copy.status));
+    }
+
+    let row = match
+        transaction.query_one(
+            &format!(
+                "UPDATE copies SET book_id = $1, barcode = $2, acquired_on = $3, condition = $4, location = $5, status = $6, version = version + 1 WHERE id = $7 RETURNING id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {}",
+                ON_LOAN
+            ),
+            &[&copy.book_id, &copy.barcode, &copy.acquired_on, &copy.condition, &copy.location, &copy.status, &id]
+        )
+    {
+        Ok(row) => row,
+        Err(e) => return copy_write_error(e),
+    };
+    let after = copy_from_row(&row);
+    //a copy back from repair, or moved to another book, may free up for a hold
+    let saved = write_audit(&mut transaction, context, "update", "copies", id, Some(&before), Some(&after))
+        .and_then(|_| holds::promote_holds(&mut transaction, context))
+        .and_then(|_| transaction.commit());
+    if saved.is_err() {
+        return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+    }
+
+    (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+}
+
+//handle restore copy request
+pub fn handle_restore_copy_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match
+                transaction.query_opt(
+                    &format!(
+                        "SELECT id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {} FROM copies WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE",
+                        ON_LOAN
+                    ),
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => copy_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Copy not found in trash".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let row = match
+                transaction.query_one(
+                    &format!(
+                        "UPDATE copies SET deleted_at = NULL, version = version + 1 WHERE id = $1 RETURNING id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {}",
+                        ON_LOAN
+                    ),
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return copy_write_error(e),
+            };
+            let after = copy_from_row(&row);
+            let saved = write_audit(&mut transaction, context, "restore", "copies", id, Some(&before), Some(&after))
+                .and_then(|_| holds::promote_holds(&mut transaction, context))
+                .and_then(|_| transaction.commit());
+            if saved.is_err() {
+                return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete copy request; a copy out on loan is kept until it is returned
+pub fn handle_delete_copy_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match lock_copy(&mut transaction, id) {
+                Ok(Some(copy)) => copy,
+                Ok(None) => return (NOT_FOUND.to_string(), "Copy not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            if before.on_loan {
+                let loans: Vec<i32> = match
+                    transaction.query("SELECT id FROM loans WHERE copy_id = $1 AND return_date IS NULL AND deleted_at IS NULL ORDER BY id", &[&id])
+                {
+                    Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+                    Err(e) => return internal_error(e),
+                };
+                let conflict = json!({ "error": "Copy is on loan", "active_loans": loans });
+                return (CONFLICT.to_string(), conflict.to_string());
+            }
+
+            //move the row to the trash; the retention job purges it later
+            let row = match
+                transaction.query_one(
+                    &format!(
+                        "UPDATE copies SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, book_id, barcode, acquired_on, condition, location, status, version, created_at, updated_at, deleted_at, {}",
+                        ON_LOAN
+                    ),
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = copy_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "copies", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), "Copy deleted".to_string())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
@@ -0,0 +1,333 @@
+//Cover images for books, with thumbnails
+
+use crate::{
+    get_header,
+    get_id,
+    get_query_param,
+    internal_error,
+    multipart,
+    storage,
+    tag_matches,
+    with_content_type,
+    with_header,
+    write_audit,
+    RequestContext,
+    BAD_REQUEST,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    NOT_MODIFIED,
+    OK_RESPONSE,
+    PAYLOAD_TOO_LARGE,
+    UNSUPPORTED_MEDIA_TYPE,
+};
+use chrono::{ DateTime, Utc };
+use image::{ DynamicImage, ImageFormat, ImageOutputFormat };
+use postgres::{ Client, NoTls, Row, Transaction };
+use sha2::{ Digest, Sha256 };
+use std::env;
+use std::io::Cursor;
+
+//Image types accepted for covers
+const COVER_TYPES: [(ImageFormat, &str); 4] = [
+    (ImageFormat::Jpeg, "image/jpeg"),
+    (ImageFormat::Png, "image/png"),
+    (ImageFormat::Gif, "image/gif"),
+    (ImageFormat::WebP, "image/webp"),
+];
+
+//Thumbnail sizes by name and width in pixels
+const THUMBNAIL_SIZES: [(&str, u32); 3] = [("small", 150), ("medium", 300), ("large", 600)];
+
+//Largest width or height decoded, to keep huge images from exhausting memory
+const MAX_DIMENSION: u32 = 10_000;
+
+//How long clients and proxies may reuse a cover before revalidating
+const CACHE_CONTROL: &str = "public, max-age=86400";
+
+//Storage prefix that new cover files wait under until their row is committed
+const STAGING_PREFIX: &str = "cover-staging";
+
+//Cover of a book as stored
+#[derive(Serialize)]
+pub struct Cover {
+    book_id: i32,
+    content_type: String,
+    byte_size: i32,
+    width: i32,
+    height: i32,
+    checksum: String,
+    sizes: Vec<String>,
+    uploaded_at: Option<DateTime<Utc>>,
+}
+
+//map book_covers row to Cover
+fn cover_from_row(row: &Row) -> Cover {
+    Cover {
+        book_id: row.get(0),
+        content_type: row.get(1),
+        byte_size: row.get(2),
+        width: row.get(3),
+        height: row.get(4),
+        checksum: row.get(5),
+        uploaded_at: row.get(6),
+        sizes: THUMBNAIL_SIZES.iter().map(|(name, _)| name.to_string()).chain(["original".to_string()]).collect(),
+    }
+}
+
+//largest accepted upload in bytes, from COVER_MAX_BYTES (default 5 MiB)
+fn max_cover_bytes() -> usize {
+    env::var("COVER_MAX_BYTES").ok().and_then(|bytes| bytes.parse().ok()).unwrap_or(5 * 1024 * 1024)
+}
+
+//storage key of a cover size
+pub fn storage_key(book_id: i32, size: &str) -> String {
+    format!("covers/{}/{}", book_id, size)
+}
+
+//storage key a request stages a cover size under; the request id keeps concurrent uploads apart
+fn staging_key(context: &RequestContext, book_id: i32, size: &str) -> String {
+    format!("{}/{}-{}", STAGING_PREFIX, storage_key(book_id, size), context.request_id)
+}
+
+//every size stored for a cover: the original and its thumbnails
+pub fn stored_sizes() -> impl Iterator<Item = &'static str> {
+    std::iter::once("original").chain(THUMBNAIL_SIZES.iter().map(|(size, _)| *size))
+}
+
+//hex SHA-256 of some bytes
+pub fn checksum(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+//check the upload is an image of an accepted type and decode it
+fn decode_cover(data: &[u8], declared: Option<&str>) -> Result<(DynamicImage, &'static str), (String, String)> {
+    let unsupported = || {
+        let types: Vec<&str> = COVER_TYPES.iter().map(|(_, mime)| *mime).collect();
+        (UNSUPPORTED_MEDIA_TYPE.to_string(), format!("Cover must be one of {}", types.join(", ")))
+    };
+
+    //trust the bytes, not the declared type, but reject a declared type that is not an image at all
+    if matches!(declared, Some(declared) if !declared.starts_with("image/") && declared != "application/octet-stream") {
+        return Err(unsupported());
+    }
+    let format = image::guess_format(data).map_err(|_| unsupported())?;
+    let mime = match COVER_TYPES.iter().find(|(accepted, _)| *accepted == format) {
+        Some((_, mime)) => *mime,
+        None => return Err(unsupported()),
+    };
+
+    let reader = image::io::Reader::with_format(Cursor::new(data), format);
+    match reader.into_dimensions() {
+        Ok((width, height)) if width <= MAX_DIMENSION && height <= MAX_DIMENSION => {}
+        Ok(_) => return Err((BAD_REQUEST.to_string(), format!("Cover may be at most {} pixels on a side", MAX_DIMENSION))),
+        Err(_) => return Err((BAD_REQUEST.to_string(), "Cover image is corrupt".to_string())),
+    }
+    let image = image::load_from_memory_with_format(data, format)
+        .map_err(|_| (BAD_REQUEST.to_string(), "Cover image is corrupt".to_string()))?;
+    Ok((image, mime))
+}
+
+//JPEG thumbnail no wider than `width`; smaller images are not enlarged
+fn thumbnail(image: &DynamicImage, width: u32) -> Result<Vec<u8>, image::ImageError> {
+    let resized = if image.width() > width { image.thumbnail(width, u32::MAX) } else { image.clone() };
+    let mut data = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(resized.to_rgb8()).write_to(&mut data, ImageOutputFormat::Jpeg(85))?;
+    Ok(data.into_inner())
+}
+
+//handle post cover request: store the upload and its thumbnails, replacing any earlier cover
+pub fn handle_post_cover_request(request: &str, body: &[u8], context: &RequestContext) -> (String, String) {
+    let id = match get_id(request).parse::<i32>() {
+        Ok(id) => id,
+        Err(_) => return (BAD_REQUEST.to_string(), "Invalid book id".to_string()),
+    };
+    let file = match multipart::parse(request, body).map(|parts| multipart::take_file(parts, "cover")) {
+        Ok(Some(file)) => file,
+        Ok(None) => return (BAD_REQUEST.to_string(), "No cover file in upload".to_string()),
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return internal_error(e),
+    };
+    match transaction.query_opt("SELECT 1 FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+        Ok(Some(_)) => {}
+        Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+    let cover = match store_cover(&mut transaction, context, id, &file.data, file.content_type.as_deref()) {
+        Ok(cover) => cover,
+        Err(e) => return e,
+    };
+    if let Err(e) = transaction.commit() {
+        discard_cover(context, id);
+        return internal_error(e);
+    }
+    if let Err(e) = publish_cover(context, id) {
+        return e;
+    }
+
+    (OK_RESPONSE.to_string(), serde_json::to_string(&cover).unwrap())
+}
+
+//validate an image and record it with its thumbnails as the cover of a book locked by the caller.
+//The files are staged: once the transaction commits the caller publishes them with publish_cover,
+//and discards them with discard_cover if it does not.
+pub fn store_cover(
+    transaction: &mut Transaction,
+    context: &RequestContext,
+    id: i32,
+    data: &[u8],
+    declared: Option<&str>
+) -> Result<Cover, (String, String)> {
+    if data.len() > max_cover_bytes() {
+        return Err((PAYLOAD_TOO_LARGE.to_string(), format!("Cover may be at most {} bytes", max_cover_bytes())));
+    }
+    let (image, content_type) = decode_cover(data, declared)?;
+
+    let storage = storage::configured_storage();
+    let mut files = vec![("original".to_string(), data.to_vec())];
+    for (size, width) in THUMBNAIL_SIZES {
+        match thumbnail(&image, width) {
+            Ok(data) => files.push((size.to_string(), data)),
+            Err(_) => return Err((INTERNAL_ERROR.to_string(), "Failed to create thumbnail".to_string())),
+        }
+    }
+    for (size, data) in &files {
+        if let Err(e) = storage.put(&staging_key(context, id, size), data) {
+            eprintln!("Unable to store cover for book {}: {}", id, e);
+            discard_cover(context, id);
+            return Err((INTERNAL_ERROR.to_string(), "Failed to store cover".to_string()));
+        }
+    }
+
+    let internal = |e| {
+        discard_cover(context, id);
+        internal_error(e)
+    };
+    let before = transaction
+        .query_opt("SELECT book_id, content_type, byte_size, width, height, checksum, uploaded_at FROM book_covers WHERE book_id = $1", &[&id])
+        .map_err(internal)?
+        .map(|row| cover_from_row(&row));
+    let row = transaction
+        .query_one(
+            "INSERT INTO book_covers (book_id, content_type, byte_size, width, height, checksum) VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (book_id) DO UPDATE SET content_type = EXCLUDED.content_type, byte_size = EXCLUDED.byte_size, width = EXCLUDED.width, height = EXCLUDED.height, checksum = EXCLUDED.checksum, uploaded_at = now()
+            RETURNING book_id, content_type, byte_size, width, height, checksum, uploaded_at",
+            &[&id, &content_type, &(data.len() as i32), &(image.width() as i32), &(image.height() as i32), &checksum(data)]
+        )
+        .map_err(internal)?;
+    let after = cover_from_row(&row);
+    let action = if before.is_some() { "update" } else { "create" };
+    write_audit(transaction, context, action, "book_covers", id, before.as_ref(), Some(&after)).map_err(internal)?;
+    Ok(after)
+}
+
+//move the cover files a request staged into place, once their row is committed
+pub fn publish_cover(context: &RequestContext, id: i32) -> Result<(), (String, String)> {
+    let storage = storage::configured_storage();
+    for size in stored_sizes() {
+        if let Err(e) = storage.rename(&staging_key(context, id, size), &storage_key(id, size)) {
+            eprintln!("Unable to store cover for book {}: {}", id, e);
+            discard_cover(context, id);
+            return Err((INTERNAL_ERROR.to_string(), "Failed to store cover".to_string()));
+        }
+    }
+    Ok(())
+}
+
+//remove the cover files a request staged for a row that was not committed
+pub fn discard_cover(context: &RequestContext, id: i32) {
+    let storage = storage::configured_storage();
+    for size in stored_sizes() {
+        if let Err(e) = storage.delete(&staging_key(context, id, size)) {
+            eprintln!("Unable to delete staged cover of book {}: {}", id, e);
+        }
+    }
+}
+
+//handle get cover request; ?size= is small, medium, large or original (the default)
+pub fn handle_get_cover_request(request: &str) -> (String, Vec<u8>) {
+    let size = get_query_param(request, "size").unwrap_or_else(|| "original".to_string());
+    if size != "original" && !THUMBNAIL_SIZES.iter().any(|(name, _)| *name == size) {
+        return (BAD_REQUEST.to_string(), b"Unknown cover size".to_vec());
+    }
+
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let cover = match
+                client.query_opt(
+                    "SELECT c.book_id, c.content_type, c.byte_size, c.width, c.height, c.checksum, c.uploaded_at FROM book_covers c JOIN books b ON b.id = c.book_id WHERE c.book_id = $1 AND b.deleted_at IS NULL",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => cover_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), b"Cover not found".to_vec()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+            };
+
+            //the checksum of the upload names every size made from it
+            let tag = format!("\"{}-{}\"", cover.checksum, size);
+            if matches!(get_header(request, "If-None-Match"), Some(header) if tag_matches(header, &tag, true)) {
+                let status_line = with_header(NOT_MODIFIED, "ETag", &tag);
+                return (with_header(&status_line, "Cache-Control", CACHE_CONTROL), Vec::new());
+            }
+
+            let data = match storage::configured_storage().get(&storage_key(id, &size)) {
+                Ok(Some(data)) => data,
+                Ok(None) => return (NOT_FOUND.to_string(), b"Cover file missing".to_vec()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+            };
+            let content_type = if size == "original" { cover.content_type.as_str() } else { "image/jpeg" };
+
+            let status_line = with_content_type(OK_RESPONSE, content_type);
+            let status_line = with_header(&status_line, "ETag", &tag);
+            let status_line = with_header(&status_line, "Cache-Control", CACHE_CONTROL);
+            (with_header(&status_line, "Content-Length", &data.len().to_string()), data)
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), b"Invalid book id".to_vec()),
+        _ => (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+    }
+}
+
+//handle delete cover request
+pub fn handle_delete_cover_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match
+                transaction.query_opt(
+                    "DELETE FROM book_covers WHERE book_id = $1 RETURNING book_id, content_type, byte_size, width, height, checksum, uploaded_at",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => cover_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Cover not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "book_covers", id, Some(&before), None).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            //files go after the row; a leftover file is harmless, a dangling row is not
+            let storage = storage::configured_storage();
+            for size in THUMBNAIL_SIZES.iter().map(|(name, _)| *name).chain(["original"]) {
+                if let Err(e) = storage.delete(&storage_key(id, size)) {
+                    eprintln!("Unable to remove cover file for book {}: {}", id, e);
+                }
+            }
+
+            (OK_RESPONSE.to_string(), "Cover deleted".to_string())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
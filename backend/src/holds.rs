@@ -0,0 +1,223 @@
+//Holds: a borrower's place in the queue for a book until a copy is free for them
+
+use crate::{
+    copies::ON_LOAN,
+    get_id,
+    get_query_param,
+    write_audit,
+    RequestContext,
+    BAD_REQUEST,
+    CONFLICT,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    OK_RESPONSE,
+};
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, NoTls, Row, Transaction };
+use postgres::error::SqlState;
+use postgres::Error as PostgresError;
+
+//States of a hold: waiting in the queue, ready to collect, then fulfilled by a loan or cancelled
+pub const STATUSES: [&str; 4] = ["waiting", "ready", "fulfilled", "cancelled"];
+
+//Hold struct with id, user, book, status and when it became ready
+#[derive(Serialize, Deserialize)]
+pub struct Hold {
+    pub id: Option<i32>,
+    pub user_id: i32,
+    pub book_id: i32,
+    #[serde(skip_deserializing)]
+    pub status: String,
+    #[serde(skip_deserializing)]
+    pub ready_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+//map holds row to Hold
+pub fn hold_from_row(row: &Row) -> Hold {
+    Hold {
+        id: row.get(0),
+        user_id: row.get(1),
+        book_id: row.get(2),
+        status: row.get(3),
+        ready_at: row.get(4),
+        created_at: row.get(5),
+    }
+}
+
+fn get_hold_request_body(request: &str) -> Result<Hold, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//make waiting holds ready, oldest first, while a book has free copies that no ready hold has claimed.
+//Returns the holds that became ready.
+pub fn promote_holds(transaction: &mut Transaction, context: &RequestContext) -> Result<Vec<Hold>, PostgresError> {
+    //one promotion at a time, or two runs could hand the same copy to two borrowers
+    transaction.execute("LOCK TABLE holds IN SHARE ROW EXCLUSIVE MODE", &[])?;
+    let rows = transaction.query(
+        &format!(
+            "WITH free AS (
+                SELECT book_id, count(*) AS copies FROM copies WHERE deleted_at IS NULL AND status = 'available' AND NOT {} GROUP BY book_id
+            ), claimed AS (
+                SELECT book_id, count(*) AS holds FROM holds WHERE status = 'ready' GROUP BY book_id
+            ), queue AS (
+                SELECT id, book_id, row_number() OVER (PARTITION BY book_id ORDER BY created_at, id) AS place FROM holds WHERE status = 'waiting'
+            )
+            UPDATE holds SET status = 'ready', ready_at = now()
+            FROM queue JOIN free ON free.book_id = queue.book_id LEFT JOIN claimed ON claimed.book_id = queue.book_id
+            WHERE holds.id = queue.id AND queue.place <= free.copies - COALESCE(claimed.holds, 0)
+            RETURNING holds.id, holds.user_id, holds.book_id, holds.status, holds.ready_at, holds.created_at",
+            ON_LOAN
+        ),
+        &[]
+    )?;
+    let promoted: Vec<Hold> = rows.iter().map(hold_from_row).collect();
+    for hold in &promoted {
+        write_audit(transaction, context, "update", "holds", hold.id.unwrap_or_default(), None::<&Hold>, Some(hold))?;
+    }
+    Ok(promoted)
+}
+
+//close a borrower's open hold on a book once they have it on loan
+pub fn fulfil_holds(transaction: &mut Transaction, context: &RequestContext, user_id: i32, book_id: i32) -> Result<(), PostgresError> {
+    let rows = transaction.query(
+        "UPDATE holds SET status = 'fulfilled' WHERE user_id = $1 AND book_id = $2 AND status IN ('waiting', 'ready') RETURNING id, user_id, book_id, status, ready_at, created_at",
+        &[&user_id, &book_id]
+    )?;
+    for row in rows {
+        let hold = hold_from_row(&row);
+        write_audit(transaction, context, "update", "holds", hold.id.unwrap_or_default(), None::<&Hold>, Some(&hold))?;
+    }
+    Ok(())
+}
+
+//handle post hold request: join the queue for a book
+pub fn handle_post_hold_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_hold_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(hold), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            match transaction.query_opt("SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL", &[&hold.user_id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (BAD_REQUEST.to_string(), format!("User {} not found", hold.user_id)),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+            match transaction.query_opt("SELECT 1 FROM books WHERE id = $1 AND deleted_at IS NULL", &[&hold.book_id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (BAD_REQUEST.to_string(), format!("Book {} not found", hold.book_id)),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+
+            let row = match
+                transaction.query_one(
+                    "INSERT INTO holds (user_id, book_id) VALUES ($1, $2) RETURNING id, user_id, book_id, status, ready_at, created_at",
+                    &[&hold.user_id, &hold.book_id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) =>
+                    return (CONFLICT.to_string(), "User already has an open hold on this book".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            let hold = hold_from_row(&row);
+            //a hold on a book with a copy free is ready at once
+            let promoted = write_audit(&mut transaction, context, "create", "holds", hold.id.unwrap_or_default(), None::<&Hold>, Some(&hold))
+                .and_then(|_| promote_holds(&mut transaction, context));
+            let hold = match promoted.and_then(|promoted| transaction.commit().map(|_| promoted)) {
+                Ok(promoted) => promoted.into_iter().find(|promoted| promoted.id == hold.id).unwrap_or(hold),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&hold).unwrap())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get hold request
+pub fn handle_get_hold_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) =>
+            match client.query_opt("SELECT id, user_id, book_id, status, ready_at, created_at FROM holds WHERE id = $1", &[&id]) {
+                Ok(Some(row)) => (OK_RESPONSE.to_string(), serde_json::to_string(&hold_from_row(&row)).unwrap()),
+                Ok(None) => (NOT_FOUND.to_string(), "Hold not found".to_string()),
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid hold id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all hold request, optionally filtered by ?user_id=, ?book_id= and ?status=; queue order within a book
+pub fn handle_get_all_hold_request(request: &str) -> (String, String) {
+    let mut ids = Vec::new();
+    for name in ["user_id", "book_id"] {
+        match get_query_param(request, name).map(|id| id.parse::<i32>()) {
+            Some(Err(_)) => return (BAD_REQUEST.to_string(), format!("Invalid {}", name)),
+            id => ids.push(id.and_then(Result::ok)),
+        }
+    }
+    let status = get_query_param(request, "status");
+    if let Some(status) = &status {
+        if !STATUSES.contains(&status.as_str()) {
+            return (BAD_REQUEST.to_string(), format!("Invalid status: {} (expected one of {})", status, STATUSES.join(", ")));
+        }
+    }
+
+    match Client::connect(DB_URL, NoTls) {
+        Ok(mut client) => {
+            let rows = client.query(
+                "SELECT id, user_id, book_id, status, ready_at, created_at FROM holds WHERE ($1::int IS NULL OR user_id = $1) AND ($2::int IS NULL OR book_id = $2) AND ($3::varchar IS NULL OR status = $3) ORDER BY book_id, created_at, id",
+                &[&ids[0], &ids[1], &status]
+            );
+            match rows {
+                Ok(rows) => {
+                    let holds: Vec<Hold> = rows.iter().map(hold_from_row).collect();
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&holds).unwrap())
+                }
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        }
+        Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete hold request: cancel an open hold, which frees its place for the next in line
+pub fn handle_delete_hold_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            let before = match transaction.query_opt("SELECT id, user_id, book_id, status, ready_at, created_at FROM holds WHERE id = $1 FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => hold_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Hold not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            if before.status != "waiting" && before.status != "ready" {
+                return (CONFLICT.to_string(), format!("Hold is already {}", before.status));
+            }
+            let hold = match transaction.query_one("UPDATE holds SET status = 'cancelled' WHERE id = $1 RETURNING id, user_id, book_id, status, ready_at, created_at", &[&id]) {
+                Ok(row) => hold_from_row(&row),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            //a cancelled ready hold leaves its copy to the next in line
+            let saved = write_audit(&mut transaction, context, "cancel", "holds", id, Some(&before), Some(&hold))
+                .and_then(|_| promote_holds(&mut transaction, context))
+                .and_then(|_| transaction.commit());
+            if saved.is_err() {
+                return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&hold).unwrap())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid hold id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
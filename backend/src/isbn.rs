@@ -0,0 +1,159 @@
+//ISBN normalization, checksum validation and 10 <-> 13 conversion
+
+//strip hyphens and spaces, uppercase the ISBN-10 check character
+pub fn normalize(isbn: &str) -> String {
+    isbn.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+//ISBN-10 check character for the first nine digits
+fn isbn10_check(digits: &str) -> char {
+    let sum: u32 = digits
+        .chars()
+        .zip((2..=10).rev())
+        .map(|(c, weight)| c.to_digit(10).unwrap_or_default() * weight)
+        .sum();
+    match (11 - (sum % 11)) % 11 {
+        10 => 'X',
+        check => char::from_digit(check, 10).unwrap_or('0'),
+    }
+}
+
+//ISBN-13 check digit for the first twelve digits
+fn isbn13_check(digits: &str) -> char {
+    let sum: u32 = digits
+        .chars()
+        .zip([1, 3].iter().cycle())
+        .map(|(c, weight)| c.to_digit(10).unwrap_or_default() * weight)
+        .sum();
+    char::from_digit((10 - (sum % 10)) % 10, 10).unwrap_or('0')
+}
+
+//normalized ISBN-10 with a valid checksum; checked for ASCII first so slicing stays on char boundaries
+pub fn is_valid_isbn10(isbn: &str) -> bool {
+    isbn.len() == 10 &&
+        isbn.is_ascii() &&
+        isbn[..9].chars().all(|c| c.is_ascii_digit()) &&
+        isbn.ends_with(isbn10_check(&isbn[..9]))
+}
+
+//normalized ISBN-13 with a valid checksum and a Bookland prefix
+pub fn is_valid_isbn13(isbn: &str) -> bool {
+    isbn.len() == 13 &&
+        isbn.chars().all(|c| c.is_ascii_digit()) &&
+        (isbn.starts_with("978") || isbn.starts_with("979")) &&
+        isbn.ends_with(isbn13_check(&isbn[..12]))
+}
+
+//convert a valid ISBN-10 to ISBN-13
+pub fn to_isbn13(isbn10: &str) -> String {
+    let digits = format!("978{}", &isbn10[..9]);
+    let check = isbn13_check(&digits);
+    format!("{}{}", digits, check)
+}
+
+//convert a valid ISBN-13 to ISBN-10; 979 numbers have no ISBN-10
+pub fn to_isbn10(isbn13: &str) -> Option<String> {
+    if !isbn13.starts_with("978") {
+        return None;
+    }
+    let digits = isbn13.get(3..12)?;
+    Some(format!("{}{}", digits, isbn10_check(digits)))
+}
+
+//parse either form of ISBN and return it as ISBN-13
+pub fn parse(isbn: &str) -> Result<String, String> {
+    let isbn = normalize(isbn);
+    if is_valid_isbn13(&isbn) {
+        Ok(isbn)
+    } else if is_valid_isbn10(&isbn) {
+        Ok(to_isbn13(&isbn))
+    } else {
+        Err(format!("Invalid ISBN: {}", isbn))
+    }
+}
+
+//validate the ISBNs supplied for a book and fill in the missing form
+pub fn reconcile(isbn10: Option<&str>, isbn13: Option<&str>) -> Result<(Option<String>, Option<String>), String> {
+    let isbn10 = isbn10.map(normalize).filter(|isbn| !isbn.is_empty());
+    let isbn13 = isbn13.map(normalize).filter(|isbn| !isbn.is_empty());
+
+    if let Some(isbn) = &isbn10 {
+        if !is_valid_isbn10(isbn) {
+            return Err(format!("Invalid ISBN-10: {}", isbn));
+        }
+    }
+    if let Some(isbn) = &isbn13 {
+        if !is_valid_isbn13(isbn) {
+            return Err(format!("Invalid ISBN-13: {}", isbn));
+        }
+    }
+
+    match (isbn10, isbn13) {
+        (Some(isbn10), Some(isbn13)) if to_isbn13(&isbn10) != isbn13 =>
+            Err(format!("ISBN-10 {} and ISBN-13 {} are different books", isbn10, isbn13)),
+        (Some(isbn10), Some(isbn13)) => Ok((Some(isbn10), Some(isbn13))),
+        (Some(isbn10), None) => {
+            let isbn13 = to_isbn13(&isbn10);
+            Ok((Some(isbn10), Some(isbn13)))
+        }
+        (None, Some(isbn13)) => Ok((to_isbn10(&isbn13), Some(isbn13))),
+        (None, None) => Ok((None, None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_isbn10_checksums() {
+        assert!(is_valid_isbn10("0441013597"));
+        assert!(is_valid_isbn10("080442957X"));
+        assert!(!is_valid_isbn10("0441013598"));
+        assert!(!is_valid_isbn10("044101359"));
+        assert!(!is_valid_isbn10("X441013597"));
+    }
+
+    #[test]
+    fn validates_isbn13_checksums_and_prefix() {
+        assert!(is_valid_isbn13("9780441013593"));
+        assert!(is_valid_isbn13("9791032305690"));
+        assert!(!is_valid_isbn13("9780441013594"));
+        assert!(!is_valid_isbn13("9770441013593"));
+        assert!(!is_valid_isbn13("978044101359X"));
+    }
+
+    #[test]
+    fn rejects_non_ascii_input_without_panicking() {
+        assert!(!is_valid_isbn10("12345678é"));
+        assert!(!is_valid_isbn10("１２３４５６７８９X"));
+        assert!(!is_valid_isbn13("97804410135é"));
+        assert!(parse("12345678é").is_err());
+        assert!(reconcile(Some("12345678é"), None).is_err());
+        assert_eq!(to_isbn10("978é"), None);
+    }
+
+    #[test]
+    fn converts_between_isbn10_and_isbn13() {
+        assert_eq!(to_isbn13("0441013597"), "9780441013593");
+        assert_eq!(to_isbn13("080442957X"), "9780804429573");
+        assert_eq!(to_isbn10("9780441013593").as_deref(), Some("0441013597"));
+        assert_eq!(to_isbn10("9780804429573").as_deref(), Some("080442957X"));
+        assert_eq!(to_isbn10("9791032305690"), None);
+    }
+
+    #[test]
+    fn parses_and_reconciles_either_form() {
+        assert_eq!(parse("0-441-01359-7"), Ok("9780441013593".to_string()));
+        assert_eq!(parse("978 0 441 01359 3"), Ok("9780441013593".to_string()));
+        assert_eq!(parse("080442957x"), Ok("9780804429573".to_string()));
+        assert_eq!(
+            reconcile(None, Some("9780441013593")),
+            Ok((Some("0441013597".to_string()), Some("9780441013593".to_string())))
+        );
+        assert!(reconcile(Some("0441013597"), Some("9780804429573")).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//ISO 639 language codes
+
+//ISO 639-1 codes with their ISO 639-2 terminology and bibliographic codes
+const LANGUAGES: [(&str, &str, &str); 183] = [
+    ("aa", "aar", "aar"),
+    ("ab", "abk", "abk"),
+    ("ae", "ave", "ave"),
+    ("af", "afr", "afr"),
+    ("ak", "aka", "aka"),
+    ("am", "amh", "amh"),
+    ("an", "arg", "arg"),
+    ("ar", "ara", "ara"),
+    ("as", "asm", "asm"),
+    ("av", "ava", "ava"),
+    ("ay", "aym", "aym"),
+    ("az", "aze", "aze"),
+    ("ba", "bak", "bak"),
+    ("be", "bel", "bel"),
+    ("bg", "bul", "bul"),
+    ("bi", "bis", "bis"),
+    ("bm", "bam", "bam"),
+    ("bn", "ben", "ben"),
+    ("bo", "bod", "tib"),
+    ("br", "bre", "bre"),
+    ("bs", "bos", "bos"),
+    ("ca", "cat", "cat"),
+    ("ce", "che", "che"),
+    ("ch", "cha", "cha"),
+    ("co", "cos", "cos"),
+    ("cr", "cre", "cre"),
+    ("cs", "ces", "cze"),
+    ("cu", "chu", "chu"),
+    ("cv", "chv", "chv"),
+    ("cy", "cym", "wel"),
+    ("da", "dan", "dan"),
+    ("de", "deu", "ger"),
+    ("dv", "div", "div"),
+    ("dz", "dzo", "dzo"),
+    ("ee", "ewe", "ewe"),
+    ("el", "ell", "gre"),
+    ("en", "eng", "eng"),
+    ("eo", "epo", "epo"),
+    ("es", "spa", "spa"),
+    ("et", "est", "est"),
+    ("eu", "eus", "baq"),
+    ("fa", "fas", "per"),
+    ("ff", "ful", "ful"),
+    ("fi", "fin", "fin"),
+    ("fj", "fij", "fij"),
+    ("fo", "fao", "fao"),
+    ("fr", "fra", "fre"),
+    ("fy", "fry", "fry"),
+    ("ga", "gle", "gle"),
+    ("gd", "gla", "gla"),
+    ("gl", "glg", "glg"),
+    ("gn", "grn", "grn"),
+    ("gu", "guj", "guj"),
+    ("gv", "glv", "glv"),
+    ("ha", "hau", "hau"),
+    ("he", "heb", "heb"),
+    ("hi", "hin", "hin"),
+    ("ho", "hmo", "hmo"),
+    ("hr", "hrv", "hrv"),
+    ("ht", "hat", "hat"),
+    ("hu", "hun", "hun"),
+    ("hy", "hye", "arm"),
+    ("hz", "her", "her"),
+    ("ia", "ina", "ina"),
+    ("id", "ind", "ind"),
+    ("ie", "ile", "ile"),
+    ("ig", "ibo", "ibo"),
+    ("ii", "iii", "iii"),
+    ("ik", "ipk", "ipk"),
+    ("io", "ido", "ido"),
+    ("is", "isl", "ice"),
+    ("it", "ita", "ita"),
+    ("iu", "iku", "iku"),
+    ("ja", "jpn", "jpn"),
+    ("jv", "jav", "jav"),
+    ("ka", "kat", "geo"),
+    ("kg", "kon", "kon"),
+    ("ki", "kik", "kik"),
+    ("kj", "kua", "kua"),
+    ("kk", "kaz", "kaz"),
+    ("kl", "kal", "kal"),
+    ("km", "khm", "khm"),
+    ("kn", "kan", "kan"),
+    ("ko", "kor", "kor"),
+    ("kr", "kau", "kau"),
+    ("ks", "kas", "kas"),
+    ("ku", "kur", "kur"),
+    ("kv", "kom", "kom"),
+    ("kw", "cor", "cor"),
+    ("ky", "kir", "kir"),
+    ("la", "lat", "lat"),
+    ("lb", "ltz", "ltz"),
+    ("lg", "lug", "lug"),
+    ("li", "lim", "lim"),
+    ("ln", "lin", "lin"),
+    ("lo", "lao", "lao"),
+    ("lt", "lit", "lit"),
+    ("lu", "lub", "lub"),
+    ("lv", "lav", "lav"),
+    ("mg", "mlg", "mlg"),
+    ("mh", "mah", "mah"),
+    ("mi", "mri", "mao"),
+    ("mk", "mkd", "mac"),
+    ("ml", "mal", "mal"),
+    ("mn", "mon", "mon"),
+    ("mr", "mar", "mar"),
+    ("ms", "msa", "may"),
+    ("mt", "mlt", "mlt"),
+    ("my", "mya", "bur"),
+    ("na", "nau", "nau"),
+    ("nb", "nob", "nob"),
+    ("nd", "nde", "nde"),
+    ("ne", "nep", "nep"),
+    ("ng", "ndo", "ndo"),
+    ("nl", "nld", "dut"),
+    ("nn", "nno", "nno"),
+    ("no", "nor", "nor"),
+    ("nr", "nbl", "nbl"),
+    ("nv", "nav", "nav"),
+    ("ny", "nya", "nya"),
+    ("oc", "oci", "oci"),
+    ("oj", "oji", "oji"),
+    ("om", "orm", "orm"),
+    ("or", "ori", "ori"),
+    ("os", "oss", "oss"),
+    ("pa", "pan", "pan"),
+    ("pi", "pli", "pli"),
+    ("pl", "pol", "pol"),
+    ("ps", "pus", "pus"),
+    ("pt", "por", "por"),
+    ("qu", "que", "que"),
+    ("rm", "roh", "roh"),
+    ("rn", "run", "run"),
+    ("ro", "ron", "rum"),
+    ("ru", "rus", "rus"),
+    ("rw", "kin", "kin"),
+    ("sa", "san", "san"),
+    ("sc", "srd", "srd"),
+    ("sd", "snd", "snd"),
+    ("se", "sme", "sme"),
+    ("sg", "sag", "sag"),
+    ("si", "sin", "sin"),
+    ("sk", "slk", "slo"),
+    ("sl", "slv", "slv"),
+    ("sm", "smo", "smo"),
+    ("sn", "sna", "sna"),
+    ("so", "som", "som"),
+    ("sq", "sqi", "alb"),
+    ("sr", "srp", "srp"),
+    ("ss", "ssw", "ssw"),
+    ("st", "sot", "sot"),
+    ("su", "sun", "sun"),
+    ("sv", "swe", "swe"),
+    ("sw", "swa", "swa"),
+    ("ta", "tam", "tam"),
+    ("te", "tel", "tel"),
+    ("tg", "tgk", "tgk"),
+    ("th", "tha", "tha"),
+    ("ti", "tir", "tir"),
+    ("tk", "tuk", "tuk"),
+    ("tl", "tgl", "tgl"),
+    ("tn", "tsn", "tsn"),
+    ("to", "ton", "ton"),
+    ("tr", "tur", "tur"),
+    ("ts", "tso", "tso"),
+    ("tt", "tat", "tat"),
+    ("tw", "twi", "twi"),
+    ("ty", "tah", "tah"),
+    ("ug", "uig", "uig"),
+    ("uk", "ukr", "ukr"),
+    ("ur", "urd", "urd"),
+    ("uz", "uzb", "uzb"),
+    ("ve", "ven", "ven"),
+    ("vi", "vie", "vie"),
+    ("vo", "vol", "vol"),
+    ("wa", "wln", "wln"),
+    ("wo", "wol", "wol"),
+    ("xh", "xho", "xho"),
+    ("yi", "yid", "yid"),
+    ("yo", "yor", "yor"),
+    ("za", "zha", "zha"),
+    ("zh", "zho", "chi"),
+    ("zu", "zul", "zul"),
+];
+
+//ISO 639-2 codes of languages that have no ISO 639-1 code, mostly historical languages,
+//plus the special codes for multiple, undetermined, uncoded and no linguistic content
+const ALPHA3_ONLY: [&str; 58] = [
+    "ain", "akk", "ang", "arc", "ast", "bho", "ceb", "chg", "chr", "cop",
+    "doi", "dsb", "dum", "egy", "enm", "fil", "frm", "fro", "frr", "gmh",
+    "goh", "got", "grc", "gsw", "haw", "hmn", "hsb", "jbo", "kab", "lad",
+    "mai", "mga", "mis", "mni", "moh", "mul", "myn", "nah", "nap", "nds",
+    "new", "non", "ota", "pal", "peo", "pro", "sat", "scn", "sga", "sma",
+    "smj", "smn", "sux", "syc", "tlh", "und", "war", "zxx",
+];
+
+//ISO 639-1 code for a language given in either ISO 639-1 or ISO 639-2,
+//or the ISO 639-2 code itself when the language has no two-letter code
+pub fn normalize(code: &str) -> Result<String, String> {
+    let code = code.trim().to_lowercase();
+    LANGUAGES
+        .iter()
+        .find(|(alpha2, terminology, bibliographic)| code == *alpha2 || code == *terminology || code == *bibliographic)
+        .map(|(alpha2, _, _)| alpha2.to_string())
+        .or_else(|| ALPHA3_ONLY.contains(&code.as_str()).then(|| code.clone()))
+        .ok_or_else(|| format!("Invalid language: {} (expected an ISO 639 code)", code))
+}
+
+//ISO 639-2 bibliographic code, as MARC records use, for a normalized code
+pub fn to_bibliographic(code: &str) -> Option<&'static str> {
+    LANGUAGES
+        .iter()
+        .find(|(alpha2, _, _)| *alpha2 == code)
+        .map(|(_, _, bibliographic)| *bibliographic)
+        .or_else(|| ALPHA3_ONLY.iter().find(|alpha3| **alpha3 == code).copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_to_the_two_letter_code_where_there_is_one() {
+        assert_eq!(normalize("en"), Ok("en".to_string()));
+        assert_eq!(normalize(" ENG "), Ok("en".to_string()));
+        assert_eq!(normalize("ger"), Ok("de".to_string()));
+        assert_eq!(normalize("deu"), Ok("de".to_string()));
+        assert!(normalize("zz").is_err());
+        assert!(normalize("xyz").is_err());
+    }
+
+    #[test]
+    fn keeps_three_letter_codes_of_languages_without_a_two_letter_one() {
+        for code in ["grc", "ang", "haw", "mul", "und"] {
+            assert_eq!(normalize(code), Ok(code.to_string()));
+            assert_eq!(to_bibliographic(code), Some(code));
+        }
+        assert_eq!(to_bibliographic("de"), Some("ger"));
+        assert_eq!(to_bibliographic("xyz"), None);
+    }
+
+    #[test]
+    fn two_and_three_letter_codes_do_not_overlap() {
+        for code in ALPHA3_ONLY {
+            assert!(!LANGUAGES.iter().any(|(_, terminology, bibliographic)| code == *terminology || code == *bibliographic), "{}", code);
+        }
+    }
+}
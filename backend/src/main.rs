@@ -1,48 +1,155 @@
-use postgres::{ Client, NoTls };
+use postgres::{ Client, NoTls, Row, Transaction };
 use postgres::Error as PostgresError;
+use postgres::error::SqlState;
 use std::net::{ TcpListener, TcpStream };
 use std::io::{ Read, Write };
 use std::env;
+use std::thread;
+use std::time::Duration;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::collections::HashMap;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{ json, Value };
+use chrono::{ Datelike, DateTime, NaiveDate, Utc };
 
 #[macro_use]
 extern crate serde_derive;
 
-//User struct with id, name and email
+mod attachments;
+mod authors;
+mod backup;
+mod bulk;
+mod calibre;
+mod citation;
+mod copies;
+mod covers;
+mod holds;
+mod isbn;
+mod language;
+mod marc;
+mod metadata;
+mod multipart;
+mod negotiation;
+mod notifications;
+mod opds;
+mod reading;
+mod series;
+mod sru;
+mod storage;
+mod taxonomy;
+
+//User struct with id, name, email and version
 #[derive(Serialize, Deserialize)]
 struct User {
     id: Option<i32>,
     name: String,
     email: String,
+    version: Option<i32>,
+    #[serde(skip_deserializing)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    deleted_at: Option<DateTime<Utc>>,
 }
 
-//Book struct with id, title, author and genre
-#[derive(Serialize, Deserialize)]
+//Book struct with id, title, author, genre, ISBNs and version
+#[derive(Serialize, Deserialize, Default)]
 struct Book {
     id: Option<i32>,
     title: String,
     author: String,
     genre: Option<String>,
+    isbn10: Option<String>,
+    isbn13: Option<String>,
+    publisher: Option<String>,
+    publication_year: Option<i32>,
+    page_count: Option<i32>,
+    language: Option<String>,
+    format: Option<String>,
+    edition: Option<String>,
+    version: Option<i32>,
+    #[serde(skip_deserializing)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    deleted_at: Option<DateTime<Utc>>,
 }
 
-//Loan struct with id, title, author and genre
-#[derive(Serialize, Deserialize)]
+//Loan struct with id, user, book, copy, dates and version
+#[derive(Serialize, Deserialize, Clone)]
 struct Loan {
     id: Option<i32>,
     user_id: i32,
     book_id: i32,
+    copy_id: Option<i32>,
     checkout_date: String,
     due_date: String,
     return_date: Option<String>,
+    version: Option<i32>,
+    #[serde(skip_deserializing)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    deleted_at: Option<DateTime<Utc>>,
 }
 
-//Review struct with id, title, author and genre
-#[derive(Serialize, Deserialize)]
+//Review struct with id, book, user, rating, text and version
+#[derive(Serialize, Deserialize, Default)]
 struct Review {
     id: Option<i32>,
     book_id: i32,
-    user_id: i32,
+    user_id: Option<i32>,
     rating: i32,
     review_text: Option<String>,
+    version: Option<i32>,
+    #[serde(skip_deserializing)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+//Audit log entry for a single mutation
+#[derive(Serialize)]
+struct AuditEntry {
+    id: i64,
+    occurred_at: DateTime<Utc>,
+    actor: Option<String>,
+    action: String,
+    resource_type: String,
+    resource_id: i32,
+    before: Option<Value>,
+    after: Option<Value>,
+    client_ip: Option<String>,
+    request_id: Option<String>,
+}
+
+//Metadata lookup request body
+#[derive(Deserialize)]
+struct LookupRequest {
+    isbn: String,
+}
+
+//What deleting a book or user does to the loans and reviews that reference it
+#[derive(Serialize)]
+struct DeleteImpact {
+    blocked: bool,
+    active_loans: Vec<Loan>,
+    trashed_loans: Vec<Loan>,
+    trashed_reviews: Vec<Review>,
+    anonymized_reviews: Vec<Review>,
+}
+
+//Who made a request and from where, recorded with every mutation
+struct RequestContext {
+    actor: Option<String>,
+    client_ip: Option<String>,
+    request_id: String,
 }
 
 //DB URL
@@ -50,20 +157,76 @@ const DB_URL: &str = env!("DATABASE_URL");
 
 //Constraints
 const OK_RESPONSE: &str =
-    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, PUT, DELETE\r\nAccess-Control-Allow-Headers: Content-Type\r\n\r\n";
+    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET, POST, PUT, PATCH, DELETE\r\nAccess-Control-Allow-Headers: Content-Type, If-Match, If-None-Match, If-Range, Range, X-User-Id, X-Admin-Token, X-Request-Id, X-Checksum-Sha256\r\nAccess-Control-Expose-Headers: ETag, X-Request-Id, Content-Range, Content-Disposition\r\n\r\n";
+const PARTIAL_CONTENT: &str =
+    "HTTP/1.1 206 PARTIAL CONTENT\r\nContent-Type: application/json\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Expose-Headers: ETag, X-Request-Id, Content-Range, Content-Disposition\r\n\r\n";
+const NOT_MODIFIED: &str =
+    "HTTP/1.1 304 NOT MODIFIED\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Expose-Headers: ETag, X-Request-Id\r\n\r\n";
+const BAD_REQUEST: &str = "HTTP/1.1 400 BAD REQUEST\r\n\r\n";
+const FORBIDDEN: &str = "HTTP/1.1 403 FORBIDDEN\r\n\r\n";
 const NOT_FOUND: &str = "HTTP/1.1 404 NOT FOUND\r\n\r\n";
+const NOT_ACCEPTABLE: &str = "HTTP/1.1 406 NOT ACCEPTABLE\r\n\r\n";
+const CONFLICT: &str = "HTTP/1.1 409 CONFLICT\r\n\r\n";
+const PRECONDITION_FAILED: &str = "HTTP/1.1 412 PRECONDITION FAILED\r\n\r\n";
+const PAYLOAD_TOO_LARGE: &str = "HTTP/1.1 413 PAYLOAD TOO LARGE\r\n\r\n";
+const UNSUPPORTED_MEDIA_TYPE: &str = "HTTP/1.1 415 UNSUPPORTED MEDIA TYPE\r\n\r\n";
+const RANGE_NOT_SATISFIABLE: &str = "HTTP/1.1 416 RANGE NOT SATISFIABLE\r\n\r\n";
 const INTERNAL_ERROR: &str = "HTTP/1.1 500 INTERNAL ERROR\r\n\r\n";
+const BAD_GATEWAY: &str = "HTTP/1.1 502 BAD GATEWAY\r\n\r\n";
+
+//Request id counter
+static REQUEST_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+//Formats a book can be catalogued in
+const BOOK_FORMATS: [&str; 4] = ["hardcover", "paperback", "ebook", "audiobook"];
+
+//Earliest accepted publication year, around the first printed books
+const FIRST_PUBLICATION_YEAR: i32 = 1450;
+
+//Columns the book list can be sorted by, mapped to their SQL
+const BOOK_SORTS: [(&str, &str); 8] = [
+    ("id", "id"),
+    ("title", "lower(title)"),
+    ("author", "lower(author)"),
+    ("publisher", "lower(publisher)"),
+    ("publication_year", "publication_year"),
+    ("page_count", "page_count"),
+    ("created_at", "created_at"),
+    ("updated_at", "updated_at"),
+];
 
 //main function
 fn main() {
     //Set DB
-    if let Err(_) = set_database() {
+    if set_database().is_err() {
         println!("Error setting database");
         return;
     }
 
+    //admin commands run once and exit instead of serving
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some(command) = args.first() {
+        let result = match command.as_str() {
+            "backup" => backup::run_backup_command(&args[1..]),
+            "restore" => backup::run_restore_command(&args[1..]),
+            "import-calibre" => calibre::run_import_command(&args[1..]),
+            "notify" => notifications::run_notify_command(&args[1..]),
+            _ => Err(format!("Unknown command: {}", command)),
+        };
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    //purge old trash in the background
+    thread::spawn(run_trash_retention);
+    //send due and overdue reminders in the background when SMTP is configured
+    thread::spawn(notifications::run_notification_schedule);
+
     //start server and print port
-    let listener = TcpListener::bind(format!("0.0.0.0:8080")).unwrap();
+    let listener = TcpListener::bind("0.0.0.0:8080").unwrap();
     println!("Server listening on port 8080");
 
     for stream in listener.incoming() {
@@ -78,6 +241,105 @@ fn main() {
     }
 }
 
+//Trash retention job, purges soft-deleted rows older than TRASH_RETENTION_DAYS (default 30)
+fn run_trash_retention() {
+    let days: i32 = env::var("TRASH_RETENTION_DAYS").ok().and_then(|value| value.parse().ok()).unwrap_or(30);
+
+    loop {
+        match purge_trash(days) {
+            Ok(0) => {}
+            Ok(purged) => println!("Purged {} trashed rows", purged),
+            Err(e) => println!("Error purging trash: {}", e),
+        }
+        thread::sleep(Duration::from_secs(60 * 60));
+    }
+}
+
+//hard delete expired trash, skipping rows that live rows still reference
+fn purge_trash(days: i32) -> Result<u64, PostgresError> {
+    let mut client = Client::connect(DB_URL, NoTls)?;
+    let mut transaction = client.transaction()?;
+    let context = RequestContext {
+        actor: Some("trash-retention".to_string()),
+        client_ip: None,
+        request_id: next_request_id(),
+    };
+    let expired = "deleted_at < now() - make_interval(days => $1)";
+    let purges = [
+        ("reviews", format!("DELETE FROM reviews WHERE {} RETURNING id", expired)),
+        ("loans", format!("DELETE FROM loans WHERE {} RETURNING id", expired)),
+        (
+            "books",
+            format!(
+                "DELETE FROM books WHERE {} AND NOT EXISTS (SELECT 1 FROM loans WHERE book_id = books.id) AND NOT EXISTS (SELECT 1 FROM reviews WHERE book_id = books.id) RETURNING id",
+                expired
+            ),
+        ),
+        (
+            "users",
+            format!(
+                "DELETE FROM users WHERE {} AND NOT EXISTS (SELECT 1 FROM loans WHERE user_id = users.id) AND NOT EXISTS (SELECT 1 FROM reviews WHERE user_id = users.id) RETURNING id",
+                expired
+            ),
+        ),
+        ("authors", format!("DELETE FROM authors WHERE {} RETURNING id", expired)),
+        (
+            "genres",
+            format!(
+                "DELETE FROM genres WHERE {} AND NOT EXISTS (SELECT 1 FROM genres g WHERE g.parent_id = genres.id) RETURNING id",
+                expired
+            ),
+        ),
+        (
+            "copies",
+            format!(
+                "DELETE FROM copies WHERE {} AND NOT EXISTS (SELECT 1 FROM loans WHERE copy_id = copies.id) RETURNING id",
+                expired
+            ),
+        ),
+        ("tags", format!("DELETE FROM tags WHERE {} RETURNING id", expired)),
+        ("series", format!("DELETE FROM series WHERE {} RETURNING id", expired)),
+    ];
+
+    //covers and attachments go with their book's rows, so note their storage keys before the delete
+    let mut stored: HashMap<i32, Vec<String>> = HashMap::new();
+    let candidates = format!(
+        "SELECT book_id, NULL::varchar FROM book_covers WHERE book_id IN (SELECT id FROM books WHERE {0}) UNION ALL SELECT book_id, format FROM book_files WHERE book_id IN (SELECT id FROM books WHERE {0})",
+        expired
+    );
+    for row in transaction.query(candidates.as_str(), &[&days])? {
+        let (book_id, format): (i32, Option<String>) = (row.get(0), row.get(1));
+        let keys = stored.entry(book_id).or_default();
+        match format {
+            Some(format) => keys.push(attachments::storage_key(book_id, &format)),
+            None => keys.extend(covers::stored_sizes().map(|size| covers::storage_key(book_id, size))),
+        }
+    }
+
+    let mut purged = 0;
+    let mut orphaned = Vec::new();
+    for (table, query) in purges.iter() {
+        for row in transaction.query(query.as_str(), &[&days])? {
+            let id: i32 = row.get(0);
+            if *table == "books" {
+                orphaned.extend(stored.remove(&id).unwrap_or_default());
+            }
+            write_audit::<Value>(&mut transaction, &context, "purge", table, id, None, None)?;
+            purged += 1;
+        }
+    }
+    transaction.commit()?;
+
+    //only once the rows are gone for good; a leftover file is harmless, a missing one is not
+    let storage = storage::configured_storage();
+    for key in orphaned {
+        if let Err(e) = storage.delete(&key) {
+            eprintln!("Unable to delete {}: {}", key, e);
+        }
+    }
+    Ok(purged)
+}
+
 //DB Setup
 fn set_database() -> Result<(), PostgresError> {
     let mut client = Client::connect(DB_URL, NoTls)?;
@@ -125,287 +387,1449 @@ fn set_database() -> Result<(), PostgresError> {
             review_text TEXT
         )
         "
-    )?;    
+    )?;
+
+    //Authors and their credits on books
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS authors (
+            id SERIAL PRIMARY KEY,
+            name VARCHAR NOT NULL,
+            sort_name VARCHAR,
+            birth_year INTEGER,
+            death_year INTEGER,
+            bio TEXT,
+            version INTEGER NOT NULL DEFAULT 1
+        );
+        CREATE INDEX IF NOT EXISTS authors_name_idx ON authors (lower(name));
+        CREATE TABLE IF NOT EXISTS book_authors (
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            author_id INTEGER NOT NULL REFERENCES authors(id) ON DELETE CASCADE,
+            role VARCHAR NOT NULL DEFAULT 'author' CHECK (role IN ('author', 'editor', 'translator', 'illustrator')),
+            position INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY (book_id, author_id, role)
+        );
+        CREATE INDEX IF NOT EXISTS book_authors_author_idx ON book_authors (author_id);
+        "
+    )?;
+
+    //Genre hierarchy and free-form tags
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS genres (
+            id SERIAL PRIMARY KEY,
+            name VARCHAR NOT NULL,
+            slug VARCHAR NOT NULL,
+            parent_id INTEGER REFERENCES genres(id),
+            version INTEGER NOT NULL DEFAULT 1
+        );
+        CREATE TABLE IF NOT EXISTS book_genres (
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            genre_id INTEGER NOT NULL REFERENCES genres(id) ON DELETE CASCADE,
+            PRIMARY KEY (book_id, genre_id)
+        );
+        CREATE INDEX IF NOT EXISTS book_genres_genre_idx ON book_genres (genre_id);
+        CREATE TABLE IF NOT EXISTS tags (
+            id SERIAL PRIMARY KEY,
+            name VARCHAR NOT NULL,
+            version INTEGER NOT NULL DEFAULT 1
+        );
+        CREATE TABLE IF NOT EXISTS book_tags (
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+            PRIMARY KEY (book_id, tag_id)
+        );
+        CREATE INDEX IF NOT EXISTS book_tags_tag_idx ON book_tags (tag_id);
+        "
+    )?;
+
+    //Physical copies; loans are for a particular copy
+    let copies_existed: bool = client.query_one("SELECT to_regclass('copies') IS NOT NULL", &[])?.get(0);
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS copies (
+            id SERIAL PRIMARY KEY,
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            barcode VARCHAR,
+            acquired_on DATE,
+            condition VARCHAR CHECK (condition IN ('new', 'good', 'fair', 'poor', 'damaged')),
+            location VARCHAR,
+            status VARCHAR NOT NULL DEFAULT 'available' CHECK (status IN ('available', 'in_repair', 'lost', 'withdrawn')),
+            version INTEGER NOT NULL DEFAULT 1
+        );
+        CREATE INDEX IF NOT EXISTS copies_book_idx ON copies (book_id);
+        ALTER TABLE loans ADD COLUMN IF NOT EXISTS copy_id INTEGER REFERENCES copies(id);
+        CREATE INDEX IF NOT EXISTS loans_copy_idx ON loans (copy_id);
+        "
+    )?;
+
+    //Series and the position of each book in them
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS series (
+            id SERIAL PRIMARY KEY,
+            name VARCHAR NOT NULL,
+            description TEXT,
+            version INTEGER NOT NULL DEFAULT 1
+        );
+        CREATE TABLE IF NOT EXISTS book_series (
+            series_id INTEGER NOT NULL REFERENCES series(id) ON DELETE CASCADE,
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            position DOUBLE PRECISION NOT NULL CHECK (position >= 0),
+            PRIMARY KEY (series_id, book_id)
+        );
+        CREATE INDEX IF NOT EXISTS book_series_book_idx ON book_series (book_id);
+        "
+    )?;
+
+    //Row versions for optimistic concurrency
+    client.batch_execute(
+        "
+        ALTER TABLE users ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE loans ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;
+        ALTER TABLE reviews ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;
+        "
+    )?;
+
+    //Server-managed timestamps, kept honest by a trigger on every table, and soft deletes
+    client.batch_execute(
+        "
+        CREATE OR REPLACE FUNCTION set_timestamps() RETURNS TRIGGER AS $$
+        BEGIN
+            IF TG_OP = 'INSERT' THEN
+                NEW.created_at := now();
+            ELSE
+                NEW.created_at := OLD.created_at;
+            END IF;
+            NEW.updated_at := now();
+            RETURN NEW;
+        END;
+        $$ LANGUAGE plpgsql;
+        "
+    )?;
+    for table in ["users", "books", "loans", "reviews", "authors", "genres", "tags", "copies", "series"] {
+        client.batch_execute(
+            &format!(
+                "
+                ALTER TABLE {table} ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+                ALTER TABLE {table} ADD COLUMN IF NOT EXISTS updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+                ALTER TABLE {table} ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMPTZ;
+                CREATE INDEX IF NOT EXISTS {table}_created_at_idx ON {table} (created_at);
+                CREATE INDEX IF NOT EXISTS {table}_updated_at_idx ON {table} (updated_at);
+                DROP TRIGGER IF EXISTS {table}_timestamps ON {table};
+                CREATE TRIGGER {table}_timestamps BEFORE INSERT OR UPDATE ON {table}
+                    FOR EACH ROW EXECUTE FUNCTION set_timestamps();
+                ",
+                table = table
+            )
+        )?;
+    }
+
+    //ISBNs, unique among books that are not in the trash
+    client.batch_execute(
+        "
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS isbn10 VARCHAR(10);
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS isbn13 VARCHAR(13);
+        CREATE UNIQUE INDEX IF NOT EXISTS books_isbn13_key ON books (isbn13) WHERE deleted_at IS NULL;
+        "
+    )?;
+
+    //Publication details
+    client.batch_execute(
+        "
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS publisher VARCHAR;
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS publication_year INTEGER;
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS page_count INTEGER CHECK (page_count > 0);
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS language VARCHAR(3);
+        ALTER TABLE books ALTER COLUMN language TYPE VARCHAR(3);
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS format VARCHAR CHECK (format IN ('hardcover', 'paperback', 'ebook', 'audiobook'));
+        ALTER TABLE books ADD COLUMN IF NOT EXISTS edition VARCHAR;
+        CREATE INDEX IF NOT EXISTS books_publication_year_idx ON books (publication_year);
+        "
+    )?;
+
+    //genre slugs, tag names and barcodes are unique among live rows
+    client.batch_execute(
+        "
+        CREATE UNIQUE INDEX IF NOT EXISTS genres_slug_key ON genres (slug) WHERE deleted_at IS NULL;
+        CREATE UNIQUE INDEX IF NOT EXISTS tags_name_key ON tags (lower(name)) WHERE deleted_at IS NULL;
+        CREATE UNIQUE INDEX IF NOT EXISTS copies_barcode_key ON copies (barcode) WHERE deleted_at IS NULL;
+        "
+    )?;
+
+    //Book covers; the image files themselves live in storage
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS book_covers (
+            book_id INTEGER PRIMARY KEY REFERENCES books(id) ON DELETE CASCADE,
+            content_type VARCHAR NOT NULL,
+            byte_size INTEGER NOT NULL,
+            width INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            checksum VARCHAR(64) NOT NULL,
+            uploaded_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )
+        "
+    )?;
+
+    //E-book files attached to books, one per format; the files themselves live in storage
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS book_files (
+            id SERIAL PRIMARY KEY,
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            format VARCHAR NOT NULL CHECK (format IN ('epub', 'pdf')),
+            content_type VARCHAR NOT NULL,
+            filename VARCHAR NOT NULL,
+            byte_size BIGINT NOT NULL,
+            checksum VARCHAR(64) NOT NULL,
+            uploaded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            UNIQUE (book_id, format)
+        )
+        "
+    )?;
+
+    //Identifiers of books in other catalogues, such as goodreads, amazon or a Calibre uuid
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS book_identifiers (
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            scheme VARCHAR NOT NULL,
+            value VARCHAR NOT NULL,
+            PRIMARY KEY (book_id, scheme)
+        );
+        CREATE INDEX IF NOT EXISTS book_identifiers_value_idx ON book_identifiers (scheme, value);
+        "
+    )?;
+
+    //Reading history: shelves and read dates, mostly imported from other services
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS reading_history (
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            status VARCHAR NOT NULL,
+            shelves TEXT[] NOT NULL DEFAULT '{}',
+            date_added DATE,
+            date_read DATE,
+            read_count INTEGER NOT NULL DEFAULT 0 CHECK (read_count >= 0),
+            source VARCHAR NOT NULL,
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            PRIMARY KEY (user_id, book_id)
+        )
+        "
+    )?;
+
+    //Cached metadata lookups
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS metadata_cache (
+            isbn13 VARCHAR(13) PRIMARY KEY,
+            metadata JSONB NOT NULL,
+            fetched_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        )
+        "
+    )?;
+
+    //Audit log of every mutation
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS audit_log (
+            id BIGSERIAL PRIMARY KEY,
+            occurred_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+            actor VARCHAR,
+            action VARCHAR NOT NULL,
+            resource_type VARCHAR NOT NULL,
+            resource_id INTEGER NOT NULL,
+            before_data JSONB,
+            after_data JSONB,
+            client_ip VARCHAR,
+            request_id VARCHAR
+        );
+        CREATE INDEX IF NOT EXISTS audit_log_resource_idx ON audit_log (resource_type, resource_id);
+        CREATE INDEX IF NOT EXISTS audit_log_occurred_at_idx ON audit_log (occurred_at);
+        "
+    )?;
+
+    //Holds on books, and email notifications with per-user settings and a log of what was sent.
+    //A notice is claimed as pending before it is sent; the unique key keeps it from going out twice.
+    client.batch_execute(
+        "
+        CREATE TABLE IF NOT EXISTS holds (
+            id SERIAL PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+            status VARCHAR NOT NULL DEFAULT 'waiting' CHECK (status IN ('waiting', 'ready', 'fulfilled', 'cancelled')),
+            ready_at TIMESTAMPTZ,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        CREATE UNIQUE INDEX IF NOT EXISTS holds_open_key ON holds (user_id, book_id) WHERE status IN ('waiting', 'ready');
+        CREATE INDEX IF NOT EXISTS holds_book_idx ON holds (book_id, status);
+        CREATE TABLE IF NOT EXISTS notification_preferences (
+            user_id INTEGER PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+            due_soon BOOLEAN NOT NULL DEFAULT true,
+            overdue BOOLEAN NOT NULL DEFAULT true,
+            hold_ready BOOLEAN NOT NULL DEFAULT true
+        );
+        CREATE TABLE IF NOT EXISTS notification_log (
+            id SERIAL PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            kind VARCHAR NOT NULL CHECK (kind IN ('due_soon', 'overdue', 'hold_ready')),
+            loan_id INTEGER REFERENCES loans(id) ON DELETE SET NULL,
+            hold_id INTEGER REFERENCES holds(id) ON DELETE SET NULL,
+            recipient VARCHAR NOT NULL,
+            subject VARCHAR NOT NULL,
+            status VARCHAR NOT NULL CHECK (status IN ('pending', 'sent', 'failed')),
+            error TEXT,
+            dedupe_key VARCHAR NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+        );
+        CREATE UNIQUE INDEX IF NOT EXISTS notification_log_dedupe_key ON notification_log (dedupe_key) WHERE status IN ('pending', 'sent');
+        CREATE INDEX IF NOT EXISTS notification_log_user_idx ON notification_log (user_id);
+        "
+    )?;
+
+    //split author strings of books that predate the authors table
+    authors::migrate_book_authors(&mut client)?;
+    //normalize free-text genres and link them to the taxonomy
+    taxonomy::migrate_book_genres(&mut client)?;
+    //books catalogued before copies existed were each one copy on the shelf
+    if !copies_existed {
+        copies::migrate_book_copies(&mut client)?;
+    }
     Ok(())
 }
 
 //Get id from request URL
 fn get_id(request: &str) -> &str {
-    request.split("/").nth(4).unwrap_or_default().split_whitespace().next().unwrap_or_default()
+    request.split("/").nth(4).unwrap_or_default().split(['?', ' ']).next().unwrap_or_default()
 }
 
-//deserialize user from request body without id
-fn get_user_request_body(request: &str) -> Result<User, serde_json::Error> {
-    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+//Get a segment of the request path, e.g. 5 is "restore" in /api/rust/books/1/restore
+fn get_path_segment(request: &str, index: usize) -> &str {
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    target.split('?').next().unwrap_or_default().split('/').nth(index).unwrap_or_default()
 }
 
-//deserialize book from request body without id
-fn get_book_request_body(request: &str) -> Result<Book, serde_json::Error> {
-    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+//Get decoded query parameter from request URL
+fn get_query_param(request: &str, name: &str) -> Option<String> {
+    get_raw_query_param(request, name).map(percent_decode)
 }
 
-//deserialize loan from request body without id
-fn get_loan_request_body(request: &str) -> Result<Loan, serde_json::Error> {
-    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+//query parameter as a form or SRU client encodes it, where + stands for a space
+fn get_form_param(request: &str, name: &str) -> Option<String> {
+    get_raw_query_param(request, name).map(|value| percent_decode(&value.replace('+', "%20")))
 }
 
-//deserialize review from request body without id
-fn get_review_request_body(request: &str) -> Result<Review, serde_json::Error> {
-    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+fn get_raw_query_param<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    let target = request.split_whitespace().nth(1).unwrap_or_default();
+    let query = target.split_once('?').map(|(_, query)| query).unwrap_or_default();
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
 }
 
-//handle requests
-fn handle_client(mut stream: TcpStream) {
-    let mut buffer = [0; 1024];
-    let mut request = String::new();
-
-    match stream.read(&mut buffer) {
-        Ok(size) => {
-            request.push_str(String::from_utf8_lossy(&buffer[..size]).as_ref());
-
-            let (status_line, content) = match &*request {
-                r if r.starts_with("OPTIONS") => (OK_RESPONSE.to_string(), "".to_string()),
-                r if r.starts_with("POST /api/rust/users") => handle_post_user_request(r),
-                r if r.starts_with("GET /api/rust/users/") => handle_get_user_request(r),
-                r if r.starts_with("GET /api/rust/users") => handle_get_all_user_request(r),
-                r if r.starts_with("PUT /api/rust/users/") => handle_put_user_request(r),
-                r if r.starts_with("DELETE /api/rust/users/") => handle_delete_user_request(r),
-
-                r if r.starts_with("POST /api/rust/books") => handle_post_book_request(r),
-                r if r.starts_with("GET /api/rust/books/") => handle_get_book_request(r),
-                r if r.starts_with("GET /api/rust/books") => handle_get_all_book_request(r),
-                r if r.starts_with("PUT /api/rust/books/") => handle_put_book_request(r),
-                r if r.starts_with("DELETE /api/rust/books/") => handle_delete_book_request(r),
-
-                r if r.starts_with("POST /api/rust/loans") => handle_post_loan_request(r),
-                r if r.starts_with("GET /api/rust/loans/") => handle_get_loan_request(r),
-                r if r.starts_with("GET /api/rust/loans") => handle_get_all_loan_request(r),
-                r if r.starts_with("PUT /api/rust/loans/") => handle_put_loan_request(r),
-                r if r.starts_with("DELETE /api/rust/loans/") => handle_delete_loan_request(r),
+//decode %XX escapes in a query parameter
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 3 <= bytes.len() {
+            std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
 
-                r if r.starts_with("POST /api/rust/reviews") => handle_post_review_request(r),
-                r if r.starts_with("GET /api/rust/reviews/") => handle_get_review_request(r),
-                r if r.starts_with("GET /api/rust/reviews") => handle_get_all_review_request(r),
-                r if r.starts_with("PUT /api/rust/reviews/") => handle_put_review_request(r),
-                r if r.starts_with("DELETE /api/rust/reviews/") => handle_delete_review_request(r),
+//parse a timestamp query parameter, accepting RFC 3339 or a plain date
+fn get_timestamp_param(request: &str, name: &str) -> Result<Option<DateTime<Utc>>, chrono::ParseError> {
+    match get_query_param(request, name) {
+        Some(value) =>
+            match NaiveDate::parse_from_str(&value, "%Y-%m-%d") {
+                Ok(date) => Ok(date.and_hms_opt(0, 0, 0).map(|time| time.and_utc())),
+                Err(_) => DateTime::parse_from_rfc3339(&value).map(|time| Some(time.with_timezone(&Utc))),
+            }
+        None => Ok(None),
+    }
+}
 
-                _ => (NOT_FOUND.to_string(), "404 not found".to_string()),
-            };
+//parse an integer query parameter
+fn get_int_param(request: &str, name: &str) -> Result<Option<i32>, std::num::ParseIntError> {
+    get_query_param(request, name).map(|value| value.parse()).transpose()
+}
 
-            stream.write_all(format!("{}{}", status_line, content).as_bytes()).unwrap();
+//ORDER BY clause for ?sort=, a comma-separated list of fields each optionally prefixed with '-' for descending
+fn get_sort_clause(request: &str, sorts: &[(&str, &str)]) -> Result<String, String> {
+    let mut clause = Vec::new();
+    for field in get_query_param(request, "sort").unwrap_or_default().split(',').filter(|field| !field.is_empty()) {
+        let (name, direction) = match field.strip_prefix('-') {
+            Some(name) => (name, "DESC"),
+            None => (field, "ASC"),
+        };
+        match sorts.iter().find(|(key, _)| *key == name) {
+            Some((_, column)) => clause.push(format!("{} {} NULLS LAST", column, direction)),
+            None => return Err(format!("Invalid sort field: {}", name)),
         }
-        Err(e) => eprintln!("Unable to read stream: {}", e),
     }
+    clause.push("id".to_string());
+    Ok(clause.join(", "))
 }
 
-//handle post user request
-fn handle_post_user_request(request: &str) -> (String, String) {
-    match (get_user_request_body(request), Client::connect(DB_URL, NoTls)) {
-        (Ok(user), Ok(mut client)) => {
-            // Insert the user and retrieve the ID
-            let row = client
-                .query_one(
-                    "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id",
-                    &[&user.name, &user.email]
-                )
-                .unwrap();
+//Get header value from request, case-insensitive
+fn get_header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .split("\r\n\r\n")
+        .next()
+        .unwrap_or_default()
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim())
+}
 
-            let user_id: i32 = row.get(0);
+//add a header to a status line
+fn with_header(status_line: &str, name: &str, value: &str) -> String {
+    let headers = status_line.strip_suffix("\r\n").unwrap_or(status_line);
+    format!("{}{}: {}\r\n\r\n", headers, name, value)
+}
 
-            // Fetch the created user data
-            match client.query_one("SELECT id, name, email FROM users WHERE id = $1", &[&user_id]) {
-                Ok(row) => {
-                    let user = User {
-                        id: Some(row.get(0)),
-                        name: row.get(1),
-                        email: row.get(2),
-                    };
+//replace the JSON content type of a status line
+fn with_content_type(status_line: &str, content_type: &str) -> String {
+    status_line.replacen("Content-Type: application/json", &format!("Content-Type: {}", content_type), 1)
+}
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&user).unwrap())
-                }
-                Err(_) =>
-                    (INTERNAL_ERROR.to_string(), "Failed to retrieve created user".to_string()),
-            }
-        }
-        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
-    }
+//ETag for a row version
+fn etag(version: i32) -> String {
+    format!("\"{}\"", version)
 }
 
-//handle post book request
-fn handle_post_book_request(request: &str) -> (String, String) {
-    match (get_book_request_body(request), Client::connect(DB_URL, NoTls)) {
-        (Ok(book), Ok(mut client)) => {
-            // Insert the book and retrieve the ID
-            let row = client
-                .query_one(
-                    "INSERT INTO books (title, author, genre) VALUES ($1, $2, $3) RETURNING id",
-                    &[&book.title, &book.author, &book.genre]
-                )
-                .unwrap();
+//check an If-Match (strong) or If-None-Match (weak) header value against a row version
+fn etag_matches(header: &str, version: i32, weak: bool) -> bool {
+    tag_matches(header, &etag(version), weak)
+}
 
-            let book_id: i32 = row.get(0);
+//check an entity tag header value; weak comparison ignores W/ prefixes, strong comparison never matches a weak tag (RFC 9110 8.8.3.2)
+fn tag_matches(header: &str, current: &str, weak: bool) -> bool {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| weak || !tag.starts_with("W/"))
+        .map(|tag| tag.trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == current)
+}
 
-            // Fetch the created book data
-            match client.query_one("SELECT id, title, author, genre FROM books WHERE id = $1", &[&book_id]) {
-                Ok(row) => {
-                    let book = Book {
-                        id: Some(row.get(0)),
-                        title: row.get(1),
-                        author: row.get(2),
-                        genre: row.get(3),
-                    };
+//If-Match precondition fails when the header is present and names another version
+fn precondition_failed(request: &str, version: i32) -> bool {
+    matches!(get_header(request, "If-Match"), Some(header) if !etag_matches(header, version, false))
+}
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&book).unwrap())
-                }
-                Err(_) =>
-                    (INTERNAL_ERROR.to_string(), "Failed to retrieve created book".to_string()),
-            }
-        }
-        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+//If-None-Match matches when the client already holds the current version
+fn not_modified(request: &str, version: i32) -> bool {
+    matches!(get_header(request, "If-None-Match"), Some(header) if etag_matches(header, version, true))
+}
+
+//admin requests carry X-Admin-Token matching the ADMIN_TOKEN environment variable
+fn is_admin(request: &str) -> bool {
+    match (env::var("ADMIN_TOKEN"), get_header(request, "X-Admin-Token")) {
+        (Ok(token), Some(header)) => !token.is_empty() && token == header,
+        _ => false,
     }
 }
 
-//handle post loan request
-fn handle_post_loan_request(request: &str) -> (String, String) {
-    match (get_loan_request_body(request), Client::connect(DB_URL, NoTls)) {
-        (Ok(loan), Ok(mut client)) => {
-            // Insert the loan and retrieve the ID
-            let row = client
-                .query_one(
-                    "INSERT INTO loans (user_id, book_id, checkout_date, due_date, return_date) VALUES ($1, $2, $3, $4, $5) RETURNING id",
-                    &[&loan.user_id, &loan.book_id, &loan.checkout_date, &loan.due_date, &loan.return_date]
-                )
-                .unwrap();
+//?include_deleted=true shows trashed rows and is reserved for admins
+fn get_include_deleted(request: &str) -> Result<bool, ()> {
+    match get_query_param(request, "include_deleted").as_deref() {
+        Some("true") if is_admin(request) => Ok(true),
+        Some("true") => Err(()),
+        _ => Ok(false),
+    }
+}
 
-            let loan_id: i32 = row.get(0);
+//?cascade=true deletes dependent rows instead of blocking and is reserved for admins
+fn get_cascade(request: &str) -> Result<bool, ()> {
+    match get_query_param(request, "cascade").as_deref() {
+        Some("true") if is_admin(request) => Ok(true),
+        Some("true") => Err(()),
+        _ => Ok(false),
+    }
+}
 
-            // Fetch the created loan data
-            match client.query_one("SELECT id, user_id, book_id, checkout_date, due_date, return_date FROM loans WHERE id = $1", &[&loan_id]) {
-                Ok(row) => {
-                    let loan = Loan {
-                        id: Some(row.get(0)),
-                        user_id: row.get(1),
-                        book_id: row.get(2),
-                        checkout_date: row.get(3),
-                        due_date: row.get(4),
-                        return_date: row.get(5),
-                    };
+//generate an id for requests that did not bring their own X-Request-Id
+fn next_request_id() -> String {
+    format!("{:x}-{:x}", Utc::now().timestamp_millis(), REQUEST_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&loan).unwrap())
+//reduce before/after snapshots of an update to the fields that changed
+fn diff_fields(before: Value, after: Value) -> (Value, Value) {
+    match (before, after) {
+        (Value::Object(mut before), Value::Object(mut after)) => {
+            let keys: Vec<String> = before.keys().chain(after.keys()).cloned().collect();
+            for key in keys {
+                if before.get(&key) == after.get(&key) {
+                    before.remove(&key);
+                    after.remove(&key);
                 }
-                Err(_) =>
-                    (INTERNAL_ERROR.to_string(), "Failed to retrieve created loan".to_string()),
             }
+            (Value::Object(before), Value::Object(after))
         }
-        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+        (before, after) => (before, after),
     }
 }
 
-//handle post review request
-fn handle_post_review_request(request: &str) -> (String, String) {
-    match (get_review_request_body(request), Client::connect(DB_URL, NoTls)) {
-        (Ok(review), Ok(mut client)) => {
-            // Insert the review and retrieve the ID
-            let row = client
-                .query_one(
-                    "INSERT INTO reviews (book_id, user_id, rating, review_text) VALUES ($1, $2, $3, $4) RETURNING id",
-                    &[&review.book_id, &review.user_id, &review.rating, &review.review_text]
-                )
-                .unwrap();
+//record a mutation in the audit log inside the caller's transaction
+fn write_audit<T: Serialize>(
+    transaction: &mut Transaction,
+    context: &RequestContext,
+    action: &str,
+    resource_type: &str,
+    resource_id: i32,
+    before: Option<&T>,
+    after: Option<&T>
+) -> Result<(), PostgresError> {
+    let before = before.and_then(|record| serde_json::to_value(record).ok());
+    let after = after.and_then(|record| serde_json::to_value(record).ok());
+    let (before, after) = match (before, after) {
+        (Some(before), Some(after)) => {
+            let (before, after) = diff_fields(before, after);
+            (Some(before), Some(after))
+        }
+        snapshots => snapshots,
+    };
+
+    transaction.execute(
+        "INSERT INTO audit_log (actor, action, resource_type, resource_id, before_data, after_data, client_ip, request_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        &[
+            &context.actor,
+            &action,
+            &resource_type,
+            &resource_id,
+            &before,
+            &after,
+            &context.client_ip,
+            &context.request_id,
+        ]
+    )?;
+    Ok(())
+}
 
-            let review_id: i32 = row.get(0);
+//Work out what deleting a book or user does to its loans and reviews.
+//Active loans block the delete unless cascading. Returned loans stay as history
+//unless cascading. Reviews go to the trash with their book; a deleted user's
+//reviews are anonymized, or trashed when cascading.
+fn get_delete_impact(transaction: &mut Transaction, table: &str, id: i32, cascade: bool) -> Result<DeleteImpact, PostgresError> {
+    let column = if table == "books" { "book_id" } else { "user_id" };
+
+    let active_loans: Vec<Loan> = transaction
+        .query(
+            &format!("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE {} = $1 AND return_date IS NULL AND deleted_at IS NULL ORDER BY id", column),
+            &[&id]
+        )?
+        .iter()
+        .map(loan_from_row)
+        .collect();
+    let trashed_loans: Vec<Loan> = if cascade {
+        transaction
+            .query(&format!("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE {} = $1 AND deleted_at IS NULL ORDER BY id FOR UPDATE", column), &[&id])?
+            .iter()
+            .map(loan_from_row)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let reviews: Vec<Review> = transaction
+        .query(&format!("SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE {} = $1 AND deleted_at IS NULL ORDER BY id FOR UPDATE", column), &[&id])?
+        .iter()
+        .map(review_from_row)
+        .collect();
+
+    let (trashed_reviews, anonymized_reviews) = if table == "books" || cascade {
+        (reviews, Vec::new())
+    } else {
+        (Vec::new(), reviews)
+    };
+
+    Ok(DeleteImpact {
+        blocked: !cascade && !active_loans.is_empty(),
+        active_loans,
+        trashed_loans,
+        trashed_reviews,
+        anonymized_reviews,
+    })
+}
 
-            // Fetch the created review data
-            match client.query_one("SELECT id, book_id, user_id, rating, review_text FROM reviews WHERE id = $1", &[&review_id]) {
-                Ok(row) => {
-                    let review = Review {
-                        id: Some(row.get(0)),
-                        book_id: row.get(1),
-                        user_id: row.get(2),
-                        rating: row.get(3),
-                        review_text: row.get(4),
-                    };
+//map a database error nothing more specific applies to; the request fails, the server carries on
+fn internal_error(e: PostgresError) -> (String, String) {
+    eprintln!("Database error: {}", e);
+    (INTERNAL_ERROR.to_string(), "Internal error".to_string())
+}
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&review).unwrap())
-                }
-                Err(_) =>
-                    (INTERNAL_ERROR.to_string(), "Failed to retrieve created review".to_string()),
-            }
-        }
+//map a failed delete to a response; rows that still depend on the record are a conflict
+fn delete_write_error(e: PostgresError) -> (String, String) {
+    match e.code() {
+        Some(code) if *code == SqlState::FOREIGN_KEY_VIOLATION || *code == SqlState::RESTRICT_VIOLATION =>
+            (CONFLICT.to_string(), "Record is still referenced by other records".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle get user request
-fn handle_get_user_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
-        (Ok(id), Ok(mut client)) =>
-            match client.query_one("SELECT * FROM users WHERE id = $1", &[&id]) {
-                Ok(row) => {
-                    let user = User {
-                        id: row.get(0),
-                        name: row.get(1),
-                        email: row.get(2),
+//apply a delete impact to the dependent rows, auditing each one
+fn apply_delete_impact(transaction: &mut Transaction, context: &RequestContext, impact: &DeleteImpact) -> Result<(), PostgresError> {
+    for before in &impact.trashed_loans {
+        let id = before.id.unwrap_or_default();
+        let row = transaction.query_one(
+            "UPDATE loans SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id",
+            &[&id]
+        )?;
+        write_audit(transaction, context, "delete", "loans", id, Some(before), Some(&loan_from_row(&row)))?;
+    }
+    for before in &impact.trashed_reviews {
+        let id = before.id.unwrap_or_default();
+        let row = transaction.query_one(
+            "UPDATE reviews SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
+            &[&id]
+        )?;
+        write_audit(transaction, context, "delete", "reviews", id, Some(before), Some(&review_from_row(&row)))?;
+    }
+    for before in &impact.anonymized_reviews {
+        let id = before.id.unwrap_or_default();
+        let row = transaction.query_one(
+            "UPDATE reviews SET user_id = NULL, version = version + 1 WHERE id = $1 RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
+            &[&id]
+        )?;
+        write_audit(transaction, context, "update", "reviews", id, Some(before), Some(&review_from_row(&row)))?;
+    }
+    //copies of trashed open loans are free again
+    if impact.trashed_loans.iter().any(|loan| loan.return_date.is_none()) {
+        holds::promote_holds(transaction, context)?;
+    }
+    Ok(())
+}
+
+//Bring back the loans and reviews that deleting a book or user trashed with it.
+//They were trashed in the delete's transaction, so they share its deleted_at.
+//Anonymized reviews of a user stay anonymous. An open loan whose copy has been
+//lent out since blocks the restore.
+fn restore_delete_impact(transaction: &mut Transaction, context: &RequestContext, table: &str, id: i32, deleted_at: DateTime<Utc>) -> Result<(), (String, String)> {
+    let column = if table == "books" { "book_id" } else { "user_id" };
+
+    let loans: Vec<Loan> = transaction
+        .query(
+            &format!("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE {} = $1 AND deleted_at = $2 ORDER BY id FOR UPDATE", column),
+            &[&id, &deleted_at]
+        )
+        .map_err(internal_error)?
+        .iter()
+        .map(loan_from_row)
+        .collect();
+    for before in &loans {
+        let loan_id = before.id.unwrap_or_default();
+        let mut loan = before.clone();
+        copies::assign_copy(transaction, &mut loan, Some(loan_id))?;
+        let row = transaction
+            .query_one(
+                "UPDATE loans SET deleted_at = NULL, copy_id = $2, version = version + 1 WHERE id = $1 RETURNING id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id",
+                &[&loan_id, &loan.copy_id]
+            )
+            .map_err(internal_error)?;
+        write_audit(transaction, context, "restore", "loans", loan_id, Some(before), Some(&loan_from_row(&row))).map_err(internal_error)?;
+    }
+
+    let reviews: Vec<Review> = transaction
+        .query(
+            &format!("SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE {} = $1 AND deleted_at = $2 ORDER BY id FOR UPDATE", column),
+            &[&id, &deleted_at]
+        )
+        .map_err(internal_error)?
+        .iter()
+        .map(review_from_row)
+        .collect();
+    for before in &reviews {
+        let review_id = before.id.unwrap_or_default();
+        let row = transaction
+            .query_one(
+                "UPDATE reviews SET deleted_at = NULL, version = version + 1 WHERE id = $1 RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
+                &[&review_id]
+            )
+            .map_err(internal_error)?;
+        write_audit(transaction, context, "restore", "reviews", review_id, Some(before), Some(&review_from_row(&row))).map_err(internal_error)?;
+    }
+    Ok(())
+}
+
+//validate a book's ISBNs and publication details, fill in the missing ISBN form and use the canonical genre name
+fn normalize_book(book: &mut Book) -> Result<(), String> {
+    let (isbn10, isbn13) = isbn::reconcile(book.isbn10.as_deref(), book.isbn13.as_deref())?;
+    book.isbn10 = isbn10;
+    book.isbn13 = isbn13;
+    book.genre = book.genre.as_deref().map(taxonomy::normalize_genre_name).filter(|genre| !genre.is_empty());
+
+    if let Some(year) = book.publication_year {
+        let latest = Utc::now().year() + 1;
+        if !(FIRST_PUBLICATION_YEAR..=latest).contains(&year) {
+            return Err(format!("Invalid publication year: {} (expected {} to {})", year, FIRST_PUBLICATION_YEAR, latest));
+        }
+    }
+    if let Some(pages) = book.page_count {
+        if pages < 1 {
+            return Err(format!("Invalid page count: {}", pages));
+        }
+    }
+    book.language = match book.language.as_deref().filter(|language| !language.trim().is_empty()) {
+        Some(language) => Some(language::normalize(language)?),
+        None => None,
+    };
+    book.format = book.format.as_deref().map(|format| format.trim().to_lowercase()).filter(|format| !format.is_empty());
+    if let Some(format) = &book.format {
+        if !BOOK_FORMATS.contains(&format.as_str()) {
+            return Err(format!("Invalid format: {} (expected one of {})", format, BOOK_FORMATS.join(", ")));
+        }
+    }
+    Ok(())
+}
+
+//map a failed book write to a response; a duplicate ISBN is a conflict
+fn book_write_error(e: PostgresError) -> (String, String) {
+    match e.code() {
+        Some(code) if *code == SqlState::UNIQUE_VIOLATION =>
+            (CONFLICT.to_string(), "A book with this ISBN already exists".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//map a failed loan or review write to a response; a user or book that does not exist,
+//or a value a CHECK constraint rejects, is the client's error
+fn reference_write_error(e: PostgresError) -> (String, String) {
+    match e.as_db_error() {
+        Some(db) if *db.code() == SqlState::FOREIGN_KEY_VIOLATION =>
+            (BAD_REQUEST.to_string(), db.detail().unwrap_or("Referenced record not found").to_string()),
+        Some(db) if *db.code() == SqlState::CHECK_VIOLATION =>
+            (BAD_REQUEST.to_string(), format!("Invalid value: {}", db.constraint().unwrap_or("check constraint violated"))),
+        _ => internal_error(e),
+    }
+}
+
+//deserialize metadata lookup request body
+fn get_lookup_request_body(request: &str) -> Result<LookupRequest, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize user from request body without id
+fn get_user_request_body(request: &str) -> Result<User, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize book from request body without id
+fn get_book_request_body(request: &str) -> Result<Book, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize loan from request body without id
+fn get_loan_request_body(request: &str) -> Result<Loan, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize review from request body without id
+fn get_review_request_body(request: &str) -> Result<Review, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize JSON merge patch (RFC 7396) from request body
+fn get_patch_request_body(request: &str) -> Result<Value, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//apply JSON merge patch (RFC 7396) to target in place
+fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(fields) => {
+            if !target.is_object() {
+                *target = Value::Object(serde_json::Map::new());
+            }
+            let target_fields = target.as_object_mut().unwrap();
+            for (key, value) in fields {
+                if value.is_null() {
+                    target_fields.remove(key);
+                } else {
+                    merge_patch(target_fields.entry(key.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        _ => {
+            *target = patch.clone();
+        }
+    }
+}
+
+//patch a record; only the supplied fields can fail validation since the rest come from the stored row
+fn apply_merge_patch<T: Serialize + DeserializeOwned>(current: &T, patch: &Value) -> Result<T, serde_json::Error> {
+    let mut document = serde_json::to_value(current)?;
+    merge_patch(&mut document, patch);
+    serde_json::from_value(document)
+}
+
+//map users row to User
+fn user_from_row(row: &Row) -> User {
+    User {
+        id: row.get(0),
+        name: row.get(1),
+        email: row.get(2),
+        version: row.get(3),
+        created_at: row.get(4),
+        updated_at: row.get(5),
+        deleted_at: row.get(6),
+    }
+}
+
+//map books row to Book
+fn book_from_row(row: &Row) -> Book {
+    Book {
+        id: row.get(0),
+        title: row.get(1),
+        author: row.get(2),
+        genre: row.get(3),
+        isbn10: row.get(4),
+        isbn13: row.get(5),
+        version: row.get(6),
+        created_at: row.get(7),
+        updated_at: row.get(8),
+        deleted_at: row.get(9),
+        publisher: row.get(10),
+        publication_year: row.get(11),
+        page_count: row.get(12),
+        language: row.get(13),
+        format: row.get(14),
+        edition: row.get(15),
+    }
+}
+
+//map loans row to Loan
+fn loan_from_row(row: &Row) -> Loan {
+    Loan {
+        id: row.get(0),
+        user_id: row.get(1),
+        book_id: row.get(2),
+        copy_id: row.get(10),
+        checkout_date: row.get(3),
+        due_date: row.get(4),
+        return_date: row.get(5),
+        version: row.get(6),
+        created_at: row.get(7),
+        updated_at: row.get(8),
+        deleted_at: row.get(9),
+    }
+}
+
+//map reviews row to Review
+fn review_from_row(row: &Row) -> Review {
+    Review {
+        id: row.get(0),
+        book_id: row.get(1),
+        user_id: row.get(2),
+        rating: row.get(3),
+        review_text: row.get(4),
+        version: row.get(5),
+        created_at: row.get(6),
+        updated_at: row.get(7),
+        deleted_at: row.get(8),
+    }
+}
+
+//handle requests
+fn handle_client(mut stream: TcpStream) {
+    match read_request(&mut stream) {
+        Ok(Some((request, body))) => {
+            let context = RequestContext {
+                actor: get_header(&request, "X-User-Id").map(str::to_string),
+                client_ip: stream.peer_addr().ok().map(|address| address.ip().to_string()),
+                request_id: get_header(&request, "X-Request-Id")
+                    .map(str::to_string)
+                    .unwrap_or_else(next_request_id),
+            };
+
+            let (status_line, content) = match &*request {
+                r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "cover" =>
+                    covers::handle_get_cover_request(r),
+                r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "files" && !get_path_segment(r, 6).is_empty() =>
+                    attachments::handle_get_file_request(r),
+                //exports write straight to the socket so a large table is never held in memory
+                r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 4).ends_with(".marc") =>
+                    marc::handle_get_book_marc_request(r),
+                r if r.starts_with("GET /api/rust/admin/backup") =>
+                    match backup::handle_backup_request(r, &context, &mut stream) {
+                        Some((status_line, content)) => (status_line, content.into_bytes()),
+                        None => return,
+                    }
+                r if r.starts_with("GET /api/rust/export/") => {
+                    let exported = if get_path_segment(r, 4).ends_with(".csv") {
+                        bulk::handle_export_request(r, &context, &mut stream)
+                    } else {
+                        marc::handle_export_request(r, &context, &mut stream)
                     };
+                    match exported {
+                        Some((status_line, content)) => (status_line, content.into_bytes()),
+                        None => return,
+                    }
+                }
+                r => {
+                    let (status_line, content) = route(&negotiation::revalidate(r), &body, &context);
+                    let (status_line, content) = negotiation::negotiate(r, status_line, content);
+                    (status_line, content.into_bytes())
+                }
+            };
+            let status_line = with_header(&status_line, "X-Request-Id", &context.request_id);
+
+            stream.write_all(status_line.as_bytes()).and_then(|_| stream.write_all(&content)).unwrap_or_else(|e| {
+                eprintln!("Unable to write stream: {}", e);
+            });
+        }
+        Ok(None) => {
+            let status_line = with_header(PAYLOAD_TOO_LARGE, "Connection", "close");
+            let _ = stream.write_all(format!("{}Request body too large", status_line).as_bytes());
+        }
+        Err(e) => eprintln!("Unable to read stream: {}", e),
+    }
+}
+
+//largest request body accepted in bytes, from MAX_REQUEST_BYTES (default 64 MiB)
+fn max_request_bytes() -> usize {
+    env::var("MAX_REQUEST_BYTES").ok().and_then(|bytes| bytes.parse().ok()).unwrap_or(64 * 1024 * 1024)
+}
+
+//read a request head and its Content-Length body; None when the body is too large to accept
+fn read_request(stream: &mut TcpStream) -> std::io::Result<Option<(String, Vec<u8>)>> {
+    let mut buffer = [0; 8192];
+    let mut data = Vec::new();
+    let head_end = loop {
+        let size = stream.read(&mut buffer)?;
+        data.extend_from_slice(&buffer[..size]);
+        if let Some(position) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        if size == 0 || data.len() > 64 * 1024 {
+            break data.len();
+        }
+    };
+    let head = String::from_utf8_lossy(&data[..head_end]).to_string();
+
+    let length = get_header(&head, "Content-Length").and_then(|length| length.parse::<usize>().ok()).unwrap_or(0);
+    if length > max_request_bytes() {
+        return Ok(None);
+    }
+    //clients waiting on 100-continue send the body only once told to
+    if data.len() == head_end && length > 0 && matches!(get_header(&head, "Expect"), Some(expect) if expect.eq_ignore_ascii_case("100-continue")) {
+        stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    while data.len() < head_end + length {
+        let size = stream.read(&mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..size]);
+    }
+
+    let body = data.split_off(head_end);
+    let request = head + String::from_utf8_lossy(&body).as_ref();
+    Ok(Some((request, body)))
+}
+
+//route a request to its handler
+fn route(request: &str, body: &[u8], context: &RequestContext) -> (String, String) {
+    match request {
+        r if r.starts_with("OPTIONS") => (OK_RESPONSE.to_string(), "".to_string()),
+        r if r.starts_with("POST /api/rust/users/") && get_path_segment(r, 5) == "restore" =>
+            handle_restore_user_request(r, context),
+        r if r.starts_with("POST /api/rust/users") => handle_post_user_request(r, context),
+        r if r.starts_with("GET /api/rust/users/") && get_path_segment(r, 5) == "reading" =>
+            reading::handle_get_reading_history_request(r),
+        r if r.starts_with("GET /api/rust/users/") && get_path_segment(r, 5) == "notifications" =>
+            notifications::handle_get_user_notifications_request(r),
+        r if r.starts_with("GET /api/rust/users/") => handle_get_user_request(r),
+        r if r.starts_with("GET /api/rust/users") => handle_get_all_user_request(r),
+        r if r.starts_with("PUT /api/rust/users/") && get_path_segment(r, 5) == "notifications" =>
+            notifications::handle_put_user_notifications_request(r, context),
+        r if r.starts_with("PUT /api/rust/users/") => handle_put_user_request(r, context),
+        r if r.starts_with("PATCH /api/rust/users/") => handle_patch_user_request(r, context),
+        r if r.starts_with("DELETE /api/rust/users/") => handle_delete_user_request(r, context),
+
+        r if r.starts_with("POST /api/rust/books/") && get_path_segment(r, 5) == "restore" =>
+            handle_restore_book_request(r, context),
+        r if r.starts_with("POST /api/rust/books/") && get_path_segment(r, 5) == "cover" =>
+            covers::handle_post_cover_request(r, body, context),
+        r if r.starts_with("POST /api/rust/books/") && get_path_segment(r, 5) == "files" =>
+            attachments::handle_post_file_request(r, body, context),
+        r if r.starts_with("POST /api/rust/books/lookup") => handle_lookup_book_request(r),
+        r if r.starts_with("POST /api/rust/books") => handle_post_book_request(r, context),
+        r if r.starts_with("GET /api/rust/books/isbn/") => handle_get_book_by_isbn_request(r),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "copies" =>
+            copies::handle_get_book_copies_request(r),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "availability" =>
+            copies::handle_get_book_availability_request(r),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "series" =>
+            series::handle_get_book_series_request(r),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "genres" =>
+            taxonomy::handle_get_book_genres_request(r),
+        r if r.starts_with("PUT /api/rust/books/") && get_path_segment(r, 5) == "genres" =>
+            taxonomy::handle_put_book_genres_request(r, context),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "tags" =>
+            taxonomy::handle_get_book_tags_request(r),
+        r if r.starts_with("PUT /api/rust/books/") && get_path_segment(r, 5) == "tags" =>
+            taxonomy::handle_put_book_tags_request(r, context),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "authors" =>
+            authors::handle_get_book_authors_request(r),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "files" =>
+            attachments::handle_get_book_files_request(r),
+        r if r.starts_with("GET /api/rust/books/") && get_path_segment(r, 5) == "citation" =>
+            citation::handle_get_book_citation_request(r),
+        r if r.starts_with("GET /api/rust/books/") => handle_get_book_request(r),
+        r if r.starts_with("GET /api/rust/books") => handle_get_all_book_request(r),
+        r if r.starts_with("GET /api/rust/citations") => citation::handle_get_citations_request(r),
+        r if r.starts_with("PUT /api/rust/books/") && get_path_segment(r, 5) == "authors" =>
+            authors::handle_put_book_authors_request(r, context),
+        r if r.starts_with("PUT /api/rust/books/") => handle_put_book_request(r, context),
+        r if r.starts_with("PATCH /api/rust/books/") => handle_patch_book_request(r, context),
+        r if r.starts_with("DELETE /api/rust/books/") && get_path_segment(r, 5) == "cover" =>
+            covers::handle_delete_cover_request(r, context),
+        r if r.starts_with("DELETE /api/rust/books/") && get_path_segment(r, 5) == "files" =>
+            attachments::handle_delete_file_request(r, context),
+        r if r.starts_with("DELETE /api/rust/books/") => handle_delete_book_request(r, context),
+
+        r if r.starts_with("POST /api/rust/loans/") && get_path_segment(r, 5) == "restore" =>
+            handle_restore_loan_request(r, context),
+        r if r.starts_with("POST /api/rust/loans") => handle_post_loan_request(r, context),
+        r if r.starts_with("GET /api/rust/loans/") => handle_get_loan_request(r),
+        r if r.starts_with("GET /api/rust/loans") => handle_get_all_loan_request(r),
+        r if r.starts_with("PUT /api/rust/loans/") => handle_put_loan_request(r, context),
+        r if r.starts_with("PATCH /api/rust/loans/") => handle_patch_loan_request(r, context),
+        r if r.starts_with("DELETE /api/rust/loans/") => handle_delete_loan_request(r, context),
+
+        r if r.starts_with("POST /api/rust/holds") => holds::handle_post_hold_request(r, context),
+        r if r.starts_with("GET /api/rust/holds/") => holds::handle_get_hold_request(r),
+        r if r.starts_with("GET /api/rust/holds") => holds::handle_get_all_hold_request(r),
+        r if r.starts_with("DELETE /api/rust/holds/") => holds::handle_delete_hold_request(r, context),
+
+        r if r.starts_with("POST /api/rust/copies/") && get_path_segment(r, 5) == "restore" =>
+            copies::handle_restore_copy_request(r, context),
+        r if r.starts_with("POST /api/rust/copies") => copies::handle_post_copy_request(r, context),
+        r if r.starts_with("GET /api/rust/copies/") => copies::handle_get_copy_request(r),
+        r if r.starts_with("GET /api/rust/copies") => copies::handle_get_all_copy_request(r),
+        r if r.starts_with("PUT /api/rust/copies/") => copies::handle_put_copy_request(r, context),
+        r if r.starts_with("PATCH /api/rust/copies/") => copies::handle_patch_copy_request(r, context),
+        r if r.starts_with("DELETE /api/rust/copies/") => copies::handle_delete_copy_request(r, context),
+
+        r if r.starts_with("POST /api/rust/reviews/") && get_path_segment(r, 5) == "restore" =>
+            handle_restore_review_request(r, context),
+        r if r.starts_with("POST /api/rust/reviews") => handle_post_review_request(r, context),
+        r if r.starts_with("GET /api/rust/reviews/") => handle_get_review_request(r),
+        r if r.starts_with("GET /api/rust/reviews") => handle_get_all_review_request(r),
+        r if r.starts_with("PUT /api/rust/reviews/") => handle_put_review_request(r, context),
+        r if r.starts_with("PATCH /api/rust/reviews/") => handle_patch_review_request(r, context),
+        r if r.starts_with("DELETE /api/rust/reviews/") => handle_delete_review_request(r, context),
+
+        r if r.starts_with("POST /api/rust/authors/") && get_path_segment(r, 5) == "restore" =>
+            authors::handle_restore_author_request(r, context),
+        r if r.starts_with("POST /api/rust/authors") => authors::handle_post_author_request(r, context),
+        r if r.starts_with("GET /api/rust/authors/") && get_path_segment(r, 5) == "books" =>
+            authors::handle_get_author_books_request(r),
+        r if r.starts_with("GET /api/rust/authors/") => authors::handle_get_author_request(r),
+        r if r.starts_with("GET /api/rust/authors") => authors::handle_get_all_author_request(r),
+        r if r.starts_with("PUT /api/rust/authors/") => authors::handle_put_author_request(r, context),
+        r if r.starts_with("PATCH /api/rust/authors/") => authors::handle_patch_author_request(r, context),
+        r if r.starts_with("DELETE /api/rust/authors/") => authors::handle_delete_author_request(r, context),
+
+        r if r.starts_with("POST /api/rust/series/") && get_path_segment(r, 5) == "restore" =>
+            series::handle_restore_series_request(r, context),
+        r if r.starts_with("POST /api/rust/series") => series::handle_post_series_request(r, context),
+        r if r.starts_with("GET /api/rust/series/") && get_path_segment(r, 5) == "next" =>
+            series::handle_get_next_unread_request(r),
+        r if r.starts_with("GET /api/rust/series/") => series::handle_get_series_request(r),
+        r if r.starts_with("GET /api/rust/series") => series::handle_get_all_series_request(r),
+        r if r.starts_with("PUT /api/rust/series/") && get_path_segment(r, 5) == "books" =>
+            series::handle_put_series_book_request(r, context),
+        r if r.starts_with("DELETE /api/rust/series/") && get_path_segment(r, 5) == "books" =>
+            series::handle_delete_series_book_request(r, context),
+        r if r.starts_with("PUT /api/rust/series/") => series::handle_put_series_request(r, context),
+        r if r.starts_with("PATCH /api/rust/series/") => series::handle_patch_series_request(r, context),
+        r if r.starts_with("DELETE /api/rust/series/") => series::handle_delete_series_request(r, context),
+
+        r if r.starts_with("POST /api/rust/genres/") && get_path_segment(r, 5) == "merge" =>
+            taxonomy::handle_merge_genre_request(r, context),
+        r if r.starts_with("POST /api/rust/genres") => taxonomy::handle_post_genre_request(r, context),
+        r if r.starts_with("GET /api/rust/genres/") => taxonomy::handle_get_genre_request(r),
+        r if r.starts_with("GET /api/rust/genres") => taxonomy::handle_get_all_genre_request(r),
+        r if r.starts_with("PATCH /api/rust/genres/") => taxonomy::handle_patch_genre_request(r, context),
+        r if r.starts_with("DELETE /api/rust/genres/") => taxonomy::handle_delete_genre_request(r, context),
+        r if r.starts_with("POST /api/rust/tags/") && get_path_segment(r, 5) == "merge" =>
+            taxonomy::handle_merge_tag_request(r, context),
+        r if r.starts_with("POST /api/rust/tags") => taxonomy::handle_post_tag_request(r, context),
+        r if r.starts_with("GET /api/rust/tags") => taxonomy::handle_get_all_tag_request(r),
+        r if r.starts_with("PATCH /api/rust/tags/") => taxonomy::handle_patch_tag_request(r, context),
+        r if r.starts_with("DELETE /api/rust/tags/") => taxonomy::handle_delete_tag_request(r, context),
+
+        r if r.starts_with("POST /api/rust/import/marc") => marc::handle_import_request(r, body, context),
+        r if r.starts_with("POST /api/rust/import/calibre") => calibre::handle_import_calibre_request(r, body, context),
+        r if r.starts_with("POST /api/rust/import/goodreads") || r.starts_with("POST /api/rust/import/storygraph") =>
+            reading::handle_import_history_request(r, body, context),
+        r if r.starts_with("POST /api/rust/import/") => bulk::handle_import_request(r, body, context),
+
+        r if r.starts_with("POST /api/rust/files/metadata") => attachments::handle_file_metadata_request(r, body),
+
+        r if r.starts_with("GET /api/rust/opds") => opds::handle_opds_request(r),
+        r if r.starts_with("GET /api/rust/sru") => sru::handle_sru_request(r),
+        r if r.starts_with("GET /api/rust/audit") => handle_get_audit_request(r),
+        r if r.starts_with("POST /api/rust/admin/notifications/run") => notifications::handle_run_notifications_request(r),
+        r if r.starts_with("GET /api/rust/admin/notifications") => notifications::handle_get_notification_log_request(r),
+        r if r.starts_with("POST /api/rust/admin/restore") => backup::handle_restore_request(r, body),
+
+        _ => (NOT_FOUND.to_string(), "404 not found".to_string()),
+    }
+}
+
+//handle post user request
+fn handle_post_user_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_user_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(user), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            match insert_user(&mut transaction, context, &user) {
+                Ok(user) => {
+                    if let Err(e) = transaction.commit() {
+                        return internal_error(e);
+                    }
 
                     (OK_RESPONSE.to_string(), serde_json::to_string(&user).unwrap())
                 }
-                _ => (NOT_FOUND.to_string(), "User not found".to_string()),
+                Err(_) =>
+                    (INTERNAL_ERROR.to_string(), "Failed to retrieve created user".to_string()),
+            }
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//insert a user and audit it
+fn insert_user(transaction: &mut Transaction, context: &RequestContext, user: &User) -> Result<User, PostgresError> {
+    let row = transaction.query_one(
+        "INSERT INTO users (name, email) VALUES ($1, $2) RETURNING id, name, email, version, created_at, updated_at, deleted_at",
+        &[&user.name, &user.email]
+    )?;
+    let user = user_from_row(&row);
+    write_audit(transaction, context, "create", "users", user.id.unwrap_or_default(), None, Some(&user))?;
+    Ok(user)
+}
+
+//handle post book request
+fn handle_post_book_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_book_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(mut book), Ok(mut client)) => {
+            //validate ISBNs, fill in the missing form and canonicalize the genre
+            if let Err(e) = normalize_book(&mut book) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let book = match insert_book(&mut transaction, context, &book) {
+                Ok(book) => book,
+                Err(e) => return book_write_error(e),
+            };
+            //a book catalogued by hand starts out as one copy on the shelf; e-books have no copies,
+            //and imported books get theirs added once they are actually on the shelf
+            if book.format.as_deref() != Some("ebook") {
+                if let Err(e) = copies::create_first_copy(&mut transaction, context, book.id.unwrap_or_default()) {
+                    return internal_error(e);
+                }
+            }
+            if let Err(e) = transaction.commit() {
+                return internal_error(e);
             }
 
+            (OK_RESPONSE.to_string(), serde_json::to_string(&book).unwrap())
+        }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle get book request
-fn handle_get_book_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
-        (Ok(id), Ok(mut client)) =>
-            match client.query_one("SELECT * FROM books WHERE id = $1", &[&id]) {
+//insert a normalized book with its authors and genre, and audit it
+fn insert_book(transaction: &mut Transaction, context: &RequestContext, book: &Book) -> Result<Book, PostgresError> {
+    let row = transaction.query_one(
+        "INSERT INTO books (title, author, genre, isbn10, isbn13, publisher, publication_year, page_count, language, format, edition) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+        &[&book.title, &book.author, &book.genre, &book.isbn10, &book.isbn13, &book.publisher, &book.publication_year, &book.page_count, &book.language, &book.format, &book.edition]
+    )?;
+    let book = book_from_row(&row);
+    let book_id = book.id.unwrap_or_default();
+
+    authors::link_book_authors(transaction, context, book_id, &book.author)?;
+    taxonomy::link_book_genre(transaction, context, book_id, None, book.genre.as_deref())?;
+    write_audit(transaction, context, "create", "books", book_id, None, Some(&book))?;
+    Ok(book)
+}
+
+//handle post loan request
+fn handle_post_loan_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_loan_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(loan), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            match insert_loan(&mut transaction, context, loan) {
+                Ok(loan) => {
+                    if let Err(e) = transaction.commit() {
+                        return internal_error(e);
+                    }
+
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&loan).unwrap())
+                }
+                Err(e) => e,
+            }
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//lend a copy, insert the loan, close the borrower's hold and audit it
+fn insert_loan(transaction: &mut Transaction, context: &RequestContext, mut loan: Loan) -> Result<Loan, (String, String)> {
+    //lend the requested copy, or the first free copy of the book
+    copies::assign_copy(transaction, &mut loan, None)?;
+
+    let row = transaction
+        .query_one(
+            "INSERT INTO loans (user_id, book_id, copy_id, checkout_date, due_date, return_date) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id",
+            &[&loan.user_id, &loan.book_id, &loan.copy_id, &loan.checkout_date, &loan.due_date, &loan.return_date]
+        )
+        .map_err(reference_write_error)?;
+    let loan = loan_from_row(&row);
+
+    //a borrower who gets the book no longer needs their place in the queue
+    if loan.return_date.is_none() {
+        holds::fulfil_holds(transaction, context, loan.user_id, loan.book_id).map_err(internal_error)?;
+    }
+    write_audit(transaction, context, "create", "loans", loan.id.unwrap_or_default(), None, Some(&loan)).map_err(internal_error)?;
+    Ok(loan)
+}
+
+//handle post review request
+fn handle_post_review_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_review_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(review), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            match insert_review(&mut transaction, context, &review) {
+                Ok(review) => {
+                    if let Err(e) = transaction.commit() {
+                        return internal_error(e);
+                    }
+
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&review).unwrap())
+                }
+                Err(e) => reference_write_error(e),
+            }
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//insert a review and audit it
+fn insert_review(transaction: &mut Transaction, context: &RequestContext, review: &Review) -> Result<Review, PostgresError> {
+    let row = transaction.query_one(
+        "INSERT INTO reviews (book_id, user_id, rating, review_text) VALUES ($1, $2, $3, $4) RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
+        &[&review.book_id, &review.user_id, &review.rating, &review.review_text]
+    )?;
+    let review = review_from_row(&row);
+    write_audit(transaction, context, "create", "reviews", review.id.unwrap_or_default(), None, Some(&review))?;
+    Ok(review)
+}
+
+//handle get user request
+fn handle_get_user_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(include_deleted), Ok(mut client)) =>
+            match client.query_one("SELECT id, name, email, version, created_at, updated_at, deleted_at FROM users WHERE id = $1 AND ($2 OR deleted_at IS NULL)", &[&id, &include_deleted]) {
                 Ok(row) => {
-                    let book = Book {
+                    let user = User {
                         id: row.get(0),
-                        title: row.get(1),
-                        author: row.get(2),
-                        genre: row.get(3),
+                        name: row.get(1),
+                        email: row.get(2),
+                        version: row.get(3),
+                        created_at: row.get(4),
+                        updated_at: row.get(5),
+                        deleted_at: row.get(6),
                     };
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&book).unwrap())
+                    //conditional GET
+                    let version = user.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&user).unwrap())
+                }
+                _ => (NOT_FOUND.to_string(), "User not found".to_string()),
+            }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book request
+fn handle_get_book_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(include_deleted), Ok(mut client)) =>
+            match client.query_one("SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND ($2 OR deleted_at IS NULL)", &[&id, &include_deleted]) {
+                Ok(row) => {
+                    let book = book_from_row(&row);
+
+                    //conditional GET
+                    let version = book.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&book).unwrap())
+                }
+                _ => (NOT_FOUND.to_string(), "Book not found".to_string()),
+            }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book by isbn request
+fn handle_get_book_by_isbn_request(request: &str) -> (String, String) {
+    match (isbn::parse(get_path_segment(request, 5)), Client::connect(DB_URL, NoTls)) {
+        (Ok(isbn13), Ok(mut client)) =>
+            match client.query_one("SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE isbn13 = $1 AND deleted_at IS NULL", &[&isbn13]) {
+                Ok(row) => {
+                    let book = book_from_row(&row);
+
+                    //conditional GET
+                    let version = book.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&book).unwrap())
                 }
                 _ => (NOT_FOUND.to_string(), "Book not found".to_string()),
             }
+        (Err(e), _) => (BAD_REQUEST.to_string(), e),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle book metadata lookup request
+fn handle_lookup_book_request(request: &str) -> (String, String) {
+    let isbn13 = match get_lookup_request_body(request).map(|body| isbn::parse(&body.isbn)) {
+        Ok(Ok(isbn13)) => isbn13,
+        Ok(Err(e)) => return (BAD_REQUEST.to_string(), e),
+        Err(_) => return (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+    };
+    let cache_days: i32 = env::var("METADATA_CACHE_DAYS").ok().and_then(|value| value.parse().ok()).unwrap_or(30);
+
+    match Client::connect(DB_URL, NoTls) {
+        Ok(mut client) => {
+            //serve from the cache while it is fresh
+            if
+                let Ok(Some(row)) = client.query_opt(
+                    "SELECT metadata FROM metadata_cache WHERE isbn13 = $1 AND fetched_at > now() - make_interval(days => $2)",
+                    &[&isbn13, &cache_days]
+                )
+            {
+                let metadata: Value = row.get(0);
+                return (OK_RESPONSE.to_string(), metadata.to_string());
+            }
+
+            match metadata::configured_provider().lookup(&isbn13) {
+                Ok(Some(metadata)) => {
+                    let metadata = serde_json::to_value(&metadata).unwrap();
+                    //the cache only saves a later lookup; failing to fill it must not fail this one
+                    if let Err(e) =
+                        client.execute(
+                            "INSERT INTO metadata_cache (isbn13, metadata) VALUES ($1, $2) ON CONFLICT (isbn13) DO UPDATE SET metadata = $2, fetched_at = now()",
+                            &[&isbn13, &metadata]
+                        )
+                    {
+                        eprintln!("Metadata cache write failed for {}: {}", isbn13, e);
+                    }
+
+                    (OK_RESPONSE.to_string(), metadata.to_string())
+                }
+                Ok(None) => (NOT_FOUND.to_string(), "No metadata found for ISBN".to_string()),
+                Err(e) => (BAD_GATEWAY.to_string(), e),
+            }
+        }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle get loan request
 fn handle_get_loan_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
-        (Ok(id), Ok(mut client)) =>
-            match client.query_one("SELECT * FROM loans WHERE id = $1", &[&id]) {
+    match (get_id(request).parse::<i32>(), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(include_deleted), Ok(mut client)) =>
+            match client.query_one("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE id = $1 AND ($2 OR deleted_at IS NULL)", &[&id, &include_deleted]) {
                 Ok(row) => {
                     let loan = Loan {
                         id: row.get(0),
                         user_id: row.get(1),
                         book_id: row.get(2),
+                        copy_id: row.get(10),
                         checkout_date: row.get(3),
                         due_date: row.get(4),
                         return_date: row.get(5),
+                        version: row.get(6),
+                        created_at: row.get(7),
+                        updated_at: row.get(8),
+                        deleted_at: row.get(9),
                     };
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&loan).unwrap())
+                    //conditional GET
+                    let version = loan.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&loan).unwrap())
                 }
                 _ => (NOT_FOUND.to_string(), "Loan not found".to_string()),
             }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle get review request
 fn handle_get_review_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
-        (Ok(id), Ok(mut client)) =>
-            match client.query_one("SELECT * FROM reviews WHERE id = $1", &[&id]) {
+    match (get_id(request).parse::<i32>(), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(include_deleted), Ok(mut client)) =>
+            match client.query_one("SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE id = $1 AND ($2 OR deleted_at IS NULL)", &[&id, &include_deleted]) {
                 Ok(row) => {
                     let review = Review {
                         id: row.get(0),
@@ -413,237 +1837,1045 @@ fn handle_get_review_request(request: &str) -> (String, String) {
                         user_id: row.get(2),
                         rating: row.get(3),
                         review_text: row.get(4),
+                        version: row.get(5),
+                        created_at: row.get(6),
+                        updated_at: row.get(7),
+                        deleted_at: row.get(8),
                     };
 
-                    (OK_RESPONSE.to_string(), serde_json::to_string(&review).unwrap())
+                    //conditional GET
+                    let version = review.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&review).unwrap())
                 }
                 _ => (NOT_FOUND.to_string(), "Review not found".to_string()),
             }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle get all user request
-fn handle_get_all_user_request(_request: &str) -> (String, String) {
-    match Client::connect(DB_URL, NoTls) {
-        Ok(mut client) => {
+fn handle_get_all_user_request(request: &str) -> (String, String) {
+    match
+        (
+            get_timestamp_param(request, "created_after"),
+            get_timestamp_param(request, "updated_since"),
+            get_include_deleted(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(created_after), Ok(updated_since), Ok(include_deleted), Ok(mut client)) => {
             let mut users = Vec::new(); // Vector to store the users
 
-            for row in client.query("SELECT id, name, email FROM users", &[]).unwrap() {
+            let rows = match
+                client.query(
+                    "SELECT id, name, email, version, created_at, updated_at, deleted_at FROM users WHERE ($1::timestamptz IS NULL OR created_at > $1) AND ($2::timestamptz IS NULL OR updated_at >= $2) AND ($3 OR deleted_at IS NULL) ORDER BY id",
+                    &[&created_after, &updated_since, &include_deleted]
+                )
+            {
+                Ok(rows) => rows,
+                Err(e) => return internal_error(e),
+            };
+            for row in rows {
                 users.push(User {
                     id: row.get(0),
                     name: row.get(1),
                     email: row.get(2),
+                    version: row.get(3),
+                    created_at: row.get(4),
+                    updated_at: row.get(5),
+                    deleted_at: row.get(6),
                 });
             }
 
             (OK_RESPONSE.to_string(), serde_json::to_string(&users).unwrap())
         }
+        (_, _, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        (Err(_), _, _, _) | (_, Err(_), _, _) =>
+            (BAD_REQUEST.to_string(), "Invalid timestamp filter".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle get all book request
-fn handle_get_all_book_request(_request: &str) -> (String, String) {
-    match Client::connect(DB_URL, NoTls) {
-        Ok(mut client) => {
+fn handle_get_all_book_request(request: &str) -> (String, String) {
+    match
+        (
+            get_timestamp_param(request, "created_after"),
+            get_timestamp_param(request, "updated_since"),
+            get_include_deleted(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(created_after), Ok(updated_since), Ok(include_deleted), Ok(mut client)) => {
             let mut books = Vec::new(); // Vector to store the books
 
-            for row in client.query("SELECT id, title, author, genre FROM books", &[]).unwrap() {
-                books.push(Book {
+            //?genre= takes an id or slug and includes subgenres; ?tags= requires every listed tag
+            let genre = get_query_param(request, "genre");
+            let genre_id = match genre.as_deref().map(|genre| taxonomy::resolve_genre(&mut client, genre)) {
+                Some(Ok(Some(id))) => Some(id),
+                Some(Ok(None)) => return (OK_RESPONSE.to_string(), "[]".to_string()),
+                Some(Err(_)) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+                None => None,
+            };
+            let tags: Vec<String> = get_query_param(request, "tags")
+                .unwrap_or_default()
+                .split(',')
+                .map(|tag| tag.trim().to_lowercase())
+                .filter(|tag| !tag.is_empty())
+                .collect();
+            let tag_count = tags.len() as i64;
+
+            //publication filters; ?year= is shorthand for a one-year range
+            let language = match get_query_param(request, "language").map(|language| language::normalize(&language)).transpose() {
+                Ok(language) => language,
+                Err(e) => return (BAD_REQUEST.to_string(), e),
+            };
+            let publisher = get_query_param(request, "publisher").map(|publisher| format!("%{}%", publisher));
+            //?format=csv and the like pick the response representation rather than filtering
+            let format = get_query_param(request, "format")
+                .map(|format| format.to_lowercase())
+                .filter(|format| !negotiation::is_representation(format));
+            let ranges = (
+                get_int_param(request, "year"),
+                get_int_param(request, "year_from"),
+                get_int_param(request, "year_to"),
+                get_int_param(request, "min_pages"),
+                get_int_param(request, "max_pages"),
+            );
+            let (year_from, year_to, min_pages, max_pages) = match ranges {
+                (Ok(year), Ok(year_from), Ok(year_to), Ok(min_pages), Ok(max_pages)) =>
+                    (year.or(year_from), year.or(year_to), min_pages, max_pages),
+                _ => return (BAD_REQUEST.to_string(), "Invalid number filter".to_string()),
+            };
+            let order = match get_sort_clause(request, &BOOK_SORTS) {
+                Ok(order) => order,
+                Err(e) => return (BAD_REQUEST.to_string(), e),
+            };
+
+            let rows = match
+                client.query(
+                    &format!(
+                        "WITH RECURSIVE subgenres AS (SELECT id FROM genres WHERE id = $4 UNION SELECT g.id FROM genres g JOIN subgenres s ON g.parent_id = s.id)
+                        SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE ($1::timestamptz IS NULL OR created_at > $1) AND ($2::timestamptz IS NULL OR updated_at >= $2) AND ($3 OR deleted_at IS NULL)
+                        AND ($4::int IS NULL OR EXISTS (SELECT 1 FROM book_genres bg JOIN subgenres s ON s.id = bg.genre_id WHERE bg.book_id = books.id))
+                        AND ($6::bigint = 0 OR (SELECT count(*) FROM book_tags bt JOIN tags t ON t.id = bt.tag_id WHERE bt.book_id = books.id AND t.deleted_at IS NULL AND lower(t.name) = ANY($5)) = $6)
+                        AND ($7::varchar IS NULL OR language = $7) AND ($8::varchar IS NULL OR publisher ILIKE $8) AND ($9::varchar IS NULL OR format = $9)
+                        AND ($10::int IS NULL OR publication_year >= $10) AND ($11::int IS NULL OR publication_year <= $11)
+                        AND ($12::int IS NULL OR page_count >= $12) AND ($13::int IS NULL OR page_count <= $13)
+                        ORDER BY {}",
+                        order
+                    ),
+                    &[&created_after, &updated_since, &include_deleted, &genre_id, &tags, &tag_count, &language, &publisher, &format, &year_from, &year_to, &min_pages, &max_pages]
+                )
+            {
+                Ok(rows) => rows,
+                Err(e) => return internal_error(e),
+            };
+            for row in rows {
+                books.push(book_from_row(&row));
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&books).unwrap())
+        }
+        (_, _, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        (Err(_), _, _, _) | (_, Err(_), _, _) =>
+            (BAD_REQUEST.to_string(), "Invalid timestamp filter".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all loan request
+fn handle_get_all_loan_request(request: &str) -> (String, String) {
+    match
+        (
+            get_timestamp_param(request, "created_after"),
+            get_timestamp_param(request, "updated_since"),
+            get_include_deleted(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(created_after), Ok(updated_since), Ok(include_deleted), Ok(mut client)) => {
+            let mut loans = Vec::new(); // Vector to store the loans
+
+            let rows = match
+                client.query(
+                    "SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE ($1::timestamptz IS NULL OR created_at > $1) AND ($2::timestamptz IS NULL OR updated_at >= $2) AND ($3 OR deleted_at IS NULL) ORDER BY id",
+                    &[&created_after, &updated_since, &include_deleted]
+                )
+            {
+                Ok(rows) => rows,
+                Err(e) => return internal_error(e),
+            };
+            for row in rows {
+                loans.push(Loan {
                     id: row.get(0),
-                    title: row.get(1),
-                    author: row.get(2),
-                    genre: row.get(3),
+                    user_id: row.get(1),
+                    book_id: row.get(2),
+                    copy_id: row.get(10),
+                    checkout_date: row.get(3),
+                    due_date: row.get(4),
+                    return_date: row.get(5),
+                    version: row.get(6),
+                    created_at: row.get(7),
+                    updated_at: row.get(8),
+                    deleted_at: row.get(9),
                 });
             }
 
-            (OK_RESPONSE.to_string(), serde_json::to_string(&books).unwrap())
+            (OK_RESPONSE.to_string(), serde_json::to_string(&loans).unwrap())
+        }
+        (_, _, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        (Err(_), _, _, _) | (_, Err(_), _, _) =>
+            (BAD_REQUEST.to_string(), "Invalid timestamp filter".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all review request
+fn handle_get_all_review_request(request: &str) -> (String, String) {
+    match
+        (
+            get_timestamp_param(request, "created_after"),
+            get_timestamp_param(request, "updated_since"),
+            get_include_deleted(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(created_after), Ok(updated_since), Ok(include_deleted), Ok(mut client)) => {
+            let mut reviews = Vec::new(); // Vector to store the reviews
+
+            let rows = match
+                client.query(
+                    "SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE ($1::timestamptz IS NULL OR created_at > $1) AND ($2::timestamptz IS NULL OR updated_at >= $2) AND ($3 OR deleted_at IS NULL) ORDER BY id",
+                    &[&created_after, &updated_since, &include_deleted]
+                )
+            {
+                Ok(rows) => rows,
+                Err(e) => return internal_error(e),
+            };
+            for row in rows {
+                reviews.push(Review {
+                    id: row.get(0),
+                    book_id: row.get(1),
+                    user_id: row.get(2),
+                    rating: row.get(3),
+                    review_text: row.get(4),
+                    version: row.get(5),
+                    created_at: row.get(6),
+                    updated_at: row.get(7),
+                    deleted_at: row.get(8),
+                });
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&reviews).unwrap())
+        }
+        (_, _, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        (Err(_), _, _, _) | (_, Err(_), _, _) =>
+            (BAD_REQUEST.to_string(), "Invalid timestamp filter".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put user request
+fn handle_put_user_request(request: &str, context: &RequestContext) -> (String, String) {
+    match
+        (
+            get_id(request).parse::<i32>(),
+            get_user_request_body(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(id), Ok(user), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, name, email, version, created_at, updated_at, deleted_at FROM users WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => user_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "User not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE users SET name = $1, email = $2, version = version + 1 WHERE id = $3 RETURNING id, name, email, version, created_at, updated_at, deleted_at",
+                    &[&user.name, &user.email, &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = user_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "users", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), "User updated".to_string())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put book request
+fn handle_put_book_request(request: &str, context: &RequestContext) -> (String, String) {
+    match
+        (
+            get_id(request).parse::<i32>(),
+            get_book_request_body(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(id), Ok(mut book), Ok(mut client)) => {
+            //validate ISBNs, fill in the missing form and canonicalize the genre
+            if let Err(e) = normalize_book(&mut book) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE books SET title = $1, author = $2, genre = $3, isbn10 = $4, isbn13 = $5, publisher = $6, publication_year = $7, page_count = $8, language = $9, format = $10, edition = $11, version = version + 1 WHERE id = $12 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+                    &[&book.title, &book.author, &book.genre, &book.isbn10, &book.isbn13, &book.publisher, &book.publication_year, &book.page_count, &book.language, &book.format, &book.edition, &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return book_write_error(e),
+            };
+            let after = book_from_row(&row);
+            if after.author != before.author {
+                if let Err(e) = authors::link_book_authors(&mut transaction, context, id, &after.author) {
+                    return internal_error(e);
+                }
+            }
+            if after.genre != before.genre {
+                if let Err(e) = taxonomy::link_book_genre(&mut transaction, context, id, before.genre.as_deref(), after.genre.as_deref()) {
+                    return internal_error(e);
+                }
+            }
+            if let Err(e) = write_audit(&mut transaction, context, "update", "books", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), "Book updated".to_string())
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle get all loan request
-fn handle_get_all_loan_request(_request: &str) -> (String, String) {
-    match Client::connect(DB_URL, NoTls) {
-        Ok(mut client) => {
-            let mut loans = Vec::new(); // Vector to store the loans
+//handle put loan request
+fn handle_put_loan_request(request: &str, context: &RequestContext) -> (String, String) {
+    match
+        (
+            get_id(request).parse::<i32>(),
+            get_loan_request_body(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(id), Ok(mut loan), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
 
-            for row in client.query("SELECT id, user_id, book_id, checkout_date, due_date, return_date FROM loans", &[]).unwrap() {
-                loans.push(Loan {
-                    id: row.get(0),
-                    user_id: row.get(1),
-                    book_id: row.get(2),
-                    checkout_date: row.get(3),
-                    due_date: row.get(4),
-                    return_date: row.get(5),
-                });
+            let before = match transaction.query_opt("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => loan_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Loan not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
             }
 
-            (OK_RESPONSE.to_string(), serde_json::to_string(&loans).unwrap())
+            //a replacement that leaves out the copy keeps the one already lent for the same book
+            if loan.copy_id.is_none() && loan.book_id == before.book_id {
+                loan.copy_id = before.copy_id;
+            }
+            if let Err(e) = copies::assign_copy(&mut transaction, &mut loan, Some(id)) {
+                return e;
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE loans SET user_id = $1, book_id = $2, copy_id = $3, checkout_date = $4, due_date = $5, return_date = $6, version = version + 1 WHERE id = $7 RETURNING id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id",
+                    &[&loan.user_id, &loan.book_id, &loan.copy_id, &loan.checkout_date, &loan.due_date, &loan.return_date, &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return reference_write_error(e),
+            };
+            let after = loan_from_row(&row);
+            //a returned or moved loan may free a copy for the next hold in line
+            let saved = write_audit(&mut transaction, context, "update", "loans", id, Some(&before), Some(&after))
+                .and_then(|_| holds::promote_holds(&mut transaction, context))
+                .and_then(|_| transaction.commit());
+            if saved.is_err() {
+                return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), "Loan updated".to_string())
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle get all review request
-fn handle_get_all_review_request(_request: &str) -> (String, String) {
-    match Client::connect(DB_URL, NoTls) {
-        Ok(mut client) => {
-            let mut reviews = Vec::new(); // Vector to store the reviews
+//handle put review request
+fn handle_put_review_request(request: &str, context: &RequestContext) -> (String, String) {
+    match
+        (
+            get_id(request).parse::<i32>(),
+            get_review_request_body(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(id), Ok(review), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
 
-            for row in client.query("SELECT id, book_id, user_id, rating, review_text FROM reviews", &[]).unwrap() {
-                reviews.push(Review {
-                    id: row.get(0),
-                    book_id: row.get(1),
-                    user_id: row.get(2),
-                    rating: row.get(3),
-                    review_text: row.get(4),
-                });
+            let before = match transaction.query_opt("SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => review_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Review not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
             }
 
-            (OK_RESPONSE.to_string(), serde_json::to_string(&reviews).unwrap())
+            let row = match
+                transaction.query_one(
+                    "UPDATE reviews SET book_id = $1, user_id = $2, rating = $3, review_text = $4, version = version + 1 WHERE id = $5 RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
+                    &[&review.book_id, &review.user_id, &review.rating, &review.review_text, &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return reference_write_error(e),
+            };
+            let after = review_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "reviews", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), "Review updated".to_string())
         }
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle put user request
-fn handle_put_user_request(request: &str) -> (String, String) {
+//handle patch user request
+fn handle_patch_user_request(request: &str, context: &RequestContext) -> (String, String) {
     match
         (
-            get_id(&request).parse::<i32>(),
-            get_user_request_body(&request),
+            get_id(request).parse::<i32>(),
+            get_patch_request_body(request),
             Client::connect(DB_URL, NoTls),
         )
     {
-        (Ok(id), Ok(user), Ok(mut client)) => {
-            client
-                .execute(
-                    "UPDATE users SET name = $1, email = $2 WHERE id = $3",
+        (Ok(id), Ok(patch), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let current = match transaction.query_opt("SELECT id, name, email, version, created_at, updated_at, deleted_at FROM users WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => user_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "User not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, current.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //merge the patch over the stored record and validate the result
+            let user: User = match apply_merge_patch(&current, &patch) {
+                Ok(user) => user,
+                Err(e) => return (BAD_REQUEST.to_string(), format!("Invalid patch: {}", e)),
+            };
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE users SET name = $1, email = $2, version = version + 1 WHERE id = $3 RETURNING id, name, email, version, created_at, updated_at, deleted_at",
                     &[&user.name, &user.email, &id]
                 )
-                .unwrap();
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let user = user_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "users", id, Some(&current), Some(&user)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
 
-            (OK_RESPONSE.to_string(), "User updated".to_string())
+            let version = user.version.unwrap_or_default();
+            (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&user).unwrap())
         }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle put book request
-fn handle_put_book_request(request: &str) -> (String, String) {
+//handle patch book request
+fn handle_patch_book_request(request: &str, context: &RequestContext) -> (String, String) {
     match
         (
-            get_id(&request).parse::<i32>(),
-            get_book_request_body(&request),
+            get_id(request).parse::<i32>(),
+            get_patch_request_body(request),
             Client::connect(DB_URL, NoTls),
         )
     {
-        (Ok(id), Ok(book), Ok(mut client)) => {
-            client
-                .execute(
-                    "UPDATE books SET title = $1, author = $2, genre = $3 WHERE id = $4",
-                    &[&book.title, &book.author, &book.genre, &id]
+        (Ok(id), Ok(patch), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let current = match transaction.query_opt("SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, current.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //merge the patch over the stored record and validate the result
+            let mut book: Book = match apply_merge_patch(&current, &patch) {
+                Ok(book) => book,
+                Err(e) => return (BAD_REQUEST.to_string(), format!("Invalid patch: {}", e)),
+            };
+            if let Err(e) = normalize_book(&mut book) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE books SET title = $1, author = $2, genre = $3, isbn10 = $4, isbn13 = $5, publisher = $6, publication_year = $7, page_count = $8, language = $9, format = $10, edition = $11, version = version + 1 WHERE id = $12 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+                    &[&book.title, &book.author, &book.genre, &book.isbn10, &book.isbn13, &book.publisher, &book.publication_year, &book.page_count, &book.language, &book.format, &book.edition, &id]
                 )
-                .unwrap();
+            {
+                Ok(row) => row,
+                Err(e) => return book_write_error(e),
+            };
+            let book = book_from_row(&row);
+            if book.author != current.author {
+                if let Err(e) = authors::link_book_authors(&mut transaction, context, id, &book.author) {
+                    return internal_error(e);
+                }
+            }
+            if book.genre != current.genre {
+                if let Err(e) = taxonomy::link_book_genre(&mut transaction, context, id, current.genre.as_deref(), book.genre.as_deref()) {
+                    return internal_error(e);
+                }
+            }
+            if let Err(e) = write_audit(&mut transaction, context, "update", "books", id, Some(&current), Some(&book)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
 
-            (OK_RESPONSE.to_string(), "Book updated".to_string())
+            let version = book.version.unwrap_or_default();
+            (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&book).unwrap())
         }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle put loan request
-fn handle_put_loan_request(request: &str) -> (String, String) {
+//handle patch loan request
+fn handle_patch_loan_request(request: &str, context: &RequestContext) -> (String, String) {
     match
         (
-            get_id(&request).parse::<i32>(),
-            get_loan_request_body(&request),
+            get_id(request).parse::<i32>(),
+            get_patch_request_body(request),
             Client::connect(DB_URL, NoTls),
         )
     {
-        (Ok(id), Ok(loan), Ok(mut client)) => {
-            client
-                .execute(
-                    "UPDATE loans SET user_id = $1, book_id = $2, checkout_date = $3, due_date = $4, return_date = $5 WHERE id = $6",
-                    &[&loan.user_id, &loan.book_id, &loan.checkout_date, &loan.due_date, &loan.return_date, &id]
+        (Ok(id), Ok(patch), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let current = match transaction.query_opt("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => loan_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Loan not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, current.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //merge the patch over the stored record and validate the result
+            let mut loan: Loan = match apply_merge_patch(&current, &patch) {
+                Ok(loan) => loan,
+                Err(e) => return (BAD_REQUEST.to_string(), format!("Invalid patch: {}", e)),
+            };
+
+            //moving the loan to another book picks a copy of that book unless one is given
+            if loan.book_id != current.book_id && patch.get("copy_id").is_none() {
+                loan.copy_id = None;
+            }
+            if let Err(e) = copies::assign_copy(&mut transaction, &mut loan, Some(id)) {
+                return e;
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE loans SET user_id = $1, book_id = $2, copy_id = $3, checkout_date = $4, due_date = $5, return_date = $6, version = version + 1 WHERE id = $7 RETURNING id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id",
+                    &[&loan.user_id, &loan.book_id, &loan.copy_id, &loan.checkout_date, &loan.due_date, &loan.return_date, &id]
                 )
-                .unwrap();
+            {
+                Ok(row) => row,
+                Err(e) => return reference_write_error(e),
+            };
+            let loan = loan_from_row(&row);
+            //a returned or moved loan may free a copy for the next hold in line
+            let saved = write_audit(&mut transaction, context, "update", "loans", id, Some(&current), Some(&loan))
+                .and_then(|_| holds::promote_holds(&mut transaction, context))
+                .and_then(|_| transaction.commit());
+            if saved.is_err() {
+                return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+            }
 
-            (OK_RESPONSE.to_string(), "Loan updated".to_string())
+            let version = loan.version.unwrap_or_default();
+            (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&loan).unwrap())
         }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle put review request
-fn handle_put_review_request(request: &str) -> (String, String) {
+//handle patch review request
+fn handle_patch_review_request(request: &str, context: &RequestContext) -> (String, String) {
     match
         (
-            get_id(&request).parse::<i32>(),
-            get_review_request_body(&request),
+            get_id(request).parse::<i32>(),
+            get_patch_request_body(request),
             Client::connect(DB_URL, NoTls),
         )
     {
-        (Ok(id), Ok(review), Ok(mut client)) => {
-            client
-                .execute(
-                    "UPDATE reviews SET book_id = $1, user_id = $2, rating = $3, review_text = $4 WHERE id = $5",
+        (Ok(id), Ok(patch), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let current = match transaction.query_opt("SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => review_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Review not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, current.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //merge the patch over the stored record and validate the result
+            let review: Review = match apply_merge_patch(&current, &patch) {
+                Ok(review) => review,
+                Err(e) => return (BAD_REQUEST.to_string(), format!("Invalid patch: {}", e)),
+            };
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE reviews SET book_id = $1, user_id = $2, rating = $3, review_text = $4, version = version + 1 WHERE id = $5 RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
                     &[&review.book_id, &review.user_id, &review.rating, &review.review_text, &id]
                 )
-                .unwrap();
+            {
+                Ok(row) => row,
+                Err(e) => return reference_write_error(e),
+            };
+            let review = review_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "reviews", id, Some(&current), Some(&review)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
 
-            (OK_RESPONSE.to_string(), "Review updated".to_string())
+            let version = review.version.unwrap_or_default();
+            (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&review).unwrap())
         }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
-//handle delete user request
-fn handle_delete_user_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+//handle restore user request
+fn handle_restore_user_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, name, email, version, created_at, updated_at, deleted_at FROM users WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => user_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "User not found in trash".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE users SET deleted_at = NULL, version = version + 1 WHERE id = $1 RETURNING id, name, email, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = user_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "restore", "users", id, Some(&before), Some(&after)) {
+                return internal_error(e);
+            }
+
+            //the loans and reviews trashed with the user come back with it
+            if let Err(e) = restore_delete_impact(&mut transaction, context, "users", id, before.deleted_at.unwrap_or_default()) {
+                return e;
+            }
+            if let Err(e) = transaction.commit() {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle restore book request
+fn handle_restore_book_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
         (Ok(id), Ok(mut client)) => {
-            let rows_affected = client.execute("DELETE FROM users WHERE id = $1", &[&id]).unwrap();
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found in trash".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE books SET deleted_at = NULL, version = version + 1 WHERE id = $1 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return book_write_error(e),
+            };
+            let after = book_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "restore", "books", id, Some(&before), Some(&after)) {
+                return internal_error(e);
+            }
+
+            //the loans and reviews trashed with the book come back with it
+            if let Err(e) = restore_delete_impact(&mut transaction, context, "books", id, before.deleted_at.unwrap_or_default()) {
+                return e;
+            }
+            if let Err(e) = transaction.commit() {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle restore loan request
+fn handle_restore_loan_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => loan_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Loan not found in trash".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //an open loan only comes back if its copy has not been lent out since
+            let mut loan = before.clone();
+            if let Err(e) = copies::assign_copy(&mut transaction, &mut loan, Some(id)) {
+                return e;
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE loans SET deleted_at = NULL, copy_id = $2, version = version + 1 WHERE id = $1 RETURNING id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id",
+                    &[&id, &loan.copy_id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = loan_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "restore", "loans", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle restore review request
+fn handle_restore_review_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => review_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Review not found in trash".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE reviews SET deleted_at = NULL, version = version + 1 WHERE id = $1 RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = review_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "restore", "reviews", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete user request
+fn handle_delete_user_request(request: &str, context: &RequestContext) -> (String, String) {
+    let preview = get_query_param(request, "preview").as_deref() == Some("true");
+
+    match (get_id(request).parse::<i32>(), get_cascade(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(cascade), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return delete_write_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, name, email, version, created_at, updated_at, deleted_at FROM users WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => user_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "User not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
 
-            //if rows affected is 0, user not found
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "User not found".to_string());
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //work out what happens to the loans and reviews of this user
+            let impact = match get_delete_impact(&mut transaction, "users", id, cascade) {
+                Ok(impact) => impact,
+                Err(e) => return delete_write_error(e),
+            };
+            if preview {
+                return (OK_RESPONSE.to_string(), serde_json::to_string(&impact).unwrap());
+            }
+            if impact.blocked {
+                let conflict = json!({ "error": "User has active loans", "active_loans": impact.active_loans });
+                return (CONFLICT.to_string(), conflict.to_string());
+            }
+            if let Err(e) = apply_delete_impact(&mut transaction, context, &impact) {
+                return delete_write_error(e);
+            }
+
+            //move the row to the trash; the retention job purges it later
+            let row = match
+                transaction.query_one(
+                    "UPDATE users SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, name, email, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return delete_write_error(e),
+            };
+            let after = user_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "users", id, Some(&before), Some(&after)) {
+                return delete_write_error(e);
+            }
+            if let Err(e) = transaction.commit() {
+                return delete_write_error(e);
             }
 
             (OK_RESPONSE.to_string(), "User deleted".to_string())
         }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle delete book request
-fn handle_delete_book_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
-        (Ok(id), Ok(mut client)) => {
-            let rows_affected = client.execute("DELETE FROM books WHERE id = $1", &[&id]).unwrap();
+fn handle_delete_book_request(request: &str, context: &RequestContext) -> (String, String) {
+    let preview = get_query_param(request, "preview").as_deref() == Some("true");
+
+    match (get_id(request).parse::<i32>(), get_cascade(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(cascade), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return delete_write_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
 
-            //if rows affected is 0, book not found
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "Book not found".to_string());
+            //work out what happens to the loans and reviews of this book
+            let impact = match get_delete_impact(&mut transaction, "books", id, cascade) {
+                Ok(impact) => impact,
+                Err(e) => return delete_write_error(e),
+            };
+            if preview {
+                return (OK_RESPONSE.to_string(), serde_json::to_string(&impact).unwrap());
+            }
+            if impact.blocked {
+                let conflict = json!({ "error": "Book has active loans", "active_loans": impact.active_loans });
+                return (CONFLICT.to_string(), conflict.to_string());
+            }
+            if let Err(e) = apply_delete_impact(&mut transaction, context, &impact) {
+                return delete_write_error(e);
+            }
+
+            //move the row to the trash; the retention job purges it later
+            let row = match
+                transaction.query_one(
+                    "UPDATE books SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return delete_write_error(e),
+            };
+            let after = book_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "books", id, Some(&before), Some(&after)) {
+                return delete_write_error(e);
+            }
+            if let Err(e) = transaction.commit() {
+                return delete_write_error(e);
             }
 
             (OK_RESPONSE.to_string(), "Book deleted".to_string())
         }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
 
 //handle delete loan request
-fn handle_delete_loan_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+fn handle_delete_loan_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
         (Ok(id), Ok(mut client)) => {
-            let rows_affected = client.execute("DELETE FROM loans WHERE id = $1", &[&id]).unwrap();
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return delete_write_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id FROM loans WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => loan_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Loan not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
 
-            //if rows affected is 0, loan not found
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "Loan not found".to_string());
+            //move the row to the trash; the retention job purges it later
+            let row = match
+                transaction.query_one(
+                    "UPDATE loans SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, user_id, book_id, checkout_date, due_date, return_date, version, created_at, updated_at, deleted_at, copy_id",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return delete_write_error(e),
+            };
+            let after = loan_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "loans", id, Some(&before), Some(&after)) {
+                return delete_write_error(e);
+            }
+            if let Err(e) = holds::promote_holds(&mut transaction, context) {
+                return delete_write_error(e);
+            }
+            if let Err(e) = transaction.commit() {
+                return delete_write_error(e);
             }
 
             (OK_RESPONSE.to_string(), "Loan deleted".to_string())
@@ -653,14 +2885,41 @@ fn handle_delete_loan_request(request: &str) -> (String, String) {
 }
 
 //handle delete review request
-fn handle_delete_review_request(request: &str) -> (String, String) {
-    match (get_id(&request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+fn handle_delete_review_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
         (Ok(id), Ok(mut client)) => {
-            let rows_affected = client.execute("DELETE FROM reviews WHERE id = $1", &[&id]).unwrap();
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return delete_write_error(e),
+            };
+
+            let before = match transaction.query_opt("SELECT id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at FROM reviews WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(row)) => review_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Review not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
 
-            //if rows affected is 0, review not found
-            if rows_affected == 0 {
-                return (NOT_FOUND.to_string(), "Review not found".to_string());
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //move the row to the trash; the retention job purges it later
+            let row = match
+                transaction.query_one(
+                    "UPDATE reviews SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, book_id, user_id, rating, review_text, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return delete_write_error(e),
+            };
+            let after = review_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "reviews", id, Some(&before), Some(&after)) {
+                return delete_write_error(e);
+            }
+            if let Err(e) = transaction.commit() {
+                return delete_write_error(e);
             }
 
             (OK_RESPONSE.to_string(), "Review deleted".to_string())
@@ -668,3 +2927,128 @@ fn handle_delete_review_request(request: &str) -> (String, String) {
         _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
     }
 }
+
+//handle get audit log request
+fn handle_get_audit_request(request: &str) -> (String, String) {
+    if !is_admin(request) {
+        return (FORBIDDEN.to_string(), "Admin token required".to_string());
+    }
+
+    let resource_id = get_query_param(request, "resource_id").map(|value| value.parse::<i32>()).transpose();
+    let limit = get_query_param(request, "limit").map(|value| value.parse::<i64>()).transpose();
+
+    match
+        (
+            resource_id,
+            limit,
+            get_timestamp_param(request, "since"),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(resource_id), Ok(limit), Ok(since), Ok(mut client)) => {
+            let mut entries = Vec::new(); // Vector to store the audit entries
+
+            let rows = match
+                client.query(
+                    "SELECT id, occurred_at, actor, action, resource_type, resource_id, before_data, after_data, client_ip, request_id FROM audit_log WHERE ($1::varchar IS NULL OR resource_type = $1) AND ($2::int IS NULL OR resource_id = $2) AND ($3::varchar IS NULL OR actor = $3) AND ($4::varchar IS NULL OR action = $4) AND ($5::timestamptz IS NULL OR occurred_at >= $5) ORDER BY id DESC LIMIT $6",
+                    &[
+                        &get_query_param(request, "resource_type"),
+                        &resource_id,
+                        &get_query_param(request, "actor"),
+                        &get_query_param(request, "action"),
+                        &since,
+                        &limit.unwrap_or(100).clamp(1, 1000),
+                    ]
+                )
+            {
+                Ok(rows) => rows,
+                Err(e) => return internal_error(e),
+            };
+            for row in rows {
+                entries.push(AuditEntry {
+                    id: row.get(0),
+                    occurred_at: row.get(1),
+                    actor: row.get(2),
+                    action: row.get(3),
+                    resource_type: row.get(4),
+                    resource_id: row.get(5),
+                    before: row.get(6),
+                    after: row.get(7),
+                    client_ip: row.get(8),
+                    request_id: row.get(9),
+                });
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&entries).unwrap())
+        }
+        (Err(_), _, _, _) | (_, Err(_), _, _) | (_, _, Err(_), _) =>
+            (BAD_REQUEST.to_string(), "Invalid audit filter".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn patched(target: Value, patch: Value) -> Value {
+        let mut target = target;
+        merge_patch(&mut target, &patch);
+        target
+    }
+
+    //the examples from RFC 7396 appendix A
+    #[test]
+    fn merge_patch_follows_rfc_7396_examples() {
+        assert_eq!(patched(json!({"a": "b"}), json!({"a": "c"})), json!({"a": "c"}));
+        assert_eq!(patched(json!({"a": "b"}), json!({"b": "c"})), json!({"a": "b", "b": "c"}));
+        assert_eq!(patched(json!({"a": "b"}), json!({"a": null})), json!({}));
+        assert_eq!(patched(json!({"a": "b", "b": "c"}), json!({"a": null})), json!({"b": "c"}));
+        assert_eq!(patched(json!({"a": ["b"]}), json!({"a": "c"})), json!({"a": "c"}));
+        assert_eq!(patched(json!({"a": "c"}), json!({"a": ["b"]})), json!({"a": ["b"]}));
+        assert_eq!(patched(json!({"a": {"b": "c"}}), json!({"a": {"b": "d", "c": null}})), json!({"a": {"b": "d"}}));
+        assert_eq!(patched(json!({"a": [{"b": "c"}]}), json!({"a": [1]})), json!({"a": [1]}));
+        assert_eq!(patched(json!(["a", "b"]), json!(["c", "d"])), json!(["c", "d"]));
+        assert_eq!(patched(json!({"a": "b"}), json!(["c"])), json!(["c"]));
+        assert_eq!(patched(json!({"a": "foo"}), json!(null)), json!(null));
+        assert_eq!(patched(json!({"a": "foo"}), json!("bar")), json!("bar"));
+        assert_eq!(patched(json!({"e": null}), json!({"a": 1})), json!({"e": null, "a": 1}));
+        assert_eq!(patched(json!([1, 2]), json!({"a": "b", "c": null})), json!({"a": "b"}));
+        assert_eq!(patched(json!({}), json!({"a": {"bb": {"ccc": null}}})), json!({"a": {"bb": {}}}));
+    }
+
+    #[test]
+    fn tag_matches_uses_strong_comparison_for_if_match() {
+        assert!(tag_matches("\"3\"", "\"3\"", false));
+        assert!(!tag_matches("W/\"3\"", "\"3\"", false));
+        assert!(tag_matches("W/\"3\"", "\"3\"", true));
+        assert!(tag_matches("\"2\", \"3\"", "\"3\"", false));
+        assert!(tag_matches("*", "\"3\"", false));
+        assert!(!tag_matches("\"2\"", "\"3\"", true));
+    }
+
+    #[test]
+    fn apply_merge_patch_keeps_unpatched_fields() {
+        let review = Review {
+            id: Some(1),
+            book_id: 2,
+            user_id: Some(3),
+            rating: 4,
+            review_text: Some("Good".to_string()),
+            version: Some(1),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+        };
+        let patched: Review = apply_merge_patch(&review, &json!({"rating": 5, "review_text": null})).unwrap();
+        assert_eq!((patched.book_id, patched.user_id, patched.rating, patched.review_text), (2, Some(3), 5, None));
+    }
+
+    #[test]
+    fn apply_merge_patch_rejects_wrong_types() {
+        let user = User { id: Some(1), name: "Ann".to_string(), email: "ann@example.org".to_string(), version: None, created_at: None, updated_at: None, deleted_at: None };
+        assert!(apply_merge_patch(&user, &json!({"name": 5})).is_err());
+        assert!(apply_merge_patch(&user, &json!({"email": null})).is_err());
+    }
+}
@@ -0,0 +1,799 @@
+//MARC21 records in ISO 2709 and MARCXML, mapped to and from books
+
+use crate::{
+    authors,
+    book_from_row,
+    get_header,
+    get_path_segment,
+    get_query_param,
+    insert_book,
+    internal_error,
+    isbn,
+    language,
+    multipart,
+    normalize_book,
+    taxonomy,
+    with_content_type,
+    with_header,
+    Book,
+    RequestContext,
+    BAD_REQUEST,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    OK_RESPONSE,
+};
+use postgres::{ Client, GenericClient, NoTls, Transaction };
+use postgres::Error as PostgresError;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::{ BufWriter, Write };
+
+//ISO 2709 separators
+const SUBFIELD_DELIMITER: u8 = 0x1f;
+const FIELD_TERMINATOR: u8 = 0x1e;
+const RECORD_TERMINATOR: u8 = 0x1d;
+
+//Leader of a new record: new, language material, monograph, UTF-8, ISBD punctuation
+const BOOK_LEADER: &str = "00000nam a2200000 i 4500";
+
+const MARCXML_NAMESPACE: &str = "http://www.loc.gov/MARC21/slim";
+
+//Rows fetched from the database per round trip while exporting
+const EXPORT_BATCH: i32 = 200;
+
+//One field of a record
+pub enum Field {
+    Control {
+        tag: String,
+        value: String,
+    },
+    Data {
+        tag: String,
+        indicators: [char; 2],
+        subfields: Vec<(char, String)>,
+    },
+}
+
+//A MARC21 bibliographic record
+pub struct Record {
+    leader: String,
+    fields: Vec<Field>,
+}
+
+impl Record {
+    //value of a control field
+    fn control(&self, wanted: &str) -> Option<&str> {
+        self.fields.iter().find_map(|field| match field {
+            Field::Control { tag, value } if tag == wanted => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    //indicators and subfields of every data field with a tag
+    fn data<'a>(&'a self, wanted: &'a str) -> impl Iterator<Item = ([char; 2], &'a [(char, String)])> + 'a {
+        self.fields.iter().filter_map(move |field| match field {
+            Field::Data { tag, indicators, subfields } if tag == wanted => Some((*indicators, subfields.as_slice())),
+            _ => None,
+        })
+    }
+
+    fn data_field(&mut self, tag: &str, indicators: [char; 2], subfields: Vec<(char, String)>) {
+        self.fields.push(Field::Data { tag: tag.to_string(), indicators, subfields });
+    }
+}
+
+//first value of a subfield code
+fn subfield(subfields: &[(char, String)], code: char) -> Option<&str> {
+    subfields.iter().find(|(found, _)| *found == code).map(|(_, value)| value.as_str())
+}
+
+//drop the ISBD punctuation that ends a subfield, e.g. "Dune /" or "Ace Books,"
+fn trim_punctuation(value: &str) -> String {
+    value.trim().trim_end_matches([' ', '/', ':', ';', ',', '.', '=']).trim().to_string()
+}
+
+//first run of four digits, as in "c1965." or "[1989]"
+fn find_year(value: &str) -> Option<i32> {
+    let digits: Vec<char> = value.chars().collect();
+    digits.windows(4).find(|window| window.iter().all(char::is_ascii_digit)).and_then(|window| window.iter().collect::<String>().parse().ok())
+}
+
+fn parse_number(value: &[u8]) -> Option<usize> {
+    std::str::from_utf8(value).ok()?.trim().parse().ok()
+}
+
+//records of an ISO 2709 file; character data is read as UTF-8
+pub fn read_iso2709(data: &[u8]) -> Result<Vec<Record>, String> {
+    let mut records = Vec::new();
+    let mut rest = data;
+    loop {
+        //some files put line breaks between records
+        while let Some((first, remaining)) = rest.split_first() {
+            if !first.is_ascii_whitespace() {
+                break;
+            }
+            rest = remaining;
+        }
+        if rest.is_empty() {
+            return Ok(records);
+        }
+        let number = records.len() + 1;
+        let invalid = |what: &str| format!("Record {}: {}", number, what);
+
+        let length = rest.get(..5).and_then(parse_number).ok_or_else(|| invalid("bad record length"))?;
+        let record = rest.get(..length).filter(|_| length > 24).ok_or_else(|| invalid("record is truncated"))?;
+        let base = parse_number(&record[12..17]).filter(|base| *base > 24 && *base <= length).ok_or_else(|| invalid("bad base address"))?;
+
+        let mut fields = Vec::new();
+        for entry in record[24..base - 1].chunks(12).filter(|entry| entry.len() == 12) {
+            let tag = String::from_utf8_lossy(&entry[..3]).to_string();
+            let field_length = parse_number(&entry[3..7]).ok_or_else(|| invalid("bad directory entry"))?;
+            let start = parse_number(&entry[7..12]).ok_or_else(|| invalid("bad directory entry"))?;
+            let value = record.get(base + start..base + start + field_length).ok_or_else(|| invalid("field outside record"))?;
+            let value = value.strip_suffix(&[FIELD_TERMINATOR]).unwrap_or(value);
+
+            if tag.starts_with("00") {
+                fields.push(Field::Control { tag, value: String::from_utf8_lossy(value).to_string() });
+            } else {
+                let indicator = |index: usize| value.get(index).map(|byte| *byte as char).unwrap_or(' ');
+                let subfields = value
+                    .get(2..)
+                    .unwrap_or_default()
+                    .split(|byte| *byte == SUBFIELD_DELIMITER)
+                    .skip(1)
+                    .filter_map(|part| part.split_first())
+                    .map(|(code, value)| (*code as char, String::from_utf8_lossy(value).to_string()))
+                    .collect();
+                fields.push(Field::Data { tag, indicators: [indicator(0), indicator(1)], subfields });
+            }
+        }
+        records.push(Record { leader: String::from_utf8_lossy(&record[..24]).to_string(), fields });
+        rest = &rest[length..];
+    }
+}
+
+//a record as ISO 2709, with the lengths and directory filled in
+pub fn write_iso2709(record: &Record) -> Vec<u8> {
+    let mut directory = Vec::new();
+    let mut data = Vec::new();
+    for field in &record.fields {
+        let (tag, mut bytes) = match field {
+            Field::Control { tag, value } => (tag, value.as_bytes().to_vec()),
+            Field::Data { tag, indicators, subfields } => {
+                let mut bytes = indicators.iter().collect::<String>().into_bytes();
+                for (code, value) in subfields {
+                    bytes.push(SUBFIELD_DELIMITER);
+                    bytes.extend(code.to_string().as_bytes());
+                    bytes.extend(value.as_bytes());
+                }
+                (tag, bytes)
+            }
+        };
+        bytes.push(FIELD_TERMINATOR);
+        directory.extend(format!("{:0>3}{:04}{:05}", tag, bytes.len(), data.len()).into_bytes());
+        data.extend(bytes);
+    }
+    directory.push(FIELD_TERMINATOR);
+
+    let base = 24 + directory.len();
+    let length = base + data.len() + 1;
+    let leader = format!("{:05}{}{:05}{}", length, &record.leader[5..12], base, &record.leader[17..24]);
+    let mut bytes = leader.into_bytes();
+    bytes.extend(directory);
+    bytes.extend(data);
+    bytes.push(RECORD_TERMINATOR);
+    bytes
+}
+
+//escape text and attribute values for XML
+pub fn escape_xml(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+//a record as a MARCXML <record> element
+pub fn write_marcxml(record: &Record) -> String {
+    let mut xml = format!("<record xmlns=\"{}\"><leader>{}</leader>", MARCXML_NAMESPACE, escape_xml(&record.leader));
+    for field in &record.fields {
+        match field {
+            Field::Control { tag, value } => xml.push_str(&format!("<controlfield tag=\"{}\">{}</controlfield>", tag, escape_xml(value))),
+            Field::Data { tag, indicators, subfields } => {
+                xml.push_str(&format!("<datafield tag=\"{}\" ind1=\"{}\" ind2=\"{}\">", tag, indicators[0], indicators[1]));
+                for (code, value) in subfields {
+                    xml.push_str(&format!("<subfield code=\"{}\">{}</subfield>", escape_xml(&code.to_string()), escape_xml(value)));
+                }
+                xml.push_str("</datafield>");
+            }
+        }
+    }
+    xml.push_str("</record>");
+    xml
+}
+
+//records of a MARCXML document, either a <collection> or a single <record>
+pub fn read_marcxml(data: &[u8]) -> Result<Vec<Record>, String> {
+    let mut reader = Reader::from_reader(data);
+    let mut buffer = Vec::new();
+    let mut records = Vec::new();
+    let mut record: Option<Record> = None;
+    let mut text = String::new();
+    let mut current: Option<Field> = None;
+    let mut code = ' ';
+
+    loop {
+        let event = reader.read_event_into(&mut buffer).map_err(|e| format!("Invalid MARCXML at {}: {}", reader.buffer_position(), e))?;
+        match event {
+            Event::Start(element) | Event::Empty(element) => {
+                let mut attributes = HashMap::new();
+                for attribute in element.attributes().flatten() {
+                    let value = attribute.unescape_value().map_err(|e| e.to_string())?.to_string();
+                    attributes.insert(String::from_utf8_lossy(attribute.key.local_name().as_ref()).to_string(), value);
+                }
+                let attribute = |name: &str| attributes.get(name).cloned().unwrap_or_default();
+                let indicator = |name: &str| attribute(name).chars().next().unwrap_or(' ');
+                text.clear();
+                match element.local_name().as_ref() {
+                    b"record" => record = Some(Record { leader: BOOK_LEADER.to_string(), fields: Vec::new() }),
+                    b"controlfield" => current = Some(Field::Control { tag: attribute("tag"), value: String::new() }),
+                    b"datafield" =>
+                        current = Some(Field::Data {
+                            tag: attribute("tag"),
+                            indicators: [indicator("ind1"), indicator("ind2")],
+                            subfields: Vec::new(),
+                        }),
+                    b"subfield" => code = attribute("code").chars().next().unwrap_or(' '),
+                    _ => {}
+                }
+            }
+            Event::Text(content) => text.push_str(&content.unescape().map_err(|e| e.to_string())?),
+            Event::CData(content) => text.push_str(&String::from_utf8_lossy(&content)),
+            Event::End(element) =>
+                match element.local_name().as_ref() {
+                    b"leader" => {
+                        if let Some(record) = record.as_mut() {
+                            record.leader = format!("{:24}", text);
+                        }
+                    }
+                    b"subfield" => {
+                        if let Some(Field::Data { subfields, .. }) = current.as_mut() {
+                            subfields.push((code, text.clone()));
+                        }
+                    }
+                    b"controlfield" | b"datafield" => {
+                        if let (Some(mut field), Some(record)) = (current.take(), record.as_mut()) {
+                            if let Field::Control { value, .. } = &mut field {
+                                *value = text.clone();
+                            }
+                            record.fields.push(field);
+                        }
+                    }
+                    b"record" => records.extend(record.take()),
+                    _ => {}
+                },
+            //a document cut off mid-record would otherwise quietly lose that record
+            Event::Eof if record.is_some() => return Err("MARCXML ends inside a record".to_string()),
+            Event::Eof => return Ok(records),
+            _ => {}
+        }
+        buffer.clear();
+    }
+}
+
+//leading characters a title index skips, e.g. 4 for "The "
+fn nonfiling_characters(title: &str) -> char {
+    let lower = title.to_lowercase();
+    let skip = ["the ", "an ", "a "].iter().find(|article| lower.starts_with(**article)).map(|article| article.len()).unwrap_or(0);
+    char::from_digit(skip as u32, 10).unwrap_or('0')
+}
+
+//MARC record for a book, with its genres (650 _0) and tags (650 _4) as subjects
+pub fn book_record(book: &Book, genres: &[String], tags: &[String]) -> Record {
+    let mut record = Record { leader: BOOK_LEADER.to_string(), fields: Vec::new() };
+    record.fields.push(Field::Control { tag: "001".to_string(), value: book.id.unwrap_or_default().to_string() });
+    if let Some(updated_at) = book.updated_at {
+        record.fields.push(Field::Control { tag: "005".to_string(), value: updated_at.format("%Y%m%d%H%M%S.0").to_string() });
+    }
+    let entered = book.created_at.map(|created_at| created_at.format("%y%m%d").to_string()).unwrap_or_else(|| "000000".to_string());
+    let (date_type, year) = match book.publication_year {
+        Some(year) => ('s', format!("{:04}", year)),
+        None => ('n', "uuuu".to_string()),
+    };
+    let language = book.language.as_deref().and_then(language::to_bibliographic).unwrap_or("und");
+    record.fields.push(Field::Control {
+        tag: "008".to_string(),
+        value: format!("{}{}{}    xx {:17}{} d", entered, date_type, year, "", language),
+    });
+
+    for isbn in [&book.isbn13, &book.isbn10].into_iter().flatten() {
+        record.data_field("020", [' ', ' '], vec![('a', isbn.clone())]);
+    }
+    let names = authors::split_names(&book.author);
+    if let Some(name) = names.first() {
+        record.data_field("100", ['1', ' '], vec![('a', format!("{},", authors::sort_name(name))), ('e', "author.".to_string())]);
+    }
+    let title = if names.is_empty() {
+        vec![('a', format!("{}.", book.title))]
+    } else {
+        vec![('a', format!("{} /", book.title)), ('c', format!("{}.", names.join(", ")))]
+    };
+    let first_indicator = if names.is_empty() { '0' } else { '1' };
+    record.data_field("245", [first_indicator, nonfiling_characters(&book.title)], title);
+    if let Some(edition) = &book.edition {
+        record.data_field("250", [' ', ' '], vec![('a', format!("{}.", edition))]);
+    }
+    let mut imprint = Vec::new();
+    if let Some(publisher) = &book.publisher {
+        imprint.push(('b', format!("{},", publisher)));
+    }
+    if let Some(year) = book.publication_year {
+        imprint.push(('c', format!("{}.", year)));
+    }
+    if !imprint.is_empty() {
+        record.data_field("260", [' ', ' '], imprint);
+    }
+    if let Some(pages) = book.page_count {
+        record.data_field("300", [' ', ' '], vec![('a', format!("{} pages", pages))]);
+    }
+    for genre in genres {
+        record.data_field("650", [' ', '0'], vec![('a', format!("{}.", genre))]);
+    }
+    for tag in tags {
+        record.data_field("650", [' ', '4'], vec![('a', tag.clone())]);
+    }
+    for name in names.iter().skip(1) {
+        record.data_field("700", ['1', ' '], vec![('a', format!("{},", authors::sort_name(name))), ('e', "author.".to_string())]);
+    }
+    record
+}
+
+//book described by a record, with its genre and tag subjects
+fn record_to_book(record: &Record) -> (Book, Vec<String>, Vec<String>) {
+    let fixed = record.control("008").unwrap_or_default();
+    let title = record
+        .data("245")
+        .next()
+        .map(|(_, subfields)| {
+            let main = trim_punctuation(subfield(subfields, 'a').unwrap_or_default());
+            match subfield(subfields, 'b').map(trim_punctuation) {
+                Some(subtitle) if !subtitle.is_empty() => format!("{}: {}", main, subtitle),
+                _ => main,
+            }
+        })
+        .unwrap_or_default();
+    let names: Vec<String> = record
+        .data("100")
+        .chain(record.data("700"))
+        .filter_map(|(_, subfields)| subfield(subfields, 'a'))
+        .map(|name| authors::display_name(&trim_punctuation(name)))
+        .filter(|name| !name.is_empty())
+        .collect();
+    //"0575046066 (pbk.)": the number is the first word
+    let isbn13 = record
+        .data("020")
+        .filter_map(|(_, subfields)| subfield(subfields, 'a'))
+        .find_map(|value| isbn::parse(value.split_whitespace().next().unwrap_or_default()).ok());
+    //RDA records put the imprint in 264 _1
+    let imprint = record.data("260").chain(record.data("264").filter(|(indicators, _)| indicators[1] == '1')).next();
+
+    let book = Book {
+        title,
+        author: names.join(" & "),
+        isbn13,
+        publisher: imprint.and_then(|(_, subfields)| subfield(subfields, 'b')).map(trim_punctuation),
+        publication_year: imprint
+            .and_then(|(_, subfields)| subfield(subfields, 'c'))
+            .and_then(find_year)
+            .or_else(|| fixed.get(7..11).and_then(|year| year.parse().ok())),
+        page_count: record
+            .data("300")
+            .filter_map(|(_, subfields)| subfield(subfields, 'a'))
+            .find_map(|extent| extent.split_whitespace().find_map(|word| word.parse().ok())),
+        language: fixed.get(35..38).and_then(|code| language::normalize(code).ok()),
+        edition: record.data("250").filter_map(|(_, subfields)| subfield(subfields, 'a')).map(trim_punctuation).next(),
+        ..Book::default()
+    };
+    let subject = |(_, subfields): ([char; 2], &[(char, String)])| subfield(subfields, 'a').map(trim_punctuation);
+    let genres = record.data("650").filter(|(indicators, _)| indicators[1] != '4').filter_map(subject).collect();
+    let tags = record.data("650").filter(|(indicators, _)| indicators[1] == '4').filter_map(subject).collect();
+    (book, genres, tags)
+}
+
+//Genre and tag names by book id
+pub type Subjects = HashMap<i32, (Vec<String>, Vec<String>)>;
+
+//genre and tag names of books, primary genre first
+pub fn get_subjects(client: &mut impl GenericClient, book_ids: &[i32]) -> Result<Subjects, PostgresError> {
+    let rows = client.query(
+        "SELECT bg.book_id, g.name, false FROM book_genres bg JOIN genres g ON g.id = bg.genre_id AND g.deleted_at IS NULL WHERE bg.book_id = ANY($1)
+        UNION ALL
+        SELECT bt.book_id, t.name, true FROM book_tags bt JOIN tags t ON t.id = bt.tag_id AND t.deleted_at IS NULL WHERE bt.book_id = ANY($1)
+        ORDER BY 1, 3, 2",
+        &[&book_ids]
+    )?;
+    let primary: HashMap<i32, String> = client
+        .query("SELECT id, genre FROM books WHERE id = ANY($1) AND genre IS NOT NULL", &[&book_ids])?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+
+    let mut subjects = Subjects::new();
+    for row in rows {
+        let book_id: i32 = row.get(0);
+        let (genres, tags) = subjects.entry(book_id).or_default();
+        if row.get(2) { tags.push(row.get(1)) } else { genres.push(row.get(1)) }
+    }
+    for (book_id, (genres, _)) in subjects.iter_mut() {
+        if let Some(index) = genres.iter().position(|genre| Some(genre) == primary.get(book_id)) {
+            let genre = genres.remove(index);
+            genres.insert(0, genre);
+        }
+    }
+    Ok(subjects)
+}
+
+//handle get book MARC request: /books/{id}.marc is ISO 2709, with ?format=xml MARCXML
+pub fn handle_get_book_marc_request(request: &str) -> (String, Vec<u8>) {
+    let id = get_path_segment(request, 4).trim_end_matches(".marc");
+    match (id.parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let book = match
+                client.query_opt(
+                    "SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), b"Book not found".to_vec()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+            };
+            let (genres, tags) = match get_subjects(&mut client, &[id]) {
+                Ok(mut subjects) => subjects.remove(&id).unwrap_or_default(),
+                Err(_) => return (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+            };
+            let record = book_record(&book, &genres, &tags);
+
+            if get_query_param(request, "format").as_deref() == Some("xml") {
+                let xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", write_marcxml(&record));
+                (with_content_type(OK_RESPONSE, "application/marcxml+xml"), xml.into_bytes())
+            } else {
+                (with_content_type(OK_RESPONSE, "application/marc"), write_iso2709(&record))
+            }
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), b"Invalid book id".to_vec()),
+        _ => (INTERNAL_ERROR.to_string(), b"Internal error".to_vec()),
+    }
+}
+
+//handle full catalogue export as books.marc or books.marcxml, written a batch at a time.
+//Returns a response only when nothing has been written yet.
+pub fn handle_export_request(request: &str, context: &RequestContext, stream: &mut dyn Write) -> Option<(String, String)> {
+    let name = get_path_segment(request, 4);
+    let xml = match name {
+        "books.marc" => false,
+        "books.marcxml" => true,
+        _ => return Some((NOT_FOUND.to_string(), "Unknown export resource".to_string())),
+    };
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return Some((INTERNAL_ERROR.to_string(), "Internal error".to_string())),
+    };
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return Some(internal_error(e)),
+    };
+    let portal = match
+        transaction.bind(
+            "SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE deleted_at IS NULL ORDER BY id",
+            &[]
+        )
+    {
+        Ok(portal) => portal,
+        Err(_) => return Some((INTERNAL_ERROR.to_string(), "Internal error".to_string())),
+    };
+
+    let content_type = if xml { "application/marcxml+xml" } else { "application/marc" };
+    let status_line = with_content_type(OK_RESPONSE, content_type);
+    let status_line = with_header(&status_line, "Content-Disposition", &format!("attachment; filename=\"{}\"", name));
+    let status_line = with_header(&status_line, "X-Request-Id", &context.request_id);
+    let mut stream = BufWriter::new(stream);
+
+    let result = (|| -> Result<(), String> {
+        stream.write_all(status_line.as_bytes()).map_err(|e| e.to_string())?;
+        if xml {
+            let opening = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<collection xmlns=\"{}\">\n", MARCXML_NAMESPACE);
+            stream.write_all(opening.as_bytes()).map_err(|e| e.to_string())?;
+        }
+        loop {
+            let rows = transaction.query_portal(&portal, EXPORT_BATCH).map_err(|e| e.to_string())?;
+            let books: Vec<Book> = rows.iter().map(book_from_row).collect();
+            let ids: Vec<i32> = books.iter().filter_map(|book| book.id).collect();
+            let mut subjects = get_subjects(&mut transaction, &ids).map_err(|e| e.to_string())?;
+            for book in &books {
+                let (genres, tags) = subjects.remove(&book.id.unwrap_or_default()).unwrap_or_default();
+                let record = book_record(book, &genres, &tags);
+                let bytes = if xml { format!("{}\n", write_marcxml(&record)).into_bytes() } else { write_iso2709(&record) };
+                stream.write_all(&bytes).map_err(|e| e.to_string())?;
+            }
+            if rows.len() < EXPORT_BATCH as usize {
+                break;
+            }
+        }
+        if xml {
+            stream.write_all(b"</collection>\n").map_err(|e| e.to_string())?;
+        }
+        stream.flush().map_err(|e| e.to_string())
+    })();
+    if let Err(e) = result {
+        eprintln!("MARC export stopped: {}", e);
+    }
+    None
+}
+
+//What became of one record of an import
+#[derive(Serialize)]
+struct EntryResult {
+    record: usize,
+    title: String,
+    outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+//Outcome of an import
+#[derive(Serialize)]
+struct ImportReport {
+    format: String,
+    dry_run: bool,
+    records: usize,
+    matched: usize,
+    created: usize,
+    skipped: usize,
+    entries: Vec<EntryResult>,
+}
+
+//catalogue a record's book unless a live book has its ISBN or title and first author
+fn import_record(transaction: &mut Transaction, context: &RequestContext, record: &Record) -> Result<(&'static str, i32), String> {
+    let (mut book, genres, tags) = record_to_book(record);
+    if book.title.is_empty() || book.author.is_empty() {
+        return Err("Record has no title (245) or author (100)".to_string());
+    }
+
+    if let Some(isbn13) = &book.isbn13 {
+        if let Some(row) = transaction.query_opt("SELECT id FROM books WHERE isbn13 = $1 AND deleted_at IS NULL", &[isbn13]).map_err(|e| e.to_string())? {
+            return Ok(("matched", row.get(0)));
+        }
+    }
+    let first_author = authors::split_names(&book.author).into_iter().next().unwrap_or_default();
+    if let Some(book_id) = authors::find_book(transaction, &book.title, &first_author).map_err(|e| e.to_string())? {
+        return Ok(("matched", book_id));
+    }
+
+    book.genre = genres.first().cloned();
+    normalize_book(&mut book)?;
+    let book_id = insert_book(transaction, context, &book).map_err(|e| e.to_string())?.id.unwrap_or_default();
+    let link = |transaction: &mut Transaction| -> Result<(), PostgresError> {
+        for genre in genres.iter().skip(1) {
+            let genre_id = taxonomy::find_or_create_genre(transaction, context, &taxonomy::normalize_genre_name(genre))?;
+            transaction.execute("INSERT INTO book_genres (book_id, genre_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", &[&book_id, &genre_id])?;
+        }
+        for tag in &tags {
+            let tag_id = taxonomy::find_or_create_tag(transaction, context, tag)?;
+            transaction.execute("INSERT INTO book_tags (book_id, tag_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", &[&book_id, &tag_id])?;
+        }
+        Ok(())
+    };
+    link(transaction).map_err(|e| e.to_string())?;
+    Ok(("created", book_id))
+}
+
+//handle MARC import request: an ISO 2709 or MARCXML file of any number of records; ?dry_run=true only reports
+pub fn handle_import_request(request: &str, body: &[u8], context: &RequestContext) -> (String, String) {
+    let dry_run = get_query_param(request, "dry_run").as_deref() == Some("true");
+    let data = if get_header(request, "Content-Type").unwrap_or_default().to_lowercase().starts_with("multipart/form-data") {
+        match multipart::parse(request, body).map(|parts| multipart::take_file(parts, "file")) {
+            Ok(Some(file)) => file.data,
+            Ok(None) => return (BAD_REQUEST.to_string(), "No file in upload".to_string()),
+            Err(e) => return (BAD_REQUEST.to_string(), e),
+        }
+    } else {
+        body.to_vec()
+    };
+
+    //MARCXML starts with markup, ISO 2709 with the five digits of the record length
+    let xml = data.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'<');
+    let records = match if xml { read_marcxml(&data) } else { read_iso2709(&data) } {
+        Ok(records) if !records.is_empty() => records,
+        Ok(_) => return (BAD_REQUEST.to_string(), "No MARC records in upload".to_string()),
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return internal_error(e),
+    };
+    let mut report = ImportReport {
+        format: if xml { "marcxml" } else { "marc21" }.to_string(),
+        dry_run,
+        records: records.len(),
+        matched: 0,
+        created: 0,
+        skipped: 0,
+        entries: Vec::new(),
+    };
+    for (index, record) in records.iter().enumerate() {
+        let (book, _, _) = record_to_book(record);
+        let mut result = EntryResult { record: index + 1, title: book.title, outcome: "skipped".to_string(), book_id: None, reason: None };
+
+        //a savepoint per record, so one bad record is skipped without losing the rest
+        let mut savepoint = match transaction.transaction() {
+            Ok(savepoint) => savepoint,
+            Err(e) => return internal_error(e),
+        };
+        match import_record(&mut savepoint, context, record) {
+            Ok((outcome, book_id)) => {
+                if let Err(e) = savepoint.commit() {
+                    return internal_error(e);
+                }
+                result.outcome = outcome.to_string();
+                result.book_id = Some(book_id);
+            }
+            Err(e) => result.reason = Some(e),
+        }
+        match result.outcome.as_str() {
+            "matched" => report.matched += 1,
+            "created" => report.created += 1,
+            _ => report.skipped += 1,
+        }
+        report.entries.push(result);
+    }
+
+    if !dry_run {
+        if let Err(e) = transaction.commit() {
+            return internal_error(e);
+        }
+    }
+    (OK_RESPONSE.to_string(), serde_json::to_string(&report).unwrap())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dune() -> Book {
+        Book {
+            title: "The Dune".to_string(),
+            author: "Frank Herbert, Brian Herbert".to_string(),
+            isbn13: Some("9780441013593".to_string()),
+            publisher: Some("Ace Books".to_string()),
+            publication_year: Some(1965),
+            page_count: Some(412),
+            language: Some("en".to_string()),
+            edition: Some("1st ed".to_string()),
+            ..Book::default()
+        }
+    }
+
+    fn assert_dune(book: &Book) {
+        assert_eq!(book.title, "The Dune");
+        assert_eq!(book.author, "Frank Herbert & Brian Herbert");
+        assert_eq!(book.isbn13.as_deref(), Some("9780441013593"));
+        assert_eq!(book.publisher.as_deref(), Some("Ace Books"));
+        assert_eq!(book.publication_year, Some(1965));
+        assert_eq!(book.page_count, Some(412));
+        assert_eq!(book.language.as_deref(), Some("en"));
+        assert_eq!(book.edition.as_deref(), Some("1st ed"));
+    }
+
+    fn dune_iso2709() -> Vec<u8> {
+        write_iso2709(&book_record(&dune(), &["Science fiction".to_string()], &["classic".to_string()]))
+    }
+
+    fn read_error(data: &[u8]) -> String {
+        read_iso2709(data).err().expect("record should be rejected")
+    }
+
+    #[test]
+    fn iso2709_round_trips_a_book() {
+        let records = read_iso2709(&dune_iso2709()).unwrap();
+        assert_eq!(records.len(), 1);
+        let (book, genres, tags) = record_to_book(&records[0]);
+        assert_dune(&book);
+        assert_eq!(genres, vec!["Science fiction".to_string()]);
+        assert_eq!(tags, vec!["classic".to_string()]);
+    }
+
+    #[test]
+    fn fixed_field_keeps_three_letter_languages() {
+        let book = Book { language: Some("grc".to_string()), ..dune() };
+        let records = read_iso2709(&write_iso2709(&book_record(&book, &[], &[]))).unwrap();
+        assert_eq!(&records[0].control("008").unwrap_or_default()[35..38], "grc");
+        assert_eq!(record_to_book(&records[0]).0.language.as_deref(), Some("grc"));
+    }
+
+    #[test]
+    fn iso2709_reads_several_records_separated_by_line_breaks() {
+        let mut data = dune_iso2709();
+        data.extend(b"\r\n");
+        data.extend(dune_iso2709());
+        data.extend(b"\n");
+        assert_eq!(read_iso2709(&data).unwrap().len(), 2);
+        assert_eq!(read_iso2709(b"").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn iso2709_rejects_truncated_records() {
+        let data = dune_iso2709();
+        assert_eq!(read_error(&data[..data.len() - 10]), "Record 1: record is truncated");
+        assert_eq!(read_error(&data[..20]), "Record 1: record is truncated");
+        assert_eq!(read_error(b"012"), "Record 1: bad record length");
+
+        let mut second = data.clone();
+        second.extend(&data[..30]);
+        assert_eq!(read_error(&second), "Record 2: record is truncated");
+    }
+
+    #[test]
+    fn iso2709_rejects_bad_leaders_and_directory_entries() {
+        let data = dune_iso2709();
+
+        let mut length = data.clone();
+        length[..5].copy_from_slice(b"abcde");
+        assert_eq!(read_error(&length), "Record 1: bad record length");
+
+        let mut base = data.clone();
+        base[12..17].copy_from_slice(b"00010");
+        assert_eq!(read_error(&base), "Record 1: bad base address");
+        base[12..17].copy_from_slice(b"99999");
+        assert_eq!(read_error(&base), "Record 1: bad base address");
+
+        //first directory entry: tag at 24..27, length at 27..31, start at 31..36
+        let mut entry_length = data.clone();
+        entry_length[27..31].copy_from_slice(b"x1y2");
+        assert_eq!(read_error(&entry_length), "Record 1: bad directory entry");
+
+        let mut entry_start = data.clone();
+        entry_start[31..36].copy_from_slice(b"99999");
+        assert_eq!(read_error(&entry_start), "Record 1: field outside record");
+    }
+
+    #[test]
+    fn marcxml_round_trips_a_book() {
+        let record = book_record(&dune(), &[], &[]);
+        let xml = format!("<?xml version=\"1.0\"?><collection xmlns=\"{}\">{}{}</collection>", MARCXML_NAMESPACE, write_marcxml(&record), write_marcxml(&record));
+        let records = read_marcxml(xml.as_bytes()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_dune(&record_to_book(&records[1]).0);
+    }
+
+    #[test]
+    fn marcxml_reads_prefixed_single_records_and_escapes() {
+        let xml = r#"<marc:record xmlns:marc="http://www.loc.gov/MARC21/slim">
+            <marc:leader>00000nam a2200000 i 4500</marc:leader>
+            <marc:datafield tag="245" ind1="1" ind2="0"><marc:subfield code="a">Salt &amp; Pepper /</marc:subfield></marc:datafield>
+            <marc:datafield tag="100" ind1="1" ind2=" "><marc:subfield code="a"><![CDATA[Doe, Jane,]]></marc:subfield></marc:datafield>
+        </marc:record>"#;
+        let records = read_marcxml(xml.as_bytes()).unwrap();
+        let (book, _, _) = record_to_book(&records[0]);
+        assert_eq!(book.title, "Salt & Pepper");
+        assert_eq!(book.author, "Jane Doe");
+    }
+
+    #[test]
+    fn marcxml_rejects_malformed_documents() {
+        assert!(read_marcxml(b"<collection><record><leader>x</record></collection>").is_err());
+        assert!(read_marcxml(b"<record><datafield tag=\"245\" ind1=\"1></record>").is_err());
+        assert!(read_marcxml(b"<record><subfield code=\"a\">&bogus;</subfield></record>").is_err());
+        assert_eq!(read_marcxml(b"<collection><record><leader>00000nam a2200000 i 4500</leader>").err().as_deref(), Some("MARCXML ends inside a record"));
+        assert_eq!(read_marcxml(b"").unwrap().len(), 0);
+        //fields outside a record are dropped rather than attached to nothing
+        assert_eq!(read_marcxml(b"<collection><datafield tag=\"245\"/></collection>").unwrap().len(), 0);
+    }
+}
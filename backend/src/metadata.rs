@@ -0,0 +1,206 @@
+//Bibliographic metadata lookup used to prefill new books
+
+use crate::{authors, isbn};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+//Metadata found for an ISBN; the fields line up with Book so it can be posted back as one
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct BookMetadata {
+    pub title: String,
+    pub author: String,
+    pub authors: Vec<String>,
+    pub genre: Option<String>,
+    pub isbn10: Option<String>,
+    pub isbn13: Option<String>,
+    pub publisher: Option<String>,
+    #[serde(alias = "year")]
+    pub publication_year: Option<i32>,
+    #[serde(alias = "pages")]
+    pub page_count: Option<i32>,
+    pub cover_url: Option<String>,
+}
+
+impl BookMetadata {
+    //fill in the derived fields once a provider has found a record
+    fn complete(mut self, isbn13: &str) -> BookMetadata {
+        if self.author.is_empty() {
+            self.author = authors::join_names(&self.authors);
+        }
+        self.isbn13 = Some(isbn13.to_string());
+        self.isbn10 = isbn::to_isbn10(isbn13);
+        self
+    }
+}
+
+//Source of bibliographic metadata, looked up by ISBN-13
+pub trait MetadataProvider {
+    fn lookup(&self, isbn13: &str) -> Result<Option<BookMetadata>, String>;
+}
+
+//Open Library books API, or anything that answers in its format
+pub struct OpenLibraryProvider {
+    base_url: String,
+}
+
+impl OpenLibraryProvider {
+    pub fn new(base_url: &str) -> OpenLibraryProvider {
+        OpenLibraryProvider { base_url: base_url.trim_end_matches('/').to_string() }
+    }
+}
+
+impl MetadataProvider for OpenLibraryProvider {
+    fn lookup(&self, isbn13: &str) -> Result<Option<BookMetadata>, String> {
+        let url = format!("{}/api/books?bibkeys=ISBN:{}&format=json&jscmd=data", self.base_url, isbn13);
+        let body = ureq::get(&url)
+            .timeout(Duration::from_secs(10))
+            .call()
+            .map_err(|e| format!("Metadata provider request failed: {}", e))?
+            .into_string()
+            .map_err(|e| format!("Metadata provider response unreadable: {}", e))?;
+        let response: Value = serde_json::from_str(&body).map_err(|e| format!("Metadata provider response invalid: {}", e))?;
+
+        let record = match response.get(format!("ISBN:{}", isbn13)) {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let names = |field: &str| -> Vec<String> {
+            record[field]
+                .as_array()
+                .map(|entries| entries.iter().filter_map(|entry| entry["name"].as_str().map(str::to_string)).collect())
+                .unwrap_or_default()
+        };
+
+        let metadata = BookMetadata {
+            title: record["title"].as_str().unwrap_or_default().to_string(),
+            authors: names("authors"),
+            genre: names("subjects").into_iter().next(),
+            publisher: names("publishers").into_iter().next(),
+            publication_year: record["publish_date"].as_str().and_then(parse_year),
+            page_count: record["number_of_pages"].as_i64().map(|pages| pages as i32),
+            cover_url: record["cover"]["large"]
+                .as_str()
+                .or_else(|| record["cover"]["medium"].as_str())
+                .map(str::to_string),
+            ..BookMetadata::default()
+        };
+        Ok(Some(metadata.complete(isbn13)))
+    }
+}
+
+//Local JSON file of metadata keyed by ISBN, for offline use and tests
+pub struct FileProvider {
+    path: String,
+}
+
+impl FileProvider {
+    pub fn new(path: &str) -> FileProvider {
+        FileProvider { path: path.to_string() }
+    }
+}
+
+impl MetadataProvider for FileProvider {
+    fn lookup(&self, isbn13: &str) -> Result<Option<BookMetadata>, String> {
+        let contents = fs::read_to_string(&self.path).map_err(|e| format!("Metadata file unreadable: {}", e))?;
+        let records: HashMap<String, BookMetadata> =
+            serde_json::from_str(&contents).map_err(|e| format!("Metadata file invalid: {}", e))?;
+
+        //keys may be written as either form of ISBN, with or without hyphens
+        Ok(
+            records
+                .into_iter()
+                .find(|(key, _)| isbn::parse(key).as_deref() == Ok(isbn13))
+                .map(|(_, metadata)| metadata.complete(isbn13))
+        )
+    }
+}
+
+//first four-digit run in a free-form publication date such as "March 1990"
+fn parse_year(date: &str) -> Option<i32> {
+    date.split(|c: char| !c.is_ascii_digit())
+        .find(|part| part.len() == 4)
+        .and_then(|part| part.parse().ok())
+}
+
+//provider chosen by METADATA_PROVIDER: "openlibrary" (default, OPENLIBRARY_URL) or "file" (METADATA_FILE)
+pub fn configured_provider() -> Box<dyn MetadataProvider> {
+    match env::var("METADATA_PROVIDER").as_deref() {
+        Ok("file") => {
+            let path = env::var("METADATA_FILE").unwrap_or_else(|_| "metadata.json".to_string());
+            Box::new(FileProvider::new(&path))
+        }
+        _ => {
+            let base_url = env::var("OPENLIBRARY_URL").unwrap_or_else(|_| "https://openlibrary.org".to_string());
+            Box::new(OpenLibraryProvider::new(&base_url))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    //metadata file unique to one test, removed again when the test is done
+    struct MetadataFile(PathBuf);
+
+    impl MetadataFile {
+        fn new(name: &str, contents: &str) -> MetadataFile {
+            let path = env::temp_dir().join(format!("metadata-{}-{}.json", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            MetadataFile(path)
+        }
+
+        fn provider(&self) -> FileProvider {
+            FileProvider::new(self.0.to_str().unwrap())
+        }
+    }
+
+    impl Drop for MetadataFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn file_provider_finds_a_record_and_completes_it() {
+        let file = MetadataFile::new("hit", r#"{"9780441013593": {"title": "Dune", "authors": ["Frank Herbert"], "year": 1965, "pages": 896}}"#);
+        let metadata = file.provider().lookup("9780441013593").unwrap().unwrap();
+        assert_eq!(metadata.title, "Dune");
+        assert_eq!(metadata.author, "Frank Herbert");
+        assert_eq!(metadata.publication_year, Some(1965));
+        assert_eq!(metadata.page_count, Some(896));
+        assert_eq!(metadata.isbn13.as_deref(), Some("9780441013593"));
+        assert_eq!(metadata.isbn10.as_deref(), Some("0441013597"));
+    }
+
+    #[test]
+    fn file_provider_misses_unknown_isbns() {
+        let file = MetadataFile::new("miss", r#"{"9780441013593": {"title": "Dune"}}"#);
+        assert!(file.provider().lookup("9780804429573").unwrap().is_none());
+    }
+
+    #[test]
+    fn file_provider_rejects_malformed_and_missing_files() {
+        let file = MetadataFile::new("malformed", r#"{"9780441013593": "#);
+        assert!(file.provider().lookup("9780441013593").err().unwrap().starts_with("Metadata file invalid"));
+
+        let missing = FileProvider::new("/nonexistent/metadata.json");
+        assert!(missing.lookup("9780441013593").err().unwrap().starts_with("Metadata file unreadable"));
+    }
+
+    #[test]
+    fn file_provider_keys_match_either_isbn_form() {
+        let file = MetadataFile::new(
+            "keys",
+            r#"{"0-441-01359-7": {"title": "Dune"}, "978-0-8044-2957-3": {"title": "Ten"}, "not an isbn": {"title": "Ignored"}}"#
+        );
+        let provider = file.provider();
+        assert_eq!(provider.lookup("9780441013593").unwrap().unwrap().title, "Dune");
+        assert_eq!(provider.lookup("9780804429573").unwrap().unwrap().isbn10.as_deref(), Some("080442957X"));
+    }
+}
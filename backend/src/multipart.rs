@@ -0,0 +1,85 @@
+//multipart/form-data request bodies
+
+use crate::get_header;
+
+//One field of a multipart body
+pub struct Part {
+    pub name: String,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+//position of `needle` in `haystack` at or after `from`
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+//value of a `key="value"` parameter in a header such as Content-Disposition
+fn get_parameter(header: &str, key: &str) -> Option<String> {
+    header.split(';').skip(1).find_map(|parameter| {
+        let (name, value) = parameter.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case(key) {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+//split a multipart/form-data body into its parts
+pub fn parse(request: &str, body: &[u8]) -> Result<Vec<Part>, String> {
+    let content_type = get_header(request, "Content-Type").unwrap_or_default();
+    if !content_type.to_lowercase().starts_with("multipart/form-data") {
+        return Err("Expected a multipart/form-data body".to_string());
+    }
+    let boundary = match get_parameter(content_type, "boundary") {
+        Some(boundary) if !boundary.is_empty() => format!("--{}", boundary),
+        _ => return Err("Multipart boundary missing".to_string()),
+    };
+    let delimiter = format!("\r\n{}", boundary);
+
+    let mut position = match find(body, boundary.as_bytes(), 0) {
+        Some(start) => start + boundary.len(),
+        None => return Err("Multipart body is empty".to_string()),
+    };
+    let mut parts = Vec::new();
+    loop {
+        //"--" after a boundary closes the body
+        if body[position..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        let head_start = position + 2;
+        let head_end = find(body, b"\r\n\r\n", head_start).ok_or("Multipart part headers are incomplete")?;
+        let data_end = find(body, delimiter.as_bytes(), head_end + 4).ok_or("Multipart body is not terminated")?;
+
+        let head = String::from_utf8_lossy(&body[head_start..head_end]);
+        let header = |name: &str| {
+            head.lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_string())
+        };
+        let disposition = header("Content-Disposition").unwrap_or_default();
+        parts.push(Part {
+            name: get_parameter(&disposition, "name").unwrap_or_default(),
+            filename: get_parameter(&disposition, "filename"),
+            content_type: header("Content-Type"),
+            data: body[head_end + 4..data_end].to_vec(),
+        });
+        position = data_end + delimiter.len();
+    }
+}
+
+//the named part, or else the first part carrying a file
+pub fn take_file(mut parts: Vec<Part>, name: &str) -> Option<Part> {
+    let index = parts
+        .iter()
+        .position(|part| part.name == name)
+        .or_else(|| parts.iter().position(|part| part.filename.is_some()))?;
+    Some(parts.swap_remove(index))
+}
@@ -0,0 +1,295 @@
+//Content negotiation: JSON responses served as JSON, CSV or newline-delimited JSON
+
+use crate::{ get_header, get_query_param, with_content_type, with_header, NOT_ACCEPTABLE };
+use serde_json::Value;
+use std::borrow::Cow;
+
+//Representations of a JSON response, in order of preference, with the media types that select them
+const REPRESENTATIONS: [(&str, &str, &[&str]); 3] = [
+    ("json", "application/json", &["application/json"]),
+    ("csv", "text/csv; charset=utf-8", &["text/csv"]),
+    ("ndjson", "application/x-ndjson", &["application/x-ndjson", "application/ndjson"]),
+];
+
+//quality the Accept header gives a media type; the most specific matching range decides, as in RFC 9110
+fn quality(accept: &str, media_type: &str) -> f32 {
+    let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+    let mut best: Option<(u8, f32)> = None;
+    for range in accept.split(',') {
+        let mut params = range.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default().to_lowercase();
+        let specificity = match name.as_str() {
+            "*/*" => 0,
+            name if name.strip_suffix("/*") == Some(kind) => 1,
+            name if name == media_type => 2,
+            _ => continue,
+        };
+        let q = params
+            .filter_map(|param| param.strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if best.is_none_or(|(current, _)| specificity > current) {
+            best = Some((specificity, q));
+        }
+    }
+    best.map_or(0.0, |(_, q)| q)
+}
+
+//whether a ?format= value names a representation rather than something endpoint-specific
+pub fn is_representation(format: &str) -> bool {
+    REPRESENTATIONS.iter().any(|(name, _, _)| name.eq_ignore_ascii_case(format))
+}
+
+//pick a representation: ?format=json|csv|ndjson wins, then Accept, then JSON.
+//Other ?format= values belong to the endpoint itself (book formats, citation styles) and are left alone.
+fn choose(request: &str) -> Option<(&'static str, &'static str)> {
+    let format = get_query_param(request, "format").map(|format| format.to_lowercase());
+    if let Some((name, content_type, _)) = REPRESENTATIONS.iter().find(|(name, _, _)| Some(*name) == format.as_deref()) {
+        return Some((name, content_type));
+    }
+    let accept = match get_header(request, "Accept") {
+        Some(accept) if !accept.trim().is_empty() => accept,
+        _ => return Some(("json", "application/json")),
+    };
+    let mut chosen = None;
+    let mut chosen_q = 0.0;
+    for (name, content_type, media_types) in REPRESENTATIONS {
+        let q = media_types.iter().map(|media_type| quality(accept, media_type)).fold(0.0, f32::max);
+        //ties go to the earlier representation, so */* stays JSON
+        if q > chosen_q {
+            chosen = Some((name, content_type));
+            chosen_q = q;
+        }
+    }
+    chosen
+}
+
+//CSV cell for a JSON value: strings as they are, nested values as JSON text
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+//a list becomes one row per item, an object one row; columns are every key in order of first appearance
+fn to_csv(value: &Value) -> Result<String, csv::Error> {
+    let items = match value {
+        Value::Array(items) => items.clone(),
+        value => vec![value.clone()],
+    };
+    let mut columns: Vec<String> = Vec::new();
+    for item in &items {
+        match item {
+            Value::Object(item) => {
+                for key in item.keys() {
+                    if !columns.contains(key) {
+                        columns.push(key.clone());
+                    }
+                }
+            }
+            _ if !columns.iter().any(|column| column == "value") => columns.push("value".to_string()),
+            _ => {}
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&columns)?;
+    for item in &items {
+        let record: Vec<String> = columns
+            .iter()
+            .map(|column| match item {
+                Value::Object(item) => item.get(column).map(cell).unwrap_or_default(),
+                item if column == "value" => cell(item),
+                _ => String::new(),
+            })
+            .collect();
+        writer.write_record(&record)?;
+    }
+    let data = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8(data).unwrap_or_default())
+}
+
+//a list becomes one line per item, anything else a single line
+fn to_ndjson(value: &Value) -> String {
+    match value {
+        Value::Array(items) => items.iter().map(|item| format!("{}\n", item)).collect(),
+        value => format!("{}\n", value),
+    }
+}
+
+//representation other than JSON chosen for a GET request, if any
+fn converted(request: &str) -> Option<&'static str> {
+    match choose(request) {
+        Some((name, _)) if request.starts_with("GET ") && name != "json" => Some(name),
+        _ => None,
+    }
+}
+
+//entity tag of a converted representation: the row version tag with the representation appended, "3" becoming "3-csv"
+fn representation_tag(tag: &str, name: &str) -> String {
+    match tag.strip_suffix('"') {
+        Some(opaque) => format!("{}-{}\"", opaque, name),
+        None => tag.to_string(),
+    }
+}
+
+//row version tag a client holds for a representation, or None when the tag belongs to another representation
+fn version_tag(tag: &str, name: &str) -> Option<String> {
+    if tag == "*" {
+        return Some(tag.to_string());
+    }
+    tag.strip_suffix(&format!("-{}\"", name)).map(|opaque| format!("{}\"", opaque))
+}
+
+//rewrite If-None-Match of a request for a converted representation so handlers compare it with their own row version tags.
+//Tags of other representations are dropped, so a cached JSON body never answers a CSV request with 304.
+pub fn revalidate(request: &str) -> Cow<'_, str> {
+    let (name, header) = match (converted(request), get_header(request, "If-None-Match")) {
+        (Some(name), Some(header)) => (name, header),
+        _ => return Cow::Borrowed(request),
+    };
+    let tags: Vec<String> = header.split(',').map(str::trim).filter_map(|tag| version_tag(tag, name)).collect();
+    let (head, rest) = request.split_once("\r\n\r\n").unwrap_or((request, ""));
+    let lines: Vec<String> = head
+        .split("\r\n")
+        .filter_map(|line| match line.split_once(':') {
+            Some((key, _)) if key.trim().eq_ignore_ascii_case("If-None-Match") =>
+                (!tags.is_empty()).then(|| format!("If-None-Match: {}", tags.join(", "))),
+            _ => Some(line.to_string()),
+        })
+        .collect();
+    Cow::Owned(format!("{}\r\n\r\n{}", lines.join("\r\n"), rest))
+}
+
+//give the ETag header of a response the tag of the representation it carries
+fn with_representation_tag(status_line: &str, name: &str) -> String {
+    status_line
+        .split("\r\n")
+        .map(|line| match line.split_once(':') {
+            Some((key, tag)) if key.eq_ignore_ascii_case("ETag") => format!("{}: {}", key, representation_tag(tag.trim(), name)),
+            _ => line.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+//serve a GET response in the representation the client asked for.
+//Only successful JSON responses are converted; errors and other media types pass through as they are.
+//Converted bodies carry their own ETag, and so do 304 responses to requests passed through revalidate.
+pub fn negotiate(request: &str, status_line: String, content: String) -> (String, String) {
+    if status_line.starts_with("HTTP/1.1 304") {
+        return match converted(request) {
+            Some(name) => (with_header(&with_representation_tag(&status_line, name), "Vary", "Accept"), content),
+            None => (status_line, content),
+        };
+    }
+    if !request.starts_with("GET ") || !status_line.starts_with("HTTP/1.1 200") || !status_line.contains("Content-Type: application/json") {
+        return (status_line, content);
+    }
+    let status_line = with_header(&status_line, "Vary", "Accept");
+    let (name, content_type) = match choose(request) {
+        Some(chosen) => chosen,
+        None => {
+            let types: Vec<&str> = REPRESENTATIONS.iter().map(|(_, _, media_types)| media_types[0]).collect();
+            return (NOT_ACCEPTABLE.to_string(), format!("Acceptable representations: {}", types.join(", ")));
+        }
+    };
+    if name == "json" {
+        return (status_line, content);
+    }
+    let value: Value = match serde_json::from_str(&content) {
+        Ok(value) => value,
+        Err(_) => return (status_line, content),
+    };
+    let content = match name {
+        "csv" =>
+            match to_csv(&value) {
+                Ok(content) => content,
+                Err(e) => {
+                    eprintln!("Unable to write CSV: {}", e);
+                    return (status_line, content);
+                }
+            }
+        _ => to_ndjson(&value),
+    };
+    let status_line = with_representation_tag(&status_line, name);
+    (with_content_type(&status_line, content_type), content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ etag, NOT_MODIFIED, OK_RESPONSE };
+
+    fn get(path: &str, headers: &str) -> String {
+        format!("GET {} HTTP/1.1\r\nHost: localhost\r\n{}\r\n", path, headers)
+    }
+
+    #[test]
+    fn quality_uses_the_most_specific_range() {
+        assert_eq!(quality("text/csv", "text/csv"), 1.0);
+        assert_eq!(quality("text/*;q=0.5, text/csv;q=0.2", "text/csv"), 0.2);
+        assert_eq!(quality("*/*;q=0.1, text/*;q=0.4", "text/csv"), 0.4);
+        assert_eq!(quality("application/json", "text/csv"), 0.0);
+        assert_eq!(quality("TEXT/CSV; q=0.7", "text/csv"), 0.7);
+    }
+
+    #[test]
+    fn choose_prefers_format_then_accept_then_json() {
+        assert_eq!(choose(&get("/api/rust/books", "")), Some(("json", "application/json")));
+        assert_eq!(choose(&get("/api/rust/books", "Accept: */*\r\n")), Some(("json", "application/json")));
+        assert_eq!(choose(&get("/api/rust/books", "Accept: text/csv\r\n")).map(|(name, _)| name), Some("csv"));
+        assert_eq!(choose(&get("/api/rust/books", "Accept: application/ndjson\r\n")).map(|(name, _)| name), Some("ndjson"));
+        assert_eq!(choose(&get("/api/rust/books?format=NDJSON", "Accept: text/csv\r\n")).map(|(name, _)| name), Some("ndjson"));
+        assert_eq!(choose(&get("/api/rust/books?format=epub", "Accept: text/csv\r\n")).map(|(name, _)| name), Some("csv"));
+    }
+
+    #[test]
+    fn choose_honours_q_zero() {
+        assert_eq!(choose(&get("/api/rust/books", "Accept: application/json;q=0, text/csv\r\n")).map(|(name, _)| name), Some("csv"));
+        assert_eq!(choose(&get("/api/rust/books", "Accept: */*, application/json;q=0\r\n")).map(|(name, _)| name), Some("csv"));
+        assert_eq!(choose(&get("/api/rust/books", "Accept: text/csv;q=0\r\n")), None);
+        assert_eq!(choose(&get("/api/rust/books", "Accept: image/png\r\n")), None);
+    }
+
+    #[test]
+    fn converted_responses_carry_their_own_etag() {
+        let status_line = with_header(OK_RESPONSE, "ETag", &etag(3));
+        let request = get("/api/rust/books/1", "Accept: text/csv\r\n");
+        let (status_line, content) = negotiate(&request, status_line, "{\"id\":1,\"title\":\"Dune\"}".to_string());
+        assert!(status_line.contains("ETag: \"3-csv\"\r\n"));
+        assert!(status_line.contains("Content-Type: text/csv"));
+        assert_eq!(content, "id,title\n1,Dune\n");
+
+        let status_line = with_header(OK_RESPONSE, "ETag", &etag(3));
+        let request = get("/api/rust/books/1", "");
+        let (status_line, _) = negotiate(&request, status_line, "{\"id\":1}".to_string());
+        assert!(status_line.contains("ETag: \"3\"\r\n"));
+    }
+
+    #[test]
+    fn revalidate_keeps_only_tags_of_the_chosen_representation() {
+        let request = get("/api/rust/books/1", "Accept: text/csv\r\nIf-None-Match: \"3\", W/\"3-csv\", \"2-ndjson\"\r\n");
+        assert_eq!(get_header(&revalidate(&request), "If-None-Match"), Some("W/\"3\""));
+
+        let request = get("/api/rust/books/1", "Accept: text/csv\r\nIf-None-Match: \"3\"\r\n");
+        assert_eq!(get_header(&revalidate(&request), "If-None-Match"), None);
+
+        let request = get("/api/rust/books/1", "If-None-Match: \"3\"\r\n");
+        assert!(matches!(revalidate(&request), Cow::Borrowed(_)));
+
+        let request = get("/api/rust/books/1?format=ndjson", "If-None-Match: *\r\n");
+        assert_eq!(get_header(&revalidate(&request), "If-None-Match"), Some("*"));
+    }
+
+    #[test]
+    fn not_modified_responses_carry_the_representation_tag() {
+        let request = get("/api/rust/books/1?format=ndjson", "If-None-Match: \"3-ndjson\"\r\n");
+        let (status_line, _) = negotiate(&request, with_header(NOT_MODIFIED, "ETag", &etag(3)), String::new());
+        assert!(status_line.contains("ETag: \"3-ndjson\"\r\n"));
+        assert!(status_line.contains("Vary: Accept\r\n"));
+        assert!(status_line.ends_with("\r\n\r\n"));
+    }
+}
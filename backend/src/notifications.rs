@@ -0,0 +1,553 @@
+//Email notifications over SMTP: loans due soon, overdue loans and holds ready to collect.
+//SMTP_HOST turns sending on; SMTP_PORT, SMTP_TLS (none, starttls or tls), SMTP_USERNAME, SMTP_PASSWORD and MAIL_FROM
+//configure it. A local stand-in such as MailHog takes SMTP_HOST=localhost SMTP_PORT=1025 SMTP_TLS=none.
+
+use crate::{
+    get_id,
+    get_query_param,
+    holds,
+    is_admin,
+    next_request_id,
+    write_audit,
+    RequestContext,
+    BAD_REQUEST,
+    DB_URL,
+    FORBIDDEN,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    OK_RESPONSE,
+};
+use chrono::{ DateTime, NaiveDate, Utc };
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{ Message, SmtpTransport, Transport };
+use postgres::{ Client, NoTls, Row };
+use postgres::Error as PostgresError;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+//Minutes a claimed notice may stay pending before it counts as abandoned
+const PENDING_TIMEOUT_MINUTES: i32 = 15;
+
+//Kinds of notification; each can be turned off per user
+pub const KINDS: [&str; 3] = ["due_soon", "overdue", "hold_ready"];
+
+//Built-in templates as (kind, subject, body). NOTIFICATION_TEMPLATE_DIR may hold {kind}.txt files to use instead,
+//with the subject on the first line. {{name}}, {{title}}, {{due_date}} and {{when}} are filled in.
+const TEMPLATES: [(&str, &str, &str); 3] = [
+    (
+        "due_soon",
+        "Due {{when}}: {{title}}",
+        "Hello {{name}},\n\n\"{{title}}\" is due back {{when}}, on {{due_date}}.\n\nThank you,\nThe library\n",
+    ),
+    (
+        "overdue",
+        "Overdue: {{title}}",
+        "Hello {{name}},\n\n\"{{title}}\" was due back on {{due_date}} and is now {{when}} overdue. Please return it as soon as you can.\n\nThank you,\nThe library\n",
+    ),
+    (
+        "hold_ready",
+        "Ready to collect: {{title}}",
+        "Hello {{name}},\n\n\"{{title}}\", which you placed a hold on, is ready for you to collect.\n\nThank you,\nThe library\n",
+    ),
+];
+
+//A user's notification settings; a user without a row gets everything
+#[derive(Serialize, Deserialize)]
+struct Preferences {
+    #[serde(skip_deserializing)]
+    user_id: i32,
+    #[serde(default = "enabled")]
+    due_soon: bool,
+    #[serde(default = "enabled")]
+    overdue: bool,
+    #[serde(default = "enabled")]
+    hold_ready: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+//A notification_log row
+#[derive(Serialize)]
+struct LogEntry {
+    id: i32,
+    user_id: i32,
+    kind: String,
+    loan_id: Option<i32>,
+    hold_id: Option<i32>,
+    recipient: String,
+    subject: String,
+    status: String,
+    error: Option<String>,
+    created_at: Option<DateTime<Utc>>,
+}
+
+//map notification_log row to LogEntry
+fn entry_from_row(row: &Row) -> LogEntry {
+    LogEntry {
+        id: row.get(0),
+        user_id: row.get(1),
+        kind: row.get(2),
+        loan_id: row.get(3),
+        hold_id: row.get(4),
+        recipient: row.get(5),
+        subject: row.get(6),
+        status: row.get(7),
+        error: row.get(8),
+        created_at: row.get(9),
+    }
+}
+
+//An email about to go out
+struct Notice {
+    kind: &'static str,
+    user_id: i32,
+    name: String,
+    recipient: String,
+    loan_id: Option<i32>,
+    hold_id: Option<i32>,
+    //the same reminder always has the same key, which is what stops it going out twice
+    dedupe_key: String,
+    subject: String,
+    body: String,
+}
+
+//What happened to one notice in a run
+#[derive(Serialize)]
+struct NoticeReport {
+    kind: &'static str,
+    user_id: i32,
+    recipient: String,
+    subject: String,
+    loan_id: Option<i32>,
+    hold_id: Option<i32>,
+    status: &'static str,
+    error: Option<String>,
+}
+
+//Outcome of a notification run
+#[derive(Serialize, Default)]
+pub struct RunSummary {
+    dry_run: bool,
+    holds_ready: usize,
+    abandoned: usize,
+    sent: usize,
+    failed: usize,
+    opted_out: usize,
+    already_sent: usize,
+    no_email: usize,
+    notices: Vec<NoticeReport>,
+}
+
+//SMTP settings from the environment
+struct SmtpConfig {
+    host: String,
+    port: u16,
+    tls: String,
+    username: Option<String>,
+    password: Option<String>,
+    from: String,
+}
+
+//SMTP settings, or None when SMTP_HOST is not set and nothing should be sent
+fn smtp_config() -> Option<SmtpConfig> {
+    let host = env::var("SMTP_HOST").ok().filter(|host| !host.trim().is_empty())?;
+    let tls = env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string()).to_lowercase();
+    let default_port = match tls.as_str() {
+        "none" => 25,
+        "tls" => 465,
+        _ => 587,
+    };
+    Some(SmtpConfig {
+        host,
+        port: env::var("SMTP_PORT").ok().and_then(|port| port.parse().ok()).unwrap_or(default_port),
+        tls,
+        username: env::var("SMTP_USERNAME").ok().filter(|username| !username.is_empty()),
+        password: env::var("SMTP_PASSWORD").ok(),
+        from: env::var("MAIL_FROM").unwrap_or_else(|_| "Library <library@localhost>".to_string()),
+    })
+}
+
+fn mailer(config: &SmtpConfig) -> Result<SmtpTransport, String> {
+    let builder = match config.tls.as_str() {
+        "none" => SmtpTransport::builder_dangerous(&config.host),
+        "starttls" => SmtpTransport::starttls_relay(&config.host).map_err(|e| e.to_string())?,
+        "tls" => SmtpTransport::relay(&config.host).map_err(|e| e.to_string())?,
+        other => return Err(format!("Invalid SMTP_TLS: {} (expected none, starttls or tls)", other)),
+    };
+    let mut builder = builder.port(config.port).timeout(Some(Duration::from_secs(30)));
+    if let Some(username) = &config.username {
+        builder = builder.credentials(Credentials::new(username.clone(), config.password.clone().unwrap_or_default()));
+    }
+    Ok(builder.build())
+}
+
+//subject and body of a kind of notice with its values filled in
+fn render(kind: &str, values: &[(&str, &str)]) -> (String, String) {
+    let (_, subject, body) = TEMPLATES.iter().find(|(name, _, _)| *name == kind).copied().unwrap_or_default();
+    let mut subject = subject.to_string();
+    let mut body = body.to_string();
+    if let Ok(directory) = env::var("NOTIFICATION_TEMPLATE_DIR") {
+        if let Ok(template) = fs::read_to_string(Path::new(&directory).join(format!("{}.txt", kind))) {
+            let (first, rest) = template.split_once('\n').unwrap_or((&template, ""));
+            subject = first.trim().to_string();
+            body = rest.trim_start_matches(['\r', '\n']).to_string();
+        }
+    }
+    for (name, value) in values {
+        let placeholder = format!("{{{{{}}}}}", name);
+        subject = subject.replace(&placeholder, value);
+        body = body.replace(&placeholder, value);
+    }
+    (subject, body)
+}
+
+fn days(count: i64) -> String {
+    if count == 1 { "1 day".to_string() } else { format!("{} days", count) }
+}
+
+//everything due to go out: loans due within NOTIFY_DUE_SOON_DAYS (default 3), overdue loans and ready holds,
+//less what users have turned off and what has gone out before
+fn collect_notices(client: &mut Client, summary: &mut RunSummary) -> Result<Vec<Notice>, PostgresError> {
+    let due_soon_days: i64 = env::var("NOTIFY_DUE_SOON_DAYS").ok().and_then(|days| days.parse().ok()).unwrap_or(3);
+    let today = Utc::now().date_naive();
+    let mut notices = Vec::new();
+    let mut add = |summary: &mut RunSummary, wanted: bool, notice: Notice| {
+        if !wanted {
+            summary.opted_out += 1;
+        } else if notice.recipient.trim().is_empty() {
+            summary.no_email += 1;
+        } else {
+            notices.push(notice);
+        }
+    };
+
+    let loans = client.query(
+        "SELECT l.id, l.due_date, u.id, u.name, u.email, b.title, COALESCE(p.due_soon, true), COALESCE(p.overdue, true)
+        FROM loans l JOIN users u ON u.id = l.user_id AND u.deleted_at IS NULL JOIN books b ON b.id = l.book_id
+        LEFT JOIN notification_preferences p ON p.user_id = u.id
+        WHERE l.return_date IS NULL AND l.deleted_at IS NULL ORDER BY l.id",
+        &[]
+    )?;
+    for row in loans {
+        let (loan_id, due_date, user_id, name, recipient, title): (i32, String, i32, String, String, String) =
+            (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4), row.get(5));
+        //due dates are free text; ones that are not dates cannot be reminded about
+        let due = match due_date.get(..10).and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()) {
+            Some(due) => due,
+            None => continue,
+        };
+        let remaining = (due - today).num_days();
+        let (kind, wanted, when) = match remaining {
+            0 => ("due_soon", row.get(6), "today".to_string()),
+            1 => ("due_soon", row.get(6), "tomorrow".to_string()),
+            remaining if remaining > 0 && remaining <= due_soon_days => ("due_soon", row.get(6), format!("in {}", days(remaining))),
+            remaining if remaining < 0 => ("overdue", row.get(7), days(-remaining)),
+            _ => continue,
+        };
+        let due_date = due.to_string();
+        let (subject, body) = render(kind, &[("name", &name), ("title", &title), ("due_date", &due_date), ("when", &when)]);
+        //keyed by due date too, so a renewed loan is reminded again about its new date
+        let dedupe_key = format!("{}:loan:{}:{}", kind, loan_id, due_date);
+        add(summary, wanted, Notice { kind, user_id, name, recipient, loan_id: Some(loan_id), hold_id: None, dedupe_key, subject, body });
+    }
+
+    let holds = client.query(
+        "SELECT h.id, u.id, u.name, u.email, b.title, COALESCE(p.hold_ready, true)
+        FROM holds h JOIN users u ON u.id = h.user_id AND u.deleted_at IS NULL JOIN books b ON b.id = h.book_id
+        LEFT JOIN notification_preferences p ON p.user_id = u.id
+        WHERE h.status = 'ready' ORDER BY h.id",
+        &[]
+    )?;
+    for row in holds {
+        let (hold_id, user_id, name, recipient, title): (i32, i32, String, String, String) = (row.get(0), row.get(1), row.get(2), row.get(3), row.get(4));
+        let (subject, body) = render("hold_ready", &[("name", &name), ("title", &title)]);
+        let dedupe_key = format!("hold_ready:hold:{}", hold_id);
+        add(summary, row.get(5), Notice { kind: "hold_ready", user_id, name, recipient, loan_id: None, hold_id: Some(hold_id), dedupe_key, subject, body });
+    }
+
+    let keys: Vec<&str> = notices.iter().map(|notice| notice.dedupe_key.as_str()).collect();
+    let sent: HashSet<String> = client
+        .query("SELECT dedupe_key FROM notification_log WHERE dedupe_key = ANY($1) AND status IN ('pending', 'sent')", &[&keys])?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    summary.already_sent += sent.len();
+    notices.retain(|notice| !sent.contains(&notice.dedupe_key));
+    Ok(notices)
+}
+
+//a log row's dedupe key with its loan or hold id replaced, for rows copied into a database where ids differ.
+//A key whose loan or hold is gone is marked so it never matches a notice about a new row given the same id.
+pub fn remap_dedupe_key(key: &str, loan_id: Option<i64>, hold_id: Option<i64>) -> String {
+    let mut parts: Vec<String> = key.splitn(4, ':').map(str::to_string).collect();
+    let id = match parts.get(1).map(String::as_str) {
+        Some("loan") => loan_id,
+        Some("hold") => hold_id,
+        _ => return key.to_string(),
+    };
+    if let Some(old) = parts.get_mut(2) {
+        *old = id.map_or_else(|| format!("gone-{}", old), |id| id.to_string());
+    }
+    parts.join(":")
+}
+
+fn send(mailer: &SmtpTransport, config: &SmtpConfig, notice: &Notice) -> Result<(), String> {
+    let from: Mailbox = config.from.parse().map_err(|e| format!("Invalid MAIL_FROM: {}", e))?;
+    let address = notice.recipient.trim().parse().map_err(|e| format!("Invalid email address: {}", e))?;
+    let message = Message::builder()
+        .from(from)
+        .to(Mailbox::new(Some(notice.name.clone()), address))
+        .subject(&notice.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(notice.body.clone())
+        .map_err(|e| e.to_string())?;
+    mailer.send(&message).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn report(notice: &Notice, status: &'static str, error: Option<String>) -> NoticeReport {
+    NoticeReport {
+        kind: notice.kind,
+        user_id: notice.user_id,
+        recipient: notice.recipient.clone(),
+        subject: notice.subject.clone(),
+        loan_id: notice.loan_id,
+        hold_id: notice.hold_id,
+        status,
+        error,
+    }
+}
+
+//promote holds that copies have come free for, then send every notice that is due.
+//A dry run changes nothing and reports what would be sent.
+pub fn run_notifications(dry_run: bool) -> Result<RunSummary, String> {
+    let config = smtp_config();
+    if config.is_none() && !dry_run {
+        return Err("SMTP_HOST is not set".to_string());
+    }
+    let context = RequestContext {
+        actor: Some("notifications".to_string()),
+        client_ip: None,
+        request_id: next_request_id(),
+    };
+    let mut client = Client::connect(DB_URL, NoTls).map_err(|e| e.to_string())?;
+    let mut summary = RunSummary { dry_run, ..RunSummary::default() };
+
+    if !dry_run {
+        let mut transaction = client.transaction().map_err(|e| e.to_string())?;
+        summary.holds_ready = holds::promote_holds(&mut transaction, &context).map_err(|e| e.to_string())?.len();
+        transaction.commit().map_err(|e| e.to_string())?;
+
+        //a run that stopped between claiming a notice and sending it left the row pending;
+        //once that is long past, the row is marked failed so the notice goes out again
+        summary.abandoned = client
+            .execute(
+                "UPDATE notification_log SET status = 'failed', error = 'Abandoned while pending' WHERE status = 'pending' AND created_at < now() - make_interval(mins => $1)",
+                &[&PENDING_TIMEOUT_MINUTES]
+            )
+            .map_err(|e| e.to_string())? as usize;
+    }
+    let notices = collect_notices(&mut client, &mut summary).map_err(|e| e.to_string())?;
+    let (config, mailer) = match config {
+        Some(config) if !dry_run => {
+            let mailer = mailer(&config)?;
+            (config, mailer)
+        }
+        _ => {
+            summary.notices = notices.iter().map(|notice| report(notice, "preview", None)).collect();
+            return Ok(summary);
+        }
+    };
+
+    for notice in notices {
+        //the log row is claimed before sending, so overlapping runs cannot both send it
+        let claimed = client
+            .query_opt(
+                "INSERT INTO notification_log (user_id, kind, loan_id, hold_id, recipient, subject, status, dedupe_key) VALUES ($1, $2, $3, $4, $5, $6, 'pending', $7)
+                ON CONFLICT (dedupe_key) WHERE status IN ('pending', 'sent') DO NOTHING RETURNING id",
+                &[&notice.user_id, &notice.kind, &notice.loan_id, &notice.hold_id, &notice.recipient, &notice.subject, &notice.dedupe_key]
+            )
+            .map_err(|e| e.to_string())?;
+        let id: i32 = match claimed {
+            Some(row) => row.get(0),
+            None => {
+                summary.already_sent += 1;
+                continue;
+            }
+        };
+
+        let error = send(&mailer, &config, &notice).err();
+        let status = if error.is_some() { "failed" } else { "sent" };
+        client
+            .execute("UPDATE notification_log SET status = $1, error = $2 WHERE id = $3", &[&status, &error, &id])
+            .map_err(|e| e.to_string())?;
+        if error.is_some() { summary.failed += 1 } else { summary.sent += 1 }
+        summary.notices.push(report(&notice, status, error));
+    }
+    Ok(summary)
+}
+
+//Notification job, runs every NOTIFY_INTERVAL_MINUTES (default 60) while SMTP_HOST is set
+pub fn run_notification_schedule() {
+    let minutes: u64 = env::var("NOTIFY_INTERVAL_MINUTES").ok().and_then(|value| value.parse().ok()).unwrap_or(60);
+    if smtp_config().is_none() || minutes == 0 {
+        return;
+    }
+
+    loop {
+        match run_notifications(false) {
+            Ok(summary) if summary.sent + summary.failed > 0 => println!("Sent {} notifications, {} failed", summary.sent, summary.failed),
+            Ok(_) => {}
+            Err(e) => println!("Error sending notifications: {}", e),
+        }
+        thread::sleep(Duration::from_secs(minutes * 60));
+    }
+}
+
+//`backend notify [--dry-run]`
+pub fn run_notify_command(args: &[String]) -> Result<(), String> {
+    let dry_run = match args {
+        [] => false,
+        [flag] if flag == "--dry-run" => true,
+        _ => return Err("Usage: backend notify [--dry-run]".to_string()),
+    };
+    let summary = run_notifications(dry_run)?;
+    println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+    Ok(())
+}
+
+//handle notification run request: send what is due now, or with ?dry_run=true preview it
+pub fn handle_run_notifications_request(request: &str) -> (String, String) {
+    if !is_admin(request) {
+        return (FORBIDDEN.to_string(), "Admin token required".to_string());
+    }
+    match run_notifications(get_query_param(request, "dry_run").as_deref() == Some("true")) {
+        Ok(summary) => (OK_RESPONSE.to_string(), serde_json::to_string(&summary).unwrap()),
+        Err(e) => {
+            eprintln!("Notification run failed: {}", e);
+            (INTERNAL_ERROR.to_string(), e)
+        }
+    }
+}
+
+//handle get notification log request, newest first, optionally filtered by ?user_id=, ?kind= and ?status=
+pub fn handle_get_notification_log_request(request: &str) -> (String, String) {
+    if !is_admin(request) {
+        return (FORBIDDEN.to_string(), "Admin token required".to_string());
+    }
+    let user_id = match get_query_param(request, "user_id").map(|id| id.parse::<i32>()) {
+        Some(Err(_)) => return (BAD_REQUEST.to_string(), "Invalid user_id".to_string()),
+        user_id => user_id.and_then(Result::ok),
+    };
+    let kind = get_query_param(request, "kind");
+    if let Some(kind) = &kind {
+        if !KINDS.contains(&kind.as_str()) {
+            return (BAD_REQUEST.to_string(), format!("Invalid kind: {} (expected one of {})", kind, KINDS.join(", ")));
+        }
+    }
+    let status = get_query_param(request, "status");
+
+    match Client::connect(DB_URL, NoTls) {
+        Ok(mut client) => {
+            let rows = client.query(
+                "SELECT id, user_id, kind, loan_id, hold_id, recipient, subject, status, error, created_at FROM notification_log
+                WHERE ($1::int IS NULL OR user_id = $1) AND ($2::varchar IS NULL OR kind = $2) AND ($3::varchar IS NULL OR status = $3) ORDER BY id DESC",
+                &[&user_id, &kind, &status]
+            );
+            match rows {
+                Ok(rows) => {
+                    let entries: Vec<LogEntry> = rows.iter().map(entry_from_row).collect();
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&entries).unwrap())
+                }
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        }
+        Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//a user's preferences, or everything on when they have never set any
+fn get_preferences(client: &mut impl postgres::GenericClient, user_id: i32) -> Result<Preferences, PostgresError> {
+    let row = client.query_opt("SELECT due_soon, overdue, hold_ready FROM notification_preferences WHERE user_id = $1", &[&user_id])?;
+    Ok(match row {
+        Some(row) => Preferences { user_id, due_soon: row.get(0), overdue: row.get(1), hold_ready: row.get(2) },
+        None => Preferences { user_id, due_soon: true, overdue: true, hold_ready: true },
+    })
+}
+
+//handle get user notifications request
+pub fn handle_get_user_notifications_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            match client.query_opt("SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL", &[&id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (NOT_FOUND.to_string(), "User not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+            match get_preferences(&mut client, id) {
+                Ok(preferences) => (OK_RESPONSE.to_string(), serde_json::to_string(&preferences).unwrap()),
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid user id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put user notifications request: kinds left out of the body are turned on
+pub fn handle_put_user_notifications_request(request: &str, context: &RequestContext) -> (String, String) {
+    let body = request.split("\r\n\r\n").last().unwrap_or_default();
+    match (get_id(request).parse::<i32>(), serde_json::from_str::<Preferences>(body), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut preferences), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            match transaction.query_opt("SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL FOR UPDATE", &[&id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (NOT_FOUND.to_string(), "User not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+            preferences.user_id = id;
+            let saved = get_preferences(&mut transaction, id)
+                .and_then(|before| {
+                    transaction.execute(
+                        "INSERT INTO notification_preferences (user_id, due_soon, overdue, hold_ready) VALUES ($1, $2, $3, $4)
+                        ON CONFLICT (user_id) DO UPDATE SET due_soon = EXCLUDED.due_soon, overdue = EXCLUDED.overdue, hold_ready = EXCLUDED.hold_ready",
+                        &[&id, &preferences.due_soon, &preferences.overdue, &preferences.hold_ready]
+                    )?;
+                    write_audit(&mut transaction, context, "update", "notification_preferences", id, Some(&before), Some(&preferences))
+                })
+                .and_then(|_| transaction.commit());
+            if saved.is_err() {
+                return (INTERNAL_ERROR.to_string(), "Internal error".to_string());
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&preferences).unwrap())
+        }
+        (Err(_), _, _) => (BAD_REQUEST.to_string(), "Invalid user id".to_string()),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remap_dedupe_key_follows_the_new_ids() {
+        assert_eq!(remap_dedupe_key("overdue:loan:12:2026-10-01", Some(3), None), "overdue:loan:3:2026-10-01");
+        assert_eq!(remap_dedupe_key("due_soon:loan:12:2026-10-01", None, None), "due_soon:loan:gone-12:2026-10-01");
+        assert_eq!(remap_dedupe_key("hold_ready:hold:7", None, Some(9)), "hold_ready:hold:9");
+        assert_eq!(remap_dedupe_key("hold_ready:hold:7", Some(1), None), "hold_ready:hold:gone-7");
+        assert_eq!(remap_dedupe_key("something:else", Some(1), Some(2)), "something:else");
+    }
+}
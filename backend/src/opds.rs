@@ -0,0 +1,619 @@
+//OPDS catalog for e-reader apps: OPDS 1.2 Atom under /api/rust/opds, OPDS 2.0 JSON under /api/rust/opds2
+
+use crate::{
+    book_from_row,
+    get_header,
+    get_path_segment,
+    get_query_param,
+    marc::escape_xml,
+    with_content_type,
+    Book,
+    BAD_REQUEST,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    OK_RESPONSE,
+};
+use chrono::{ DateTime, SecondsFormat, Utc };
+use postgres::{ Client, NoTls };
+use postgres::types::ToSql;
+use postgres::Error as PostgresError;
+use serde_json::{ json, Value };
+use std::collections::HashMap;
+use std::env;
+
+//Entries per page of a feed
+const PAGE_SIZE: i64 = 50;
+
+//Highest page a client may ask for, so the page offset and the next page number never overflow
+const MAX_PAGE: i64 = i64::MAX / PAGE_SIZE;
+
+//Media types of OPDS 1.2 feeds and the OpenSearch description
+const NAVIGATION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+
+//Media type of OPDS 2.0 feeds
+const OPDS2_TYPE: &str = "application/opds+json";
+
+//Whether a feed lists other feeds or books
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Navigation,
+    Acquisition,
+}
+
+//An entry of a navigation feed
+struct Navigation {
+    title: String,
+    path: String,
+    kind: Kind,
+    rel: &'static str,
+    count: Option<i64>,
+}
+
+//A book as an acquisition feed shows it
+struct Publication {
+    book: Book,
+    authors: Vec<(i32, String)>,
+    genres: Vec<(String, String)>,
+    cover: Option<String>,
+    //attached e-book files as (format, content type, byte size)
+    files: Vec<(String, String, i64)>,
+}
+
+//A feed, independent of how it is serialized; paths are relative to the catalog root
+struct Feed {
+    path: String,
+    title: String,
+    kind: Kind,
+    up: Option<String>,
+    query: Option<String>,
+    page: i64,
+    more: bool,
+    navigation: Vec<Navigation>,
+    publications: Vec<Publication>,
+}
+
+//OPDS 1.2 or 2.0, and where each version's catalog lives
+#[derive(Clone, Copy, PartialEq)]
+enum Version {
+    Atom,
+    Json,
+}
+
+impl Version {
+    fn href(self, path: &str) -> String {
+        let root = match self {
+            Version::Atom => "/api/rust/opds",
+            Version::Json => "/api/rust/opds2",
+        };
+        if path.is_empty() { root.to_string() } else { format!("{}/{}", root, path) }
+    }
+}
+
+//name of the catalog shown by readers, from OPDS_TITLE
+fn catalog_title() -> String {
+    env::var("OPDS_TITLE").unwrap_or_else(|_| "Library".to_string())
+}
+
+//path with ?page= for a page other than the first, keeping the search query
+fn page_path(path: &str, query: Option<&str>, page: i64) -> String {
+    let mut params = Vec::new();
+    if let Some(query) = query {
+        params.push(format!("q={}", percent_encode(query)));
+    }
+    if page > 1 {
+        params.push(format!("page={}", page));
+    }
+    if params.is_empty() { path.to_string() } else { format!("{}?{}", path, params.join("&")) }
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+//books matching a condition, one page at a time, with their authors, genres and covers.
+//The condition and order may use $1.. for params; limit and offset are appended after them.
+fn get_publications(
+    client: &mut Client,
+    condition: &str,
+    order: &str,
+    params: &[&(dyn ToSql + Sync)],
+    page: i64
+) -> Result<(Vec<Publication>, bool), PostgresError> {
+    let sql = format!(
+        "SELECT b.id, b.title, b.author, b.genre, b.isbn10, b.isbn13, b.version, b.created_at, b.updated_at, b.deleted_at, b.publisher, b.publication_year, b.page_count, b.language, b.format, b.edition FROM books b WHERE b.deleted_at IS NULL AND {} ORDER BY {} LIMIT ${} OFFSET ${}",
+        condition,
+        order,
+        params.len() + 1,
+        params.len() + 2
+    );
+    //one row past the page tells whether there is a next page
+    let limit = PAGE_SIZE + 1;
+    let offset = (page - 1) * PAGE_SIZE;
+    let mut all_params = params.to_vec();
+    all_params.push(&limit);
+    all_params.push(&offset);
+    let mut books: Vec<Book> = client.query(&sql, &all_params)?.iter().map(book_from_row).collect();
+    let more = books.len() as i64 > PAGE_SIZE;
+    books.truncate(PAGE_SIZE as usize);
+
+    let ids: Vec<i32> = books.iter().filter_map(|book| book.id).collect();
+    let mut authors: HashMap<i32, Vec<(i32, String)>> = HashMap::new();
+    for row in client.query(
+        "SELECT ba.book_id, a.id, a.name FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = ANY($1) AND ba.role = 'author' AND a.deleted_at IS NULL ORDER BY ba.position",
+        &[&ids]
+    )? {
+        authors.entry(row.get(0)).or_default().push((row.get(1), row.get(2)));
+    }
+    let mut genres: HashMap<i32, Vec<(String, String)>> = HashMap::new();
+    for row in client.query(
+        "SELECT bg.book_id, g.slug, g.name FROM book_genres bg JOIN genres g ON g.id = bg.genre_id WHERE bg.book_id = ANY($1) AND g.deleted_at IS NULL ORDER BY g.name",
+        &[&ids]
+    )? {
+        genres.entry(row.get(0)).or_default().push((row.get(1), row.get(2)));
+    }
+    let mut covers: HashMap<i32, String> = client
+        .query("SELECT book_id, content_type FROM book_covers WHERE book_id = ANY($1)", &[&ids])?
+        .iter()
+        .map(|row| (row.get(0), row.get(1)))
+        .collect();
+    let mut files: HashMap<i32, Vec<(String, String, i64)>> = HashMap::new();
+    for row in client.query("SELECT book_id, format, content_type, byte_size FROM book_files WHERE book_id = ANY($1) ORDER BY format", &[&ids])? {
+        files.entry(row.get(0)).or_default().push((row.get(1), row.get(2), row.get(3)));
+    }
+
+    let publications = books
+        .into_iter()
+        .map(|book| {
+            let id = book.id.unwrap_or_default();
+            Publication {
+                authors: authors.remove(&id).unwrap_or_default(),
+                genres: genres.remove(&id).unwrap_or_default(),
+                cover: covers.remove(&id),
+                files: files.remove(&id).unwrap_or_default(),
+                book,
+            }
+        })
+        .collect();
+    Ok((publications, more))
+}
+
+//navigation entries from (id, name, book count) rows, one page at a time
+fn get_navigation(
+    client: &mut Client,
+    sql: &str,
+    prefix: &str,
+    page: i64
+) -> Result<(Vec<Navigation>, bool), PostgresError> {
+    let mut rows = client.query(&format!("{} LIMIT $1 OFFSET $2", sql), &[&(PAGE_SIZE + 1), &((page - 1) * PAGE_SIZE)])?;
+    let more = rows.len() as i64 > PAGE_SIZE;
+    rows.truncate(PAGE_SIZE as usize);
+    let entries = rows
+        .iter()
+        .map(|row| Navigation {
+            title: row.get(1),
+            path: format!("{}/{}", prefix, row.get::<_, i32>(0)),
+            kind: Kind::Acquisition,
+            rel: "subsection",
+            count: Some(row.get(2)),
+        })
+        .collect();
+    Ok((entries, more))
+}
+
+fn root_feed() -> Feed {
+    let entry = |title: &str, path: &str, kind: Kind, rel: &'static str| Navigation {
+        title: title.to_string(),
+        path: path.to_string(),
+        kind,
+        rel,
+        count: None,
+    };
+    Feed {
+        path: String::new(),
+        title: catalog_title(),
+        kind: Kind::Navigation,
+        up: None,
+        query: None,
+        page: 1,
+        more: false,
+        navigation: vec![
+            entry("New arrivals", "new", Kind::Acquisition, "http://opds-spec.org/sort/new"),
+            entry("All books", "books", Kind::Acquisition, "subsection"),
+            entry("By author", "authors", Kind::Navigation, "subsection"),
+            entry("By genre", "genres", Kind::Navigation, "subsection"),
+            entry("By series", "series", Kind::Navigation, "subsection"),
+        ],
+        publications: Vec::new(),
+    }
+}
+
+//build the feed at a catalog path; None when there is no such feed
+fn get_feed(client: &mut Client, request: &str, page: i64) -> Result<Option<Feed>, PostgresError> {
+    let section = get_path_segment(request, 4);
+    let id = get_path_segment(request, 5);
+    let acquisition = |path: String, title: String, up: &str, (publications, more): (Vec<Publication>, bool)| Feed {
+        path,
+        title,
+        kind: Kind::Acquisition,
+        up: Some(up.to_string()),
+        query: None,
+        page,
+        more,
+        navigation: Vec::new(),
+        publications,
+    };
+    let navigation = |path: &str, title: &str, (navigation, more): (Vec<Navigation>, bool)| Feed {
+        path: path.to_string(),
+        title: title.to_string(),
+        kind: Kind::Navigation,
+        up: Some(String::new()),
+        query: None,
+        page,
+        more,
+        navigation,
+        publications: Vec::new(),
+    };
+
+    let feed = match (section, id) {
+        ("", "") => root_feed(),
+        ("new", "") =>
+            acquisition("new".to_string(), "New arrivals".to_string(), "", get_publications(client, "TRUE", "b.created_at DESC, b.id DESC", &[], page)?),
+        ("books", "") =>
+            acquisition("books".to_string(), "All books".to_string(), "", get_publications(client, "TRUE", "lower(b.title), b.id", &[], page)?),
+        ("search", "") => {
+            //OpenSearch fills in ?q=; OPDS 2.0 templates conventionally use ?query=
+            let query = get_query_param(request, "q").or_else(|| get_query_param(request, "query")).unwrap_or_default();
+            let pattern = format!("%{}%", query.trim());
+            let isbn: String = query.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_uppercase();
+            let publications = get_publications(
+                client,
+                "(b.title ILIKE $1 OR b.author ILIKE $1 OR b.publisher ILIKE $1 OR b.isbn13 = $2 OR b.isbn10 = $2
+                OR EXISTS (SELECT 1 FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = b.id AND a.deleted_at IS NULL AND a.name ILIKE $1))",
+                "lower(b.title), b.id",
+                &[&pattern, &isbn],
+                page
+            )?;
+            let title = format!("Search: {}", query.trim());
+            Feed { query: Some(query), ..acquisition("search".to_string(), title, "", publications) }
+        }
+        ("authors", "") =>
+            navigation("authors", "By author", get_navigation(
+                client,
+                "SELECT a.id, a.name, count(b.id) FROM authors a JOIN book_authors ba ON ba.author_id = a.id JOIN books b ON b.id = ba.book_id AND b.deleted_at IS NULL
+                WHERE a.deleted_at IS NULL GROUP BY a.id ORDER BY a.sort_name, a.id",
+                "authors",
+                page
+            )?),
+        ("genres", "") =>
+            navigation("genres", "By genre", get_navigation(
+                client,
+                "SELECT g.id, g.name, count(b.id) FROM genres g JOIN book_genres bg ON bg.genre_id = g.id JOIN books b ON b.id = bg.book_id AND b.deleted_at IS NULL
+                WHERE g.deleted_at IS NULL GROUP BY g.id ORDER BY lower(g.name), g.id",
+                "genres",
+                page
+            )?),
+        ("series", "") =>
+            navigation("series", "By series", get_navigation(
+                client,
+                "SELECT s.id, s.name, count(b.id) FROM series s JOIN book_series bs ON bs.series_id = s.id JOIN books b ON b.id = bs.book_id AND b.deleted_at IS NULL
+                WHERE s.deleted_at IS NULL GROUP BY s.id ORDER BY lower(s.name), s.id",
+                "series",
+                page
+            )?),
+        ("authors", id) => {
+            let id = match id.parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => return Ok(None),
+            };
+            let name: String = match client.query_opt("SELECT name FROM authors WHERE id = $1 AND deleted_at IS NULL", &[&id])? {
+                Some(row) => row.get(0),
+                None => return Ok(None),
+            };
+            let publications = get_publications(
+                client,
+                "EXISTS (SELECT 1 FROM book_authors ba WHERE ba.book_id = b.id AND ba.author_id = $1)",
+                "b.publication_year NULLS LAST, lower(b.title), b.id",
+                &[&id],
+                page
+            )?;
+            acquisition(format!("authors/{}", id), name, "authors", publications)
+        }
+        ("genres", id) => {
+            let id = match id.parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => return Ok(None),
+            };
+            let name: String = match client.query_opt("SELECT name FROM genres WHERE id = $1 AND deleted_at IS NULL", &[&id])? {
+                Some(row) => row.get(0),
+                None => return Ok(None),
+            };
+            //a genre includes its subgenres, as in the book list
+            let publications = get_publications(
+                client,
+                "EXISTS (SELECT 1 FROM book_genres bg WHERE bg.book_id = b.id AND bg.genre_id IN (
+                    WITH RECURSIVE subgenres AS (SELECT id FROM genres WHERE id = $1 UNION SELECT g.id FROM genres g JOIN subgenres s ON g.parent_id = s.id)
+                    SELECT id FROM subgenres))",
+                "lower(b.title), b.id",
+                &[&id],
+                page
+            )?;
+            acquisition(format!("genres/{}", id), name, "genres", publications)
+        }
+        ("series", id) => {
+            let id = match id.parse::<i32>() {
+                Ok(id) => id,
+                Err(_) => return Ok(None),
+            };
+            let name: String = match client.query_opt("SELECT name FROM series WHERE id = $1 AND deleted_at IS NULL", &[&id])? {
+                Some(row) => row.get(0),
+                None => return Ok(None),
+            };
+            let publications = get_publications(
+                client,
+                "EXISTS (SELECT 1 FROM book_series bs WHERE bs.book_id = b.id AND bs.series_id = $1)",
+                "(SELECT bs.position FROM book_series bs WHERE bs.book_id = b.id AND bs.series_id = $1), lower(b.title), b.id",
+                &[&id],
+                page
+            )?;
+            acquisition(format!("series/{}", id), name, "series", publications)
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(feed))
+}
+
+fn rfc3339(time: Option<DateTime<Utc>>) -> String {
+    time.unwrap_or_else(Utc::now).to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn atom_type(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Navigation => NAVIGATION_TYPE,
+        Kind::Acquisition => ACQUISITION_TYPE,
+    }
+}
+
+fn atom_link(rel: &str, href: &str, media_type: &str) -> String {
+    format!("<link rel=\"{}\" href=\"{}\" type=\"{}\"/>", escape_xml(rel), escape_xml(href), escape_xml(media_type))
+}
+
+//links every feed carries: self, start, up, search and paging
+fn feed_links(feed: &Feed, version: Version) -> Vec<(&'static str, String)> {
+    //the search feed keeps its query across pages
+    let (path, query) = (feed.path.as_str(), feed.query.as_deref());
+    let mut links = vec![("self", version.href(&page_path(path, query, feed.page))), ("start", version.href(""))];
+    if let Some(up) = &feed.up {
+        links.push(("up", version.href(up)));
+    }
+    if feed.page > 1 {
+        links.push(("previous", version.href(&page_path(path, query, feed.page - 1))));
+    }
+    if feed.more {
+        links.push(("next", version.href(&page_path(path, query, feed.page + 1))));
+    }
+    links
+}
+
+fn atom_entry(publication: &Publication) -> String {
+    let book = &publication.book;
+    let id = book.id.unwrap_or_default();
+    let mut xml = format!(
+        "<entry><title>{}</title><id>urn:library:book:{}</id><updated>{}</updated>",
+        escape_xml(&book.title),
+        id,
+        rfc3339(book.updated_at)
+    );
+    if publication.authors.is_empty() {
+        xml.push_str(&format!("<author><name>{}</name></author>", escape_xml(&book.author)));
+    }
+    for (author_id, name) in &publication.authors {
+        xml.push_str(
+            &format!("<author><name>{}</name><uri>{}</uri></author>", escape_xml(name), Version::Atom.href(&format!("authors/{}", author_id)))
+        );
+    }
+    if let Some(isbn) = book.isbn13.as_ref().or(book.isbn10.as_ref()) {
+        xml.push_str(&format!("<dc:identifier>urn:isbn:{}</dc:identifier>", escape_xml(isbn)));
+    }
+    if let Some(language) = &book.language {
+        xml.push_str(&format!("<dc:language>{}</dc:language>", escape_xml(language)));
+    }
+    if let Some(publisher) = &book.publisher {
+        xml.push_str(&format!("<dc:publisher>{}</dc:publisher>", escape_xml(publisher)));
+    }
+    if let Some(year) = book.publication_year {
+        xml.push_str(&format!("<dc:issued>{}</dc:issued>", year));
+    }
+    for (slug, name) in &publication.genres {
+        xml.push_str(&format!("<category term=\"{}\" label=\"{}\"/>", escape_xml(slug), escape_xml(name)));
+    }
+    if let Some(content_type) = &publication.cover {
+        xml.push_str(&atom_link("http://opds-spec.org/image", &format!("/api/rust/books/{}/cover", id), content_type));
+        xml.push_str(&atom_link("http://opds-spec.org/image/thumbnail", &format!("/api/rust/books/{}/cover?size=small", id), "image/jpeg"));
+    }
+    for (format, content_type, size) in &publication.files {
+        xml.push_str(
+            &format!(
+                "<link rel=\"http://opds-spec.org/acquisition\" href=\"/api/rust/books/{}/files/{}\" type=\"{}\" length=\"{}\"/>",
+                id,
+                escape_xml(format),
+                escape_xml(content_type),
+                size
+            )
+        );
+    }
+    xml.push_str(&atom_link("alternate", &format!("/api/rust/books/{}", id), "application/json"));
+    xml.push_str("</entry>");
+    xml
+}
+
+fn write_atom(feed: &Feed) -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:dc=\"http://purl.org/dc/terms/\" xmlns:opds=\"http://opds-spec.org/2010/catalog\">"
+    );
+    xml.push_str(&format!("<id>urn:library:opds:{}</id>", escape_xml(&feed.path)));
+    xml.push_str(&format!("<title>{}</title>", escape_xml(&feed.title)));
+    let updated = feed.publications.iter().filter_map(|publication| publication.book.updated_at).max();
+    xml.push_str(&format!("<updated>{}</updated>", rfc3339(updated)));
+    xml.push_str(&format!("<author><name>{}</name></author>", escape_xml(&catalog_title())));
+    for (rel, href) in feed_links(feed, Version::Atom) {
+        //start and up always lead to navigation feeds
+        let media_type = if rel == "start" || rel == "up" { NAVIGATION_TYPE } else { atom_type(feed.kind) };
+        xml.push_str(&atom_link(rel, &href, media_type));
+    }
+    xml.push_str(&atom_link("search", &Version::Atom.href("opensearch.xml"), OPENSEARCH_TYPE));
+
+    for entry in &feed.navigation {
+        let href = Version::Atom.href(&entry.path);
+        xml.push_str(
+            &format!(
+                "<entry><title>{}</title><id>urn:library:opds:{}</id><updated>{}</updated>",
+                escape_xml(&entry.title),
+                escape_xml(&entry.path),
+                rfc3339(None)
+            )
+        );
+        if let Some(count) = entry.count {
+            xml.push_str(&format!("<content type=\"text\">{} {}</content>", count, if count == 1 { "book" } else { "books" }));
+        }
+        xml.push_str(&atom_link(entry.rel, &href, atom_type(entry.kind)));
+        xml.push_str("</entry>");
+    }
+    for publication in &feed.publications {
+        xml.push_str(&atom_entry(publication));
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+fn json_publication(publication: &Publication) -> Value {
+    let book = &publication.book;
+    let id = book.id.unwrap_or_default();
+    let authors: Vec<Value> = if publication.authors.is_empty() {
+        vec![json!({ "name": book.author })]
+    } else {
+        publication.authors
+            .iter()
+            .map(|(author_id, name)| json!({ "name": name, "links": [{ "href": Version::Json.href(&format!("authors/{}", author_id)), "type": OPDS2_TYPE }] }))
+            .collect()
+    };
+    let mut metadata = json!({
+        "@type": "http://schema.org/Book",
+        "identifier": match book.isbn13.as_ref().or(book.isbn10.as_ref()) {
+            Some(isbn) => format!("urn:isbn:{}", isbn),
+            None => format!("urn:library:book:{}", id),
+        },
+        "title": book.title,
+        "author": authors,
+        "modified": rfc3339(book.updated_at),
+    });
+    let optional = [
+        ("publisher", book.publisher.as_ref().map(|publisher| json!(publisher))),
+        ("published", book.publication_year.map(|year| json!(year.to_string()))),
+        ("language", book.language.as_ref().map(|language| json!(language))),
+        ("numberOfPages", book.page_count.map(|pages| json!(pages))),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            metadata[name] = value;
+        }
+    }
+    if !publication.genres.is_empty() {
+        metadata["subject"] = publication.genres.iter().map(|(slug, name)| json!({ "name": name, "code": slug })).collect();
+    }
+
+    let mut links = vec![json!({ "rel": "alternate", "href": format!("/api/rust/books/{}", id), "type": "application/json" })];
+    for (format, content_type, _) in &publication.files {
+        links.push(
+            json!({ "rel": "http://opds-spec.org/acquisition", "href": format!("/api/rust/books/{}/files/{}", id, format), "type": content_type })
+        );
+    }
+    let mut item = json!({ "metadata": metadata, "links": links });
+    if let Some(content_type) = &publication.cover {
+        item["images"] = json!([
+            { "href": format!("/api/rust/books/{}/cover", id), "type": content_type },
+            { "href": format!("/api/rust/books/{}/cover?size=small", id), "type": "image/jpeg", "width": 150 },
+        ]);
+    }
+    item
+}
+
+fn write_json(feed: &Feed) -> String {
+    let mut links: Vec<Value> = feed_links(feed, Version::Json)
+        .into_iter()
+        .map(|(rel, href)| json!({ "rel": rel, "href": href, "type": OPDS2_TYPE }))
+        .collect();
+    links.push(json!({ "rel": "search", "href": format!("{}{{?query}}", Version::Json.href("search")), "type": OPDS2_TYPE, "templated": true }));
+    let mut document = json!({
+        "metadata": { "title": feed.title, "itemsPerPage": PAGE_SIZE, "currentPage": feed.page },
+        "links": links,
+    });
+    if feed.kind == Kind::Navigation {
+        document["navigation"] = feed.navigation
+            .iter()
+            .map(|entry| {
+                let mut link = json!({ "href": Version::Json.href(&entry.path), "title": entry.title, "type": OPDS2_TYPE, "rel": entry.rel });
+                if let Some(count) = entry.count {
+                    link["properties"] = json!({ "numberOfItems": count });
+                }
+                link
+            })
+            .collect();
+    } else {
+        document["publications"] = feed.publications.iter().map(json_publication).collect();
+    }
+    document.to_string()
+}
+
+//OpenSearch description pointing search at both catalog versions; templates are absolute, as the spec asks
+fn write_opensearch(request: &str) -> String {
+    let scheme = get_header(request, "X-Forwarded-Proto").unwrap_or("http");
+    let base = format!("{}://{}", scheme, get_header(request, "Host").unwrap_or("localhost:8080"));
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<OpenSearchDescription xmlns=\"http://a9.com/-/spec/opensearch/1.1/\"><ShortName>{}</ShortName><Description>Search the {} catalog by title, author, publisher or ISBN</Description><InputEncoding>UTF-8</InputEncoding><OutputEncoding>UTF-8</OutputEncoding><Url type=\"{}\" template=\"{}\"/><Url type=\"{}\" template=\"{}\"/></OpenSearchDescription>\n",
+        escape_xml(&catalog_title()),
+        escape_xml(&catalog_title()),
+        escape_xml(ACQUISITION_TYPE),
+        escape_xml(&format!("{}{}?q={{searchTerms}}", base, Version::Atom.href("search"))),
+        OPDS2_TYPE,
+        escape_xml(&format!("{}{}?q={{searchTerms}}", base, Version::Json.href("search")))
+    )
+}
+
+//handle OPDS request for either catalog version
+pub fn handle_opds_request(request: &str) -> (String, String) {
+    let version = if get_path_segment(request, 3) == "opds2" { Version::Json } else { Version::Atom };
+    if version == Version::Atom && get_path_segment(request, 4) == "opensearch.xml" {
+        return (with_content_type(OK_RESPONSE, OPENSEARCH_TYPE), write_opensearch(request));
+    }
+    let page = match get_query_param(request, "page").map(|page| page.parse::<i64>()) {
+        Some(Ok(page)) if (1..=MAX_PAGE).contains(&page) => page,
+        Some(_) => return (BAD_REQUEST.to_string(), "Invalid page".to_string()),
+        None => 1,
+    };
+
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+    match get_feed(&mut client, request, page) {
+        Ok(Some(feed)) =>
+            match version {
+                Version::Atom => (with_content_type(OK_RESPONSE, &format!("{};charset=utf-8", atom_type(feed.kind))), write_atom(&feed)),
+                Version::Json => (with_content_type(OK_RESPONSE, OPDS2_TYPE), write_json(&feed)),
+            }
+        Ok(None) => (NOT_FOUND.to_string(), "Feed not found".to_string()),
+        Err(e) => {
+            eprintln!("OPDS feed failed: {}", e);
+            (INTERNAL_ERROR.to_string(), "Internal error".to_string())
+        }
+    }
+}
@@ -0,0 +1,443 @@
+//Reading history, and importing it from Goodreads and StoryGraph exports
+
+use crate::{
+    authors,
+    get_header,
+    get_id,
+    get_path_segment,
+    get_query_param,
+    insert_book,
+    insert_review,
+    internal_error,
+    isbn,
+    multipart,
+    normalize_book,
+    Book,
+    RequestContext,
+    Review,
+    BAD_REQUEST,
+    DB_URL,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    OK_RESPONSE,
+};
+use chrono::NaiveDate;
+use postgres::{ Client, NoTls, Row, Transaction };
+use postgres::Error as PostgresError;
+use std::collections::HashMap;
+
+//Shelves a book can be on; every import maps its own shelf names onto these
+const STATUSES: [&str; 4] = ["read", "currently-reading", "to-read", "did-not-finish"];
+
+//One book of a user's reading history
+#[derive(Serialize)]
+struct HistoryEntry {
+    book_id: i32,
+    title: String,
+    author: String,
+    status: String,
+    shelves: Vec<String>,
+    date_added: Option<NaiveDate>,
+    date_read: Option<NaiveDate>,
+    read_count: i32,
+    source: String,
+}
+
+//A row of an export file, read into one shape whichever service wrote it
+#[derive(Default)]
+struct ImportEntry {
+    title: String,
+    author: String,
+    isbns: Vec<String>,
+    publisher: Option<String>,
+    publication_year: Option<i32>,
+    page_count: Option<i32>,
+    format: Option<String>,
+    rating: Option<i32>,
+    review: Option<String>,
+    status: String,
+    shelves: Vec<String>,
+    date_added: Option<NaiveDate>,
+    date_read: Option<NaiveDate>,
+    read_count: i32,
+}
+
+//Reads an export row, given a lookup of its fields by column name
+type EntryReader = fn(&dyn Fn(&str) -> String) -> ImportEntry;
+
+//What became of one row of an import
+#[derive(Serialize)]
+struct EntryResult {
+    row: usize,
+    title: String,
+    outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    book_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    review: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+//Outcome of an import
+#[derive(Serialize)]
+struct ImportReport {
+    source: String,
+    user_id: i32,
+    dry_run: bool,
+    matched: usize,
+    created: usize,
+    skipped: usize,
+    reviews_created: usize,
+    entries: Vec<EntryResult>,
+}
+
+fn history_entry_from_row(row: &Row) -> HistoryEntry {
+    HistoryEntry {
+        book_id: row.get(0),
+        title: row.get(1),
+        author: row.get(2),
+        status: row.get(3),
+        shelves: row.get(4),
+        date_added: row.get(5),
+        date_read: row.get(6),
+        read_count: row.get(7),
+        source: row.get(8),
+    }
+}
+
+//importing user from ?user_id= or X-User-Id
+fn get_importer(request: &str) -> Result<i32, String> {
+    match get_query_param(request, "user_id").as_deref().or_else(|| get_header(request, "X-User-Id")) {
+        Some(user_id) => user_id.parse().map_err(|_| "Invalid user id".to_string()),
+        None => Err("user_id or X-User-Id is required".to_string()),
+    }
+}
+
+//Goodreads wraps ISBNs as ="0439023483" so spreadsheets keep the leading zero
+fn clean_isbn(value: &str) -> Option<String> {
+    let value = value.trim().trim_start_matches('=').trim_matches('"').trim();
+    isbn::parse(value).ok()
+}
+
+//"2023/01/15" as Goodreads and StoryGraph write dates, or ISO 8601
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    let value = value.trim();
+    NaiveDate::parse_from_str(value, "%Y/%m/%d").or_else(|_| NaiveDate::parse_from_str(value, "%Y-%m-%d")).ok()
+}
+
+//"Mockingjay (The Hunger Games, #3)" -> "Mockingjay"
+fn strip_series(title: &str) -> &str {
+    let title = title.trim();
+    match title.rsplit_once(" (") {
+        Some((name, series)) if series.ends_with(')') && series.contains('#') => name.trim(),
+        _ => title,
+    }
+}
+
+//map a binding or format name onto a book format
+fn parse_format(binding: &str) -> Option<String> {
+    let binding = binding.to_lowercase();
+    let format = if binding.contains("audio") {
+        "audiobook"
+    } else if binding.contains("kindle") || binding.contains("ebook") || binding.contains("digital") || binding.contains("nook") {
+        "ebook"
+    } else if binding.contains("paperback") {
+        "paperback"
+    } else if binding.contains("hardcover") {
+        "hardcover"
+    } else {
+        return None;
+    };
+    Some(format.to_string())
+}
+
+//exclusive shelf or read status onto one of STATUSES; custom exclusive shelves count as to-read
+fn parse_status(shelf: &str) -> String {
+    let shelf = shelf.trim().to_lowercase().replace(' ', "-");
+    match shelf.as_str() {
+        "dnf" | "abandoned" => "did-not-finish".to_string(),
+        "" => "to-read".to_string(),
+        status if STATUSES.contains(&status) => shelf,
+        _ => "to-read".to_string(),
+    }
+}
+
+//comma-separated shelf or tag names, without the status shelf
+fn parse_shelves(value: &str, status: &str) -> Vec<String> {
+    let mut shelves: Vec<String> = Vec::new();
+    for shelf in value.split(',').map(str::trim).filter(|shelf| !shelf.is_empty() && *shelf != status) {
+        if !shelves.iter().any(|known| known == shelf) {
+            shelves.push(shelf.to_string());
+        }
+    }
+    shelves
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    Some(value.trim().to_string()).filter(|value| !value.is_empty())
+}
+
+//read a Goodreads library export row
+fn goodreads_entry(field: &dyn Fn(&str) -> String) -> ImportEntry {
+    let status = parse_status(&field("Exclusive Shelf"));
+    let mut author = field("Author");
+    //additional authors are listed "A. Name, B. Name"; joined with & so each is credited separately
+    for additional in field("Additional Authors").split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        author = format!("{} & {}", author, additional);
+    }
+    ImportEntry {
+        title: strip_series(&field("Title")).to_string(),
+        author,
+        isbns: [field("ISBN13"), field("ISBN")].iter().filter_map(|value| clean_isbn(value)).collect(),
+        publisher: non_empty(&field("Publisher")),
+        publication_year: field("Original Publication Year").trim().parse().or_else(|_| field("Year Published").trim().parse()).ok(),
+        page_count: field("Number of Pages").trim().parse().ok().filter(|pages| *pages > 0),
+        format: parse_format(&field("Binding")),
+        rating: field("My Rating").trim().parse().ok().filter(|rating| *rating > 0),
+        review: non_empty(&field("My Review").replace("<br/>", "\n").replace("<br />", "\n")),
+        shelves: parse_shelves(&field("Bookshelves"), &status),
+        date_added: parse_date(&field("Date Added")),
+        date_read: parse_date(&field("Date Read")),
+        read_count: field("Read Count").trim().parse().unwrap_or(0),
+        status,
+    }
+}
+
+//read a StoryGraph export row; star ratings may be fractional and are rounded
+fn storygraph_entry(field: &dyn Fn(&str) -> String) -> ImportEntry {
+    let status = parse_status(&field("Read Status"));
+    ImportEntry {
+        title: field("Title").trim().to_string(),
+        author: field("Authors"),
+        isbns: clean_isbn(&field("ISBN/UID")).into_iter().collect(),
+        format: parse_format(&field("Format")),
+        rating: field("Star Rating").trim().parse::<f64>().ok().map(|rating| rating.round() as i32).filter(|rating| *rating > 0),
+        review: non_empty(&field("Review")),
+        shelves: parse_shelves(&field("Tags"), &status),
+        date_added: parse_date(&field("Date Added")),
+        date_read: parse_date(&field("Last Date Read")),
+        read_count: field("Read Count").trim().parse().unwrap_or(0),
+        status,
+        ..ImportEntry::default()
+    }
+}
+
+//a live book with one of the ISBNs, or else the same title by the same author
+fn match_book(transaction: &mut Transaction, entry: &ImportEntry) -> Result<Option<i32>, PostgresError> {
+    for isbn13 in &entry.isbns {
+        if let Some(row) = transaction.query_opt("SELECT id FROM books WHERE isbn13 = $1 AND deleted_at IS NULL", &[isbn13])? {
+            return Ok(Some(row.get(0)));
+        }
+    }
+    let author = authors::split_names(&entry.author).into_iter().next().unwrap_or_default();
+    authors::find_book(transaction, &entry.title, &author)
+}
+
+//catalogue a book that was not matched
+fn create_book(transaction: &mut Transaction, context: &RequestContext, entry: &ImportEntry) -> Result<i32, String> {
+    let mut book = Book {
+        title: entry.title.clone(),
+        author: entry.author.clone(),
+        isbn13: entry.isbns.first().cloned(),
+        publisher: entry.publisher.clone(),
+        publication_year: entry.publication_year,
+        page_count: entry.page_count,
+        format: entry.format.clone(),
+        ..Book::default()
+    };
+    //a year outside the catalogue's range is dropped rather than losing the book
+    if normalize_book(&mut book).is_err() {
+        book.publication_year = None;
+        normalize_book(&mut book)?;
+    }
+    let book = insert_book(transaction, context, &book).map_err(|e| e.to_string())?;
+    Ok(book.id.unwrap_or_default())
+}
+
+//import one entry inside its own savepoint; returns the outcome, book and review status
+fn import_entry(
+    transaction: &mut Transaction,
+    context: &RequestContext,
+    user_id: i32,
+    source: &str,
+    entry: &ImportEntry
+) -> Result<(&'static str, i32, Option<&'static str>), String> {
+    let (outcome, book_id) = match match_book(transaction, entry).map_err(|e| e.to_string())? {
+        Some(book_id) => ("matched", book_id),
+        None => ("created", create_book(transaction, context, entry)?),
+    };
+
+    //re-importing the same file leaves the review written the first time alone
+    let review = match entry.rating {
+        Some(rating) => {
+            let exists = transaction
+                .query_opt("SELECT 1 FROM reviews WHERE book_id = $1 AND user_id = $2 AND deleted_at IS NULL", &[&book_id, &user_id])
+                .map_err(|e| e.to_string())?
+                .is_some();
+            if exists {
+                Some("exists")
+            } else {
+                let review = Review {
+                    book_id,
+                    user_id: Some(user_id),
+                    rating: rating.clamp(1, 5),
+                    review_text: entry.review.clone(),
+                    ..Review::default()
+                };
+                insert_review(transaction, context, &review).map_err(|e| e.to_string())?;
+                Some("created")
+            }
+        }
+        None if entry.review.is_some() => Some("skipped: no rating"),
+        None => None,
+    };
+
+    transaction
+        .execute(
+            "INSERT INTO reading_history (user_id, book_id, status, shelves, date_added, date_read, read_count, source) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (user_id, book_id) DO UPDATE SET status = EXCLUDED.status, shelves = EXCLUDED.shelves,
+                date_added = COALESCE(EXCLUDED.date_added, reading_history.date_added), date_read = COALESCE(EXCLUDED.date_read, reading_history.date_read),
+                read_count = GREATEST(EXCLUDED.read_count, reading_history.read_count), source = EXCLUDED.source, updated_at = now()",
+            &[&user_id, &book_id, &entry.status, &entry.shelves, &entry.date_added, &entry.date_read, &entry.read_count.max(0), &source]
+        )
+        .map_err(|e| e.to_string())?;
+
+    Ok((outcome, book_id, review))
+}
+
+//handle reading history import from a Goodreads or StoryGraph CSV export; ?dry_run=true only reports
+pub fn handle_import_history_request(request: &str, body: &[u8], context: &RequestContext) -> (String, String) {
+    let source = get_path_segment(request, 4).to_string();
+    let (reader_for, required): (EntryReader, [&str; 2]) = match source.as_str() {
+        "goodreads" => (goodreads_entry, ["Title", "Author"]),
+        "storygraph" => (storygraph_entry, ["Title", "Authors"]),
+        _ => return (NOT_FOUND.to_string(), "Unknown import source".to_string()),
+    };
+    let user_id = match get_importer(request) {
+        Ok(user_id) => user_id,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+    let dry_run = get_query_param(request, "dry_run").as_deref() == Some("true");
+    let data = if get_header(request, "Content-Type").unwrap_or_default().to_lowercase().starts_with("multipart/form-data") {
+        match multipart::parse(request, body).map(|parts| multipart::take_file(parts, "file")) {
+            Ok(Some(file)) => file.data,
+            Ok(None) => return (BAD_REQUEST.to_string(), "No file in upload".to_string()),
+            Err(e) => return (BAD_REQUEST.to_string(), e),
+        }
+    } else {
+        body.to_vec()
+    };
+
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(data.as_slice());
+    let columns: HashMap<String, usize> = match reader.headers() {
+        Ok(headers) => headers.iter().enumerate().map(|(index, header)| (header.trim().trim_start_matches('\u{feff}').to_string(), index)).collect(),
+        Err(_) => return (BAD_REQUEST.to_string(), "CSV header row missing".to_string()),
+    };
+    if let Some(missing) = required.iter().find(|column| !columns.contains_key(**column)) {
+        return (BAD_REQUEST.to_string(), format!("Not a {} export: missing column {}", source, missing));
+    }
+
+    let mut client = match Client::connect(DB_URL, NoTls) {
+        Ok(client) => client,
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return internal_error(e),
+    };
+    match transaction.query_opt("SELECT 1 FROM users WHERE id = $1 AND deleted_at IS NULL", &[&user_id]) {
+        Ok(Some(_)) => {}
+        Ok(None) => return (NOT_FOUND.to_string(), "User not found".to_string()),
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+
+    let mut report = ImportReport {
+        source: source.clone(),
+        user_id,
+        dry_run,
+        matched: 0,
+        created: 0,
+        skipped: 0,
+        reviews_created: 0,
+        entries: Vec::new(),
+    };
+    for (number, record) in reader.records().enumerate() {
+        let row = number + 2;
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                report.skipped += 1;
+                report.entries.push(EntryResult { row, title: String::new(), outcome: "skipped".to_string(), book_id: None, review: None, reason: Some(e.to_string()) });
+                continue;
+            }
+        };
+        let field = |name: &str| columns.get(name).and_then(|index| record.get(*index)).unwrap_or_default().to_string();
+        let entry = reader_for(&field);
+        let mut result = EntryResult { row, title: entry.title.clone(), outcome: "skipped".to_string(), book_id: None, review: None, reason: None };
+
+        if entry.title.is_empty() || entry.author.trim().is_empty() {
+            result.reason = Some("Title and author are required".to_string());
+        } else {
+            let mut savepoint = match transaction.transaction() {
+                Ok(savepoint) => savepoint,
+                Err(e) => return internal_error(e),
+            };
+            match import_entry(&mut savepoint, context, user_id, &source, &entry) {
+                Ok((outcome, book_id, review)) => {
+                    if let Err(e) = savepoint.commit() {
+                        return internal_error(e);
+                    }
+                    result.outcome = outcome.to_string();
+                    result.book_id = Some(book_id);
+                    result.review = review.map(str::to_string);
+                }
+                Err(e) => result.reason = Some(e),
+            }
+        }
+
+        match result.outcome.as_str() {
+            "matched" => report.matched += 1,
+            "created" => report.created += 1,
+            _ => report.skipped += 1,
+        }
+        if result.review.as_deref() == Some("created") {
+            report.reviews_created += 1;
+        }
+        report.entries.push(result);
+    }
+
+    if !dry_run {
+        if let Err(e) = transaction.commit() {
+            return internal_error(e);
+        }
+    }
+    (OK_RESPONSE.to_string(), serde_json::to_string(&report).unwrap())
+}
+
+//handle get reading history request; ?status= narrows to one shelf
+pub fn handle_get_reading_history_request(request: &str) -> (String, String) {
+    let status = get_query_param(request, "status");
+    if matches!(&status, Some(status) if !STATUSES.contains(&status.as_str())) {
+        return (BAD_REQUEST.to_string(), format!("Invalid status (expected one of {})", STATUSES.join(", ")));
+    }
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) =>
+            match
+                client.query(
+                    "SELECT h.book_id, b.title, b.author, h.status, h.shelves, h.date_added, h.date_read, h.read_count, h.source FROM reading_history h
+                    JOIN books b ON b.id = h.book_id AND b.deleted_at IS NULL
+                    WHERE h.user_id = $1 AND ($2::varchar IS NULL OR h.status = $2)
+                    ORDER BY h.date_read DESC NULLS LAST, h.date_added DESC NULLS LAST, h.book_id",
+                    &[&id, &status]
+                )
+            {
+                Ok(rows) => {
+                    let entries: Vec<HistoryEntry> = rows.iter().map(history_entry_from_row).collect();
+                    (OK_RESPONSE.to_string(), serde_json::to_string(&entries).unwrap())
+                }
+                Err(_) => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
@@ -0,0 +1,586 @@
+//Book series and the order of their volumes
+
+use crate::{
+    apply_merge_patch,
+    book_from_row,
+    etag,
+    get_cascade,
+    get_header,
+    get_id,
+    get_include_deleted,
+    get_path_segment,
+    get_patch_request_body,
+    get_query_param,
+    internal_error,
+    not_modified,
+    precondition_failed,
+    with_header,
+    write_audit,
+    Book,
+    RequestContext,
+    BAD_REQUEST,
+    CONFLICT,
+    DB_URL,
+    FORBIDDEN,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    NOT_MODIFIED,
+    OK_RESPONSE,
+    PRECONDITION_FAILED,
+};
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, NoTls, Row, Transaction };
+use postgres::Error as PostgresError;
+use serde_json::{ json, Value };
+
+//Series struct with id, name, description and version
+#[derive(Serialize, Deserialize)]
+pub struct Series {
+    pub id: Option<i32>,
+    pub name: String,
+    pub description: Option<String>,
+    pub version: Option<i32>,
+    #[serde(skip_deserializing)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+//Book at its place in a series, with the reading state of the requesting user
+#[derive(Serialize)]
+struct Volume {
+    position: f64,
+    book: Book,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    borrowed: Option<bool>,
+}
+
+//Series with its volumes in order
+#[derive(Serialize)]
+struct SeriesDetail {
+    #[serde(flatten)]
+    series: Series,
+    books: Vec<Volume>,
+}
+
+//Series a book belongs to, with its position
+#[derive(Serialize)]
+struct Membership {
+    position: f64,
+    series: Series,
+}
+
+//Membership request body
+#[derive(Deserialize)]
+struct PositionRequest {
+    position: f64,
+}
+
+//map series row to Series
+fn series_from_row(row: &Row) -> Series {
+    Series {
+        id: row.get(0),
+        name: row.get(1),
+        description: row.get(2),
+        version: row.get(3),
+        created_at: row.get(4),
+        updated_at: row.get(5),
+        deleted_at: row.get(6),
+    }
+}
+
+//user whose reading state is shown: ?user_id=, else the X-User-Id header
+fn get_reader(request: &str) -> Result<Option<i32>, ()> {
+    match get_query_param(request, "user_id").as_deref().or_else(|| get_header(request, "X-User-Id")) {
+        Some(user_id) => user_id.parse().map(Some).map_err(|_| ()),
+        None => Ok(None),
+    }
+}
+
+//a book counts as read once the user has returned a loan of it, reviewed it or shelved it as read
+const READ: &str =
+    "(EXISTS (SELECT 1 FROM loans WHERE loans.book_id = b.id AND loans.user_id = $2 AND loans.return_date IS NOT NULL AND loans.deleted_at IS NULL) OR EXISTS (SELECT 1 FROM reviews WHERE reviews.book_id = b.id AND reviews.user_id = $2 AND reviews.deleted_at IS NULL) OR EXISTS (SELECT 1 FROM reading_history WHERE reading_history.book_id = b.id AND reading_history.user_id = $2 AND reading_history.status = 'read'))";
+
+//a book is borrowed while the user has a loan of it that is still open
+const BORROWED: &str =
+    "EXISTS (SELECT 1 FROM loans WHERE loans.book_id = b.id AND loans.user_id = $2 AND loans.return_date IS NULL AND loans.deleted_at IS NULL)";
+
+//volumes of a series in reading order, with read and borrowed flags when a user is given
+fn get_volumes(client: &mut Client, series_id: i32, user_id: Option<i32>) -> Result<Vec<Volume>, PostgresError> {
+    let rows = client.query(
+        &format!(
+            "SELECT b.id, b.title, b.author, b.genre, b.isbn10, b.isbn13, b.version, b.created_at, b.updated_at, b.deleted_at, b.publisher, b.publication_year, b.page_count, b.language, b.format, b.edition, bs.position, {}, {} FROM book_series bs JOIN books b ON b.id = bs.book_id WHERE bs.series_id = $1 AND b.deleted_at IS NULL ORDER BY bs.position, b.title, b.id",
+            READ,
+            BORROWED
+        ),
+        &[&series_id, &user_id]
+    )?;
+    Ok(
+        rows
+            .iter()
+            .map(|row| Volume {
+                position: row.get(16),
+                book: book_from_row(row),
+                read: user_id.map(|_| row.get(17)),
+                borrowed: user_id.map(|_| row.get(18)),
+            })
+            .collect()
+    )
+}
+
+//validate a series
+fn validate_series(series: &mut Series) -> Result<(), String> {
+    series.name = series.name.trim().to_string();
+    if series.name.is_empty() {
+        return Err("Series name is required".to_string());
+    }
+    Ok(())
+}
+
+//lock a live series
+fn lock_series(transaction: &mut Transaction, id: i32) -> Result<Option<Series>, PostgresError> {
+    let row = transaction.query_opt(
+        "SELECT id, name, description, version, created_at, updated_at, deleted_at FROM series WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+        &[&id]
+    )?;
+    Ok(row.as_ref().map(series_from_row))
+}
+
+//memberships of live books in a series, as the audit log records them
+fn get_memberships(transaction: &mut Transaction, id: i32) -> Result<Vec<Value>, PostgresError> {
+    Ok(
+        transaction
+            .query(
+                "SELECT bs.book_id, bs.position FROM book_series bs JOIN books b ON b.id = bs.book_id WHERE bs.series_id = $1 AND b.deleted_at IS NULL ORDER BY bs.position, bs.book_id",
+                &[&id]
+            )?
+            .iter()
+            .map(|row| json!({ "book_id": row.get::<_, i32>(0), "position": row.get::<_, f64>(1) }))
+            .collect()
+    )
+}
+
+//trash or restore a series and audit the memberships that this hides or shows
+fn set_series_trashed(transaction: &mut Transaction, context: &RequestContext, id: i32, trashed: bool) -> Result<Series, PostgresError> {
+    let memberships = get_memberships(transaction, id)?;
+    let row = transaction.query_one(
+        "UPDATE series SET deleted_at = CASE WHEN $2 THEN now() END, version = version + 1 WHERE id = $1 RETURNING id, name, description, version, created_at, updated_at, deleted_at",
+        &[&id, &trashed]
+    )?;
+
+    for membership in &memberships {
+        if trashed {
+            write_audit(transaction, context, "delete", "book_series", id, Some(membership), None)?;
+        } else {
+            write_audit(transaction, context, "create", "book_series", id, None, Some(membership))?;
+        }
+    }
+    Ok(series_from_row(&row))
+}
+
+//find a live series by name, creating it if needed
+pub fn find_or_create_series(transaction: &mut Transaction, context: &RequestContext, name: &str) -> Result<i32, PostgresError> {
+    let name = name.trim();
+    if let Some(row) = transaction.query_opt("SELECT id FROM series WHERE lower(name) = lower($1) AND deleted_at IS NULL ORDER BY id LIMIT 1", &[&name])? {
+        return Ok(row.get(0));
+    }
+
+    let row = transaction.query_one(
+        "INSERT INTO series (name) VALUES ($1) RETURNING id, name, description, version, created_at, updated_at, deleted_at",
+        &[&name]
+    )?;
+    let series = series_from_row(&row);
+    let id = series.id.unwrap_or_default();
+    write_audit(transaction, context, "create", "series", id, None, Some(&series))?;
+    Ok(id)
+}
+
+//deserialize series from request body without id
+fn get_series_request_body(request: &str) -> Result<Series, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize series position request body
+fn get_position_request_body(request: &str) -> Result<PositionRequest, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//handle post series request
+pub fn handle_post_series_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_series_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(mut series), Ok(mut client)) => {
+            if let Err(e) = validate_series(&mut series) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let row = match
+                transaction.query_one(
+                    "INSERT INTO series (name, description) VALUES ($1, $2) RETURNING id, name, description, version, created_at, updated_at, deleted_at",
+                    &[&series.name, &series.description]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let series = series_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "create", "series", series.id.unwrap_or_default(), None, Some(&series)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&series).unwrap())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get series request; lists the books in order with the reader's read and borrowed state
+pub fn handle_get_series_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_reader(request), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(user_id), Ok(include_deleted), Ok(mut client)) =>
+            match
+                client.query_one(
+                    "SELECT id, name, description, version, created_at, updated_at, deleted_at FROM series WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+                    &[&id, &include_deleted]
+                )
+            {
+                Ok(row) => {
+                    let series = series_from_row(&row);
+
+                    //conditional GET; the reading state changes without the series version, so only anonymous reads are cached
+                    let version = series.version.unwrap_or_default();
+                    if user_id.is_none() && not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    let books = match get_volumes(&mut client, id, user_id) {
+                        Ok(books) => books,
+                        Err(e) => return internal_error(e),
+                    };
+                    let detail = SeriesDetail { series, books };
+                    let status = if user_id.is_none() { with_header(OK_RESPONSE, "ETag", &etag(version)) } else { OK_RESPONSE.to_string() };
+                    (status, serde_json::to_string(&detail).unwrap())
+                }
+                _ => (NOT_FOUND.to_string(), "Series not found".to_string()),
+            }
+        (_, Err(_), _, _) => (BAD_REQUEST.to_string(), "Invalid user_id".to_string()),
+        (_, _, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get next unread request: the first book in the series the reader has not read yet
+pub fn handle_get_next_unread_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_reader(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(Some(user_id)), Ok(mut client)) => {
+            match client.query_opt("SELECT 1 FROM series WHERE id = $1 AND deleted_at IS NULL", &[&id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (NOT_FOUND.to_string(), "Series not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+
+            let volumes = match get_volumes(&mut client, id, Some(user_id)) {
+                Ok(volumes) => volumes,
+                Err(e) => return internal_error(e),
+            };
+            match volumes.into_iter().find(|volume| volume.read == Some(false)) {
+                Some(volume) => (OK_RESPONSE.to_string(), serde_json::to_string(&volume).unwrap()),
+                None => (NOT_FOUND.to_string(), "No unread books in series".to_string()),
+            }
+        }
+        (_, Ok(None), _) => (BAD_REQUEST.to_string(), "user_id or X-User-Id is required".to_string()),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid user_id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all series request, optionally filtered by ?name=
+pub fn handle_get_all_series_request(request: &str) -> (String, String) {
+    match (get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(include_deleted), Ok(mut client)) => {
+            let name = get_query_param(request, "name").map(|name| format!("%{}%", name));
+            let series: Vec<Series> = match
+                client.query(
+                    "SELECT id, name, description, version, created_at, updated_at, deleted_at FROM series WHERE ($1 OR deleted_at IS NULL) AND ($2::varchar IS NULL OR name ILIKE $2) ORDER BY lower(name), id",
+                    &[&include_deleted, &name]
+                )
+            {
+                Ok(rows) => rows.iter().map(series_from_row).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&series).unwrap())
+        }
+        (Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book series request
+pub fn handle_get_book_series_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let memberships: Vec<Membership> = match
+                client.query(
+                    "SELECT s.id, s.name, s.description, s.version, s.created_at, s.updated_at, s.deleted_at, bs.position FROM book_series bs JOIN series s ON s.id = bs.series_id WHERE bs.book_id = $1 AND s.deleted_at IS NULL ORDER BY lower(s.name)",
+                    &[&id]
+                )
+            {
+                Ok(rows) => rows.iter().map(|row| Membership { position: row.get(7), series: series_from_row(row) }).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&memberships).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put series book request: add a book to the series or move it to a new position
+pub fn handle_put_series_book_request(request: &str, context: &RequestContext) -> (String, String) {
+    match
+        (
+            get_id(request).parse::<i32>(),
+            get_path_segment(request, 6).parse::<i32>(),
+            get_position_request_body(request),
+            Client::connect(DB_URL, NoTls),
+        )
+    {
+        (Ok(id), Ok(book_id), Ok(membership), Ok(mut client)) => {
+            if !membership.position.is_finite() || membership.position < 0.0 {
+                return (BAD_REQUEST.to_string(), "Position must be a non-negative number".to_string());
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            match lock_series(&mut transaction, id) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (NOT_FOUND.to_string(), "Series not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+            match transaction.query_opt("SELECT 1 FROM books WHERE id = $1 AND deleted_at IS NULL", &[&book_id]) {
+                Ok(Some(_)) => {}
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            }
+
+            let before = match
+                transaction.query_opt("SELECT position FROM book_series WHERE series_id = $1 AND book_id = $2", &[&id, &book_id])
+            {
+                Ok(rows) => rows.map(|row| json!({ "book_id": book_id, "position": row.get::<_, f64>(0) })),
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) =
+                transaction.execute(
+                    "INSERT INTO book_series (series_id, book_id, position) VALUES ($1, $2, $3) ON CONFLICT (series_id, book_id) DO UPDATE SET position = EXCLUDED.position",
+                    &[&id, &book_id, &membership.position]
+                )
+            {
+                return internal_error(e);
+            }
+            let after = json!({ "book_id": book_id, "position": membership.position });
+            let action = if before.is_some() { "update" } else { "create" };
+            if let Err(e) = write_audit(&mut transaction, context, action, "book_series", id, before.as_ref(), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), after.to_string())
+        }
+        (_, Err(_), _, _) => (BAD_REQUEST.to_string(), "Invalid book id".to_string()),
+        (_, _, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete series book request: take a book out of the series
+pub fn handle_delete_series_book_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_path_segment(request, 6).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(book_id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let removed = match
+                transaction.query_opt("DELETE FROM book_series WHERE series_id = $1 AND book_id = $2 RETURNING position", &[&id, &book_id])
+            {
+                Ok(removed) => removed,
+                Err(e) => return internal_error(e),
+            };
+            let before = match removed {
+                Some(row) => json!({ "book_id": book_id, "position": row.get::<_, f64>(0) }),
+                None => return (NOT_FOUND.to_string(), "Book is not in this series".to_string()),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "book_series", id, Some(&before), None).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), "Book removed from series".to_string())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid book id".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put series request
+pub fn handle_put_series_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_series_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(series), Ok(mut client)) => update_series(&mut client, request, context, id, |_| Ok(series)),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle patch series request
+pub fn handle_patch_series_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_patch_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(patch), Ok(mut client)) =>
+            update_series(&mut client, request, context, id, |current| {
+                apply_merge_patch(current, &patch).map_err(|e| format!("Invalid patch: {}", e))
+            }),
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//lock a series, build its replacement from the stored row and write it back
+fn update_series(
+    client: &mut Client,
+    request: &str,
+    context: &RequestContext,
+    id: i32,
+    replacement: impl FnOnce(&Series) -> Result<Series, String>
+) -> (String, String) {
+    let mut transaction = match client.transaction() {
+        Ok(transaction) => transaction,
+        Err(e) => return internal_error(e),
+    };
+    let before = match lock_series(&mut transaction, id) {
+        Ok(Some(series)) => series,
+        Ok(None) => return (NOT_FOUND.to_string(), "Series not found".to_string()),
+        Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    };
+
+    //check the If-Match precondition against the locked row
+    if precondition_failed(request, before.version.unwrap_or_default()) {
+        return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+    }
+
+    let mut series = match replacement(&before) {
+        Ok(series) => series,
+        Err(e) => return (BAD_REQUEST.to_string(), e),
+    };
+    if let Err(e) = validate_series(&mut series) {
+        return (BAD_REQUEST.to_string(), e);
+    }
+
+    let row = match
+        transaction.query_one(
+            "UPDATE series SET name = $1, description = $2, version = version + 1 WHERE id = $3 RETURNING id, name, description, version, created_at, updated_at, deleted_at",
+            &[&series.name, &series.description, &id]
+        )
+    {
+        Ok(row) => row,
+        Err(e) => return internal_error(e),
+    };
+    let after = series_from_row(&row);
+    if let Err(e) = write_audit(&mut transaction, context, "update", "series", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+        return internal_error(e);
+    }
+
+    (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+}
+
+//handle restore series request
+pub fn handle_restore_series_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, name, description, version, created_at, updated_at, deleted_at FROM series WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => series_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Series not found in trash".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //its books are back in the series at the positions they kept
+            let after = match set_series_trashed(&mut transaction, context, id, false) {
+                Ok(series) => series,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "restore", "series", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), serde_json::to_string(&after).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete series request; series with live books are kept unless cascading, which hides their memberships until the series is restored
+pub fn handle_delete_series_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_cascade(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(cascade), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match lock_series(&mut transaction, id) {
+                Ok(Some(series)) => series,
+                Ok(None) => return (NOT_FOUND.to_string(), "Series not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let books: Vec<Value> = match get_memberships(&mut transaction, id) {
+                Ok(memberships) => memberships.iter().map(|membership| membership["book_id"].clone()).collect(),
+                Err(e) => return internal_error(e),
+            };
+            if !books.is_empty() && !cascade {
+                let conflict = json!({ "error": "Series has books", "book_ids": books });
+                return (CONFLICT.to_string(), conflict.to_string());
+            }
+
+            //move the row to the trash; the retention job purges it later.
+            //Its memberships stay in place, hidden while the series is trashed, so a restore brings them back.
+            let after = match set_series_trashed(&mut transaction, context, id, true) {
+                Ok(series) => series,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "series", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), "Series deleted".to_string())
+        }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
@@ -0,0 +1,974 @@
+//SRU 2.0 searchRetrieve and explain: CQL queries over the catalog, answered as Dublin Core or MARCXML
+
+use crate::{
+    book_from_row,
+    get_header,
+    get_form_param,
+    isbn,
+    language,
+    marc::{ self, escape_xml },
+    with_content_type,
+    Book,
+    DB_URL,
+    INTERNAL_ERROR,
+    OK_RESPONSE,
+};
+use postgres::types::ToSql;
+use postgres::{ Client, NoTls };
+use postgres::Error as PostgresError;
+use std::collections::HashMap;
+
+const SRU_TYPE: &str = "application/sru+xml; charset=utf-8";
+const RESPONSE_NAMESPACE: &str = "http://docs.oasis-open.org/ns/search-ws/sruResponse";
+const DIAGNOSTIC_NAMESPACE: &str = "http://docs.oasis-open.org/ns/search-ws/diagnostic";
+const EXPLAIN_NAMESPACE: &str = "http://explain.z3950.org/dtd/2.0/";
+
+//Records per response unless maximumRecords says otherwise, and the most it may ask for
+const DEFAULT_RECORDS: i64 = 10;
+const MAX_RECORDS: i64 = 100;
+
+//Deepest nesting of parentheses a query may use
+const MAX_DEPTH: usize = 32;
+
+//Record schemas served, as (short name, identifier, title)
+const SCHEMAS: [(&str, &str, &str); 2] = [
+    ("dc", "info:srw/schema/1/dc-v1.1", "Dublin Core"),
+    ("marcxml", "info:srw/schema/1/marcxml-v1.1", "MARC21 slim"),
+];
+
+//Context sets whose indexes are supported, as (prefix, identifier)
+const CONTEXT_SETS: [(&str, &str); 4] = [
+    ("cql", "info:srw/cql-context-set/1/cql-v1.2"),
+    ("dc", "info:srw/cql-context-set/1/dc-v1.1"),
+    ("bath", "http://zing.z3950.org/cql/bath/2.0/"),
+    ("rec", "info:srw/cql-context-set/2/rec-1.1"),
+];
+
+//What an index searches
+#[derive(Clone, Copy, PartialEq)]
+enum Field {
+    Anywhere,
+    AllRecords,
+    Title,
+    Creator,
+    Subject,
+    Publisher,
+    Date,
+    Language,
+    Identifier,
+    Id,
+}
+
+//Indexes by context set and name; names without a prefix match in any set
+const INDEXES: [(&str, &str, Field); 16] = [
+    ("cql", "serverChoice", Field::Anywhere),
+    ("cql", "anywhere", Field::Anywhere),
+    ("cql", "keywords", Field::Anywhere),
+    ("cql", "allRecords", Field::AllRecords),
+    ("dc", "title", Field::Title),
+    ("dc", "creator", Field::Creator),
+    ("dc", "subject", Field::Subject),
+    ("dc", "publisher", Field::Publisher),
+    ("dc", "date", Field::Date),
+    ("dc", "language", Field::Language),
+    ("dc", "identifier", Field::Identifier),
+    ("bath", "author", Field::Creator),
+    ("bath", "name", Field::Creator),
+    ("bath", "isbn", Field::Identifier),
+    ("bath", "subject", Field::Subject),
+    ("rec", "id", Field::Id),
+];
+
+//Diagnostic messages by number, from the SRU diagnostics list (info:srw/diagnostic/1/)
+const DIAGNOSTICS: [(u32, &str); 18] = [
+    (1, "General system error"),
+    (4, "Unsupported operation"),
+    (5, "Unsupported version"),
+    (6, "Unsupported parameter value"),
+    (7, "Mandatory parameter not supplied"),
+    (10, "Query syntax error"),
+    (15, "Unsupported context set"),
+    (16, "Unsupported index"),
+    (19, "Unsupported relation"),
+    (20, "Unsupported relation modifier"),
+    (36, "Term in invalid format for index or relation"),
+    (37, "Unsupported boolean operator"),
+    (46, "Unsupported boolean modifier"),
+    (48, "Query feature unsupported"),
+    (61, "First record position out of range"),
+    (66, "Unknown schema for retrieval"),
+    (71, "Unsupported record XML escaping"),
+    (82, "Unsupported sort sequence"),
+];
+
+//A diagnostic as SRU reports it: number and the detail it concerns
+struct Diagnostic {
+    code: u32,
+    details: String,
+}
+
+fn diagnostic(code: u32, details: &str) -> Diagnostic {
+    Diagnostic { code, details: details.to_string() }
+}
+
+//A token of a CQL query
+#[derive(PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Slash,
+    Symbol(String),
+    Word(String),
+    Quoted(String),
+}
+
+#[derive(Clone, Copy)]
+enum Boolean {
+    And,
+    Or,
+    Not,
+}
+
+//A parsed CQL query
+enum Query {
+    Clause {
+        index: Option<String>,
+        relation: String,
+        modifiers: Vec<String>,
+        term: String,
+    },
+    Boolean(Boolean, Box<Query>, Box<Query>),
+}
+
+//A sortby key: the index and its modifiers
+struct SortKey {
+    index: String,
+    modifiers: Vec<String>,
+}
+
+//split a query into tokens; backslash escapes stay in terms until they become patterns
+fn tokenize(query: &str) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '/' => tokens.push(Token::Slash),
+            '=' | '<' | '>' => {
+                let mut symbol = c.to_string();
+                if let Some(&next) = chars.peek() {
+                    if (c == '=' && next == '=') || (c == '<' && (next == '=' || next == '>')) || (c == '>' && next == '=') {
+                        symbol.push(next);
+                        chars.next();
+                    }
+                }
+                tokens.push(Token::Symbol(symbol));
+            }
+            '"' => {
+                let mut term = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            term.push('\\');
+                            term.extend(chars.next());
+                        }
+                        Some(c) => term.push(c),
+                        None => return Err(diagnostic(10, "unterminated quoted term")),
+                    }
+                }
+                tokens.push(Token::Quoted(term));
+            }
+            c => {
+                let mut word = c.to_string();
+                if c == '\\' {
+                    word.extend(chars.next());
+                }
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "()/=<>\"".contains(next) {
+                        break;
+                    }
+                    chars.next();
+                    word.push(next);
+                    if next == '\\' {
+                        word.extend(chars.next());
+                    }
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+//named relations, with or without the cql prefix
+fn is_named_relation(word: &str) -> bool {
+    let word = word.to_lowercase();
+    let word = word.strip_prefix("cql.").unwrap_or(&word);
+    ["adj", "all", "any", "within", "encloses", "exact"].contains(&word)
+}
+
+//Recursive descent over CQL tokens
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    //a whole query: clauses joined by booleans, then an optional sortby
+    fn parse(mut self) -> Result<(Query, Vec<SortKey>), Diagnostic> {
+        if self.tokens.is_empty() {
+            return Err(diagnostic(10, "empty query"));
+        }
+        let query = self.parse_scoped()?;
+        let mut sort = Vec::new();
+        if matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case("sortby")) {
+            self.position += 1;
+            while let Some(Token::Word(index)) = self.peek() {
+                let mut key = SortKey { index: index.clone(), modifiers: Vec::new() };
+                self.position += 1;
+                while self.peek() == Some(&Token::Slash) {
+                    self.position += 1;
+                    match self.next() {
+                        Some(Token::Word(modifier)) => key.modifiers.push(modifier.to_lowercase()),
+                        _ => return Err(diagnostic(10, "sort modifier expected after /")),
+                    }
+                }
+                sort.push(key);
+            }
+            if sort.is_empty() {
+                return Err(diagnostic(10, "sort key expected after sortby"));
+            }
+        }
+        if self.position < self.tokens.len() {
+            return Err(diagnostic(10, "unexpected text after query"));
+        }
+        Ok((query, sort))
+    }
+
+    //clauses joined by and, or and not, which bind equally and from the left
+    fn parse_scoped(&mut self) -> Result<Query, Diagnostic> {
+        let mut left = self.parse_clause()?;
+        while let Some(Token::Word(word)) = self.peek() {
+            let boolean = match word.to_lowercase().as_str() {
+                "and" => Boolean::And,
+                "or" => Boolean::Or,
+                "not" => Boolean::Not,
+                "prox" => return Err(diagnostic(37, "prox")),
+                _ => break,
+            };
+            let word = word.clone();
+            self.position += 1;
+            if self.peek() == Some(&Token::Slash) {
+                return Err(diagnostic(46, &word));
+            }
+            let right = self.parse_clause()?;
+            left = Query::Boolean(boolean, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    //a parenthesised query, `index relation term`, or a bare term
+    fn parse_clause(&mut self) -> Result<Query, Diagnostic> {
+        let first = match self.next() {
+            Some(Token::Open) => {
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    return Err(diagnostic(10, "query nested too deeply"));
+                }
+                let query = self.parse_scoped()?;
+                if self.next() != Some(&Token::Close) {
+                    return Err(diagnostic(10, "missing )"));
+                }
+                self.depth -= 1;
+                return Ok(query);
+            }
+            Some(Token::Symbol(symbol)) if symbol == ">" => return Err(diagnostic(48, "prefix assignment")),
+            Some(Token::Quoted(term)) => return Ok(Query::Clause { index: None, relation: "=".to_string(), modifiers: Vec::new(), term: term.clone() }),
+            Some(Token::Word(word)) => word.clone(),
+            _ => return Err(diagnostic(10, "search term expected")),
+        };
+
+        //a word is an index when a relation follows it; `dune any` alone is two terms short of a clause
+        let relation = match self.peek() {
+            Some(Token::Symbol(symbol)) => Some(symbol.clone()),
+            Some(Token::Word(word)) if is_named_relation(word) && matches!(self.tokens.get(self.position + 1), Some(Token::Word(_) | Token::Quoted(_) | Token::Slash)) =>
+                Some(word.to_lowercase()),
+            _ => None,
+        };
+        let relation = match relation {
+            Some(relation) => relation,
+            None => return Ok(Query::Clause { index: None, relation: "=".to_string(), modifiers: Vec::new(), term: first }),
+        };
+        self.position += 1;
+        let mut modifiers = Vec::new();
+        while self.peek() == Some(&Token::Slash) {
+            self.position += 1;
+            match self.next() {
+                Some(Token::Word(modifier)) => modifiers.push(modifier.to_lowercase()),
+                _ => return Err(diagnostic(10, "relation modifier expected after /")),
+            }
+            //a modifier may carry a value, as in /distance=1
+            if let Some(Token::Symbol(_)) = self.peek() {
+                self.position += 2;
+            }
+        }
+        match self.next() {
+            Some(Token::Word(term) | Token::Quoted(term)) => Ok(Query::Clause { index: Some(first), relation, modifiers, term: term.clone() }),
+            _ => Err(diagnostic(10, &format!("search term expected after {}", relation))),
+        }
+    }
+}
+
+//find an index by name; a prefix must name a supported context set
+fn lookup_index(index: &str) -> Result<Field, Diagnostic> {
+    let (set, name) = match index.split_once('.') {
+        Some((set, name)) => (Some(set), name),
+        None => (None, index),
+    };
+    if let Some(set) = set {
+        if !CONTEXT_SETS.iter().any(|(prefix, _)| prefix.eq_ignore_ascii_case(set)) {
+            return Err(diagnostic(15, set));
+        }
+    }
+    INDEXES
+        .iter()
+        .find(|(prefix, known, _)| known.eq_ignore_ascii_case(name) && set.is_none_or(|set| prefix.eq_ignore_ascii_case(set)))
+        .map(|(_, _, field)| *field)
+        .ok_or_else(|| diagnostic(16, index))
+}
+
+//ILIKE pattern for a CQL term: * and ? are masks, ^ anchors, a backslash makes the next character literal
+fn like_pattern(term: &str, exact: bool) -> String {
+    let mut term = term;
+    let anchored_start = exact || term.starts_with('^');
+    term = term.strip_prefix('^').unwrap_or(term);
+    //a trailing ^ anchors unless an odd run of backslashes escapes it
+    let caret_end = term.strip_suffix('^').filter(|rest| rest.chars().rev().take_while(|&c| c == '\\').count() % 2 == 0);
+    let anchored_end = exact || caret_end.is_some();
+    term = caret_end.unwrap_or(term);
+
+    let mut pattern = String::new();
+    if !anchored_start {
+        pattern.push('%');
+    }
+    let mut chars = term.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' =>
+                match chars.next() {
+                    Some(c @ ('%' | '_' | '\\')) => {
+                        pattern.push('\\');
+                        pattern.push(c);
+                    }
+                    Some(c) => pattern.push(c),
+                    None => {}
+                }
+            '*' => pattern.push('%'),
+            '?' => pattern.push('_'),
+            '%' | '_' => {
+                pattern.push('\\');
+                pattern.push(c);
+            }
+            c => pattern.push(c),
+        }
+    }
+    if !anchored_end {
+        pattern.push('%');
+    }
+    pattern
+}
+
+//condition matching a text field against the pattern in a parameter
+fn text_condition(field: Field, param: &str) -> String {
+    let title = format!("b.title ILIKE {}", param);
+    let creator = format!(
+        "b.author ILIKE {0} OR EXISTS (SELECT 1 FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = b.id AND a.deleted_at IS NULL AND (a.name ILIKE {0} OR a.sort_name ILIKE {0}))",
+        param
+    );
+    let subject = format!(
+        "b.genre ILIKE {0} OR EXISTS (SELECT 1 FROM book_genres bg JOIN genres g ON g.id = bg.genre_id WHERE bg.book_id = b.id AND g.deleted_at IS NULL AND g.name ILIKE {0})
+        OR EXISTS (SELECT 1 FROM book_tags bt JOIN tags t ON t.id = bt.tag_id WHERE bt.book_id = b.id AND t.deleted_at IS NULL AND t.name ILIKE {0})",
+        param
+    );
+    let publisher = format!("b.publisher ILIKE {}", param);
+    match field {
+        Field::Title => title,
+        Field::Creator => format!("({})", creator),
+        Field::Subject => format!("({})", subject),
+        Field::Publisher => publisher,
+        _ => format!("({} OR {} OR {} OR {} OR b.isbn13 ILIKE {4} OR b.isbn10 ILIKE {4})", title, creator, subject, publisher, param),
+    }
+}
+
+//A query translated to SQL, with its parameters numbered from $1
+#[derive(Default)]
+struct Search {
+    params: Vec<Box<dyn ToSql + Sync>>,
+}
+
+impl Search {
+    fn param<T: ToSql + Sync + 'static>(&mut self, value: T) -> String {
+        self.params.push(Box::new(value));
+        format!("${}", self.params.len())
+    }
+
+    fn translate(&mut self, query: &Query) -> Result<String, Diagnostic> {
+        let (index, relation, modifiers, term) = match query {
+            Query::Boolean(boolean, left, right) => {
+                let (left, right) = (self.translate(left)?, self.translate(right)?);
+                return Ok(match boolean {
+                    Boolean::And => format!("({} AND {})", left, right),
+                    Boolean::Or => format!("({} OR {})", left, right),
+                    Boolean::Not => format!("({} AND NOT COALESCE({}, FALSE))", left, right),
+                });
+            }
+            Query::Clause { index, relation, modifiers, term } => (index, relation, modifiers, term),
+        };
+        let field = match index {
+            Some(index) => lookup_index(index)?,
+            None => Field::Anywhere,
+        };
+        if let Some(modifier) = modifiers.first() {
+            return Err(diagnostic(20, modifier));
+        }
+        let relation = relation.strip_prefix("cql.").unwrap_or(relation);
+
+        match field {
+            Field::AllRecords => Ok("TRUE".to_string()),
+            Field::Anywhere | Field::Title | Field::Creator | Field::Subject | Field::Publisher =>
+                match relation {
+                    //every word (or any one of them) somewhere in the field
+                    "=" | "all" | "any" => {
+                        let words: Vec<&str> = term.split_whitespace().collect();
+                        if words.is_empty() {
+                            return Ok(text_condition(field, &self.param(like_pattern(term, false))));
+                        }
+                        let conditions: Vec<String> = words.iter().map(|word| text_condition(field, &self.param(like_pattern(word, false)))).collect();
+                        Ok(format!("({})", conditions.join(if relation == "any" { " OR " } else { " AND " })))
+                    }
+                    "adj" => Ok(text_condition(field, &self.param(like_pattern(term, false)))),
+                    "==" | "exact" => Ok(text_condition(field, &self.param(like_pattern(term, true)))),
+                    "<>" => Ok(format!("NOT COALESCE({}, FALSE)", text_condition(field, &self.param(like_pattern(term, true))))),
+                    relation => Err(diagnostic(19, relation)),
+                }
+            Field::Language => {
+                let code = language::normalize(term.split(['-', '_']).next().unwrap_or_default()).map_err(|_| diagnostic(36, term))?;
+                match relation {
+                    "=" | "==" | "exact" => Ok(format!("b.language = {}", self.param(code))),
+                    "<>" => Ok(format!("b.language IS DISTINCT FROM {}", self.param(code))),
+                    relation => Err(diagnostic(19, relation)),
+                }
+            }
+            Field::Identifier => {
+                let condition = match isbn::parse(term) {
+                    Ok(isbn13) => format!("b.isbn13 = {}", self.param(isbn13)),
+                    Err(_) => {
+                        let value: String = term.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_uppercase();
+                        let param = self.param(value);
+                        format!("(b.isbn13 = {0} OR b.isbn10 = {0})", param)
+                    }
+                };
+                match relation {
+                    "=" | "==" | "exact" => Ok(condition),
+                    "<>" => Ok(format!("NOT COALESCE({}, FALSE)", condition)),
+                    relation => Err(diagnostic(19, relation)),
+                }
+            }
+            Field::Date | Field::Id => {
+                let column = if field == Field::Date { "b.publication_year" } else { "b.id" };
+                //dates may be full ISO dates; the catalog keeps years
+                let number = |value: &str| -> Result<i32, Diagnostic> {
+                    value
+                        .parse()
+                        .ok()
+                        .or_else(|| if field == Field::Date { value.get(..4).and_then(|year| year.parse().ok()) } else { None })
+                        .ok_or_else(|| diagnostic(36, value))
+                };
+                match relation {
+                    "within" => {
+                        let bounds: Vec<&str> = term.split_whitespace().collect();
+                        if bounds.len() != 2 {
+                            return Err(diagnostic(36, term));
+                        }
+                        let (low, high) = (number(bounds[0])?, number(bounds[1])?);
+                        Ok(format!("{} BETWEEN {} AND {}", column, self.param(low), self.param(high)))
+                    }
+                    "=" | "==" | "exact" => Ok(format!("{} = {}", column, self.param(number(term)?))),
+                    "<>" | "<" | "<=" | ">" | ">=" => Ok(format!("{} {} {}", column, relation, self.param(number(term)?))),
+                    relation => Err(diagnostic(19, relation)),
+                }
+            }
+        }
+    }
+}
+
+//ORDER BY for sortby keys; the book id settles ties so paging is stable
+fn sort_order(keys: &[SortKey]) -> Result<String, Diagnostic> {
+    let mut order = Vec::new();
+    for key in keys {
+        let column = match lookup_index(&key.index)? {
+            Field::Title => "lower(b.title)",
+            Field::Creator => "lower(b.author)",
+            Field::Publisher => "lower(b.publisher)",
+            Field::Date => "b.publication_year",
+            Field::Language => "b.language",
+            Field::Id => "b.id",
+            _ => return Err(diagnostic(16, &key.index)),
+        };
+        let mut direction = "ASC";
+        for modifier in &key.modifiers {
+            match modifier.strip_prefix("sort.").unwrap_or(modifier) {
+                "ascending" => direction = "ASC",
+                "descending" => direction = "DESC",
+                "ignorecase" => {}
+                modifier => return Err(diagnostic(82, modifier)),
+            }
+        }
+        order.push(format!("{} {} NULLS LAST", column, direction));
+    }
+    order.push("b.id".to_string());
+    Ok(order.join(", "))
+}
+
+//a book as a Dublin Core record
+fn write_dc(book: &Book, creators: &[(String, String)], subjects: &[String]) -> String {
+    let mut xml = String::from("<srw_dc:dc xmlns:srw_dc=\"info:srw/schema/1/dc-schema\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">");
+    let mut element = |name: &str, value: &str| xml.push_str(&format!("<dc:{0}>{1}</dc:{0}>", name, escape_xml(value)));
+    element("title", &book.title);
+    if creators.iter().any(|(_, role)| role == "author") {
+        for (name, role) in creators {
+            element(if role == "author" { "creator" } else { "contributor" }, name);
+        }
+    } else {
+        element("creator", &book.author);
+    }
+    for subject in subjects {
+        element("subject", subject);
+    }
+    if let Some(publisher) = &book.publisher {
+        element("publisher", publisher);
+    }
+    if let Some(year) = book.publication_year {
+        element("date", &year.to_string());
+    }
+    element("type", "Text");
+    if let Some(format) = &book.format {
+        element("format", format);
+    }
+    if let Some(isbn) = book.isbn13.as_ref().or(book.isbn10.as_ref()) {
+        element("identifier", &format!("urn:isbn:{}", isbn));
+    }
+    if let Some(language) = &book.language {
+        element("language", language);
+    }
+    xml.push_str("</srw_dc:dc>");
+    xml
+}
+
+fn write_diagnostics(diagnostics: &[Diagnostic]) -> String {
+    if diagnostics.is_empty() {
+        return String::new();
+    }
+    let mut xml = String::from("<sruResponse:diagnostics>");
+    for Diagnostic { code, details } in diagnostics {
+        let message = DIAGNOSTICS.iter().find(|(known, _)| known == code).map(|(_, message)| *message).unwrap_or_default();
+        xml.push_str(
+            &format!(
+                "<diag:diagnostic xmlns:diag=\"{}\"><diag:uri>info:srw/diagnostic/1/{}</diag:uri><diag:details>{}</diag:details><diag:message>{}</diag:message></diag:diagnostic>",
+                DIAGNOSTIC_NAMESPACE,
+                code,
+                escape_xml(details),
+                message
+            )
+        );
+    }
+    xml.push_str("</sruResponse:diagnostics>");
+    xml
+}
+
+//a searchRetrieveResponse that carries only a diagnostic
+fn search_failure(diagnostic: Diagnostic) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sruResponse:searchRetrieveResponse xmlns:sruResponse=\"{}\"><sruResponse:version>2.0</sruResponse:version><sruResponse:numberOfRecords>0</sruResponse:numberOfRecords>{}</sruResponse:searchRetrieveResponse>",
+        RESPONSE_NAMESPACE,
+        write_diagnostics(&[diagnostic])
+    )
+}
+
+//Parameters of a searchRetrieve request once checked
+struct Retrieve {
+    start: i64,
+    maximum: i64,
+    schema: (&'static str, &'static str),
+    escaped: bool,
+}
+
+fn get_retrieve_params(request: &str) -> Result<Retrieve, Diagnostic> {
+    let number = |name: &str, default: i64, min: i64| match get_form_param(request, name) {
+        None => Ok(default),
+        Some(value) =>
+            match value.trim().parse::<i64>() {
+                Ok(number) if number >= min => Ok(number),
+                _ => Err(diagnostic(6, name)),
+            }
+    };
+    let start = number("startRecord", 1, 1)?;
+    let maximum = number("maximumRecords", DEFAULT_RECORDS, 0)?.min(MAX_RECORDS);
+    let schema = match get_form_param(request, "recordSchema") {
+        None => SCHEMAS[0],
+        Some(schema) =>
+            SCHEMAS.iter()
+                .find(|(name, identifier, _)| name.eq_ignore_ascii_case(&schema) || *identifier == schema)
+                .copied()
+                .ok_or_else(|| diagnostic(66, &schema))?,
+    };
+    let escaped = match get_form_param(request, "recordXMLEscaping").as_deref() {
+        None | Some("xml") => false,
+        Some("string") => true,
+        Some(other) => return Err(diagnostic(71, other)),
+    };
+    Ok(Retrieve { start, maximum, schema: (schema.0, schema.1), escaped })
+}
+
+//run a searchRetrieve; failures of the request itself come back as diagnostics, not errors
+fn search_retrieve(client: &mut Client, request: &str, query: &str) -> Result<String, PostgresError> {
+    let retrieve = match get_retrieve_params(request) {
+        Ok(retrieve) => retrieve,
+        Err(diagnostic) => return Ok(search_failure(diagnostic)),
+    };
+    let parsed = tokenize(query).and_then(|tokens| (Parser { tokens, position: 0, depth: 0 }).parse());
+    let mut search = Search::default();
+    let (condition, order) = match parsed.and_then(|(query, sort)| Ok((search.translate(&query)?, sort_order(&sort)?))) {
+        Ok(translated) => translated,
+        Err(diagnostic) => return Ok(search_failure(diagnostic)),
+    };
+
+    let params: Vec<&(dyn ToSql + Sync)> = search.params.iter().map(|param| param.as_ref()).collect();
+    let total: i64 = client.query_one(&format!("SELECT count(*) FROM books b WHERE b.deleted_at IS NULL AND {}", condition), &params)?.get(0);
+    let mut diagnostics = Vec::new();
+    let mut books = Vec::new();
+    if retrieve.start > total && total > 0 {
+        diagnostics.push(diagnostic(61, &retrieve.start.to_string()));
+    } else if retrieve.maximum > 0 {
+        let sql = format!(
+            "SELECT b.id, b.title, b.author, b.genre, b.isbn10, b.isbn13, b.version, b.created_at, b.updated_at, b.deleted_at, b.publisher, b.publication_year, b.page_count, b.language, b.format, b.edition FROM books b WHERE b.deleted_at IS NULL AND {} ORDER BY {} LIMIT ${} OFFSET ${}",
+            condition,
+            order,
+            params.len() + 1,
+            params.len() + 2
+        );
+        let offset = retrieve.start - 1;
+        let mut all_params = params.clone();
+        all_params.push(&retrieve.maximum);
+        all_params.push(&offset);
+        books = client.query(&sql, &all_params)?.iter().map(book_from_row).collect();
+    }
+
+    let ids: Vec<i32> = books.iter().filter_map(|book| book.id).collect();
+    let mut subjects = marc::get_subjects(client, &ids)?;
+    let mut creators: HashMap<i32, Vec<(String, String)>> = HashMap::new();
+    for row in client.query(
+        "SELECT ba.book_id, a.name, ba.role FROM book_authors ba JOIN authors a ON a.id = ba.author_id WHERE ba.book_id = ANY($1) AND a.deleted_at IS NULL ORDER BY ba.position, a.name",
+        &[&ids]
+    )? {
+        creators.entry(row.get(0)).or_default().push((row.get(1), row.get(2)));
+    }
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sruResponse:searchRetrieveResponse xmlns:sruResponse=\"{}\"><sruResponse:version>2.0</sruResponse:version><sruResponse:numberOfRecords>{}</sruResponse:numberOfRecords>",
+        RESPONSE_NAMESPACE,
+        total
+    );
+    if !books.is_empty() {
+        xml.push_str("<sruResponse:records>");
+        for (position, book) in books.iter().enumerate() {
+            let id = book.id.unwrap_or_default();
+            let (genres, tags) = subjects.remove(&id).unwrap_or_default();
+            let data = match retrieve.schema.0 {
+                "marcxml" => marc::write_marcxml(&marc::book_record(book, &genres, &tags)),
+                _ => {
+                    let all: Vec<String> = genres.into_iter().chain(tags).collect();
+                    write_dc(book, creators.get(&id).map(Vec::as_slice).unwrap_or_default(), &all)
+                }
+            };
+            xml.push_str(
+                &format!(
+                    "<sruResponse:record><sruResponse:recordSchema>{}</sruResponse:recordSchema><sruResponse:recordXMLEscaping>{}</sruResponse:recordXMLEscaping><sruResponse:recordData>{}</sruResponse:recordData><sruResponse:recordPosition>{}</sruResponse:recordPosition><sruResponse:recordIdentifier>urn:library:book:{}</sruResponse:recordIdentifier></sruResponse:record>",
+                    retrieve.schema.1,
+                    if retrieve.escaped { "string" } else { "xml" },
+                    if retrieve.escaped { escape_xml(&data) } else { data },
+                    retrieve.start + position as i64,
+                    id
+                )
+            );
+        }
+        xml.push_str("</sruResponse:records>");
+    }
+    let next = retrieve.start + books.len() as i64;
+    if !books.is_empty() && next <= total {
+        xml.push_str(&format!("<sruResponse:nextRecordPosition>{}</sruResponse:nextRecordPosition>", next));
+    }
+    xml.push_str(&write_diagnostics(&diagnostics));
+    xml.push_str("</sruResponse:searchRetrieveResponse>");
+    Ok(xml)
+}
+
+//the explain record: where the server is, its indexes, schemas and paging defaults
+fn write_explain(request: &str) -> String {
+    let host = get_header(request, "Host").unwrap_or("localhost:8080");
+    let (host, port) = host.split_once(':').unwrap_or((host, "80"));
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<sruResponse:explainResponse xmlns:sruResponse=\"{}\"><sruResponse:version>2.0</sruResponse:version><sruResponse:record><sruResponse:recordSchema>{}</sruResponse:recordSchema><sruResponse:recordXMLEscaping>xml</sruResponse:recordXMLEscaping><sruResponse:recordData>",
+        RESPONSE_NAMESPACE,
+        EXPLAIN_NAMESPACE
+    );
+    xml.push_str(
+        &format!(
+            "<zr:explain xmlns:zr=\"{}\"><zr:serverInfo protocol=\"SRU\" version=\"2.0\"><zr:host>{}</zr:host><zr:port>{}</zr:port><zr:database>api/rust/sru</zr:database></zr:serverInfo><zr:databaseInfo><zr:title>Library catalog</zr:title></zr:databaseInfo><zr:indexInfo>",
+            EXPLAIN_NAMESPACE,
+            escape_xml(host),
+            escape_xml(port)
+        )
+    );
+    for (prefix, identifier) in CONTEXT_SETS {
+        xml.push_str(&format!("<zr:set name=\"{}\" identifier=\"{}\"/>", prefix, identifier));
+    }
+    for (set, name, _) in INDEXES {
+        xml.push_str(&format!("<zr:index><zr:title>{1}</zr:title><zr:map><zr:name set=\"{0}\">{1}</zr:name></zr:map></zr:index>", set, name));
+    }
+    xml.push_str("</zr:indexInfo><zr:schemaInfo>");
+    for (name, identifier, title) in SCHEMAS {
+        xml.push_str(&format!("<zr:schema name=\"{}\" identifier=\"{}\"><zr:title>{}</zr:title></zr:schema>", name, identifier, title));
+    }
+    xml.push_str(
+        &format!(
+            "</zr:schemaInfo><zr:configInfo><zr:default type=\"numberOfRecords\">{}</zr:default><zr:setting type=\"maximumRecords\">{}</zr:setting></zr:configInfo></zr:explain>",
+            DEFAULT_RECORDS,
+            MAX_RECORDS
+        )
+    );
+    xml.push_str("</sruResponse:recordData></sruResponse:record></sruResponse:explainResponse>");
+    xml
+}
+
+//handle SRU request: searchRetrieve when there is a query, explain otherwise.
+//SRU reports bad requests as diagnostics in a 200 response, so only server failures use HTTP errors.
+pub fn handle_sru_request(request: &str) -> (String, String) {
+    if let Some(version) = get_form_param(request, "version").filter(|version| version != "2.0") {
+        return (with_content_type(OK_RESPONSE, SRU_TYPE), search_failure(diagnostic(5, &format!("{} (supported: 2.0)", version))));
+    }
+    let query = get_form_param(request, "query");
+    match (get_form_param(request, "operation").as_deref(), query) {
+        (Some("explain"), _) | (None, None) => (with_content_type(OK_RESPONSE, SRU_TYPE), write_explain(request)),
+        (Some("searchRetrieve") | None, Some(query)) => {
+            let mut client = match Client::connect(DB_URL, NoTls) {
+                Ok(client) => client,
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+            match search_retrieve(&mut client, request, &query) {
+                Ok(xml) => (with_content_type(OK_RESPONSE, SRU_TYPE), xml),
+                Err(e) => {
+                    eprintln!("SRU search failed: {}", e);
+                    (INTERNAL_ERROR.to_string(), "Internal error".to_string())
+                }
+            }
+        }
+        (Some("searchRetrieve"), None) => (with_content_type(OK_RESPONSE, SRU_TYPE), search_failure(diagnostic(7, "query"))),
+        (Some(operation), _) => (with_content_type(OK_RESPONSE, SRU_TYPE), search_failure(diagnostic(4, operation))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(query: &str) -> Vec<String> {
+        tokenize(query)
+            .unwrap_or_else(|e| panic!("{}: {}", e.code, e.details))
+            .iter()
+            .map(|token| match token {
+                Token::Open => "(".to_string(),
+                Token::Close => ")".to_string(),
+                Token::Slash => "/".to_string(),
+                Token::Symbol(symbol) => format!("sym:{}", symbol),
+                Token::Word(word) => format!("word:{}", word),
+                Token::Quoted(term) => format!("quoted:{}", term),
+            })
+            .collect()
+    }
+
+    fn parse(query: &str) -> Result<(Query, Vec<SortKey>), Diagnostic> {
+        tokenize(query).and_then(|tokens| (Parser { tokens, position: 0, depth: 0 }).parse())
+    }
+
+    //a parsed query written out fully parenthesised, so its structure can be compared
+    fn render(query: &Query) -> String {
+        match query {
+            Query::Clause { index, relation, modifiers, term } => {
+                let modifiers: String = modifiers.iter().map(|modifier| format!("/{}", modifier)).collect();
+                format!("[{} {}{} {}]", index.as_deref().unwrap_or("-"), relation, modifiers, term)
+            }
+            Query::Boolean(boolean, left, right) => {
+                let boolean = match boolean {
+                    Boolean::And => "and",
+                    Boolean::Or => "or",
+                    Boolean::Not => "not",
+                };
+                format!("({} {} {})", render(left), boolean, render(right))
+            }
+        }
+    }
+
+    fn parsed(query: &str) -> String {
+        match parse(query) {
+            Ok((query, _)) => render(&query),
+            Err(e) => panic!("{}: {}", e.code, e.details),
+        }
+    }
+
+    fn error(query: &str) -> (u32, String) {
+        match parse(query) {
+            Ok((query, _)) => panic!("parsed as {}", render(&query)),
+            Err(e) => (e.code, e.details),
+        }
+    }
+
+    fn translated(query: &str) -> Result<(String, usize), u32> {
+        let (query, _) = parse(query).map_err(|e| e.code)?;
+        let mut search = Search::default();
+        let condition = search.translate(&query).map_err(|e| e.code)?;
+        Ok((condition, search.params.len()))
+    }
+
+    #[test]
+    fn tokenize_splits_symbols_words_and_quoted_terms() {
+        assert_eq!(tokens("dc.title=dune"), ["word:dc.title", "sym:=", "word:dune"]);
+        assert_eq!(
+            tokens("a == b <> c <= d >= e < f > g"),
+            ["word:a", "sym:==", "word:b", "sym:<>", "word:c", "sym:<=", "word:d", "sym:>=", "word:e", "sym:<", "word:f", "sym:>", "word:g"]
+        );
+        assert_eq!(tokens("(title any/relevant \"the dune\")"), ["(", "word:title", "word:any", "/", "word:relevant", "quoted:the dune", ")"]);
+        assert!(tokens(" \t ").is_empty());
+    }
+
+    #[test]
+    fn tokenize_keeps_escapes_in_terms() {
+        assert_eq!(tokens(r#""say \"hi\"""#), [r#"quoted:say \"hi\""#]);
+        assert_eq!(tokens(r"a\(b\) \=x"), [r"word:a\(b\)", r"word:\=x"]);
+        assert_eq!(tokens(r"\ dune"), [r"word:\ dune"]);
+        assert_eq!(tokens("ends\\"), ["word:ends\\"]);
+        assert_eq!(tokens("\"é\\ü\""), ["quoted:é\\ü"]);
+    }
+
+    #[test]
+    fn tokenize_rejects_unterminated_quotes() {
+        for query in ["\"dune", "\"dune\\\"", "title = \"dune\\"] {
+            assert_eq!(tokenize(query).err().map(|e| e.code), Some(10), "{}", query);
+        }
+    }
+
+    #[test]
+    fn parser_builds_clauses() {
+        assert_eq!(parsed("dune"), "[- = dune]");
+        assert_eq!(parsed("\"dune messiah\""), "[- = dune messiah]");
+        assert_eq!(parsed("dc.title = dune"), "[dc.title = dune]");
+        assert_eq!(parsed("title ANY \"dune messiah\""), "[title any dune messiah]");
+        assert_eq!(parsed("title exact/ignorecase/distance=1 dune"), "[title exact/ignorecase/distance dune]");
+        //a named relation with no term after it is not taken as one
+        assert_eq!(error("dune any").1, "unexpected text after query");
+    }
+
+    #[test]
+    fn parser_joins_booleans_from_the_left_and_honours_parentheses() {
+        assert_eq!(parsed("a and b or c not d"), "((([- = a] and [- = b]) or [- = c]) not [- = d])");
+        assert_eq!(parsed("a and (b or c)"), "([- = a] and ([- = b] or [- = c]))");
+        assert_eq!(parsed("((a))"), "[- = a]");
+    }
+
+    #[test]
+    fn parser_reads_sort_keys() {
+        let (_, sort) = parse("dune sortby title/sort.descending date").unwrap_or_else(|e| panic!("{}", e.details));
+        let sort: Vec<(String, Vec<String>)> = sort.into_iter().map(|key| (key.index, key.modifiers)).collect();
+        assert_eq!(sort, [("title".to_string(), vec!["sort.descending".to_string()]), ("date".to_string(), vec![])]);
+        assert_eq!(error("dune sortby").0, 10);
+        assert_eq!(error("dune sortby title/").0, 10);
+    }
+
+    #[test]
+    fn parser_rejects_malformed_queries() {
+        assert_eq!(error("").0, 10);
+        assert_eq!(error("(").0, 10);
+        assert_eq!(error("(dune").1, "missing )");
+        assert_eq!(error("((dune)").1, "missing )");
+        assert_eq!(error("dune)").1, "unexpected text after query");
+        assert_eq!(error(")dune").0, 10);
+        assert_eq!(error("()").0, 10);
+        assert_eq!(error("dune and").0, 10);
+        assert_eq!(error("title =").1, "search term expected after =");
+        assert_eq!(error("title = /").0, 10);
+        assert_eq!(error("title any/ dune").0, 10);
+        assert_eq!(error("dune prox messiah").0, 37);
+        assert_eq!(error("dune and/rel messiah").0, 46);
+        assert_eq!(error("> dc = \"info:srw/cql-context-set/1/dc-v1.1\" dune").0, 48);
+        assert_eq!(error(&format!("{}dune{}", "(".repeat(MAX_DEPTH + 1), ")".repeat(MAX_DEPTH + 1))).1, "query nested too deeply");
+        assert_eq!(parsed(&format!("{}dune{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH))), "[- = dune]");
+    }
+
+    #[test]
+    fn like_pattern_translates_masks_anchors_and_escapes() {
+        assert_eq!(like_pattern("dune", false), "%dune%");
+        assert_eq!(like_pattern("dune", true), "dune");
+        assert_eq!(like_pattern("du*e?", false), "%du%e_%");
+        assert_eq!(like_pattern("^dune", false), "dune%");
+        assert_eq!(like_pattern("dune^", false), "%dune");
+        assert_eq!(like_pattern("^dune^", false), "dune");
+        assert_eq!(like_pattern("^", false), "%");
+        assert_eq!(like_pattern("^dune^", true), "dune");
+        assert_eq!(like_pattern("100%_off", false), r"%100\%\_off%");
+        assert_eq!(like_pattern(r"a\*b\?", false), "%a*b?%");
+        assert_eq!(like_pattern(r"a\%\_\\", false), r"%a\%\_\\%");
+        assert_eq!(like_pattern(r"caret\^", false), "%caret^%");
+        assert_eq!(like_pattern(r"slash\\^", false), r"%slash\\");
+        assert_eq!(like_pattern(r"mid^dle", false), "%mid^dle%");
+        assert_eq!(like_pattern("trailing\\", false), "%trailing%");
+        assert_eq!(like_pattern("ü^", false), "%ü");
+    }
+
+    #[test]
+    fn translate_builds_conditions_with_numbered_params() {
+        assert_eq!(translated("dc.title = dune"), Ok(("(b.title ILIKE $1)".to_string(), 1)));
+        assert_eq!(translated("title any \"dune messiah\""), Ok(("(b.title ILIKE $1 OR b.title ILIKE $2)".to_string(), 2)));
+        assert_eq!(translated("title == \"dune\" and dc.date > 1960"), Ok(("(b.title ILIKE $1 AND b.publication_year > $2)".to_string(), 2)));
+        assert_eq!(translated("title = a not title = b"), Ok(("((b.title ILIKE $1) AND NOT COALESCE((b.title ILIKE $2), FALSE))".to_string(), 2)));
+        assert_eq!(translated("dc.date within \"1960 1970-12-31\""), Ok(("b.publication_year BETWEEN $1 AND $2".to_string(), 2)));
+        assert_eq!(translated("rec.id = 7"), Ok(("b.id = $1".to_string(), 1)));
+        assert_eq!(translated("cql.allRecords = 1"), Ok(("TRUE".to_string(), 0)));
+        assert_eq!(translated("bath.isbn = 9780441013593"), Ok(("b.isbn13 = $1".to_string(), 1)));
+        assert_eq!(translated("dc.language = en"), Ok(("b.language = $1".to_string(), 1)));
+    }
+
+    #[test]
+    fn translate_reports_unsupported_parts() {
+        assert_eq!(translated("foo.title = dune"), Err(15));
+        assert_eq!(translated("dc.shelf = dune"), Err(16));
+        assert_eq!(translated("title < dune"), Err(19));
+        assert_eq!(translated("title =/stem dune"), Err(20));
+        assert_eq!(translated("dc.date = soon"), Err(36));
+        assert_eq!(translated("dc.date within 1960"), Err(36));
+        assert_eq!(translated("rec.id = 99999999999"), Err(36));
+        assert_eq!(translated("dc.language = zz-top"), Err(36));
+    }
+}
@@ -0,0 +1,78 @@
+//Blob storage for uploaded files
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{ Component, Path, PathBuf };
+
+//Place to keep uploaded files, addressed by slash-separated keys such as "covers/1/original"
+pub trait Storage {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()>;
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+    //move a stored file to another key, replacing what is there
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+}
+
+//Files under a directory on the local filesystem
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &str) -> LocalStorage {
+        LocalStorage { root: PathBuf::from(root) }
+    }
+
+    //keys may not climb out of the storage root
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        let relative = Path::new(key);
+        if key.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid storage key: {}", key)));
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl Storage for LocalStorage {
+    fn put(&self, key: &str, data: &[u8]) -> io::Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        //write beside the target and rename so readers never see a partial file
+        let partial = path.with_extension("partial");
+        fs::write(&partial, data)?;
+        fs::rename(&partial, &path)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)?) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (self.path(from)?, self.path(to)?);
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(from, to)
+    }
+}
+
+//storage for uploads; the local filesystem under STORAGE_DIR is the only backend so far
+pub fn configured_storage() -> Box<dyn Storage> {
+    let root = env::var("STORAGE_DIR").unwrap_or_else(|_| "storage".to_string());
+    Box::new(LocalStorage::new(&root))
+}
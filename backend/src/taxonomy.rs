@@ -0,0 +1,1068 @@
+//Genre taxonomy and free-form tags
+
+use crate::{
+    apply_merge_patch,
+    book_from_row,
+    etag,
+    get_cascade,
+    get_id,
+    get_include_deleted,
+    get_patch_request_body,
+    get_query_param,
+    internal_error,
+    not_modified,
+    precondition_failed,
+    with_header,
+    write_audit,
+    RequestContext,
+    BAD_REQUEST,
+    CONFLICT,
+    DB_URL,
+    FORBIDDEN,
+    INTERNAL_ERROR,
+    NOT_FOUND,
+    NOT_MODIFIED,
+    OK_RESPONSE,
+    PRECONDITION_FAILED,
+};
+use chrono::{ DateTime, Utc };
+use postgres::{ Client, NoTls, Row, Transaction };
+use postgres::error::SqlState;
+use postgres::Error as PostgresError;
+use serde_json::{ json, Value };
+
+//Spellings that drift, mapped to the canonical genre name
+const GENRE_SYNONYMS: [(&str, &str); 14] = [
+    ("scifi", "Science Fiction"),
+    ("sci fi", "Science Fiction"),
+    ("sf", "Science Fiction"),
+    ("science fiction", "Science Fiction"),
+    ("sff", "Science Fiction & Fantasy"),
+    ("ya", "Young Adult"),
+    ("young adult", "Young Adult"),
+    ("nonfiction", "Non-Fiction"),
+    ("non fiction", "Non-Fiction"),
+    ("bio", "Biography"),
+    ("biographies", "Biography"),
+    ("memoirs", "Memoir"),
+    ("classics", "Classic"),
+    ("mysteries", "Mystery"),
+];
+
+//Genre struct with id, name, slug, parent and version
+#[derive(Serialize, Deserialize)]
+pub struct Genre {
+    pub id: Option<i32>,
+    pub name: String,
+    pub slug: Option<String>,
+    pub parent_id: Option<i32>,
+    pub version: Option<i32>,
+    #[serde(skip_deserializing)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+//Genre with its subgenres, for ?tree=true
+#[derive(Serialize)]
+struct GenreNode {
+    #[serde(flatten)]
+    genre: Genre,
+    children: Vec<GenreNode>,
+}
+
+//Tag struct with id, name and version
+#[derive(Serialize, Deserialize)]
+pub struct Tag {
+    pub id: Option<i32>,
+    pub name: String,
+    pub version: Option<i32>,
+    #[serde(skip_deserializing)]
+    pub book_count: Option<i64>,
+    #[serde(skip_deserializing)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[serde(skip_deserializing)]
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+//Merge request body
+#[derive(Deserialize)]
+struct MergeRequest {
+    into: i32,
+}
+
+//map genres row to Genre
+fn genre_from_row(row: &Row) -> Genre {
+    Genre {
+        id: row.get(0),
+        name: row.get(1),
+        slug: row.get(2),
+        parent_id: row.get(3),
+        version: row.get(4),
+        created_at: row.get(5),
+        updated_at: row.get(6),
+        deleted_at: row.get(7),
+    }
+}
+
+//map tags row to Tag
+fn tag_from_row(row: &Row) -> Tag {
+    Tag {
+        id: row.get(0),
+        name: row.get(1),
+        version: row.get(2),
+        created_at: row.get(3),
+        updated_at: row.get(4),
+        deleted_at: row.get(5),
+        book_count: None,
+    }
+}
+
+//lowercase, single-spaced form of a genre used to match synonyms
+fn genre_key(name: &str) -> String {
+    name.to_lowercase()
+        .replace(['_', '-'], " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+//canonical display name for a genre string: "scifi" -> "Science Fiction", "horror" -> "Horror"
+pub fn normalize_genre_name(name: &str) -> String {
+    let key = genre_key(name);
+    if let Some((_, canonical)) = GENRE_SYNONYMS.iter().find(|(synonym, _)| *synonym == key) {
+        return canonical.to_string();
+    }
+    if name.chars().any(|c| c.is_uppercase()) {
+        return name.split_whitespace().collect::<Vec<_>>().join(" ");
+    }
+    key.split(' ')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+//URL-safe identifier for a genre name
+pub fn slugify(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+//map a failed genre or tag write to a response; duplicate names are a conflict
+fn write_error(e: PostgresError, what: &str) -> (String, String) {
+    match e.code() {
+        Some(code) if *code == SqlState::UNIQUE_VIOLATION =>
+            (CONFLICT.to_string(), format!("A {} with this name already exists", what)),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//move the books whose primary genre is one name to another, bumping and auditing each one
+fn rename_book_genre(transaction: &mut Transaction, context: &RequestContext, from: &str, to: &str) -> Result<(), PostgresError> {
+    let books: Vec<_> = transaction
+        .query(
+            "SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE genre = $1 ORDER BY id FOR UPDATE",
+            &[&from]
+        )?
+        .iter()
+        .map(book_from_row)
+        .collect();
+    for before in &books {
+        let id = before.id.unwrap_or_default();
+        let row = transaction.query_one(
+            "UPDATE books SET genre = $1, version = version + 1 WHERE id = $2 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+            &[&to, &id]
+        )?;
+        write_audit(transaction, context, "update", "books", id, Some(before), Some(&book_from_row(&row)))?;
+    }
+    Ok(())
+}
+
+//find a live genre by slug, creating a top-level one if needed
+pub fn find_or_create_genre(transaction: &mut Transaction, context: &RequestContext, name: &str) -> Result<i32, PostgresError> {
+    let slug = slugify(name);
+    if let Some(row) = transaction.query_opt("SELECT id FROM genres WHERE slug = $1 AND deleted_at IS NULL", &[&slug])? {
+        return Ok(row.get(0));
+    }
+
+    let row = transaction.query_one(
+        "INSERT INTO genres (name, slug) VALUES ($1, $2) RETURNING id, name, slug, parent_id, version, created_at, updated_at, deleted_at",
+        &[&name, &slug]
+    )?;
+    let genre = genre_from_row(&row);
+    let id = genre.id.unwrap_or_default();
+    write_audit(transaction, context, "create", "genres", id, None, Some(&genre))?;
+    Ok(id)
+}
+
+//follow a change of a book's genre string in its genre links
+pub fn link_book_genre(
+    transaction: &mut Transaction,
+    context: &RequestContext,
+    book_id: i32,
+    before: Option<&str>,
+    after: Option<&str>
+) -> Result<(), PostgresError> {
+    if let Some(before) = before {
+        transaction.execute(
+            "DELETE FROM book_genres WHERE book_id = $1 AND genre_id IN (SELECT id FROM genres WHERE slug = $2)",
+            &[&book_id, &slugify(before)]
+        )?;
+    }
+    if let Some(after) = after.filter(|genre| !slugify(genre).is_empty()) {
+        let genre_id = find_or_create_genre(transaction, context, after)?;
+        transaction.execute(
+            "INSERT INTO book_genres (book_id, genre_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&book_id, &genre_id]
+        )?;
+    }
+    Ok(())
+}
+
+//normalize legacy genre strings and link them to the taxonomy for books that have no genre links yet
+pub fn migrate_book_genres(client: &mut Client) -> Result<(), PostgresError> {
+    let mut transaction = client.transaction()?;
+    let context = RequestContext {
+        actor: Some("migration".to_string()),
+        client_ip: None,
+        request_id: crate::next_request_id(),
+    };
+
+    let rows = transaction.query(
+        "SELECT id, genre FROM books WHERE genre IS NOT NULL AND NOT EXISTS (SELECT 1 FROM book_genres WHERE book_id = books.id)",
+        &[]
+    )?;
+    for row in rows {
+        let book_id: i32 = row.get(0);
+        let genre: String = row.get(1);
+        let canonical = normalize_genre_name(&genre);
+        if canonical != genre {
+            transaction.execute("UPDATE books SET genre = $1 WHERE id = $2", &[&canonical, &book_id])?;
+        }
+        link_book_genre(&mut transaction, &context, book_id, None, Some(&canonical))?;
+    }
+    transaction.commit()
+}
+
+//resolve ?genre= given as an id or a slug
+pub fn resolve_genre(client: &mut Client, genre: &str) -> Result<Option<i32>, PostgresError> {
+    if let Ok(id) = genre.parse::<i32>() {
+        return Ok(Some(id));
+    }
+    Ok(client.query_opt("SELECT id FROM genres WHERE slug = $1 AND deleted_at IS NULL", &[&slugify(genre)])?.map(|row| row.get(0)))
+}
+
+//a genre may not become its own ancestor
+fn creates_cycle(transaction: &mut Transaction, id: i32, parent_id: i32) -> Result<bool, PostgresError> {
+    let row = transaction.query_one(
+        "WITH RECURSIVE ancestors AS (SELECT id, parent_id FROM genres WHERE id = $1 UNION ALL SELECT g.id, g.parent_id FROM genres g JOIN ancestors a ON g.id = a.parent_id) SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2)",
+        &[&parent_id, &id]
+    )?;
+    Ok(row.get(0))
+}
+
+//validate a genre and fill in its slug
+fn validate_genre(transaction: &mut Transaction, genre: &mut Genre) -> Result<(), String> {
+    genre.name = genre.name.trim().to_string();
+    if genre.name.is_empty() {
+        return Err("Genre name is required".to_string());
+    }
+    let slug = slugify(genre.slug.as_deref().unwrap_or(&genre.name));
+    if slug.is_empty() {
+        return Err("Genre slug is empty".to_string());
+    }
+    genre.slug = Some(slug);
+
+    if let Some(parent_id) = genre.parent_id {
+        let exists = transaction
+            .query_opt("SELECT 1 FROM genres WHERE id = $1 AND deleted_at IS NULL", &[&parent_id])
+            .map_err(|_| "Internal error".to_string())?;
+        if exists.is_none() {
+            return Err(format!("Parent genre {} not found", parent_id));
+        }
+        if let Some(id) = genre.id {
+            if creates_cycle(transaction, id, parent_id).map_err(|_| "Internal error".to_string())? {
+                return Err("A genre cannot be nested under itself".to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+//deserialize genre from request body without id
+fn get_genre_request_body(request: &str) -> Result<Genre, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize tag from request body without id
+fn get_tag_request_body(request: &str) -> Result<Tag, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize merge request body
+fn get_merge_request_body(request: &str) -> Result<MergeRequest, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//deserialize a list of ids or names from request body
+fn get_list_request_body<T: serde::de::DeserializeOwned>(request: &str) -> Result<Vec<T>, serde_json::Error> {
+    serde_json::from_str(request.split("\r\n\r\n").last().unwrap_or_default())
+}
+
+//nest genres under their parents
+fn build_tree(genres: Vec<Genre>, parent_id: Option<i32>) -> Vec<GenreNode> {
+    let (children, rest): (Vec<Genre>, Vec<Genre>) = genres.into_iter().partition(|genre| genre.parent_id == parent_id);
+    let mut rest = Some(rest);
+    children
+        .into_iter()
+        .map(|genre| {
+            let descendants = rest.take().unwrap_or_default();
+            let (nested, remaining) = split_subtree(descendants, genre.id);
+            rest = Some(remaining);
+            GenreNode { children: build_tree(nested, genre.id), genre }
+        })
+        .collect()
+}
+
+//separate the genres below a node from the others
+fn split_subtree(genres: Vec<Genre>, root: Option<i32>) -> (Vec<Genre>, Vec<Genre>) {
+    let mut inside = vec![root];
+    let mut nested = Vec::new();
+    let mut remaining = genres;
+    loop {
+        let (found, rest): (Vec<Genre>, Vec<Genre>) = remaining.into_iter().partition(|genre| inside.contains(&genre.parent_id));
+        remaining = rest;
+        if found.is_empty() {
+            return (nested, remaining);
+        }
+        inside.extend(found.iter().map(|genre| genre.id));
+        nested.extend(found);
+    }
+}
+
+//handle post genre request
+pub fn handle_post_genre_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_genre_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(mut genre), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            genre.id = None;
+            if let Err(e) = validate_genre(&mut transaction, &mut genre) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let row = match
+                transaction.query_one(
+                    "INSERT INTO genres (name, slug, parent_id) VALUES ($1, $2, $3) RETURNING id, name, slug, parent_id, version, created_at, updated_at, deleted_at",
+                    &[&genre.name, &genre.slug, &genre.parent_id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return write_error(e, "genre"),
+            };
+            let genre = genre_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "create", "genres", genre.id.unwrap_or_default(), None, Some(&genre)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&genre).unwrap())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get genre request
+pub fn handle_get_genre_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(include_deleted), Ok(mut client)) =>
+            match
+                client.query_one(
+                    "SELECT id, name, slug, parent_id, version, created_at, updated_at, deleted_at FROM genres WHERE id = $1 AND ($2 OR deleted_at IS NULL)",
+                    &[&id, &include_deleted]
+                )
+            {
+                Ok(row) => {
+                    let genre = genre_from_row(&row);
+
+                    //conditional GET
+                    let version = genre.version.unwrap_or_default();
+                    if not_modified(request, version) {
+                        return (with_header(NOT_MODIFIED, "ETag", &etag(version)), "".to_string());
+                    }
+
+                    (with_header(OK_RESPONSE, "ETag", &etag(version)), serde_json::to_string(&genre).unwrap())
+                }
+                _ => (NOT_FOUND.to_string(), "Genre not found".to_string()),
+            }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all genre request; ?tree=true nests subgenres under their parents
+pub fn handle_get_all_genre_request(request: &str) -> (String, String) {
+    match (get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(include_deleted), Ok(mut client)) => {
+            let genres: Vec<Genre> = match
+                client.query(
+                    "SELECT id, name, slug, parent_id, version, created_at, updated_at, deleted_at FROM genres WHERE ($1 OR deleted_at IS NULL) ORDER BY name, id",
+                    &[&include_deleted]
+                )
+            {
+                Ok(rows) => rows.iter().map(genre_from_row).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            if get_query_param(request, "tree").as_deref() == Some("true") {
+                return (OK_RESPONSE.to_string(), serde_json::to_string(&build_tree(genres, None)).unwrap());
+            }
+            (OK_RESPONSE.to_string(), serde_json::to_string(&genres).unwrap())
+        }
+        (Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle patch genre request
+pub fn handle_patch_genre_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_patch_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(patch), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let current = match
+                transaction.query_opt(
+                    "SELECT id, name, slug, parent_id, version, created_at, updated_at, deleted_at FROM genres WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => genre_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Genre not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, current.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            //a renamed genre gets a fresh slug unless one is supplied
+            let mut genre: Genre = match apply_merge_patch(&current, &patch) {
+                Ok(genre) => genre,
+                Err(e) => return (BAD_REQUEST.to_string(), format!("Invalid patch: {}", e)),
+            };
+            if genre.name != current.name && patch.get("slug").is_none() {
+                genre.slug = None;
+            }
+            genre.id = Some(id);
+            if let Err(e) = validate_genre(&mut transaction, &mut genre) {
+                return (BAD_REQUEST.to_string(), e);
+            }
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE genres SET name = $1, slug = $2, parent_id = $3, version = version + 1 WHERE id = $4 RETURNING id, name, slug, parent_id, version, created_at, updated_at, deleted_at",
+                    &[&genre.name, &genre.slug, &genre.parent_id, &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return write_error(e, "genre"),
+            };
+            let genre = genre_from_row(&row);
+
+            //books showing the old name as their primary genre follow the rename
+            if genre.name != current.name {
+                if let Err(e) = rename_book_genre(&mut transaction, context, &current.name, &genre.name) {
+                    return internal_error(e);
+                }
+            }
+            if let Err(e) = write_audit(&mut transaction, context, "update", "genres", id, Some(&current), Some(&genre)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(genre.version.unwrap_or_default())), serde_json::to_string(&genre).unwrap())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete genre request; genres with subgenres or books are kept unless cascading
+pub fn handle_delete_genre_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_cascade(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(cascade), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, name, slug, parent_id, version, created_at, updated_at, deleted_at FROM genres WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => genre_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Genre not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let subgenres: Vec<i32> = match
+                transaction.query("SELECT id FROM genres WHERE parent_id = $1 AND deleted_at IS NULL ORDER BY id", &[&id])
+            {
+                Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+                Err(e) => return internal_error(e),
+            };
+            let books: Vec<i32> = match
+                transaction.query("SELECT book_id FROM book_genres WHERE genre_id = $1 ORDER BY book_id", &[&id])
+            {
+                Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+                Err(e) => return internal_error(e),
+            };
+            if (!subgenres.is_empty() || !books.is_empty()) && !cascade {
+                let conflict = json!({ "error": "Genre is in use", "subgenre_ids": subgenres, "book_ids": books });
+                return (CONFLICT.to_string(), conflict.to_string());
+            }
+
+            //subgenres move up a level and books lose the genre
+            if let Err(e) = transaction.execute("UPDATE genres SET parent_id = $1 WHERE parent_id = $2", &[&before.parent_id, &id]) {
+                return internal_error(e);
+            }
+            if let Err(e) = transaction.execute("DELETE FROM book_genres WHERE genre_id = $1", &[&id]) {
+                return internal_error(e);
+            }
+            let row = match
+                transaction.query_one(
+                    "UPDATE genres SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, name, slug, parent_id, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = genre_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "genres", id, Some(&before), Some(&after)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), "Genre deleted".to_string())
+        }
+        (_, Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle merge genre request; books and subgenres move to the target and the source goes to the trash
+pub fn handle_merge_genre_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_merge_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(merge), Ok(mut client)) => {
+            if merge.into == id {
+                return (BAD_REQUEST.to_string(), "Cannot merge a genre into itself".to_string());
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let mut genres = Vec::new();
+            for genre_id in [id, merge.into] {
+                match
+                    transaction.query_opt(
+                        "SELECT id, name, slug, parent_id, version, created_at, updated_at, deleted_at FROM genres WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                        &[&genre_id]
+                    )
+                {
+                    Ok(Some(row)) => genres.push(genre_from_row(&row)),
+                    Ok(None) => return (NOT_FOUND.to_string(), format!("Genre {} not found", genre_id)),
+                    Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+                }
+            }
+            let target = genres.pop().unwrap();
+            let source = genres.pop().unwrap();
+
+            //check the If-Match precondition against the source genre
+            if precondition_failed(request, source.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+            match creates_cycle(&mut transaction, id, merge.into) {
+                Ok(true) => return (BAD_REQUEST.to_string(), "Cannot merge a genre into one of its subgenres".to_string()),
+                Ok(false) => {}
+                Err(e) => return internal_error(e),
+            }
+
+            if let Err(e) =
+                transaction.execute(
+                    "INSERT INTO book_genres (book_id, genre_id) SELECT book_id, $2 FROM book_genres WHERE genre_id = $1 ON CONFLICT DO NOTHING",
+                    &[&id, &merge.into]
+                )
+            {
+                return internal_error(e);
+            }
+            if let Err(e) = transaction.execute("DELETE FROM book_genres WHERE genre_id = $1", &[&id]) {
+                return internal_error(e);
+            }
+            if let Err(e) = transaction.execute("UPDATE genres SET parent_id = $2 WHERE parent_id = $1", &[&id, &merge.into]) {
+                return internal_error(e);
+            }
+            if let Err(e) = rename_book_genre(&mut transaction, context, &source.name, &target.name) {
+                return internal_error(e);
+            }
+            let row = match
+                transaction.query_one(
+                    "UPDATE genres SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, name, slug, parent_id, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "merge", "genres", id, Some(&source), Some(&genre_from_row(&row))).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&target).unwrap())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book genres request
+pub fn handle_get_book_genres_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let genres: Vec<Genre> = match
+                client.query(
+                    "SELECT g.id, g.name, g.slug, g.parent_id, g.version, g.created_at, g.updated_at, g.deleted_at FROM book_genres bg JOIN genres g ON g.id = bg.genre_id WHERE bg.book_id = $1 AND g.deleted_at IS NULL ORDER BY g.name",
+                    &[&id]
+                )
+            {
+                Ok(rows) => rows.iter().map(genre_from_row).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&genres).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put book genres request; the first genre becomes the book's primary genre
+pub fn handle_put_book_genres_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_list_request_body::<i32>(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(genre_ids), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked book
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let mut names = Vec::new();
+            for genre_id in &genre_ids {
+                match transaction.query_opt("SELECT name FROM genres WHERE id = $1 AND deleted_at IS NULL", &[genre_id]) {
+                    Ok(Some(row)) => names.push(row.get::<_, String>(0)),
+                    Ok(None) => return (BAD_REQUEST.to_string(), format!("Genre {} not found", genre_id)),
+                    Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+                }
+            }
+
+            let previous: Vec<i32> = match
+                transaction.query("SELECT genre_id FROM book_genres WHERE book_id = $1 ORDER BY genre_id", &[&id])
+            {
+                Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = transaction.execute("DELETE FROM book_genres WHERE book_id = $1", &[&id]) {
+                return internal_error(e);
+            }
+            for genre_id in &genre_ids {
+                if let Err(e) =
+                    transaction.execute("INSERT INTO book_genres (book_id, genre_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", &[&id, genre_id])
+                {
+                    return internal_error(e);
+                }
+            }
+
+            let genre = names.into_iter().next();
+            let row = match
+                transaction.query_one(
+                    "UPDATE books SET genre = $1, version = version + 1 WHERE id = $2 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+                    &[&genre, &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = book_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "books", id, Some(&before), Some(&after)) {
+                return internal_error(e);
+            }
+            let (previous, current) = (json!(previous), json!(genre_ids));
+            if let Err(e) = write_audit::<Value>(&mut transaction, context, "update", "book_genres", id, Some(&previous), Some(&current)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), current.to_string())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//find a live tag by name, creating it if needed
+pub fn find_or_create_tag(transaction: &mut Transaction, context: &RequestContext, name: &str) -> Result<i32, PostgresError> {
+    if let Some(row) = transaction.query_opt("SELECT id FROM tags WHERE lower(name) = lower($1) AND deleted_at IS NULL", &[&name])? {
+        return Ok(row.get(0));
+    }
+
+    let row = transaction.query_one(
+        "INSERT INTO tags (name) VALUES ($1) RETURNING id, name, version, created_at, updated_at, deleted_at",
+        &[&name]
+    )?;
+    let tag = tag_from_row(&row);
+    let id = tag.id.unwrap_or_default();
+    write_audit(transaction, context, "create", "tags", id, None, Some(&tag))?;
+    Ok(id)
+}
+
+//handle post tag request
+pub fn handle_post_tag_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_tag_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(tag), Ok(mut client)) => {
+            let name = tag.name.trim();
+            if name.is_empty() {
+                return (BAD_REQUEST.to_string(), "Tag name is required".to_string());
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let row = match
+                transaction.query_one(
+                    "INSERT INTO tags (name) VALUES ($1) RETURNING id, name, version, created_at, updated_at, deleted_at",
+                    &[&name]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return write_error(e, "tag"),
+            };
+            let tag = tag_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "create", "tags", tag.id.unwrap_or_default(), None, Some(&tag)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&tag).unwrap())
+        }
+        (Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get all tag request, with the number of live books carrying each tag
+pub fn handle_get_all_tag_request(request: &str) -> (String, String) {
+    match (get_include_deleted(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(include_deleted), Ok(mut client)) => {
+            let tags: Vec<Tag> = match
+                client.query(
+                    "SELECT t.id, t.name, t.version, t.created_at, t.updated_at, t.deleted_at, (SELECT count(*) FROM book_tags bt JOIN books b ON b.id = bt.book_id WHERE bt.tag_id = t.id AND b.deleted_at IS NULL) FROM tags t WHERE ($1 OR t.deleted_at IS NULL) ORDER BY lower(t.name)",
+                    &[&include_deleted]
+                )
+            {
+                Ok(rows) => rows.iter().map(|row| Tag { book_count: row.get(6), ..tag_from_row(row) }).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&tags).unwrap())
+        }
+        (Err(_), _) => (FORBIDDEN.to_string(), "Admin token required".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle patch tag request, used to rename a tag
+pub fn handle_patch_tag_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_patch_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(patch), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let current = match
+                transaction.query_opt(
+                    "SELECT id, name, version, created_at, updated_at, deleted_at FROM tags WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => tag_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Tag not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, current.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let tag: Tag = match apply_merge_patch(&current, &patch) {
+                Ok(tag) if !tag.name.trim().is_empty() => tag,
+                Ok(_) => return (BAD_REQUEST.to_string(), "Tag name is required".to_string()),
+                Err(e) => return (BAD_REQUEST.to_string(), format!("Invalid patch: {}", e)),
+            };
+
+            let row = match
+                transaction.query_one(
+                    "UPDATE tags SET name = $1, version = version + 1 WHERE id = $2 RETURNING id, name, version, created_at, updated_at, deleted_at",
+                    &[&tag.name.trim(), &id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return write_error(e, "tag"),
+            };
+            let tag = tag_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "tags", id, Some(&current), Some(&tag)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(tag.version.unwrap_or_default())), serde_json::to_string(&tag).unwrap())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle delete tag request; the tag comes off every book
+pub fn handle_delete_tag_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, name, version, created_at, updated_at, deleted_at FROM tags WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => tag_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Tag not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked row
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            if let Err(e) = transaction.execute("DELETE FROM book_tags WHERE tag_id = $1", &[&id]) {
+                return internal_error(e);
+            }
+            let row = match
+                transaction.query_one(
+                    "UPDATE tags SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, name, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "delete", "tags", id, Some(&before), Some(&tag_from_row(&row))).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), "Tag deleted".to_string())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle merge tag request; books move to the target tag and the source goes to the trash
+pub fn handle_merge_tag_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_merge_request_body(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(merge), Ok(mut client)) => {
+            if merge.into == id {
+                return (BAD_REQUEST.to_string(), "Cannot merge a tag into itself".to_string());
+            }
+
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let mut tags = Vec::new();
+            for tag_id in [id, merge.into] {
+                match
+                    transaction.query_opt(
+                        "SELECT id, name, version, created_at, updated_at, deleted_at FROM tags WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                        &[&tag_id]
+                    )
+                {
+                    Ok(Some(row)) => tags.push(tag_from_row(&row)),
+                    Ok(None) => return (NOT_FOUND.to_string(), format!("Tag {} not found", tag_id)),
+                    Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+                }
+            }
+            let target = tags.pop().unwrap();
+            let source = tags.pop().unwrap();
+
+            //check the If-Match precondition against the source tag
+            if precondition_failed(request, source.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            if let Err(e) =
+                transaction.execute(
+                    "INSERT INTO book_tags (book_id, tag_id) SELECT book_id, $2 FROM book_tags WHERE tag_id = $1 ON CONFLICT DO NOTHING",
+                    &[&id, &merge.into]
+                )
+            {
+                return internal_error(e);
+            }
+            if let Err(e) = transaction.execute("DELETE FROM book_tags WHERE tag_id = $1", &[&id]) {
+                return internal_error(e);
+            }
+            let row = match
+                transaction.query_one(
+                    "UPDATE tags SET deleted_at = now(), version = version + 1 WHERE id = $1 RETURNING id, name, version, created_at, updated_at, deleted_at",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = write_audit(&mut transaction, context, "merge", "tags", id, Some(&source), Some(&tag_from_row(&row))).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&target).unwrap())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle get book tags request
+pub fn handle_get_book_tags_request(request: &str) -> (String, String) {
+    match (get_id(request).parse::<i32>(), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(mut client)) => {
+            let tags: Vec<Tag> = match
+                client.query(
+                    "SELECT t.id, t.name, t.version, t.created_at, t.updated_at, t.deleted_at FROM book_tags bt JOIN tags t ON t.id = bt.tag_id WHERE bt.book_id = $1 AND t.deleted_at IS NULL ORDER BY lower(t.name)",
+                    &[&id]
+                )
+            {
+                Ok(rows) => rows.iter().map(tag_from_row).collect(),
+                Err(e) => return internal_error(e),
+            };
+
+            (OK_RESPONSE.to_string(), serde_json::to_string(&tags).unwrap())
+        }
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}
+
+//handle put book tags request; takes tag names and creates the ones that do not exist
+pub fn handle_put_book_tags_request(request: &str, context: &RequestContext) -> (String, String) {
+    match (get_id(request).parse::<i32>(), get_list_request_body::<String>(request), Client::connect(DB_URL, NoTls)) {
+        (Ok(id), Ok(names), Ok(mut client)) => {
+            let mut transaction = match client.transaction() {
+                Ok(transaction) => transaction,
+                Err(e) => return internal_error(e),
+            };
+            let before = match
+                transaction.query_opt(
+                    "SELECT id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition FROM books WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+                    &[&id]
+                )
+            {
+                Ok(Some(row)) => book_from_row(&row),
+                Ok(None) => return (NOT_FOUND.to_string(), "Book not found".to_string()),
+                Err(_) => return (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+            };
+
+            //check the If-Match precondition against the locked book
+            if precondition_failed(request, before.version.unwrap_or_default()) {
+                return (PRECONDITION_FAILED.to_string(), "Version mismatch".to_string());
+            }
+
+            let previous: Vec<String> = match
+                transaction.query("SELECT t.name FROM book_tags bt JOIN tags t ON t.id = bt.tag_id WHERE bt.book_id = $1 ORDER BY lower(t.name)", &[&id])
+            {
+                Ok(rows) => rows.iter().map(|row| row.get(0)).collect(),
+                Err(e) => return internal_error(e),
+            };
+            if let Err(e) = transaction.execute("DELETE FROM book_tags WHERE book_id = $1", &[&id]) {
+                return internal_error(e);
+            }
+            for name in names.iter().map(|name| name.trim()).filter(|name| !name.is_empty()) {
+                let tag_id = match find_or_create_tag(&mut transaction, context, name) {
+                    Ok(tag_id) => tag_id,
+                    Err(e) => return internal_error(e),
+                };
+                if let Err(e) =
+                    transaction.execute("INSERT INTO book_tags (book_id, tag_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", &[&id, &tag_id])
+                {
+                    return internal_error(e);
+                }
+            }
+
+            //a tag change is a new version of the book
+            let row = match
+                transaction.query_one(
+                    "UPDATE books SET version = version + 1 WHERE id = $1 RETURNING id, title, author, genre, isbn10, isbn13, version, created_at, updated_at, deleted_at, publisher, publication_year, page_count, language, format, edition",
+                    &[&id]
+                )
+            {
+                Ok(row) => row,
+                Err(e) => return internal_error(e),
+            };
+            let after = book_from_row(&row);
+            if let Err(e) = write_audit(&mut transaction, context, "update", "books", id, Some(&before), Some(&after)) {
+                return internal_error(e);
+            }
+            let (previous, current) = (json!(previous), json!(names));
+            if let Err(e) = write_audit::<Value>(&mut transaction, context, "update", "book_tags", id, Some(&previous), Some(&current)).and_then(|_| transaction.commit()) {
+                return internal_error(e);
+            }
+
+            (with_header(OK_RESPONSE, "ETag", &etag(after.version.unwrap_or_default())), current.to_string())
+        }
+        (_, Err(_), _) => (BAD_REQUEST.to_string(), "Invalid JSON".to_string()),
+        _ => (INTERNAL_ERROR.to_string(), "Internal error".to_string()),
+    }
+}